@@ -6,6 +6,32 @@ pub use isa::*;
 pub struct EmulationSettings {
     /// Print the each instruction being execute
     pub print_instructions: bool,
+    /// Handling of memory accesses and instruction fetches that aren't naturally aligned
+    pub misaligned: MisalignedPolicy,
+}
+
+/// Handling of misaligned memory accesses
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MisalignedPolicy {
+    /// Raise an address-misaligned exception
+    Trap,
+    /// Perform the access as if it was aligned, optionally counting the misaligned accesses
+    Emulate { count: bool },
+    /// Raise an access-fault exception
+    AccessFault,
+}
+
+/// Reason why the emulator stopped executing
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HaltReason {
+    /// The requested number of instructions was executed
+    InstructionLimit,
+    /// The CPU is waiting for an event that will never happen
+    Idle,
+    /// The guest requested to exit with the given exit status
+    Exit(u32),
+    /// The guest requested a reset
+    Reset,
 }
 
 pub trait CpuEmulator {
@@ -34,6 +60,7 @@ pub trait CpuEmulator {
 
     /// Execute the current machine code.
     /// 
-    /// `num_instructions` allows the user to set the amount of instructions to execute, `None` means no limit
-    fn execute(&mut self, num_instructions: Option<u32>);
+    /// `num_instructions` allows the user to set the amount of instructions to execute, `None` means no limit.
+    /// Returns the reason why execution stopped
+    fn execute(&mut self, num_instructions: Option<u32>) -> HaltReason;
 }
\ No newline at end of file
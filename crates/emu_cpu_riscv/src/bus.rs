@@ -0,0 +1,254 @@
+use std::ops::Range;
+use std::sync::Arc;
+
+use emu_cpu::HaltReason;
+
+use crate::devices::Device;
+use crate::fdt::{FdtContext, FdtNode};
+use crate::memory::{MemoryBus, SharedRam};
+
+
+/// Device mapped into the physical address space
+struct MappedDevice {
+    base: u64,
+    size: u64,
+    /// Interrupt source of the device on the interrupt controller
+    irq: Option<u32>,
+    device: Box<dyn Device>,
+}
+
+/// Size of a reservation set, which covers the largest access of LR
+const RESERVATION_SET_SIZE: u64 = 8;
+
+/// View of RAM handed to devices for DMA
+pub(crate) struct Ram<'a> {
+    data: &'a mut [u8],
+    base: u64,
+    /// RAM shared by harts executing on host threads, which is used instead of `data` while set
+    shared: Option<&'a SharedRam>,
+    reservations: &'a mut [Option<u64>],
+}
+
+impl<'a> Ram<'a> {
+    /// View of `data` mapped at `base`, tracking the reservations of `reservations.len()` harts
+    pub(crate) fn new(data: &'a mut [u8], base: u64, reservations: &'a mut [Option<u64>]) -> Self {
+        Self { data, base, shared: None, reservations }
+    }
+}
+
+impl MemoryBus for Ram<'_> {
+    fn read(&mut self, addr: u64, buf: &mut [u8]) -> bool {
+        if let Some(shared) = self.shared {
+            return shared.read(addr, buf);
+        }
+        addr.checked_sub(self.base).is_some_and(|offset| self.data.read(offset, buf))
+    }
+
+    fn write(&mut self, addr: u64, buf: &[u8]) -> bool {
+        invalidate_reservations(self.reservations, addr, buf.len());
+        if let Some(shared) = self.shared {
+            return shared.write(addr, buf);
+        }
+        addr.checked_sub(self.base).is_some_and(|offset| self.data.write(offset, buf))
+    }
+
+    fn reserve(&mut self, hart: usize, addr: u64) {
+        if let Some(reservation) = self.reservations.get_mut(hart) {
+            *reservation = Some(addr & !(RESERVATION_SET_SIZE - 1));
+        }
+    }
+
+    fn release_reservation(&mut self, hart: usize, addr: u64) -> bool {
+        release_reservation(self.reservations, hart, addr)
+    }
+}
+
+/// Release the reservation of a hart, returns `true` if it was still valid and covered `addr`
+fn release_reservation(reservations: &mut [Option<u64>], hart: usize, addr: u64) -> bool {
+    let reservation = reservations.get_mut(hart).and_then(Option::take);
+    reservation == Some(addr & !(RESERVATION_SET_SIZE - 1))
+}
+
+/// Invalidate the reservations on the reservation sets overlapping a write
+fn invalidate_reservations(reservations: &mut [Option<u64>], addr: u64, len: usize) {
+    for reservation in reservations {
+        if reservation.is_some_and(|set| set < addr.saturating_add(len as u64) && addr < set + RESERVATION_SET_SIZE) {
+            *reservation = None;
+        }
+    }
+}
+
+/// Physical address space shared by the harts, consisting of RAM and memory mapped devices
+pub struct Bus {
+    /// Main memory, mapped at `ram_base`
+    pub ram: Vec<u8>,
+    pub ram_base: u64,
+    /// RAM moved into shared storage while the harts execute on host threads, `ram` is empty while set
+    shared_ram: Option<Arc<SharedRam>>,
+
+    devices: Vec<MappedDevice>,
+    /// Reservation set of every hart, as the address of the set, which is invalidated by any write to the set
+    reservations: Vec<Option<u64>>,
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        Self { ram: Vec::new(), ram_base: 0, shared_ram: None, devices: Vec::new(), reservations: Vec::new() }
+    }
+
+    /// Physical addresses of RAM
+    pub fn ram_range(&self) -> Range<u64> {
+        self.ram_base..self.ram_base + self.ram.len() as u64
+    }
+
+    /// Move RAM into shared storage, which can be accessed by harts executing on host threads
+    pub(crate) fn share_ram(&mut self) -> Arc<SharedRam> {
+        let shared = Arc::new(SharedRam::new(std::mem::take(&mut self.ram), self.ram_base));
+        self.shared_ram = Some(shared.clone());
+        shared
+    }
+
+    /// Move RAM back out of shared storage, all other references to the shared RAM need to be dropped
+    pub(crate) fn unshare_ram(&mut self) {
+        if let Some(shared) = self.shared_ram.take() {
+            self.ram = Arc::into_inner(shared).expect("shared RAM is still referenced").into_vec();
+        }
+    }
+
+    /// Map a device at `base`, occupying `size` bytes of the address space
+    pub fn add_device(&mut self, base: u64, size: u64, device: Box<dyn Device>) {
+        self.map_device(base, size, None, device);
+    }
+
+    /// Map a device at `base`, occupying `size` bytes of the address space, with its interrupt routed to source `irq` of the interrupt controller
+    pub fn add_device_with_irq(&mut self, base: u64, size: u64, irq: u32, device: Box<dyn Device>) {
+        assert!(irq != 0, "irq 0 is reserved");
+        self.map_device(base, size, Some(irq), device);
+    }
+
+    fn map_device(&mut self, base: u64, size: u64, irq: Option<u32>, device: Box<dyn Device>) {
+        assert!(self.devices.iter().all(|mapped| base + size <= mapped.base || mapped.base + mapped.size <= base), "device at {base:X} overlaps with another device");
+        self.devices.push(MappedDevice { base, size, irq, device });
+    }
+
+    /// Advance all devices by a single tick of the virtual clock
+    pub fn tick(&mut self, retired: bool) {
+        let mut ram = Ram { data: &mut self.ram, base: self.ram_base, shared: self.shared_ram.as_deref(), reservations: &mut self.reservations };
+        for mapped in &mut self.devices {
+            mapped.device.tick(retired);
+            mapped.device.process_dma(&mut ram);
+        }
+        self.update_interrupt_sources();
+    }
+
+    /// Number of ticks until a device changes its interrupt lines while no instructions are retired
+    pub fn next_event(&self) -> Option<u64> {
+        self.devices.iter().filter_map(|mapped| mapped.device.next_event()).min()
+    }
+
+    /// Skip `ticks` ticks during which no instruction is retired, `ticks` may not exceed `next_event`
+    pub fn skip(&mut self, ticks: u64) {
+        for mapped in &mut self.devices {
+            mapped.device.skip(ticks);
+        }
+        self.update_interrupt_sources();
+    }
+
+    /// Route the interrupt levels of the devices to the interrupt controllers
+    pub fn update_interrupt_sources(&mut self) {
+        for idx in 0..self.devices.len() {
+            let Some(irq) = self.devices[idx].irq else { continue; };
+            let level = self.devices[idx].device.interrupt_level();
+            for mapped in &mut self.devices {
+                mapped.device.set_source_level(irq, level);
+            }
+        }
+    }
+
+    /// Interrupt lines driven by the devices on a hart, as `mip` bits
+    pub fn interrupt_lines(&self, hart: usize) -> u64 {
+        self.devices.iter().fold(0, |lines, mapped| lines | mapped.device.interrupt_lines(hart))
+    }
+
+    /// Value of the real-time counter of the timer on the bus, 0 without a timer
+    pub fn time(&self) -> u64 {
+        self.devices.iter().find_map(|mapped| mapped.device.time()).unwrap_or(0)
+    }
+
+    /// Take the first request of a device to halt the emulator
+    pub fn take_halt_request(&mut self) -> Option<HaltReason> {
+        self.devices.iter_mut().find_map(|mapped| mapped.device.take_halt_request())
+    }
+
+    /// Devicetree nodes of the devices on the bus, which are described using 2 address and size cells
+    pub fn devicetree_nodes(&self, fdt: &FdtContext) -> Vec<FdtNode> {
+        self.devices.iter().filter_map(|mapped| {
+            let mut node = mapped.device.devicetree_node(mapped.base, fdt)?;
+            node.property_u64s("reg", &[mapped.base, mapped.size]);
+            if let Some(irq) = mapped.irq {
+                node.property_u32("interrupts", irq);
+                node.property_u32("interrupt-parent", fdt.interrupt_parent());
+            }
+            Some(node)
+        }).collect()
+    }
+
+    /// Access the device at `addr`, handling any DMA transfers the access started
+    fn access_device(&mut self, addr: u64, len: usize, access: impl FnOnce(&mut dyn Device, u64) -> bool) -> bool {
+        let Some(mapped) = self.devices.iter_mut().find(|mapped| addr >= mapped.base && addr - mapped.base + len as u64 <= mapped.size) else {
+            return false;
+        };
+        if !access(mapped.device.as_mut(), addr - mapped.base) {
+            return false;
+        }
+        mapped.device.process_dma(&mut Ram { data: &mut self.ram, base: self.ram_base, shared: self.shared_ram.as_deref(), reservations: &mut self.reservations });
+
+        // Device accesses can change the interrupt levels, e.g. when acknowledging an interrupt
+        self.update_interrupt_sources();
+        true
+    }
+}
+
+impl Default for Bus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryBus for Bus {
+    fn read(&mut self, addr: u64, buf: &mut [u8]) -> bool {
+        if self.shared_ram.as_ref().is_some_and(|shared| shared.read(addr, buf)) {
+            return true;
+        }
+        if let Some(offset) = addr.checked_sub(self.ram_base) {
+            if self.ram.read(offset, buf) {
+                return true;
+            }
+        }
+        self.access_device(addr, buf.len(), |device, offset| device.read(offset, buf))
+    }
+
+    fn write(&mut self, addr: u64, buf: &[u8]) -> bool {
+        invalidate_reservations(&mut self.reservations, addr, buf.len());
+        if self.shared_ram.as_ref().is_some_and(|shared| shared.write(addr, buf)) {
+            return true;
+        }
+        if let Some(offset) = addr.checked_sub(self.ram_base) {
+            if self.ram.write(offset, buf) {
+                return true;
+            }
+        }
+        self.access_device(addr, buf.len(), |device, offset| device.write(offset, buf))
+    }
+
+    fn reserve(&mut self, hart: usize, addr: u64) {
+        if self.reservations.len() <= hart {
+            self.reservations.resize(hart + 1, None);
+        }
+        self.reservations[hart] = Some(addr & !(RESERVATION_SET_SIZE - 1));
+    }
+
+    fn release_reservation(&mut self, hart: usize, addr: u64) -> bool {
+        release_reservation(&mut self.reservations, hart, addr)
+    }
+}
@@ -0,0 +1,788 @@
+use crate::registers::{RegisterFile, PrivilegeMode};
+use crate::trap::Trap;
+
+
+// CSR addresses
+//
+// | 11 | 10 |  9 |  8 |  7 |  6 |  5 |  4 |  3 |  2 |  1 |  0 |
+// |   r/w   |   priv  |                 index                 |
+//
+// r/w: `11` means read-only, priv: lowest privilege level that can access the CSR (`10` being hypervisor level)
+
+// Unprivileged counters
+pub const CYCLE      : u16 = 0xC00;
+pub const TIME       : u16 = 0xC01;
+pub const INSTRET    : u16 = 0xC02;
+pub const CYCLEH     : u16 = 0xC80;
+pub const TIMEH      : u16 = 0xC81;
+pub const INSTRETH   : u16 = 0xC82;
+
+// Supervisor
+pub const SSTATUS    : u16 = 0x100;
+pub const SIE        : u16 = 0x104;
+pub const STVEC      : u16 = 0x105;
+pub const SCOUNTEREN : u16 = 0x106;
+pub const SENVCFG    : u16 = 0x10A;
+pub const SSCRATCH   : u16 = 0x140;
+pub const SEPC       : u16 = 0x141;
+pub const SCAUSE     : u16 = 0x142;
+pub const STVAL      : u16 = 0x143;
+pub const SIP        : u16 = 0x144;
+pub const SATP       : u16 = 0x180;
+
+// Hypervisor
+pub const HSTATUS    : u16 = 0x600;
+pub const HEDELEG    : u16 = 0x602;
+pub const HIDELEG    : u16 = 0x603;
+pub const HIE        : u16 = 0x604;
+pub const HTIMEDELTA : u16 = 0x605;
+pub const HCOUNTEREN : u16 = 0x606;
+pub const HGEIE      : u16 = 0x607;
+pub const HENVCFG    : u16 = 0x60A;
+pub const HTIMEDELTAH: u16 = 0x615;
+pub const HENVCFGH   : u16 = 0x61A;
+pub const HTVAL      : u16 = 0x643;
+pub const HIP        : u16 = 0x644;
+pub const HVIP       : u16 = 0x645;
+pub const HTINST     : u16 = 0x64A;
+pub const HGATP      : u16 = 0x680;
+pub const HGEIP      : u16 = 0xE12;
+
+// Virtual supervisor
+pub const VSSTATUS   : u16 = 0x200;
+pub const VSIE       : u16 = 0x204;
+pub const VSTVEC     : u16 = 0x205;
+pub const VSSCRATCH  : u16 = 0x240;
+pub const VSEPC      : u16 = 0x241;
+pub const VSCAUSE    : u16 = 0x242;
+pub const VSTVAL     : u16 = 0x243;
+pub const VSIP       : u16 = 0x244;
+pub const VSATP      : u16 = 0x280;
+
+// Machine
+pub const MVENDORID  : u16 = 0xF11;
+pub const MARCHID    : u16 = 0xF12;
+pub const MIMPID     : u16 = 0xF13;
+pub const MHARTID    : u16 = 0xF14;
+pub const MCONFIGPTR : u16 = 0xF15;
+pub const MSTATUS    : u16 = 0x300;
+pub const MISA       : u16 = 0x301;
+pub const MEDELEG    : u16 = 0x302;
+pub const MIDELEG    : u16 = 0x303;
+pub const MIE        : u16 = 0x304;
+pub const MTVEC      : u16 = 0x305;
+pub const MCOUNTEREN : u16 = 0x306;
+pub const MENVCFG    : u16 = 0x30A;
+pub const MSTATUSH   : u16 = 0x310;
+pub const MENVCFGH   : u16 = 0x31A;
+pub const MSCRATCH   : u16 = 0x340;
+pub const MEPC       : u16 = 0x341;
+pub const MCAUSE     : u16 = 0x342;
+pub const MTVAL      : u16 = 0x343;
+pub const MIP        : u16 = 0x344;
+pub const MTINST     : u16 = 0x34A;
+pub const MTVAL2     : u16 = 0x34B;
+pub const MCYCLE     : u16 = 0xB00;
+pub const MINSTRET   : u16 = 0xB02;
+pub const MCYCLEH    : u16 = 0xB80;
+pub const MINSTRETH  : u16 = 0xB82;
+
+
+// `mstatus` fields, using the RV64 layout (RV32 splits the upper half of into `mstatush`)
+pub const MSTATUS_SIE      : u64 = 1 << 1;
+pub const MSTATUS_MIE      : u64 = 1 << 3;
+pub const MSTATUS_SPIE     : u64 = 1 << 5;
+pub const MSTATUS_UBE      : u64 = 1 << 6;
+pub const MSTATUS_MPIE     : u64 = 1 << 7;
+pub const MSTATUS_SPP      : u64 = 1 << 8;
+pub const MSTATUS_MPP_SHIFT: u64 = 11;
+pub const MSTATUS_MPP      : u64 = 0x3 << MSTATUS_MPP_SHIFT;
+pub const MSTATUS_MPRV     : u64 = 1 << 17;
+pub const MSTATUS_SUM      : u64 = 1 << 18;
+pub const MSTATUS_MXR      : u64 = 1 << 19;
+pub const MSTATUS_TVM      : u64 = 1 << 20;
+pub const MSTATUS_TW       : u64 = 1 << 21;
+pub const MSTATUS_TSR      : u64 = 1 << 22;
+pub const MSTATUS_UXL      : u64 = 0x3 << 32;
+pub const MSTATUS_SXL      : u64 = 0x3 << 34;
+pub const MSTATUS_SBE      : u64 = 1 << 36;
+pub const MSTATUS_MBE      : u64 = 1 << 37;
+pub const MSTATUS_GVA      : u64 = 1 << 38;
+pub const MSTATUS_MPV      : u64 = 1 << 39;
+
+/// Fields of `mstatus` visible through `sstatus` (and `vsstatus`)
+pub const SSTATUS_MASK: u64 = MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_UBE | MSTATUS_SPP | MSTATUS_SUM | MSTATUS_MXR | MSTATUS_UXL;
+
+// `hstatus` fields
+pub const HSTATUS_VSBE     : u64 = 1 << 5;
+pub const HSTATUS_GVA      : u64 = 1 << 6;
+pub const HSTATUS_SPV      : u64 = 1 << 7;
+pub const HSTATUS_SPVP     : u64 = 1 << 8;
+pub const HSTATUS_HU       : u64 = 1 << 9;
+pub const HSTATUS_VTVM     : u64 = 1 << 20;
+pub const HSTATUS_VTW      : u64 = 1 << 21;
+pub const HSTATUS_VTSR     : u64 = 1 << 22;
+pub const HSTATUS_VSXL     : u64 = 0x3 << 32;
+
+/// Interrupt bits in `mip`/`mie` belonging to the virtual supervisor (VSSIP, VSTIP, VSEIP)
+pub const VS_INTERRUPTS: u64 = (1 << 2) | (1 << 6) | (1 << 10);
+/// Interrupt bits in `mip`/`mie` belonging to the supervisor (SSIP, STIP, SEIP)
+pub const S_INTERRUPTS: u64 = (1 << 1) | (1 << 5) | (1 << 9);
+/// Interrupt bits in `mip`/`mie` belonging to the machine (MSIP, MTIP, MEIP)
+pub const M_INTERRUPTS: u64 = (1 << 3) | (1 << 7) | (1 << 11);
+/// Supervisor guest external interrupt bit in `mip`/`mie`
+pub const SGEI: u64 = 1 << 12;
+
+// Counter bits in `mcounteren`, `hcounteren` and `scounteren`
+pub const COUNTEREN_CY: u64 = 1 << 0;
+pub const COUNTEREN_TM: u64 = 1 << 1;
+pub const COUNTEREN_IR: u64 = 1 << 2;
+
+/// Exceptions that can be delegated to HS-mode using `medeleg`, without the hypervisor extension
+const MEDELEG_MASK: u64 = 0xB3FF;
+/// Exceptions that can be delegated to HS-mode using `medeleg`, with the hypervisor extension
+const MEDELEG_MASK_H: u64 = 0xF0B7FF;
+/// Exceptions that can be delegated to VS-mode using `hedeleg`
+const HEDELEG_MASK: u64 = 0xB1FF;
+
+
+/// Get the `misa` bit for an extension letter
+pub const fn misa_bit(ext: char) -> u64 {
+    1 << (ext as u8 - b'A')
+}
+
+pub fn set_bits(val: u64, mask: u64, set: bool) -> u64 {
+    if set { val | mask } else { val & !mask }
+}
+
+/// Get the name of a CSR, as used by assemblers
+pub fn name(addr: u16) -> Option<&'static str> {
+    let name = match addr {
+        CYCLE       => "cycle",
+        TIME        => "time",
+        INSTRET     => "instret",
+        CYCLEH      => "cycleh",
+        TIMEH       => "timeh",
+        INSTRETH    => "instreth",
+        SSTATUS     => "sstatus",
+        SIE         => "sie",
+        STVEC       => "stvec",
+        SCOUNTEREN  => "scounteren",
+        SENVCFG     => "senvcfg",
+        SSCRATCH    => "sscratch",
+        SEPC        => "sepc",
+        SCAUSE      => "scause",
+        STVAL       => "stval",
+        SIP         => "sip",
+        SATP        => "satp",
+        HSTATUS     => "hstatus",
+        HEDELEG     => "hedeleg",
+        HIDELEG     => "hideleg",
+        HIE         => "hie",
+        HTIMEDELTA  => "htimedelta",
+        HCOUNTEREN  => "hcounteren",
+        HGEIE       => "hgeie",
+        HENVCFG     => "henvcfg",
+        HTIMEDELTAH => "htimedeltah",
+        HENVCFGH    => "henvcfgh",
+        HTVAL       => "htval",
+        HIP         => "hip",
+        HVIP        => "hvip",
+        HTINST      => "htinst",
+        HGATP       => "hgatp",
+        HGEIP       => "hgeip",
+        VSSTATUS    => "vsstatus",
+        VSIE        => "vsie",
+        VSTVEC      => "vstvec",
+        VSSCRATCH   => "vsscratch",
+        VSEPC       => "vsepc",
+        VSCAUSE     => "vscause",
+        VSTVAL      => "vstval",
+        VSIP        => "vsip",
+        VSATP       => "vsatp",
+        MVENDORID   => "mvendorid",
+        MARCHID     => "marchid",
+        MIMPID      => "mimpid",
+        MHARTID     => "mhartid",
+        MCONFIGPTR  => "mconfigptr",
+        MSTATUS     => "mstatus",
+        MISA        => "misa",
+        MEDELEG     => "medeleg",
+        MIDELEG     => "mideleg",
+        MIE         => "mie",
+        MTVEC       => "mtvec",
+        MCOUNTEREN  => "mcounteren",
+        MENVCFG     => "menvcfg",
+        MSTATUSH    => "mstatush",
+        MENVCFGH    => "menvcfgh",
+        MSCRATCH    => "mscratch",
+        MEPC        => "mepc",
+        MCAUSE      => "mcause",
+        MTVAL       => "mtval",
+        MIP         => "mip",
+        MTINST      => "mtinst",
+        MTVAL2      => "mtval2",
+        MCYCLE      => "mcycle",
+        MINSTRET    => "minstret",
+        MCYCLEH     => "mcycleh",
+        MINSTRETH   => "minstreth",
+        _ => return None,
+    };
+    Some(name)
+}
+
+
+/// Data endianness supported by a privilege mode, selecting the value and writability of its `*BE` bit
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Endianness {
+    /// Data accesses are little-endian, the `*BE` bit is read-only 0
+    #[default]
+    Little,
+    /// Data accesses are big-endian, the `*BE` bit is read-only 1
+    Big,
+    /// Software selects the endianness by writing the `*BE` bit, which resets to little-endian
+    Bi,
+}
+
+/// Storage for the control and status registers of a hart.
+///
+/// Registers that are a restricted view of another register (e.g. `sstatus` of `mstatus`) are not stored separately.
+#[derive(Clone)]
+pub struct CsrFile {
+    is_32_bit: bool,
+    /// `*BE` bits of `mstatus` that can be written by software
+    writable_be: u64,
+
+    pub mstatus:    u64,
+    pub misa:       u64,
+    pub medeleg:    u64,
+    pub mideleg:    u64,
+    pub mie:        u64,
+    pub mip:        u64,
+    /// Interrupt lines driven by devices, these read as pending in `mip` but can't be cleared by software
+    pub interrupt_lines: u64,
+    pub mtvec:      u64,
+    pub mcounteren: u64,
+    pub menvcfg:    u64,
+    pub mscratch:   u64,
+    pub mepc:       u64,
+    pub mcause:     u64,
+    pub mtval:      u64,
+    pub mtval2:     u64,
+    pub mtinst:     u64,
+    pub mhartid:    u64,
+    /// Ticks in which the hart executed, read through `cycle`
+    pub mcycle:     u64,
+    /// Instructions retired by the hart, read through `instret`
+    pub minstret:   u64,
+    /// Value of `mtime`, read through `time`, which the emulator updates along with the interrupt lines
+    pub mtime:      u64,
+
+    pub stvec:      u64,
+    pub scounteren: u64,
+    pub senvcfg:    u64,
+    pub sscratch:   u64,
+    pub sepc:       u64,
+    pub scause:     u64,
+    pub stval:      u64,
+    pub satp:       u64,
+
+    pub hstatus:    u64,
+    pub hedeleg:    u64,
+    pub hideleg:    u64,
+    pub hvip:       u64,
+    pub hcounteren: u64,
+    pub henvcfg:    u64,
+    pub htimedelta: u64,
+    pub htval:      u64,
+    pub htinst:     u64,
+    pub hgatp:      u64,
+
+    pub vsstatus:   u64,
+    pub vstvec:     u64,
+    pub vsscratch:  u64,
+    pub vsepc:      u64,
+    pub vscause:    u64,
+    pub vstval:     u64,
+    pub vsatp:      u64,
+}
+
+impl CsrFile {
+    pub fn new(is_32_bit: bool) -> Self {
+        let mut csr = Self {
+            is_32_bit,
+            writable_be: 0,
+            mstatus: 0,
+            misa: misa_bit('I') | misa_bit('U'),
+            medeleg: 0,
+            mideleg: 0,
+            mie: 0,
+            mip: 0,
+            interrupt_lines: 0,
+            mtvec: 0,
+            mcounteren: 0,
+            menvcfg: 0,
+            mscratch: 0,
+            mepc: 0,
+            mcause: 0,
+            mtval: 0,
+            mtval2: 0,
+            mtinst: 0,
+            mhartid: 0,
+            mcycle: 0,
+            minstret: 0,
+            mtime: 0,
+            stvec: 0,
+            scounteren: 0,
+            senvcfg: 0,
+            sscratch: 0,
+            sepc: 0,
+            scause: 0,
+            stval: 0,
+            satp: 0,
+            hstatus: 0,
+            hedeleg: 0,
+            hideleg: 0,
+            hvip: 0,
+            hcounteren: 0,
+            henvcfg: 0,
+            htimedelta: 0,
+            htval: 0,
+            htinst: 0,
+            hgatp: 0,
+            vsstatus: 0,
+            vstvec: 0,
+            vsscratch: 0,
+            vsepc: 0,
+            vscause: 0,
+            vstval: 0,
+            vsatp: 0,
+        };
+        csr.set_32_bit(is_32_bit);
+        csr
+    }
+
+    pub fn set_32_bit(&mut self, b: bool) {
+        self.is_32_bit = b;
+
+        // The XLEN of lower privilege modes is fixed to the XLEN of M-mode
+        let (mxl, xl) = if b { (1u64 << 30, 0) } else { (2u64 << 62, 2) };
+        self.misa = (self.misa & 0x3FF_FFFF) | mxl;
+        self.mstatus = (self.mstatus & !(MSTATUS_UXL | MSTATUS_SXL)) | (xl << 32) | (xl << 34);
+        self.vsstatus = (self.vsstatus & !MSTATUS_UXL) | (xl << 32);
+        self.hstatus = (self.hstatus & !HSTATUS_VSXL) | (xl << 32);
+    }
+
+    /// Set the data endianness of a privilege mode.
+    ///
+    /// The endianness of U-mode and S-mode also applies to VU-mode and VS-mode, through `vsstatus.UBE` and `hstatus.VSBE`
+    pub fn set_endianness(&mut self, mode: PrivilegeMode, endianness: Endianness) {
+        let bit = match mode {
+            PrivilegeMode::User       => MSTATUS_UBE,
+            PrivilegeMode::Supervisor => MSTATUS_SBE,
+            PrivilegeMode::Machine    => MSTATUS_MBE,
+        };
+        if endianness == Endianness::Bi {
+            self.writable_be |= bit;
+            return;
+        }
+
+        let big = endianness == Endianness::Big;
+        self.writable_be &= !bit;
+        self.mstatus = set_bits(self.mstatus, bit, big);
+        match mode {
+            PrivilegeMode::User       => self.vsstatus = set_bits(self.vsstatus, MSTATUS_UBE, big),
+            PrivilegeMode::Supervisor => self.hstatus = set_bits(self.hstatus, HSTATUS_VSBE, big),
+            PrivilegeMode::Machine    => {},
+        }
+    }
+
+    /// Check if the extension with the given letter is enabled in `misa`
+    pub fn has_extension(&self, ext: char) -> bool {
+        self.misa & misa_bit(ext) != 0
+    }
+
+    /// Get the value of `mip`, including the interrupt lines driven by devices and the virtual supervisor interrupts injected by the hypervisor using `hvip`
+    pub fn mip(&self) -> u64 {
+        self.mip | self.interrupt_lines | (self.hvip & VS_INTERRUPTS)
+    }
+
+    /// Get the value of `mideleg`, including the read-only virtual supervisor bits when the hypervisor extension is enabled
+    pub fn mideleg(&self) -> u64 {
+        if self.has_extension('H') { self.mideleg | VS_INTERRUPTS | SGEI } else { self.mideleg }
+    }
+
+    fn read(&self, addr: u16) -> u64 {
+        match addr {
+            CYCLE       => self.mcycle,
+            TIME        => self.mtime,
+            INSTRET     => self.minstret,
+            CYCLEH      => self.mcycle >> 32,
+            TIMEH       => self.mtime >> 32,
+            INSTRETH    => self.minstret >> 32,
+
+            SSTATUS     => self.mstatus & SSTATUS_MASK,
+            SIE         => self.mie & self.mideleg() & S_INTERRUPTS,
+            STVEC       => self.stvec,
+            SCOUNTEREN  => self.scounteren,
+            SENVCFG     => self.senvcfg,
+            SSCRATCH    => self.sscratch,
+            SEPC        => self.sepc,
+            SCAUSE      => self.scause,
+            STVAL       => self.stval,
+            SIP         => self.mip() & self.mideleg() & S_INTERRUPTS,
+            SATP        => self.satp,
+
+            HSTATUS     => self.hstatus,
+            HEDELEG     => self.hedeleg,
+            HIDELEG     => self.hideleg,
+            HIE         => self.mie & (VS_INTERRUPTS | SGEI),
+            HTIMEDELTA  => self.htimedelta,
+            HCOUNTEREN  => self.hcounteren,
+            HGEIE       => 0,
+            HENVCFG     => self.henvcfg,
+            HTIMEDELTAH => self.htimedelta >> 32,
+            HENVCFGH    => self.henvcfg >> 32,
+            HTVAL       => self.htval,
+            HIP         => self.mip() & (VS_INTERRUPTS | SGEI),
+            HVIP        => self.hvip,
+            HTINST      => self.htinst,
+            HGATP       => self.hgatp,
+            HGEIP       => 0,
+
+            VSSTATUS    => self.vsstatus,
+            VSIE        => (self.mie & self.hideleg & VS_INTERRUPTS) >> 1,
+            VSTVEC      => self.vstvec,
+            VSSCRATCH   => self.vsscratch,
+            VSEPC       => self.vsepc,
+            VSCAUSE     => self.vscause,
+            VSTVAL      => self.vstval,
+            VSIP        => (self.mip() & self.hideleg & VS_INTERRUPTS) >> 1,
+            VSATP       => self.vsatp,
+
+            MVENDORID   => 0,
+            MARCHID     => 0,
+            MIMPID      => 0,
+            MHARTID     => self.mhartid,
+            MCONFIGPTR  => 0,
+            MSTATUS     => self.mstatus,
+            MISA        => self.misa,
+            MEDELEG     => self.medeleg,
+            MIDELEG     => self.mideleg(),
+            MIE         => self.mie,
+            MTVEC       => self.mtvec,
+            MCOUNTEREN  => self.mcounteren,
+            MENVCFG     => self.menvcfg,
+            MSTATUSH    => self.mstatus >> 32,
+            MENVCFGH    => self.menvcfg >> 32,
+            MSCRATCH    => self.mscratch,
+            MEPC        => self.mepc,
+            MCAUSE      => self.mcause,
+            MTVAL       => self.mtval,
+            MIP         => self.mip(),
+            MTINST      => self.mtinst,
+            MTVAL2      => self.mtval2,
+            MCYCLE      => self.mcycle,
+            MINSTRET    => self.minstret,
+            MCYCLEH     => self.mcycle >> 32,
+            MINSTRETH   => self.minstret >> 32,
+            _ => unreachable!("CSR existence should be checked before reading"),
+        }
+    }
+
+    fn write(&mut self, addr: u16, value: u64) {
+        let has_s = self.has_extension('S');
+        let has_h = self.has_extension('H');
+
+        match addr {
+            SSTATUS => {
+                let mask = MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_SUM | MSTATUS_MXR | (self.writable_be & MSTATUS_UBE);
+                self.mstatus = (self.mstatus & !mask) | (value & mask);
+            },
+            SIE => {
+                let mask = self.mideleg() & S_INTERRUPTS;
+                self.mie = (self.mie & !mask) | (value & mask);
+            },
+            STVEC      => self.stvec = legalize_tvec(value),
+            SCOUNTEREN => self.scounteren = value & u32::MAX as u64,
+            SENVCFG    => self.senvcfg = value & 0x1,
+            SSCRATCH   => self.sscratch = value,
+            SEPC       => self.sepc = value & !1,
+            SCAUSE     => self.scause = value,
+            STVAL      => self.stval = value,
+            SIP => {
+                let mask = self.mideleg() & (1 << 1);
+                self.mip = (self.mip & !mask) | (value & mask);
+            },
+            SATP => if let Some(satp) = self.legalize_atp(value, false) {
+                self.satp = satp;
+            },
+
+            HSTATUS => {
+                let mut mask = HSTATUS_GVA | HSTATUS_SPV | HSTATUS_SPVP | HSTATUS_HU | HSTATUS_VTVM | HSTATUS_VTW | HSTATUS_VTSR;
+                if self.writable_be & MSTATUS_SBE != 0 {
+                    mask |= HSTATUS_VSBE;
+                }
+                self.hstatus = (self.hstatus & !mask) | (value & mask);
+            },
+            HEDELEG     => self.hedeleg = value & HEDELEG_MASK,
+            HIDELEG     => self.hideleg = value & VS_INTERRUPTS,
+            HIE         => self.mie = (self.mie & !VS_INTERRUPTS) | (value & VS_INTERRUPTS),
+            HTIMEDELTA  => self.htimedelta = if self.is_32_bit { (self.htimedelta & !(u32::MAX as u64)) | value } else { value },
+            HCOUNTEREN  => self.hcounteren = value & u32::MAX as u64,
+            HGEIE       => {}, // No guest external interrupts are supported (GEILEN = 0)
+            HENVCFG     => self.henvcfg = if self.is_32_bit { (self.henvcfg & !(u32::MAX as u64)) | (value & 0x1) } else { value & 0x1 },
+            HTIMEDELTAH => self.htimedelta = (self.htimedelta & u32::MAX as u64) | (value << 32),
+            HENVCFGH    => {},
+            HTVAL       => self.htval = value,
+            HIP         => self.hvip = (self.hvip & !(1 << 2)) | (value & (1 << 2)),
+            HVIP        => self.hvip = value & VS_INTERRUPTS,
+            HTINST      => self.htinst = value,
+            HGATP       => if let Some(hgatp) = self.legalize_atp(value, true) {
+                self.hgatp = hgatp;
+            },
+
+            VSSTATUS => {
+                let mask = MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_SUM | MSTATUS_MXR | (self.writable_be & MSTATUS_UBE);
+                self.vsstatus = (self.vsstatus & !mask) | (value & mask);
+            },
+            VSIE => {
+                let mask = self.hideleg & VS_INTERRUPTS;
+                self.mie = (self.mie & !mask) | ((value << 1) & mask);
+            },
+            VSTVEC    => self.vstvec = legalize_tvec(value),
+            VSSCRATCH => self.vsscratch = value,
+            VSEPC     => self.vsepc = value & !1,
+            VSCAUSE   => self.vscause = value,
+            VSTVAL    => self.vstval = value,
+            VSIP => {
+                let mask = self.hideleg & (1 << 2);
+                self.hvip = (self.hvip & !mask) | ((value << 1) & mask);
+            },
+            VSATP => if let Some(vsatp) = self.legalize_atp(value, false) {
+                self.vsatp = vsatp;
+            },
+
+            MSTATUS | MSTATUSH => {
+                let value = match addr {
+                    MSTATUS if self.is_32_bit => (self.mstatus & !(u32::MAX as u64)) | value,
+                    MSTATUS                   => value,
+                    _                         => (self.mstatus & u32::MAX as u64) | (value << 32),
+                };
+
+                let mut mask = MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP | MSTATUS_MPRV | (self.writable_be & (MSTATUS_MBE | MSTATUS_UBE));
+                if has_s {
+                    mask |= MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_SUM | MSTATUS_MXR | MSTATUS_TVM | MSTATUS_TW | MSTATUS_TSR | (self.writable_be & MSTATUS_SBE);
+                }
+                if has_h {
+                    mask |= MSTATUS_GVA | MSTATUS_MPV;
+                }
+
+                // MPP is WARL, keep the old value when an unsupported mode is written
+                let mpp = (value & MSTATUS_MPP) >> MSTATUS_MPP_SHIFT;
+                let value = match PrivilegeMode::from_bits(mpp) {
+                    Some(PrivilegeMode::Supervisor) if !has_s => (value & !MSTATUS_MPP) | (self.mstatus & MSTATUS_MPP),
+                    None                                      => (value & !MSTATUS_MPP) | (self.mstatus & MSTATUS_MPP),
+                    _                                         => value,
+                };
+
+                self.mstatus = (self.mstatus & !mask) | (value & mask);
+            },
+            MISA => {}, // Writes to `misa` are ignored, the enabled extensions are fixed by the emulator
+            MEDELEG => self.medeleg = value & if has_h { MEDELEG_MASK_H } else { MEDELEG_MASK },
+            MIDELEG => if has_s {
+                self.mideleg = value & S_INTERRUPTS;
+            },
+            MIE => {
+                let mut mask = M_INTERRUPTS;
+                if has_s {
+                    mask |= S_INTERRUPTS;
+                }
+                if has_h {
+                    mask |= VS_INTERRUPTS;
+                }
+                self.mie = value & mask;
+            },
+            MTVEC      => self.mtvec = legalize_tvec(value),
+            MCOUNTEREN => self.mcounteren = value & u32::MAX as u64,
+            MENVCFG    => self.menvcfg = if self.is_32_bit { (self.menvcfg & !(u32::MAX as u64)) | (value & 0x1) } else { value & 0x1 },
+            MENVCFGH   => {},
+            MSCRATCH   => self.mscratch = value,
+            MEPC       => self.mepc = value & !1,
+            MCAUSE     => self.mcause = value,
+            MTVAL      => self.mtval = value,
+            MIP => {
+                if has_s {
+                    self.mip = (self.mip & !S_INTERRUPTS) | (value & S_INTERRUPTS);
+                }
+                if has_h {
+                    self.hvip = (self.hvip & !(1 << 2)) | (value & (1 << 2));
+                }
+            },
+            MTINST     => self.mtinst = value,
+            MTVAL2     => self.mtval2 = value,
+            MCYCLE     => self.mcycle = if self.is_32_bit { (self.mcycle & !(u32::MAX as u64)) | value } else { value },
+            MINSTRET   => self.minstret = if self.is_32_bit { (self.minstret & !(u32::MAX as u64)) | value } else { value },
+            MCYCLEH    => self.mcycle = (self.mcycle & u32::MAX as u64) | (value << 32),
+            MINSTRETH  => self.minstret = (self.minstret & u32::MAX as u64) | (value << 32),
+            _ => unreachable!("CSR existence should be checked before writing"),
+        }
+    }
+
+    /// Legalize a write to `satp`, `vsatp` or `hgatp`, returns `None` if the write should be ignored because of an unsupported mode
+    fn legalize_atp(&self, value: u64, g_stage: bool) -> Option<u64> {
+        if self.is_32_bit {
+            // MODE: bit 31, ASID/VMID: bits 30:22, PPN: bits 21:0
+            let asid_mask = if g_stage { 0x7F << 22 } else { 0x1FF << 22 };
+            let ppn_mask = if g_stage { 0x3F_FFFC } else { 0x3F_FFFF };
+            Some(value & ((1 << 31) | asid_mask | ppn_mask))
+        } else {
+            // MODE: bits 63:60, ASID/VMID: bits 59:44, PPN: bits 43:0
+            match value >> 60 {
+                0 | 8 | 9 => {},
+                _ => return None,
+            }
+            let asid_mask = if g_stage { 0x3FFF << 44 } else { 0xFFFF << 44 };
+            let ppn_mask = if g_stage { 0xFFF_FFFF_FFFC } else { 0xFFF_FFFF_FFFF };
+            Some(value & ((0xF << 60) | asid_mask | ppn_mask))
+        }
+    }
+}
+
+fn legalize_tvec(value: u64) -> u64 {
+    // Only direct (0) and vectored (1) modes are supported
+    if value & 0x3 >= 2 { value & !0x3 } else { value }
+}
+
+/// Check if a CSR is implemented
+fn csr_exists(csr: &CsrFile, addr: u16) -> bool {
+    let has_s = csr.has_extension('S');
+    let has_h = csr.has_extension('H');
+
+    match addr {
+        CYCLE | TIME | INSTRET => true,
+        CYCLEH | TIMEH | INSTRETH => csr.is_32_bit,
+
+        SSTATUS | SIE | STVEC | SCOUNTEREN | SENVCFG | SSCRATCH | SEPC | SCAUSE | STVAL | SIP | SATP => has_s,
+
+        HSTATUS | HEDELEG | HIDELEG | HIE | HTIMEDELTA | HCOUNTEREN | HGEIE | HENVCFG | HTVAL | HIP | HVIP | HTINST | HGATP | HGEIP |
+        VSSTATUS | VSIE | VSTVEC | VSSCRATCH | VSEPC | VSCAUSE | VSTVAL | VSIP | VSATP => has_h,
+        HTIMEDELTAH | HENVCFGH => has_h && csr.is_32_bit,
+
+        MVENDORID | MARCHID | MIMPID | MHARTID | MCONFIGPTR | MSTATUS | MISA | MIE | MTVEC | MCOUNTEREN |
+        MENVCFG | MSCRATCH | MEPC | MCAUSE | MTVAL | MIP | MCYCLE | MINSTRET => true,
+        MEDELEG | MIDELEG => has_s,
+        MSTATUSH | MENVCFGH | MCYCLEH | MINSTRETH => csr.is_32_bit,
+        MTINST | MTVAL2 => has_h,
+        _ => false,
+    }
+}
+
+/// Get the CSR accessed by VS-mode instead of the given supervisor CSR
+fn virtual_supervisor_csr(addr: u16) -> u16 {
+    match addr {
+        SSTATUS  => VSSTATUS,
+        SIE      => VSIE,
+        STVEC    => VSTVEC,
+        SSCRATCH => VSSCRATCH,
+        SEPC     => VSEPC,
+        SCAUSE   => VSCAUSE,
+        STVAL    => VSTVAL,
+        SIP      => VSIP,
+        SATP     => VSATP,
+        _        => addr,
+    }
+}
+
+/// Get the bit of a counter in `mcounteren`, `hcounteren` and `scounteren`, `None` if the CSR isn't an unprivileged counter
+fn counter_bit(addr: u16) -> Option<u64> {
+    match addr {
+        CYCLE | CYCLEH     => Some(COUNTEREN_CY),
+        TIME | TIMEH       => Some(COUNTEREN_TM),
+        INSTRET | INSTRETH => Some(COUNTEREN_IR),
+        _                  => None,
+    }
+}
+
+/// Check if the current privilege mode can access a CSR, returns the address of the CSR that is actually accessed.
+fn check_access(register_file: &RegisterFile, addr: u16, write: bool) -> Result<u16, Trap> {
+    let csr = register_file.csr();
+    let privilege = register_file.privilege();
+    let virtualized = register_file.is_virtualized();
+
+    if !csr_exists(csr, addr) {
+        return Err(Trap::illegal_instruction());
+    }
+    if write && (addr >> 10) & 0x3 == 0x3 {
+        return Err(Trap::illegal_instruction());
+    }
+
+    // HS-mode is able to access hypervisor-level CSRs
+    let level = match privilege {
+        PrivilegeMode::Machine                       => 3,
+        PrivilegeMode::Supervisor if !virtualized    => 2,
+        PrivilegeMode::Supervisor                    => 1,
+        PrivilegeMode::User                          => 0,
+    };
+    let required = (addr >> 8) & 0x3;
+    if level < required {
+        // Accesses from VS/VU-mode that would be allowed from HS-mode raise a virtual instruction exception instead
+        return Err(if virtualized && required <= 2 { Trap::virtual_instruction() } else { Trap::illegal_instruction() });
+    }
+
+    // Below M-mode, a counter needs to be enabled in `mcounteren`, in VS-mode and VU-mode also in `hcounteren`, and in (V)U-mode also in `scounteren`
+    if let Some(bit) = counter_bit(addr) {
+        let user = privilege == PrivilegeMode::User && csr.has_extension('S');
+        if privilege != PrivilegeMode::Machine && csr.mcounteren & bit == 0 {
+            return Err(Trap::illegal_instruction());
+        }
+        if (user && csr.scounteren & bit == 0) || (virtualized && csr.hcounteren & bit == 0) {
+            return Err(if virtualized { Trap::virtual_instruction() } else { Trap::illegal_instruction() });
+        }
+    }
+
+    match addr {
+        SATP if virtualized && csr.hstatus & HSTATUS_VTVM != 0 => Err(Trap::virtual_instruction()),
+        SATP | HGATP if privilege == PrivilegeMode::Supervisor && !virtualized && csr.mstatus & MSTATUS_TVM != 0 => Err(Trap::illegal_instruction()),
+        _ if virtualized => Ok(virtual_supervisor_csr(addr)),
+        _ => Ok(addr),
+    }
+}
+
+fn truncate(register_file: &RegisterFile, value: u64) -> u64 {
+    if register_file.is_32_bit() { value & u32::MAX as u64 } else { value }
+}
+
+/// Read a CSR after its access was checked, the time of VS-mode and VU-mode is offset by `htimedelta`
+fn read_checked(register_file: &RegisterFile, addr: u16) -> u64 {
+    let csr = register_file.csr();
+    let value = match addr {
+        TIME if register_file.is_virtualized()  => csr.mtime.wrapping_add(csr.htimedelta),
+        TIMEH if register_file.is_virtualized() => csr.mtime.wrapping_add(csr.htimedelta) >> 32,
+        _ => csr.read(addr),
+    };
+    truncate(register_file, value)
+}
+
+/// Read a CSR from the current privilege mode
+pub fn read(register_file: &RegisterFile, addr: u16) -> Result<u64, Trap> {
+    let addr = check_access(register_file, addr, false)?;
+    Ok(read_checked(register_file, addr))
+}
+
+/// Write a CSR from the current privilege mode
+pub fn write(register_file: &mut RegisterFile, addr: u16, value: u64) -> Result<(), Trap> {
+    let addr = check_access(register_file, addr, true)?;
+    let value = truncate(register_file, value);
+    register_file.csr_mut().write(addr, value);
+    Ok(())
+}
+
+/// Read and write a CSR from the current privilege mode, returning the old value
+pub fn read_write(register_file: &mut RegisterFile, addr: u16, f: impl FnOnce(u64) -> u64) -> Result<u64, Trap> {
+    let addr = check_access(register_file, addr, true)?;
+    let old = read_checked(register_file, addr);
+    let value = truncate(register_file, f(old));
+    register_file.csr_mut().write(addr, value);
+    Ok(old)
+}
@@ -0,0 +1,100 @@
+use emu_cpu::HaltReason;
+
+use crate::fdt::{FdtContext, FdtNode};
+use crate::memory::MemoryBus;
+
+mod clint;
+pub use clint::*;
+mod finisher;
+pub use finisher::*;
+mod framebuffer;
+pub use framebuffer::*;
+mod htif;
+pub use htif::*;
+mod net;
+pub use net::*;
+mod plic;
+pub use plic::*;
+mod rtc;
+pub use rtc::*;
+mod serial;
+pub use serial::*;
+mod uart;
+pub use uart::*;
+mod virtio;
+pub use virtio::*;
+
+#[cfg(test)]
+mod tests;
+
+
+/// Device mapped into the physical address space of the harts.
+///
+/// Devices need to be `Send`, as harts executing on separate host threads access them through a lock
+pub trait Device: Send {
+    /// Read `buf.len()` bytes at `offset` into the device's region, returns `false` if the access is not supported
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> bool;
+
+    /// Write `buf.len()` bytes at `offset` into the device's region, returns `false` if the access is not supported
+    fn write(&mut self, offset: u64, buf: &[u8]) -> bool;
+
+    /// Advance the device by a single tick of the virtual clock, `retired` is set when an instruction was retired during the tick
+    fn tick(&mut self, _retired: bool) {}
+
+    /// Number of ticks after which the device changes its interrupt lines when no instructions are retired, `None` if it won't change on its own
+    fn next_event(&self) -> Option<u64> {
+        None
+    }
+
+    /// Skip `ticks` ticks during which no instruction is retired
+    fn skip(&mut self, _ticks: u64) {}
+
+    /// Perform the DMA transfers started by the device, called after every access to the device and every tick
+    fn process_dma(&mut self, _memory: &mut dyn MemoryBus) {}
+
+    /// Interrupt lines driven by the device on a hart, as `mip` bits
+    fn interrupt_lines(&self, _hart: usize) -> u64 {
+        0
+    }
+
+    /// Level of the interrupt source driven by the device, which is routed to the interrupt controller when the device is mapped with an irq
+    fn interrupt_level(&self) -> bool {
+        false
+    }
+
+    /// Update the level of an interrupt source, only used by interrupt controllers
+    fn set_source_level(&mut self, _source: u32, _level: bool) {}
+
+    /// Value of the real-time counter kept by the device, which the harts read through the `time` CSR. `None` if the device doesn't keep time
+    fn time(&self) -> Option<u64> {
+        None
+    }
+
+    /// Take the request of the guest to halt the emulator, if the device received one
+    fn take_halt_request(&mut self) -> Option<HaltReason> {
+        None
+    }
+
+    /// Node describing the device in the devicetree, the `reg`, `interrupts` and `interrupt-parent` properties are added by the bus. `None` if the device isn't described
+    fn devicetree_node(&self, _base: u64, _fdt: &FdtContext) -> Option<FdtNode> {
+        None
+    }
+}
+
+/// Read the bytes at `offset_in_reg` of a little-endian register into `buf`
+fn read_register(value: u64, offset_in_reg: u64, buf: &mut [u8]) -> bool {
+    let bytes = value.to_le_bytes();
+    let start = offset_in_reg as usize;
+    match bytes.get(start..start + buf.len()) {
+        Some(src) => { buf.copy_from_slice(src); true },
+        None => false,
+    }
+}
+
+/// Write `buf` to the bytes at `offset_in_reg` of a little-endian register, returns the updated register value
+fn write_register(value: u64, offset_in_reg: u64, buf: &[u8]) -> Option<u64> {
+    let mut bytes = value.to_le_bytes();
+    let start = offset_in_reg as usize;
+    bytes.get_mut(start..start + buf.len())?.copy_from_slice(buf);
+    Some(u64::from_le_bytes(bytes))
+}
@@ -0,0 +1,181 @@
+use crate::fdt::{FdtContext, FdtNode};
+use crate::trap::Interrupt;
+
+use super::{Device, read_register, write_register};
+
+
+// Memory map (SiFive CLINT, compatible with the ACLINT MSWI and MTIMER devices)
+//
+// | offset                | register             |
+// |-----------------------|----------------------|
+// | 0x0000 + 4 * hart     | msip (32-bit)        |
+// | 0x4000 + 8 * hart     | mtimecmp (64-bit)    |
+// | 0xBFF8                | mtime (64-bit)       |
+
+const MSIP_BASE: u64 = 0x0000;
+const MTIMECMP_BASE: u64 = 0x4000;
+const MTIME: u64 = 0xBFF8;
+
+/// Size of the CLINT region on the bus
+pub const CLINT_SIZE: u64 = 0x10000;
+
+/// Source `mtime` is derived from
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Timebase {
+    /// `mtime` is incremented for every retired instruction. When no instructions are retired, e.g. all harts are waiting for an interrupt, time jumps ahead to the next timer interrupt
+    InstructionsRetired,
+    /// `mtime` is incremented once every `divider` ticks of the virtual clock, whether instructions are retired or not
+    VirtualClock { divider: u64 },
+}
+
+/// Core-local interruptor, providing the machine timer and software interrupts of each hart
+pub struct Clint {
+    timebase: Timebase,
+    mtime: u64,
+    /// Ticks since `mtime` was last incremented, when using a virtual clock
+    sub_ticks: u64,
+    msip: Vec<u32>,
+    mtimecmp: Vec<u64>,
+}
+
+impl Clint {
+    pub fn new(num_harts: usize, timebase: Timebase) -> Self {
+        if let Timebase::VirtualClock { divider } = timebase {
+            assert!(divider != 0, "virtual clock divider can't be 0");
+        }
+
+        Self {
+            timebase,
+            mtime: 0,
+            sub_ticks: 0,
+            msip: vec![0; num_harts],
+            // mtimecmp is reset to the max value, so no timer interrupt is pending
+            mtimecmp: vec![u64::MAX; num_harts],
+        }
+    }
+
+    pub fn mtime(&self) -> u64 {
+        self.mtime
+    }
+
+    /// Earliest `mtimecmp` that has not been reached yet, a `mtimecmp` at the max value is treated as disabled
+    fn next_mtimecmp(&self) -> Option<u64> {
+        self.mtimecmp.iter().copied().filter(|&cmp| cmp > self.mtime && cmp != u64::MAX).min()
+    }
+}
+
+impl Device for Clint {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> bool {
+        let num_harts = self.msip.len() as u64;
+        match offset {
+            MSIP_BASE..=0x3FFF => {
+                let hart = (offset - MSIP_BASE) / 4;
+                hart < num_harts && offset % 4 + buf.len() as u64 <= 4 && read_register(self.msip[hart as usize] as u64, offset % 4, buf)
+            },
+            MTIMECMP_BASE..=0xBFF7 => {
+                let hart = (offset - MTIMECMP_BASE) / 8;
+                hart < num_harts && read_register(self.mtimecmp[hart as usize], offset % 8, buf)
+            },
+            MTIME..=0xBFFF => read_register(self.mtime, offset - MTIME, buf),
+            _ => false,
+        }
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> bool {
+        let num_harts = self.msip.len() as u64;
+        match offset {
+            MSIP_BASE..=0x3FFF => {
+                let hart = ((offset - MSIP_BASE) / 4) as usize;
+                if hart as u64 >= num_harts || offset % 4 + buf.len() as u64 > 4 {
+                    return false;
+                }
+                // Only bit 0 is writable
+                match write_register(self.msip[hart] as u64, offset % 4, buf) {
+                    Some(value) => { self.msip[hart] = value as u32 & 1; true },
+                    None => false,
+                }
+            },
+            MTIMECMP_BASE..=0xBFF7 => {
+                let hart = ((offset - MTIMECMP_BASE) / 8) as usize;
+                if hart as u64 >= num_harts {
+                    return false;
+                }
+                match write_register(self.mtimecmp[hart], offset % 8, buf) {
+                    Some(value) => { self.mtimecmp[hart] = value; true },
+                    None => false,
+                }
+            },
+            MTIME..=0xBFFF => match write_register(self.mtime, offset - MTIME, buf) {
+                Some(value) => { self.mtime = value; true },
+                None => false,
+            },
+            _ => false,
+        }
+    }
+
+    fn tick(&mut self, retired: bool) {
+        match self.timebase {
+            Timebase::InstructionsRetired => if retired {
+                self.mtime = self.mtime.wrapping_add(1);
+            },
+            Timebase::VirtualClock { divider } => {
+                self.sub_ticks += 1;
+                if self.sub_ticks == divider {
+                    self.sub_ticks = 0;
+                    self.mtime = self.mtime.wrapping_add(1);
+                }
+            },
+        }
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        let mtimecmp = self.next_mtimecmp()?;
+        match self.timebase {
+            Timebase::InstructionsRetired => Some(1),
+            Timebase::VirtualClock { divider } => Some((mtimecmp - self.mtime).saturating_mul(divider) - self.sub_ticks),
+        }
+    }
+
+    fn skip(&mut self, ticks: u64) {
+        match self.timebase {
+            // Time doesn't advance without retired instructions, so skipping jumps straight to the next timer interrupt
+            Timebase::InstructionsRetired => if ticks != 0 {
+                if let Some(mtimecmp) = self.next_mtimecmp() {
+                    self.mtime = mtimecmp;
+                }
+            },
+            Timebase::VirtualClock { divider } => {
+                let ticks = self.sub_ticks + ticks;
+                self.mtime = self.mtime.wrapping_add(ticks / divider);
+                self.sub_ticks = ticks % divider;
+            },
+        }
+    }
+
+    fn devicetree_node(&self, base: u64, fdt: &FdtContext) -> Option<FdtNode> {
+        let interrupts = (0..self.msip.len()).flat_map(|hart| {
+            let intc = fdt.cpu_intc_phandle(hart);
+            [intc, Interrupt::MachineSoftware.code() as u32, intc, Interrupt::MachineTimer.code() as u32]
+        }).collect::<Vec<_>>();
+
+        let mut node = FdtNode::new(format!("clint@{base:x}"));
+        node.property_strings("compatible", &["sifive,clint0", "riscv,clint0"])
+            .property_cells("interrupts-extended", &interrupts);
+        Some(node)
+    }
+
+    fn interrupt_lines(&self, hart: usize) -> u64 {
+        let mut lines = 0;
+        if self.msip.get(hart).is_some_and(|&msip| msip & 1 != 0) {
+            lines |= Interrupt::MachineSoftware.mask();
+        }
+        if self.mtimecmp.get(hart).is_some_and(|&mtimecmp| self.mtime >= mtimecmp) {
+            lines |= Interrupt::MachineTimer.mask();
+        }
+        lines
+    }
+
+    fn time(&self) -> Option<u64> {
+        Some(self.mtime)
+    }
+}
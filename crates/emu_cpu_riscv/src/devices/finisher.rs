@@ -0,0 +1,75 @@
+use emu_cpu::HaltReason;
+
+use crate::fdt::{FdtContext, FdtNode};
+
+use super::{Device, write_register};
+
+
+const FINISHER_FAIL: u32 = 0x3333;
+const FINISHER_PASS: u32 = 0x5555;
+const FINISHER_RESET: u32 = 0x7777;
+
+/// Size of the test finisher region on the bus
+pub const FINISHER_SIZE: u64 = 0x1000;
+
+/// SiFive test finisher, allowing the guest to stop the emulator, as used by the qemu `virt` machine.
+///
+/// The low 16 bits of the 32-bit register at offset 0 select the command, with the high 16 bits being the exit status on a failure:
+/// - `0x5555`: pass, exit with status 0
+/// - `0x3333`: fail, exit with the status in the high 16 bits
+/// - `0x7777`: reset
+///
+/// This is also the register used by the `syscon-poweroff` and `syscon-reboot` nodes of the devicetree, with a poweroff being a pass
+pub struct TestFinisher {
+    halt: Option<HaltReason>,
+}
+
+impl TestFinisher {
+    pub fn new() -> Self {
+        Self { halt: None }
+    }
+}
+
+impl Default for TestFinisher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Device for TestFinisher {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> bool {
+        if offset + buf.len() as u64 > 4 {
+            return false;
+        }
+        // The register is write-only and reads as 0
+        buf.fill(0);
+        true
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> bool {
+        if offset + buf.len() as u64 > 4 {
+            return false;
+        }
+        let Some(value) = write_register(0, offset, buf) else { return false; };
+
+        let value = value as u32;
+        self.halt = match value & 0xFFFF {
+            FINISHER_PASS  => Some(HaltReason::Exit(0)),
+            FINISHER_FAIL  => Some(HaltReason::Exit(value >> 16)),
+            FINISHER_RESET => Some(HaltReason::Reset),
+            // Unknown commands are ignored
+            _ => self.halt,
+        };
+        true
+    }
+
+    fn take_halt_request(&mut self) -> Option<HaltReason> {
+        self.halt.take()
+    }
+
+    fn devicetree_node(&self, base: u64, _fdt: &FdtContext) -> Option<FdtNode> {
+        let mut node = FdtNode::new(format!("test@{base:x}"));
+        node.property_strings("compatible", &["sifive,test1", "sifive,test0", "syscon"]);
+        Some(node)
+    }
+}
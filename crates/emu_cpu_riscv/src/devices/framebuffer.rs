@@ -0,0 +1,220 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::fdt::{FdtContext, FdtNode};
+
+use super::Device;
+
+
+/// Pixel format of the framebuffer, named as in the `simple-framebuffer` devicetree binding
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PixelFormat {
+    /// 16-bit, red in bits 15:11, green in bits 10:5, blue in bits 4:0
+    R5G6B5,
+    /// 32-bit, red in bits 23:16, green in bits 15:8, blue in bits 7:0, bits 31:24 are ignored
+    X8R8G8B8,
+    /// 32-bit, alpha in bits 31:24, red in bits 23:16, green in bits 15:8, blue in bits 7:0
+    A8R8G8B8,
+    /// 32-bit, alpha in bits 31:24, blue in bits 23:16, green in bits 15:8, red in bits 7:0
+    A8B8G8R8,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::R5G6B5 => 2,
+            _                   => 4,
+        }
+    }
+
+    /// Name used in the `format` property of a `simple-framebuffer` node
+    pub fn name(self) -> &'static str {
+        match self {
+            PixelFormat::R5G6B5   => "r5g6b5",
+            PixelFormat::X8R8G8B8 => "x8r8g8b8",
+            PixelFormat::A8R8G8B8 => "a8r8g8b8",
+            PixelFormat::A8B8G8R8 => "a8b8g8r8",
+        }
+    }
+
+    /// Convert a pixel stored in little-endian to RGB
+    fn to_rgb(self, pixel: &[u8]) -> [u8; 3] {
+        match self {
+            PixelFormat::R5G6B5 => {
+                let value = u16::from_le_bytes([pixel[0], pixel[1]]);
+                let r = (value >> 11) as u8 & 0x1F;
+                let g = (value >> 5) as u8 & 0x3F;
+                let b = value as u8 & 0x1F;
+                [(r << 3) | (r >> 2), (g << 2) | (g >> 4), (b << 3) | (b >> 2)]
+            },
+            PixelFormat::X8R8G8B8 | PixelFormat::A8R8G8B8 => [pixel[2], pixel[1], pixel[0]],
+            PixelFormat::A8B8G8R8                         => [pixel[0], pixel[1], pixel[2]],
+        }
+    }
+}
+
+/// Linear framebuffer, with rows of `stride` bytes.
+///
+/// Clones share the same pixel data, so a frame can be saved after the framebuffer is mapped on the bus
+#[derive(Clone)]
+pub struct Framebuffer {
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+    data: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Framebuffer {
+    pub fn new(width: u32, height: u32, format: PixelFormat) -> Self {
+        let size = width as usize * height as usize * format.bytes_per_pixel();
+        Self { width, height, format, data: Arc::new(Mutex::new(vec![0; size])) }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    /// Size of a row in bytes
+    pub fn stride(&self) -> u32 {
+        self.width * self.format.bytes_per_pixel() as u32
+    }
+
+    /// Size of the framebuffer region on the bus
+    pub fn size(&self) -> u64 {
+        self.stride() as u64 * self.height as u64
+    }
+
+    /// Convert the current frame to 8-bit RGB
+    pub fn to_rgb(&self) -> Vec<u8> {
+        let data = self.data.lock().unwrap();
+        data.chunks(self.format.bytes_per_pixel()).flat_map(|pixel| self.format.to_rgb(pixel)).collect()
+    }
+
+    /// Write the current frame as a binary PPM image
+    pub fn write_ppm(&self, writer: &mut dyn Write) -> io::Result<()> {
+        write!(writer, "P6\n{} {}\n255\n", self.width, self.height)?;
+        writer.write_all(&self.to_rgb())
+    }
+
+    /// Write the current frame as a PNG image
+    pub fn write_png(&self, writer: &mut dyn Write) -> io::Result<()> {
+        let rgb = self.to_rgb();
+        let row_size = self.width as usize * 3;
+
+        // Each row is prefixed by its filter type, with no filtering being used
+        let mut raw = Vec::with_capacity((row_size + 1) * self.height as usize);
+        for row in rgb.chunks(row_size.max(1)) {
+            raw.push(0);
+            raw.extend(row);
+        }
+
+        let mut header = Vec::with_capacity(13);
+        header.extend(self.width.to_be_bytes());
+        header.extend(self.height.to_be_bytes());
+        // 8-bit depth, truecolor, deflate, adaptive filtering, no interlacing
+        header.extend([8, 2, 0, 0, 0]);
+
+        writer.write_all(&[0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'])?;
+        write_png_chunk(writer, b"IHDR", &header)?;
+        write_png_chunk(writer, b"IDAT", &zlib_stored(&raw))?;
+        write_png_chunk(writer, b"IEND", &[])
+    }
+
+    /// Save the current frame to a file, as a PNG image if the extension is `png` and as a PPM image otherwise
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("png")) {
+            self.write_png(&mut writer)?;
+        } else {
+            self.write_ppm(&mut writer)?;
+        }
+        writer.flush()
+    }
+}
+
+impl Device for Framebuffer {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> bool {
+        let data = self.data.lock().unwrap();
+        match data.get(offset as usize..offset as usize + buf.len()) {
+            Some(src) => { buf.copy_from_slice(src); true },
+            None => false,
+        }
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> bool {
+        let mut data = self.data.lock().unwrap();
+        match data.get_mut(offset as usize..offset as usize + buf.len()) {
+            Some(dst) => { dst.copy_from_slice(buf); true },
+            None => false,
+        }
+    }
+
+    fn devicetree_node(&self, base: u64, _fdt: &FdtContext) -> Option<FdtNode> {
+        let mut node = FdtNode::new(format!("framebuffer@{base:x}"));
+        node.property_string("compatible", "simple-framebuffer")
+            .property_u32("width", self.width)
+            .property_u32("height", self.height)
+            .property_u32("stride", self.stride())
+            .property_string("format", self.format.name());
+        Some(node)
+    }
+}
+
+fn write_png_chunk(writer: &mut dyn Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(kind)?;
+    writer.write_all(data)?;
+    let crc = crc32(&[kind.as_slice(), data].concat());
+    writer.write_all(&crc.to_be_bytes())
+}
+
+/// Wrap data in a zlib stream, using uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    const MAX_BLOCK_SIZE: usize = 0xFFFF;
+
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(MAX_BLOCK_SIZE).peekable();
+    if blocks.peek().is_none() {
+        out.extend([1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none() as u8;
+        let len = block.len() as u16;
+        out.push(last);
+        out.extend(len.to_le_bytes());
+        out.extend((!len).to_le_bytes());
+        out.extend(block);
+    }
+    out.extend(adler32(data).to_be_bytes());
+    out
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+
+use emu_cpu::HaltReason;
+
+use crate::memory::MemoryBus;
+
+use super::SerialBackend;
+
+
+const DEVICE_SYSCALL: u8 = 0;
+const DEVICE_CONSOLE: u8 = 1;
+
+const CONSOLE_GETCHAR: u8 = 0;
+const CONSOLE_PUTCHAR: u8 = 1;
+
+const SYS_WRITE: u64 = 64;
+const SYS_EXIT: u64 = 93;
+const ENOSYS: i64 = 38;
+
+/// Host-target interface, as used by the riscv-tests and other bare-metal programs to communicate with the host.
+///
+/// The guest writes commands to the `tohost` location in memory, with the device in bits 63:56, the command in bits 55:48 and the payload in bits 47:0.
+/// Responses are written to `fromhost`. Supported are:
+/// - device 0, command 0: exit if bit 0 of the payload is set, with the exit status in the remaining bits, otherwise the payload points to a syscall to proxy
+/// - device 1, command 0: read a character from the console
+/// - device 1, command 1: write a character to the console
+pub struct Htif {
+    tohost: u64,
+    fromhost: Option<u64>,
+    console: Box<dyn SerialBackend>,
+}
+
+impl Htif {
+    pub fn new(tohost: u64, fromhost: Option<u64>, console: Box<dyn SerialBackend>) -> Self {
+        Self { tohost, fromhost, console }
+    }
+
+    /// Create the interface using the `tohost` and `fromhost` symbols, returns `None` if the program doesn't define `tohost`
+    pub fn from_symbols(symbols: &HashMap<String, u64>, console: Box<dyn SerialBackend>) -> Option<Self> {
+        let tohost = *symbols.get("tohost")?;
+        Some(Self::new(tohost, symbols.get("fromhost").copied(), console))
+    }
+
+    /// Handle a command written to `tohost`, returns the halt reason when the guest exits
+    pub fn poll(&mut self, memory: &mut dyn MemoryBus) -> Option<HaltReason> {
+        let tohost = read_u64(memory, self.tohost)?;
+        if tohost == 0 {
+            return None;
+        }
+        // Responses are only sent once the guest consumed the previous one
+        if let Some(fromhost) = self.fromhost {
+            if read_u64(memory, fromhost)? != 0 {
+                return None;
+            }
+        }
+
+        let device = (tohost >> 56) as u8;
+        let command = (tohost >> 48) as u8;
+        let payload = tohost & 0xFFFF_FFFF_FFFF;
+
+        let mut halt = None;
+        let response = match (device, command) {
+            (DEVICE_SYSCALL, 0) if payload & 1 != 0 => {
+                halt = Some(HaltReason::Exit((payload >> 1) as u32));
+                None
+            },
+            (DEVICE_SYSCALL, 0) => {
+                halt = self.syscall(memory, payload);
+                Some(1)
+            },
+            (DEVICE_CONSOLE, CONSOLE_GETCHAR) => match self.console.receive() {
+                Some(byte) => Some(byte as u64),
+                // Keep the command pending until input is available
+                None => return None,
+            },
+            (DEVICE_CONSOLE, CONSOLE_PUTCHAR) => {
+                self.console.transmit(payload as u8);
+                Some(0)
+            },
+            _ => None,
+        };
+
+        write_u64(memory, self.tohost, 0);
+        if let (Some(fromhost), Some(response)) = (self.fromhost, response) {
+            write_u64(memory, fromhost, ((device as u64) << 56) | ((command as u64) << 48) | response);
+        }
+        halt
+    }
+
+    /// Proxy a syscall, described by the 8 64-bit values at `addr`, i.e. the syscall number followed by its arguments. The result is written back to the first value
+    fn syscall(&mut self, memory: &mut dyn MemoryBus, addr: u64) -> Option<HaltReason> {
+        let mut args = [0u64; 8];
+        for (idx, arg) in args.iter_mut().enumerate() {
+            *arg = read_u64(memory, addr + idx as u64 * 8).unwrap_or(0);
+        }
+
+        let result = match args[0] {
+            SYS_WRITE if args[1] == 1 || args[1] == 2 => {
+                let mut buf = vec![0; args[3] as usize];
+                if memory.read(args[2], &mut buf) {
+                    for byte in buf {
+                        self.console.transmit(byte);
+                    }
+                    args[3] as i64
+                } else {
+                    -1
+                }
+            },
+            SYS_EXIT => return Some(HaltReason::Exit(args[1] as u32)),
+            _ => -ENOSYS,
+        };
+        write_u64(memory, addr, result as u64);
+        None
+    }
+}
+
+fn read_u64(memory: &mut dyn MemoryBus, addr: u64) -> Option<u64> {
+    let mut buf = [0; 8];
+    memory.read(addr, &mut buf).then(|| u64::from_le_bytes(buf))
+}
+
+fn write_u64(memory: &mut dyn MemoryBus, addr: u64, value: u64) {
+    memory.write(addr, &value.to_le_bytes());
+}
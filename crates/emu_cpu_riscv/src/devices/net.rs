@@ -0,0 +1,167 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+
+/// Host side of a network device, exchanging ethernet frames
+pub trait NetBackend: Send {
+    /// Send a frame from the guest
+    fn send(&mut self, frame: &[u8]);
+
+    /// Receive a frame for the guest, if one is available
+    fn receive(&mut self) -> Option<Vec<u8>>;
+
+    /// Check if frames may still arrive for the guest, i.e. if it makes sense to wait for them
+    fn may_receive(&self) -> bool {
+        false
+    }
+}
+
+/// Max number of frames queued for a switch port before new frames are dropped
+const SWITCH_QUEUE_SIZE: usize = 256;
+
+#[derive(Default)]
+struct SwitchState {
+    /// Frames queued for each port
+    ports: Vec<VecDeque<Vec<u8>>>,
+}
+
+/// In-process switch, forwarding the frames sent on a port to all other ports. Ports can be used by emulators on different threads
+#[derive(Clone, Default)]
+pub struct LoopbackSwitch {
+    state: Arc<Mutex<SwitchState>>,
+}
+
+impl LoopbackSwitch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Connect a new port to the switch
+    pub fn connect(&self) -> SwitchPort {
+        let mut state = self.state.lock().unwrap();
+        state.ports.push(VecDeque::new());
+        SwitchPort { state: self.state.clone(), port: state.ports.len() - 1 }
+    }
+}
+
+/// Port of a `LoopbackSwitch`
+pub struct SwitchPort {
+    state: Arc<Mutex<SwitchState>>,
+    port: usize,
+}
+
+impl NetBackend for SwitchPort {
+    fn send(&mut self, frame: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        for (idx, queue) in state.ports.iter_mut().enumerate() {
+            if idx != self.port && queue.len() < SWITCH_QUEUE_SIZE {
+                queue.push_back(frame.to_vec());
+            }
+        }
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        self.state.lock().unwrap().ports[self.port].pop_front()
+    }
+
+    fn may_receive(&self) -> bool {
+        // Other ports can send a frame at any time
+        self.state.lock().unwrap().ports.len() > 1
+    }
+}
+
+const PCAP_MAGIC: u32 = 0xA1B2_C3D4;
+const PCAP_MAGIC_NS: u32 = 0xA1B2_3C4D;
+const PCAP_LINKTYPE_ETHERNET: u32 = 1;
+const PCAP_SNAPLEN: u32 = 65535;
+
+/// Backend writing the frames sent by the guest to a pcap file, and replaying the frames of a pcap file to the guest
+pub struct PcapBackend {
+    output: Option<BufWriter<File>>,
+    input: VecDeque<Vec<u8>>,
+    /// Number of frames written, used as the timestamp in microseconds, so the output is reproducible
+    num_written: u64,
+}
+
+impl PcapBackend {
+    /// Create a backend writing sent frames to `output` and replaying the frames in the pcap file `input`
+    pub fn new(output: Option<&Path>, input: Option<&Path>) -> io::Result<Self> {
+        let input = match input {
+            Some(path) => Self::read_pcap(&std::fs::read(path)?)?,
+            None => VecDeque::new(),
+        };
+        let output = match output {
+            Some(path) => {
+                let mut writer = BufWriter::new(File::create(path)?);
+                let mut header = Vec::with_capacity(24);
+                header.extend(PCAP_MAGIC.to_le_bytes());
+                header.extend(2u16.to_le_bytes());
+                header.extend(4u16.to_le_bytes());
+                header.extend([0; 8]);
+                header.extend(PCAP_SNAPLEN.to_le_bytes());
+                header.extend(PCAP_LINKTYPE_ETHERNET.to_le_bytes());
+                writer.write_all(&header)?;
+                Some(writer)
+            },
+            None => None,
+        };
+        Ok(Self { output, input, num_written: 0 })
+    }
+
+    /// Parse the frames of a pcap file, in either byte order
+    fn read_pcap(data: &[u8]) -> io::Result<VecDeque<Vec<u8>>> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid pcap file");
+        let header = data.get(..24).ok_or_else(invalid)?;
+        let magic = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let big_endian = match magic {
+            PCAP_MAGIC | PCAP_MAGIC_NS => false,
+            _ if magic.swap_bytes() == PCAP_MAGIC || magic.swap_bytes() == PCAP_MAGIC_NS => true,
+            _ => return Err(invalid()),
+        };
+        let read_u32 = |bytes: &[u8]| {
+            let bytes = bytes.try_into().unwrap();
+            if big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) }
+        };
+        if read_u32(&header[20..24]) != PCAP_LINKTYPE_ETHERNET {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "pcap file does not contain ethernet frames"));
+        }
+
+        let mut frames = VecDeque::new();
+        let mut offset = 24;
+        while offset < data.len() {
+            let record = data.get(offset..offset + 16).ok_or_else(invalid)?;
+            let len = read_u32(&record[8..12]) as usize;
+            let frame = data.get(offset + 16..offset + 16 + len).ok_or_else(invalid)?;
+            frames.push_back(frame.to_vec());
+            offset += 16 + len;
+        }
+        Ok(frames)
+    }
+}
+
+impl NetBackend for PcapBackend {
+    fn send(&mut self, frame: &[u8]) {
+        let Some(output) = &mut self.output else { return; };
+
+        let mut record = Vec::with_capacity(16 + frame.len());
+        record.extend(((self.num_written / 1_000_000) as u32).to_le_bytes());
+        record.extend(((self.num_written % 1_000_000) as u32).to_le_bytes());
+        record.extend((frame.len() as u32).to_le_bytes());
+        record.extend((frame.len() as u32).to_le_bytes());
+        record.extend(frame);
+        _ = output.write_all(&record);
+        _ = output.flush();
+        self.num_written += 1;
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        self.input.pop_front()
+    }
+
+    fn may_receive(&self) -> bool {
+        !self.input.is_empty()
+    }
+}
@@ -0,0 +1,275 @@
+use crate::fdt::{FdtContext, FdtNode};
+use crate::trap::Interrupt;
+
+use super::{Device, read_register, write_register};
+
+
+// Memory map (SiFive PLIC, as used by the qemu `virt` machine)
+//
+// | offset                      | register                          |
+// |-----------------------------|-----------------------------------|
+// | 0x000000 + 4 * source       | priority (32-bit)                 |
+// | 0x001000 + 4 * word         | pending bits (32-bit)             |
+// | 0x002000 + 0x80 * context   | enable bits (32-bit words)        |
+// | 0x200000 + 0x1000 * context | priority threshold (32-bit)       |
+// | 0x200004 + 0x1000 * context | claim/complete (32-bit)           |
+
+const PRIORITY_BASE: u64 = 0x000000;
+const PENDING_BASE: u64 = 0x001000;
+const ENABLE_BASE: u64 = 0x002000;
+const ENABLE_STRIDE: u64 = 0x80;
+const CONTEXT_BASE: u64 = 0x200000;
+const CONTEXT_STRIDE: u64 = 0x1000;
+
+/// Size of the PLIC region on the bus
+pub const PLIC_SIZE: u64 = 0x400_0000;
+
+/// Max number of interrupt sources, source 0 is reserved and means 'no interrupt'
+pub const PLIC_MAX_SOURCES: usize = 1023;
+
+/// Mask of the implemented priority and threshold bits
+const PRIORITY_MASK: u32 = 0x7;
+
+/// Hart context the PLIC can deliver interrupts to
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PlicContext {
+    pub hart: usize,
+    /// External interrupt driven on the hart, i.e. `MachineExternal` or `SupervisorExternal`
+    pub interrupt: Interrupt,
+}
+
+struct ContextState {
+    context: PlicContext,
+    enable: Vec<u32>,
+    threshold: u32,
+}
+
+/// Platform-level interrupt controller, routing the interrupts of the devices to the external interrupt of the hart contexts
+pub struct Plic {
+    num_sources: usize,
+    priority: Vec<u32>,
+    /// Level of each source, as driven by the device
+    level: Vec<bool>,
+    pending: Vec<bool>,
+    /// Sources that have been claimed, but not yet completed
+    claimed: Vec<bool>,
+    contexts: Vec<ContextState>,
+}
+
+impl Plic {
+    /// Create a PLIC with an M-mode and S-mode context for each hart, with context `2 * hart` and `2 * hart + 1` respectively
+    pub fn new(num_sources: usize, num_harts: usize) -> Self {
+        let contexts = (0..num_harts).flat_map(|hart| [
+            PlicContext { hart, interrupt: Interrupt::MachineExternal },
+            PlicContext { hart, interrupt: Interrupt::SupervisorExternal },
+        ]).collect::<Vec<_>>();
+        Self::with_contexts(num_sources, &contexts)
+    }
+
+    /// Create a PLIC with `num_sources` sources (excluding the reserved source 0) and the given contexts
+    pub fn with_contexts(num_sources: usize, contexts: &[PlicContext]) -> Self {
+        assert!(num_sources <= PLIC_MAX_SOURCES, "PLIC supports at most {PLIC_MAX_SOURCES} sources");
+        assert!(contexts.iter().all(|ctx| matches!(ctx.interrupt, Interrupt::MachineExternal | Interrupt::SupervisorExternal)), "PLIC contexts can only drive external interrupts");
+
+        let num_words = (num_sources + 1).div_ceil(32);
+        Self {
+            num_sources,
+            priority: vec![0; num_sources + 1],
+            level: vec![false; num_sources + 1],
+            pending: vec![false; num_sources + 1],
+            claimed: vec![false; num_sources + 1],
+            contexts: contexts.iter().map(|&context| ContextState { context, enable: vec![0; num_words], threshold: 0 }).collect(),
+        }
+    }
+
+    fn is_enabled(&self, context: usize, source: usize) -> bool {
+        self.contexts[context].enable[source / 32] & (1 << (source % 32)) != 0
+    }
+
+    /// Highest priority pending source that is enabled for a context and exceeds its threshold, ties are won by the lowest source id
+    fn best_pending(&self, context: usize) -> Option<usize> {
+        let threshold = self.contexts[context].threshold;
+        let mut best: Option<(usize, u32)> = None;
+        for source in 1..=self.num_sources {
+            let priority = self.priority[source];
+            if self.pending[source] && priority > threshold && self.is_enabled(context, source) && best.is_none_or(|(_, best_priority)| priority > best_priority) {
+                best = Some((source, priority));
+            }
+        }
+        best.map(|(source, _)| source)
+    }
+
+    /// Forward a new request of the gateway if the source is still raised and not in flight
+    fn update_gateway(&mut self, source: usize) {
+        if self.level[source] && !self.claimed[source] {
+            self.pending[source] = true;
+        }
+    }
+
+    fn claim(&mut self, context: usize) -> u32 {
+        match self.best_pending(context) {
+            Some(source) => {
+                self.pending[source] = false;
+                self.claimed[source] = true;
+                source as u32
+            },
+            None => 0,
+        }
+    }
+
+    fn complete(&mut self, context: usize, source: usize) {
+        // Completions for sources that are not enabled for the context are ignored
+        if source == 0 || source > self.num_sources || !self.is_enabled(context, source) {
+            return;
+        }
+        self.claimed[source] = false;
+        self.update_gateway(source);
+    }
+
+    fn pending_word(&self, word: usize) -> u32 {
+        (0..32).map(|bit| word * 32 + bit)
+            .filter(|&source| source <= self.num_sources && self.pending[source])
+            .fold(0, |acc, source| acc | (1 << (source % 32)))
+    }
+
+    /// Decode an offset into a 32-bit register, returning the register offset and the offset within the register
+    fn register(offset: u64, len: usize) -> Option<(u64, u64)> {
+        let reg = offset & !3;
+        (offset - reg + len as u64 <= 4).then_some((reg, offset - reg))
+    }
+}
+
+impl Device for Plic {
+    fn devicetree_node(&self, base: u64, fdt: &FdtContext) -> Option<FdtNode> {
+        let interrupts = self.contexts.iter()
+            .flat_map(|ctx| [fdt.cpu_intc_phandle(ctx.context.hart), ctx.context.interrupt.code() as u32])
+            .collect::<Vec<_>>();
+
+        let mut node = FdtNode::new(format!("plic@{base:x}"));
+        node.property_strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"])
+            .property_u32("#address-cells", 0)
+            .property_u32("#interrupt-cells", 1)
+            .property_empty("interrupt-controller")
+            .property_u32("riscv,ndev", self.num_sources as u32)
+            .property_cells("interrupts-extended", &interrupts)
+            .property_u32("phandle", fdt.interrupt_parent());
+        Some(node)
+    }
+
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> bool {
+        let Some((reg, offset_in_reg)) = Self::register(offset, buf.len()) else { return false; };
+        let num_words = (self.num_sources as u64 + 1).div_ceil(32);
+        let num_contexts = self.contexts.len() as u64;
+
+        let value = match reg {
+            PRIORITY_BASE..=0xFFF => {
+                let source = ((reg - PRIORITY_BASE) / 4) as usize;
+                if source > self.num_sources {
+                    return false;
+                }
+                self.priority[source]
+            },
+            PENDING_BASE..=0x1FFF => {
+                let word = (reg - PENDING_BASE) / 4;
+                if word >= num_words {
+                    return false;
+                }
+                self.pending_word(word as usize)
+            },
+            ENABLE_BASE..=0x1F_FFFF => {
+                let context = (reg - ENABLE_BASE) / ENABLE_STRIDE;
+                let word = (reg - ENABLE_BASE) % ENABLE_STRIDE / 4;
+                if context >= num_contexts || word >= num_words {
+                    return false;
+                }
+                self.contexts[context as usize].enable[word as usize]
+            },
+            CONTEXT_BASE.. => {
+                let context = (reg - CONTEXT_BASE) / CONTEXT_STRIDE;
+                if context >= num_contexts {
+                    return false;
+                }
+                match (reg - CONTEXT_BASE) % CONTEXT_STRIDE {
+                    0 => self.contexts[context as usize].threshold,
+                    // Reading the claim register claims the interrupt
+                    4 => self.claim(context as usize),
+                    _ => return false,
+                }
+            },
+        };
+        read_register(value as u64, offset_in_reg, buf)
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> bool {
+        let Some((reg, offset_in_reg)) = Self::register(offset, buf.len()) else { return false; };
+        let num_words = (self.num_sources as u64 + 1).div_ceil(32);
+        let num_contexts = self.contexts.len() as u64;
+
+        match reg {
+            PRIORITY_BASE..=0xFFF => {
+                let source = ((reg - PRIORITY_BASE) / 4) as usize;
+                if source > self.num_sources {
+                    return false;
+                }
+                // Source 0 doesn't exist, so its priority is hardwired to 0
+                if let Some(value) = write_register(self.priority[source] as u64, offset_in_reg, buf) {
+                    if source != 0 {
+                        self.priority[source] = value as u32 & PRIORITY_MASK;
+                    }
+                    return true;
+                }
+                false
+            },
+            // Pending bits are read-only
+            PENDING_BASE..=0x1FFF => (reg - PENDING_BASE) / 4 < num_words,
+            ENABLE_BASE..=0x1F_FFFF => {
+                let context = ((reg - ENABLE_BASE) / ENABLE_STRIDE) as usize;
+                let word = ((reg - ENABLE_BASE) % ENABLE_STRIDE / 4) as usize;
+                if context as u64 >= num_contexts || word as u64 >= num_words {
+                    return false;
+                }
+                let enable = &mut self.contexts[context].enable[word];
+                match write_register(*enable as u64, offset_in_reg, buf) {
+                    Some(value) => {
+                        // Source 0 can't be enabled
+                        *enable = if word == 0 { value as u32 & !1 } else { value as u32 };
+                        true
+                    },
+                    None => false,
+                }
+            },
+            CONTEXT_BASE.. => {
+                let context = ((reg - CONTEXT_BASE) / CONTEXT_STRIDE) as usize;
+                if context as u64 >= num_contexts {
+                    return false;
+                }
+                match (reg - CONTEXT_BASE) % CONTEXT_STRIDE {
+                    0 => match write_register(self.contexts[context].threshold as u64, offset_in_reg, buf) {
+                        Some(value) => { self.contexts[context].threshold = value as u32 & PRIORITY_MASK; true },
+                        None => false,
+                    },
+                    4 => match write_register(0, offset_in_reg, buf) {
+                        Some(source) => { self.complete(context, source as usize); true },
+                        None => false,
+                    },
+                    _ => false,
+                }
+            },
+        }
+    }
+
+    fn set_source_level(&mut self, source: u32, level: bool) {
+        let source = source as usize;
+        if source == 0 || source > self.num_sources {
+            return;
+        }
+        self.level[source] = level;
+        self.update_gateway(source);
+    }
+
+    fn interrupt_lines(&self, hart: usize) -> u64 {
+        self.contexts.iter().enumerate()
+            .filter(|(idx, state)| state.context.hart == hart && self.best_pending(*idx).is_some())
+            .fold(0, |lines, (_, state)| lines | state.context.interrupt.mask())
+    }
+}
@@ -0,0 +1,172 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::fdt::{FdtContext, FdtNode};
+
+use super::Device;
+
+
+// Memory map (Goldfish RTC), all registers are 32-bit and times are in nanoseconds since the unix epoch
+//
+// | offset | register                                                         |
+// |--------|------------------------------------------------------------------|
+// | 0x00   | TIME_LOW, reading it latches TIME_HIGH, writing it sets the time |
+// | 0x04   | TIME_HIGH                                                        |
+// | 0x08   | ALARM_LOW, writing it arms the alarm                             |
+// | 0x0C   | ALARM_HIGH                                                       |
+// | 0x10   | IRQ_ENABLED                                                      |
+// | 0x14   | CLEAR_ALARM                                                      |
+// | 0x18   | ALARM_STATUS                                                     |
+// | 0x1C   | CLEAR_INTERRUPT                                                  |
+
+const TIME_LOW: u64 = 0x00;
+const TIME_HIGH: u64 = 0x04;
+const ALARM_LOW: u64 = 0x08;
+const ALARM_HIGH: u64 = 0x0C;
+const IRQ_ENABLED: u64 = 0x10;
+const CLEAR_ALARM: u64 = 0x14;
+const ALARM_STATUS: u64 = 0x18;
+const CLEAR_INTERRUPT: u64 = 0x1C;
+
+/// Size of the RTC region on the bus
+pub const RTC_SIZE: u64 = 0x1000;
+
+/// Source of the wall-clock time of the RTC
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RtcClock {
+    /// Time of the host
+    Host,
+    /// Time starts at `start` nanoseconds since the unix epoch and advances by `ns_per_tick` for every tick of the virtual clock, so runs are reproducible
+    Virtual { start: u64, ns_per_tick: u64 },
+}
+
+/// Goldfish RTC, as used by the qemu `virt` machine, which raises an interrupt when the alarm is reached
+pub struct Rtc {
+    clock: RtcClock,
+    /// Ticks of the virtual clock since the RTC was created
+    ticks: u64,
+    /// Difference between the time set by the guest and the time of the clock
+    offset: u64,
+    time_high: u32,
+    alarm_high: u32,
+    alarm: Option<u64>,
+    irq_enabled: bool,
+    irq_pending: bool,
+}
+
+impl Rtc {
+    pub fn new(clock: RtcClock) -> Self {
+        if let RtcClock::Virtual { ns_per_tick, .. } = clock {
+            assert!(ns_per_tick != 0, "virtual clock must advance every tick");
+        }
+
+        Self {
+            clock,
+            ticks: 0,
+            offset: 0,
+            time_high: 0,
+            alarm_high: 0,
+            alarm: None,
+            irq_enabled: false,
+            irq_pending: false,
+        }
+    }
+
+    fn clock_time(&self) -> u64 {
+        match self.clock {
+            RtcClock::Host => SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_nanos() as u64),
+            RtcClock::Virtual { start, ns_per_tick } => start.wrapping_add(self.ticks.wrapping_mul(ns_per_tick)),
+        }
+    }
+
+    /// Current time in nanoseconds since the unix epoch, as seen by the guest
+    pub fn time(&self) -> u64 {
+        self.clock_time().wrapping_add(self.offset)
+    }
+
+    fn set_time(&mut self, time: u64) {
+        self.offset = time.wrapping_sub(self.clock_time());
+        self.check_alarm();
+    }
+
+    fn check_alarm(&mut self) {
+        if self.alarm.is_some_and(|alarm| self.time() >= alarm) {
+            self.alarm = None;
+            self.irq_pending = true;
+        }
+    }
+}
+
+impl Device for Rtc {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> bool {
+        if buf.len() != 4 {
+            return false;
+        }
+        let value = match offset {
+            TIME_LOW => {
+                let time = self.time();
+                self.time_high = (time >> 32) as u32;
+                time as u32
+            },
+            TIME_HIGH    => self.time_high,
+            ALARM_LOW    => self.alarm.unwrap_or_default() as u32,
+            ALARM_HIGH   => self.alarm_high,
+            IRQ_ENABLED  => self.irq_enabled as u32,
+            ALARM_STATUS => self.alarm.is_some() as u32,
+            CLEAR_ALARM | CLEAR_INTERRUPT => 0,
+            _ => return false,
+        };
+        buf.copy_from_slice(&value.to_le_bytes());
+        true
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> bool {
+        let Ok(value) = buf.try_into().map(u32::from_le_bytes) else { return false; };
+        match offset {
+            TIME_LOW        => self.set_time(((self.time_high as u64) << 32) | value as u64),
+            TIME_HIGH       => self.time_high = value,
+            ALARM_LOW       => {
+                self.alarm = Some(((self.alarm_high as u64) << 32) | value as u64);
+                self.check_alarm();
+            },
+            ALARM_HIGH      => self.alarm_high = value,
+            IRQ_ENABLED     => self.irq_enabled = value & 1 != 0,
+            CLEAR_ALARM     => self.alarm = None,
+            CLEAR_INTERRUPT => self.irq_pending = false,
+            ALARM_STATUS    => {},
+            _ => return false,
+        }
+        true
+    }
+
+    fn tick(&mut self, _retired: bool) {
+        self.ticks += 1;
+        self.check_alarm();
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        let alarm = self.alarm?;
+        match self.clock {
+            // Host time can't be predicted in ticks, so keep checking it
+            RtcClock::Host => Some(1),
+            RtcClock::Virtual { ns_per_tick, .. } => {
+                let remaining = alarm.saturating_sub(self.time());
+                Some(remaining.div_ceil(ns_per_tick).max(1))
+            },
+        }
+    }
+
+    fn skip(&mut self, ticks: u64) {
+        self.ticks += ticks;
+        self.check_alarm();
+    }
+
+    fn interrupt_level(&self) -> bool {
+        self.irq_enabled && self.irq_pending
+    }
+
+    fn devicetree_node(&self, base: u64, _fdt: &FdtContext) -> Option<FdtNode> {
+        let mut node = FdtNode::new(format!("rtc@{base:x}"));
+        node.property_string("compatible", "google,goldfish-rtc");
+        Some(node)
+    }
+}
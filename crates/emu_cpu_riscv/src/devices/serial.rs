@@ -0,0 +1,140 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+
+
+/// Host side of a serial device
+pub trait SerialBackend: Send {
+    /// Receive a byte from the host, if one is available
+    fn receive(&mut self) -> Option<u8>;
+
+    /// Transmit a byte to the host
+    fn transmit(&mut self, byte: u8);
+
+    /// Check if input may still arrive from the host, i.e. if it makes sense to wait for it
+    fn may_receive(&self) -> bool {
+        false
+    }
+}
+
+/// Backend connected to the stdin and stdout of the host
+pub struct StdioBackend {
+    /// Stdin is read on a separate thread, as reading it blocks
+    input: Receiver<u8>,
+    eof: bool,
+}
+
+impl StdioBackend {
+    pub fn new() -> Self {
+        let (sender, input) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut buf = [0; 64];
+            loop {
+                match io::stdin().read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(len) => if buf[..len].iter().any(|&byte| sender.send(byte).is_err()) {
+                        break;
+                    },
+                }
+            }
+        });
+        Self { input, eof: false }
+    }
+}
+
+impl Default for StdioBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SerialBackend for StdioBackend {
+    fn receive(&mut self) -> Option<u8> {
+        match self.input.try_recv() {
+            Ok(byte) => Some(byte),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => { self.eof = true; None },
+        }
+    }
+
+    fn transmit(&mut self, byte: u8) {
+        let mut stdout = io::stdout();
+        _ = stdout.write_all(&[byte]);
+        _ = stdout.flush();
+    }
+
+    fn may_receive(&self) -> bool {
+        !self.eof
+    }
+}
+
+/// Backend writing the output to a file, with the input read from a file up front
+pub struct FileBackend {
+    input: VecDeque<u8>,
+    output: File,
+}
+
+impl FileBackend {
+    /// Create a backend writing to `output`, and optionally reading the contents of `input`
+    pub fn new(output: &Path, input: Option<&Path>) -> io::Result<Self> {
+        let input = match input {
+            Some(path) => std::fs::read(path)?.into(),
+            None => VecDeque::new(),
+        };
+        Ok(Self { input, output: File::create(output)? })
+    }
+}
+
+impl SerialBackend for FileBackend {
+    fn receive(&mut self) -> Option<u8> {
+        self.input.pop_front()
+    }
+
+    fn transmit(&mut self, byte: u8) {
+        _ = self.output.write_all(&[byte]);
+    }
+
+    fn may_receive(&self) -> bool {
+        !self.input.is_empty()
+    }
+}
+
+/// In-memory backend, clones share the same buffers, so the input and output can be accessed after the backend is handed to a device
+#[derive(Clone, Default)]
+pub struct BufferBackend {
+    input: Arc<Mutex<VecDeque<u8>>>,
+    output: Arc<Mutex<Vec<u8>>>,
+}
+
+impl BufferBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue input to be received by the device
+    pub fn push_input(&self, input: &[u8]) {
+        self.input.lock().unwrap().extend(input);
+    }
+
+    /// Take all output transmitted by the device so far
+    pub fn take_output(&self) -> Vec<u8> {
+        std::mem::take(&mut self.output.lock().unwrap())
+    }
+}
+
+impl SerialBackend for BufferBackend {
+    fn receive(&mut self) -> Option<u8> {
+        self.input.lock().unwrap().pop_front()
+    }
+
+    fn transmit(&mut self, byte: u8) {
+        self.output.lock().unwrap().push(byte);
+    }
+
+    fn may_receive(&self) -> bool {
+        !self.input.lock().unwrap().is_empty()
+    }
+}
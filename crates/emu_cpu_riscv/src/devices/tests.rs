@@ -0,0 +1,815 @@
+use emu_cpu::{CpuEmulator, EmulationSettings, HaltReason, MisalignedPolicy};
+
+use crate::csr::*;
+use crate::memory::MemoryBus;
+use crate::trap::Interrupt;
+use crate::bus::Bus;
+use crate::elf::{Elf, ElfError, ELF_MAX_RAM_SIZE};
+use crate::RiscvEmulator;
+
+use super::*;
+
+#[test]
+fn test_clint_registers() {
+    let mut clint = Clint::new(2, Timebase::InstructionsRetired);
+    assert_eq!(clint.interrupt_lines(0), 0);
+
+    // msip
+    assert!(clint.write(0x4, &3u32.to_le_bytes()));
+    assert_eq!(clint.interrupt_lines(0), 0);
+    assert_eq!(clint.interrupt_lines(1), Interrupt::MachineSoftware.mask());
+
+    let mut buf = [0; 4];
+    assert!(clint.read(0x4, &mut buf));
+    assert_eq!(u32::from_le_bytes(buf), 1);
+
+    // mtimecmp, written as 2 32-bit halves
+    assert!(clint.write(0x4000, &10u32.to_le_bytes()));
+    assert!(clint.write(0x4004, &0u32.to_le_bytes()));
+    for _ in 0..9 {
+        clint.tick(true);
+    }
+    clint.tick(false);
+    assert_eq!(clint.mtime(), 9);
+    assert_eq!(clint.interrupt_lines(0), 0);
+
+    clint.tick(true);
+    assert_eq!(clint.interrupt_lines(0), Interrupt::MachineTimer.mask());
+
+    let mut buf = [0; 8];
+    assert!(clint.read(0xBFF8, &mut buf));
+    assert_eq!(u64::from_le_bytes(buf), 10);
+
+    // Harts that don't exist
+    assert!(!clint.write(0x8, &1u32.to_le_bytes()));
+    assert!(!clint.read(0x4010, &mut buf));
+}
+
+#[test]
+fn test_clint_virtual_clock() {
+    let mut clint = Clint::new(1, Timebase::VirtualClock { divider: 4 });
+    assert_eq!(clint.next_event(), None);
+
+    clint.tick(false);
+    assert!(clint.write(0x4000, &3u64.to_le_bytes()));
+    assert_eq!(clint.next_event(), Some(11));
+
+    clint.skip(10);
+    assert_eq!(clint.mtime(), 2);
+    assert_eq!(clint.interrupt_lines(0), 0);
+
+    clint.tick(true);
+    assert_eq!(clint.mtime(), 3);
+    assert_eq!(clint.interrupt_lines(0), Interrupt::MachineTimer.mask());
+    assert_eq!(clint.next_event(), None);
+}
+
+#[test]
+fn test_bus() {
+    let mut bus = Bus::new();
+    bus.ram = vec![0; 0x1000];
+    bus.ram_base = 0x8000_0000;
+    bus.add_device(0x200_0000, CLINT_SIZE, Box::new(Clint::new(1, Timebase::InstructionsRetired)));
+
+    assert!(bus.write(0x8000_0FFC, &[1, 2, 3, 4]));
+    assert_eq!(&bus.ram[0xFFC..], &[1, 2, 3, 4]);
+    assert!(!bus.write(0x8000_0FFE, &[1, 2, 3, 4]));
+    assert!(!bus.write(0, &[0]));
+
+    assert!(bus.write(0x200_0000, &1u32.to_le_bytes()));
+    assert_eq!(bus.interrupt_lines(0), Interrupt::MachineSoftware.mask());
+}
+
+#[test]
+fn test_bus_reservations() {
+    // Reservations are invalidated by writes of any hart to the reservation set
+    let mut bus = Bus::new();
+    bus.ram = vec![0; 0x1000];
+    bus.reserve(0, 0x100);
+    bus.reserve(1, 0x100);
+    assert!(bus.write(0x104, &[1]));
+    assert!(!bus.release_reservation(0, 0x100));
+    bus.reserve(0, 0x100);
+    assert!(bus.write(0x108, &[1]));
+    assert!(!bus.release_reservation(1, 0x100));
+    assert!(bus.release_reservation(0, 0x104));
+    assert!(!bus.release_reservation(0, 0x104));
+}
+
+#[test]
+fn test_wfi_timer_interrupt() {
+    let mut emulator = RiscvEmulator::new(EmulationSettings { print_instructions: false, misaligned: MisalignedPolicy::Trap });
+    emulator.memory.add_device(0x200_0000, CLINT_SIZE, Box::new(Clint::new(1, Timebase::VirtualClock { divider: 1 })));
+
+    // 0x000: wfi
+    // 0x100: j 0x100
+    let mut code = vec![0; 0x104];
+    code[0x000..0x004].copy_from_slice(&0x10500073u32.to_le_bytes());
+    code[0x100..0x104].copy_from_slice(&0x0000006Fu32.to_le_bytes());
+    emulator.set_code(code);
+
+    let csr = emulator.harts[0].csr_mut();
+    csr.mtvec = 0x100;
+    csr.mie = Interrupt::MachineTimer.mask();
+    csr.mstatus |= MSTATUS_MIE;
+    assert!(emulator.memory.write(0x200_4000, &500u64.to_le_bytes()));
+
+    assert_eq!(emulator.execute(Some(1000)), HaltReason::InstructionLimit);
+    assert_eq!(emulator.cycles, 1000);
+    assert_eq!(emulator.harts[0].csr().mcause, (1 << 63) | Interrupt::MachineTimer.code());
+    assert_eq!(emulator.harts[0].csr().mepc, 4);
+    assert_eq!(emulator.harts[0].read_pc(), 0x100);
+
+    // Without any enabled interrupts, running until the hart halts returns once it is idle
+    emulator.harts[0].csr_mut().mie = 0;
+    emulator.harts[0].write_pc(0);
+    assert_eq!(emulator.execute(None), HaltReason::Idle);
+    assert!(emulator.harts[0].is_waiting());
+    assert_eq!(emulator.harts[0].read_pc(), 4);
+}
+
+/// Device driving an interrupt source, raised by writing 1
+struct IrqLine {
+    level: bool,
+}
+
+impl Device for IrqLine {
+    fn read(&mut self, _offset: u64, _buf: &mut [u8]) -> bool {
+        false
+    }
+
+    fn write(&mut self, _offset: u64, buf: &[u8]) -> bool {
+        self.level = buf[0] != 0;
+        true
+    }
+
+    fn interrupt_level(&self) -> bool {
+        self.level
+    }
+}
+
+fn read_u32(bus: &mut Bus, addr: u64) -> u32 {
+    let mut buf = [0; 4];
+    assert!(bus.read(addr, &mut buf));
+    u32::from_le_bytes(buf)
+}
+
+#[test]
+fn test_plic() {
+    const PLIC_BASE: u64 = 0xC00_0000;
+    // Context 1: S-mode of hart 0
+    const S_ENABLE: u64 = PLIC_BASE + 0x2080;
+    const S_THRESHOLD: u64 = PLIC_BASE + 0x201000;
+    const S_CLAIM: u64 = PLIC_BASE + 0x201004;
+
+    let mut bus = Bus::new();
+    bus.add_device(PLIC_BASE, PLIC_SIZE, Box::new(Plic::new(3, 1)));
+    bus.add_device_with_irq(0x1000, 0x10, 1, Box::new(IrqLine { level: false }));
+    bus.add_device_with_irq(0x2000, 0x10, 2, Box::new(IrqLine { level: false }));
+
+    assert!(bus.write(PLIC_BASE + 0x4, &1u32.to_le_bytes()));
+    assert!(bus.write(PLIC_BASE + 0x8, &2u32.to_le_bytes()));
+    assert!(bus.write(S_ENABLE, &0b111u32.to_le_bytes()));
+    // Source 0 can't be enabled
+    assert_eq!(read_u32(&mut bus, S_ENABLE), 0b110);
+
+    assert!(bus.write(0x1000, &[1]));
+    assert!(bus.write(0x2000, &[1]));
+    assert_eq!(read_u32(&mut bus, PLIC_BASE + 0x1000), 0b110);
+    assert_eq!(bus.interrupt_lines(0), Interrupt::SupervisorExternal.mask());
+
+    // Only interrupts with a priority above the threshold are delivered
+    assert!(bus.write(S_THRESHOLD, &2u32.to_le_bytes()));
+    assert_eq!(bus.interrupt_lines(0), 0);
+    assert_eq!(read_u32(&mut bus, S_CLAIM), 0);
+    assert!(bus.write(S_THRESHOLD, &0u32.to_le_bytes()));
+
+    // Claims are handed out in priority order
+    assert_eq!(read_u32(&mut bus, S_CLAIM), 2);
+    assert_eq!(read_u32(&mut bus, S_CLAIM), 1);
+    assert_eq!(read_u32(&mut bus, S_CLAIM), 0);
+    assert_eq!(bus.interrupt_lines(0), 0);
+
+    // Completing a source that is still raised makes it pending again
+    assert!(bus.write(S_CLAIM, &2u32.to_le_bytes()));
+    assert_eq!(bus.interrupt_lines(0), Interrupt::SupervisorExternal.mask());
+    assert!(bus.write(0x2000, &[0]));
+    assert_eq!(read_u32(&mut bus, S_CLAIM), 2);
+    assert!(bus.write(S_CLAIM, &2u32.to_le_bytes()));
+    assert!(bus.write(0x1000, &[0]));
+    assert!(bus.write(S_CLAIM, &1u32.to_le_bytes()));
+    assert_eq!(bus.interrupt_lines(0), 0);
+
+    // The M-mode context drives the machine external interrupt
+    assert!(bus.write(PLIC_BASE + 0x2000, &0b10u32.to_le_bytes()));
+    assert!(bus.write(0x1000, &[1]));
+    assert_eq!(bus.interrupt_lines(0), Interrupt::MachineExternal.mask() | Interrupt::SupervisorExternal.mask());
+    assert_eq!(read_u32(&mut bus, PLIC_BASE + 0x200004), 1);
+    assert_eq!(bus.interrupt_lines(0), 0);
+
+    // Contexts that don't exist
+    assert!(!bus.write(PLIC_BASE + 0x202000, &0u32.to_le_bytes()));
+}
+
+fn read_u8(bus: &mut Bus, addr: u64) -> u8 {
+    let mut buf = [0; 1];
+    assert!(bus.read(addr, &mut buf));
+    buf[0]
+}
+
+#[test]
+fn test_uart() {
+    const PLIC_BASE: u64 = 0xC00_0000;
+    const UART_BASE: u64 = 0x1000_0000;
+
+    let backend = BufferBackend::new();
+    let mut bus = Bus::new();
+    bus.add_device(PLIC_BASE, PLIC_SIZE, Box::new(Plic::new(10, 1)));
+    bus.add_device_with_irq(UART_BASE, UART_SIZE, 10, Box::new(Uart::new(Box::new(backend.clone()))));
+    assert!(bus.write(PLIC_BASE + 4 * 10, &1u32.to_le_bytes()));
+    assert!(bus.write(PLIC_BASE + 0x2080, &(1u32 << 10).to_le_bytes()));
+
+    // Transmit
+    for byte in b"Hi" {
+        assert!(bus.write(UART_BASE, &[*byte]));
+    }
+    assert_eq!(backend.take_output(), b"Hi");
+    assert_eq!(read_u8(&mut bus, UART_BASE + 5), 0x60);
+    assert!(!bus.write(UART_BASE, &[0, 0]));
+
+    // Divisor latch
+    assert!(bus.write(UART_BASE + 3, &[0x83]));
+    assert!(bus.write(UART_BASE, &[0x0C]));
+    assert!(bus.write(UART_BASE + 1, &[0x00]));
+    assert_eq!(read_u8(&mut bus, UART_BASE), 0x0C);
+    assert!(bus.write(UART_BASE + 3, &[0x03]));
+    assert!(backend.take_output().is_empty());
+
+    // Receive, with the interrupt delivered through the PLIC
+    assert!(bus.write(UART_BASE + 2, &[0x07]));
+    assert!(bus.write(UART_BASE + 1, &[0x01]));
+    assert_eq!(read_u8(&mut bus, UART_BASE + 2), 0xC1);
+    backend.push_input(b"ok");
+    bus.tick(false);
+    bus.tick(false);
+    assert_eq!(read_u8(&mut bus, UART_BASE + 5) & 0x01, 0x01);
+    assert_eq!(read_u8(&mut bus, UART_BASE + 2), 0xC4);
+    assert_eq!(bus.interrupt_lines(0), Interrupt::SupervisorExternal.mask());
+    assert_eq!(read_u32(&mut bus, PLIC_BASE + 0x201004), 10);
+    assert_eq!(read_u8(&mut bus, UART_BASE), b'o');
+    assert_eq!(read_u8(&mut bus, UART_BASE), b'k');
+    assert_eq!(read_u8(&mut bus, UART_BASE + 5) & 0x01, 0x00);
+    assert!(bus.write(PLIC_BASE + 0x201004, &10u32.to_le_bytes()));
+    assert_eq!(bus.interrupt_lines(0), 0);
+
+    // THR empty interrupt, cleared by reading the IIR
+    assert!(bus.write(UART_BASE + 1, &[0x02]));
+    assert_eq!(read_u8(&mut bus, UART_BASE + 2), 0xC2);
+    assert_eq!(read_u8(&mut bus, UART_BASE + 2), 0xC1);
+}
+
+#[test]
+fn test_finisher() {
+    let mut finisher = TestFinisher::new();
+    assert_eq!(finisher.take_halt_request(), None);
+    assert!(finisher.write(0, &0x1234u32.to_le_bytes()));
+    assert_eq!(finisher.take_halt_request(), None);
+    assert!(finisher.write(0, &0x0003_3333u32.to_le_bytes()));
+    assert_eq!(finisher.take_halt_request(), Some(HaltReason::Exit(3)));
+    assert!(finisher.write(0, &0x7777u16.to_le_bytes()));
+    assert_eq!(finisher.take_halt_request(), Some(HaltReason::Reset));
+    assert_eq!(finisher.take_halt_request(), None);
+    assert!(!finisher.write(4, &0u32.to_le_bytes()));
+
+    let mut emulator = RiscvEmulator::new(EmulationSettings { print_instructions: false, misaligned: MisalignedPolicy::Trap });
+    emulator.memory.add_device(0x10_0000, FINISHER_SIZE, Box::new(TestFinisher::new()));
+
+    // lui  x1, 0x100
+    // lui  x2, 0x5
+    // addi x2, x2, 0x555
+    // sw   x2, 0(x1)
+    // j    .
+    let code = [0x001000B7u32, 0x00005137, 0x55510113, 0x0020A023, 0x0000006F];
+    emulator.set_code(code.iter().flat_map(|instr| instr.to_le_bytes()).collect());
+
+    assert_eq!(emulator.execute(None), HaltReason::Exit(0));
+    assert_eq!(emulator.harts[0].read_pc(), 16);
+    assert_eq!(emulator.execute(Some(10)), HaltReason::InstructionLimit);
+}
+
+/// Build a little-endian RISC-V ELF64 file, with a single segment loaded at `addr` and a symbol table
+fn build_elf(addr: u64, segment: &[u8], symbols: &[(&str, u64)]) -> Vec<u8> {
+    const SEGMENT_OFFSET: usize = 0x1000;
+
+    let mut strtab = vec![0];
+    let mut symtab = vec![0; 24];
+    for (name, value) in symbols {
+        symtab.extend((strtab.len() as u32).to_le_bytes());
+        symtab.extend([0; 4]);
+        symtab.extend(value.to_le_bytes());
+        symtab.extend([0; 8]);
+        strtab.extend(name.as_bytes());
+        strtab.push(0);
+    }
+
+    let symtab_offset = SEGMENT_OFFSET + segment.len();
+    let strtab_offset = symtab_offset + symtab.len();
+    let shoff = strtab_offset + strtab.len();
+
+    let mut elf = vec![0; SEGMENT_OFFSET];
+    elf[..8].copy_from_slice(&[0x7F, b'E', b'L', b'F', 2, 1, 1, 0]);
+    elf[16..20].copy_from_slice(&[2, 0, 243, 0]);
+    elf[24..32].copy_from_slice(&addr.to_le_bytes());
+    elf[32..40].copy_from_slice(&64u64.to_le_bytes());
+    elf[40..48].copy_from_slice(&(shoff as u64).to_le_bytes());
+    elf[54..56].copy_from_slice(&56u16.to_le_bytes());
+    elf[56..58].copy_from_slice(&1u16.to_le_bytes());
+    elf[58..60].copy_from_slice(&64u16.to_le_bytes());
+    elf[60..62].copy_from_slice(&3u16.to_le_bytes());
+
+    // PT_LOAD
+    elf[64..68].copy_from_slice(&1u32.to_le_bytes());
+    elf[72..80].copy_from_slice(&(SEGMENT_OFFSET as u64).to_le_bytes());
+    elf[88..96].copy_from_slice(&addr.to_le_bytes());
+    elf[96..104].copy_from_slice(&(segment.len() as u64).to_le_bytes());
+    elf[104..112].copy_from_slice(&(segment.len() as u64).to_le_bytes());
+
+    elf.extend(segment);
+    elf.extend(&symtab);
+    elf.extend(&strtab);
+
+    // Section headers: null, symtab, strtab
+    let mut section = |sh_type: u32, offset: usize, size: usize, link: u32, entsize: u64| {
+        let mut header = [0; 64];
+        header[4..8].copy_from_slice(&sh_type.to_le_bytes());
+        header[24..32].copy_from_slice(&(offset as u64).to_le_bytes());
+        header[32..40].copy_from_slice(&(size as u64).to_le_bytes());
+        header[40..44].copy_from_slice(&link.to_le_bytes());
+        header[56..64].copy_from_slice(&entsize.to_le_bytes());
+        elf.extend(header);
+    };
+    section(0, 0, 0, 0, 0);
+    section(2, symtab_offset, symtab.len(), 2, 24);
+    section(3, strtab_offset, strtab.len(), 0, 0);
+    elf
+}
+
+#[test]
+fn test_htif() {
+    // 0x000: auipc x1, 0
+    // 0x004: addi x1, x1, 0x100    # tohost
+    // 0x008: addi x3, x1, 0x40     # fromhost
+    // 0x00C: addi x2, x1, 0x80     # syscall
+    // 0x010: sw   x2, 0(x1)        # write(1, "hi", 2)
+    // 0x014: sw   x0, 0(x3)
+    // 0x018: addi x2, x0, 7
+    // 0x01C: sw   x2, 0(x1)        # exit(3)
+    // 0x020: j    .
+    let code = [0x00000097u32, 0x10008093, 0x04008193, 0x08008113, 0x0020A023, 0x0001A023, 0x00700113, 0x0020A023, 0x0000006F];
+    let mut segment = vec![0; 0x200];
+    for (idx, instr) in code.iter().enumerate() {
+        segment[idx * 4..idx * 4 + 4].copy_from_slice(&instr.to_le_bytes());
+    }
+    for (idx, arg) in [64u64, 1, 0x8000_01C0, 2].iter().enumerate() {
+        segment[0x180 + idx * 8..0x188 + idx * 8].copy_from_slice(&arg.to_le_bytes());
+    }
+    segment[0x1C0..0x1C2].copy_from_slice(b"hi");
+
+    let elf = build_elf(0x8000_0000, &segment, &[("tohost", 0x8000_0100), ("fromhost", 0x8000_0140), ("_start", 0x8000_0000)]);
+
+    let mut emulator = RiscvEmulator::new(EmulationSettings { print_instructions: false, misaligned: MisalignedPolicy::Trap });
+    let loaded = emulator.load_elf(&elf).unwrap();
+    assert!(loaded.is_64_bit);
+    assert_eq!(loaded.symbols["_start"], 0x8000_0000);
+    assert_eq!(emulator.memory.ram_base, 0x8000_0000);
+    assert_eq!(emulator.harts[0].read_pc(), 0x8000_0000);
+
+    let console = BufferBackend::new();
+    emulator.htif = Htif::from_symbols(&loaded.symbols, Box::new(console.clone()));
+
+    assert_eq!(emulator.execute(None), HaltReason::Exit(3));
+    assert_eq!(console.take_output(), b"hi");
+    assert_eq!(emulator.harts[0].read_pc(), 0x8000_0020);
+    // Syscall result
+    assert_eq!(&emulator.memory.ram[0x180..0x188], &2u64.to_le_bytes());
+
+    assert_eq!(Elf::parse(&elf[1..]).err(), Some(ElfError::InvalidMagic));
+}
+
+#[test]
+fn test_load_elf_bounds() {
+    let new_emulator = || RiscvEmulator::new(EmulationSettings { print_instructions: false, misaligned: MisalignedPolicy::Trap });
+    let with_mem_size = |mem_size: u64| {
+        let mut elf = build_elf(0x8000_0000, &[0x13, 0, 0, 0], &[]);
+        elf[104..112].copy_from_slice(&mem_size.to_le_bytes());
+        elf
+    };
+
+    // Sizes that overflow or exceed the RAM limit are rejected, without allocating RAM
+    for mem_size in [u64::MAX - 0x10, ELF_MAX_RAM_SIZE + 1] {
+        let mut emulator = new_emulator();
+        assert_eq!(emulator.load_elf(&with_mem_size(mem_size)).err(), Some(ElfError::SegmentOutsideRam(0x8000_0000)));
+        assert!(emulator.memory.ram.is_empty());
+    }
+
+    let mut emulator = new_emulator();
+    assert!(emulator.load_elf(&with_mem_size(0x2000)).is_ok());
+    assert_eq!(emulator.memory.ram.len(), 0x2000);
+
+    // Configured RAM is not grown
+    let mut emulator = new_emulator();
+    emulator.memory.ram = vec![0xFF; 0x1000];
+    emulator.memory.ram_base = 0x8000_0000;
+    assert_eq!(emulator.load_elf(&with_mem_size(0x2000)).err(), Some(ElfError::SegmentOutsideRam(0x8000_0000)));
+    assert_eq!(emulator.memory.ram.len(), 0x1000);
+    assert!(emulator.load_elf(&with_mem_size(0x1000)).is_ok());
+    assert_eq!(&emulator.memory.ram[..8], &[0x13, 0, 0, 0, 0, 0, 0, 0]);
+}
+
+const VIRTIO_BASE: u64 = 0x1000_1000;
+const QUEUE_BASE: u64 = 0x8000_8000;
+const QUEUE_SIZE: u16 = 16;
+
+fn write_u32(bus: &mut Bus, addr: u64, value: u32) {
+    assert!(bus.write(addr, &value.to_le_bytes()));
+}
+
+fn read_u16(bus: &mut Bus, addr: u64) -> u16 {
+    let mut buf = [0; 2];
+    assert!(bus.read(addr, &mut buf));
+    u16::from_le_bytes(buf)
+}
+
+/// Addresses of the descriptor table, available ring and used ring of a queue
+fn queue_addrs(queue: u32) -> (u64, u64, u64) {
+    let base = QUEUE_BASE + queue as u64 * 0x400;
+    (base, base + 0x100, base + 0x200)
+}
+
+/// Bus with RAM at 0x8000_0000 and a virtio device at `VIRTIO_BASE`, going through the driver initialization with `num_queues` queues of 16 descriptors
+fn virtio_bus(device: Box<dyn Device>, num_queues: u32) -> Bus {
+    let mut bus = Bus::new();
+    bus.ram = vec![0; 0x10000];
+    bus.ram_base = 0x8000_0000;
+    bus.add_device_with_irq(VIRTIO_BASE, VIRTIO_MMIO_SIZE, 1, device);
+
+    assert_eq!(read_u32(&mut bus, VIRTIO_BASE), 0x7472_6976);
+    assert_eq!(read_u32(&mut bus, VIRTIO_BASE + 0x4), 2);
+
+    // ACKNOWLEDGE | DRIVER
+    write_u32(&mut bus, VIRTIO_BASE + 0x70, 0x3);
+    write_u32(&mut bus, VIRTIO_BASE + 0x14, 0);
+    let features = read_u32(&mut bus, VIRTIO_BASE + 0x10);
+    write_u32(&mut bus, VIRTIO_BASE + 0x14, 1);
+    assert_eq!(read_u32(&mut bus, VIRTIO_BASE + 0x10) & 1, 1);
+    write_u32(&mut bus, VIRTIO_BASE + 0x24, 0);
+    write_u32(&mut bus, VIRTIO_BASE + 0x20, features);
+    write_u32(&mut bus, VIRTIO_BASE + 0x24, 1);
+    write_u32(&mut bus, VIRTIO_BASE + 0x20, 1);
+    write_u32(&mut bus, VIRTIO_BASE + 0x70, 0xB);
+    assert_eq!(read_u32(&mut bus, VIRTIO_BASE + 0x70), 0xB);
+
+    for queue in 0..num_queues {
+        let (desc, avail, used) = queue_addrs(queue);
+        write_u32(&mut bus, VIRTIO_BASE + 0x30, queue);
+        assert_eq!(read_u32(&mut bus, VIRTIO_BASE + 0x34), 256);
+        write_u32(&mut bus, VIRTIO_BASE + 0x38, QUEUE_SIZE as u32);
+        write_u32(&mut bus, VIRTIO_BASE + 0x80, desc as u32);
+        write_u32(&mut bus, VIRTIO_BASE + 0x84, (desc >> 32) as u32);
+        write_u32(&mut bus, VIRTIO_BASE + 0x90, avail as u32);
+        write_u32(&mut bus, VIRTIO_BASE + 0x94, (avail >> 32) as u32);
+        write_u32(&mut bus, VIRTIO_BASE + 0xA0, used as u32);
+        write_u32(&mut bus, VIRTIO_BASE + 0xA4, (used >> 32) as u32);
+        write_u32(&mut bus, VIRTIO_BASE + 0x44, 1);
+    }
+    write_u32(&mut bus, VIRTIO_BASE + 0x30, num_queues);
+    assert_eq!(read_u32(&mut bus, VIRTIO_BASE + 0x34), 0);
+
+    // DRIVER_OK
+    write_u32(&mut bus, VIRTIO_BASE + 0x70, 0xF);
+    bus
+}
+
+/// Make a descriptor chain of up to 4 descriptors available in a queue, without notifying the device. Up to 4 chains can be in flight
+fn virtio_make_available(bus: &mut Bus, queue: u32, descriptors: &[(u64, u32, bool)]) {
+    let (desc_table, avail, _) = queue_addrs(queue);
+    let avail_idx = read_u16(bus, avail + 2);
+    let head = (avail_idx % 4) * 4;
+
+    for (idx, &(addr, len, write)) in descriptors.iter().enumerate() {
+        let next = idx + 1 < descriptors.len();
+        let flags = next as u16 | ((write as u16) << 1);
+        let desc = desc_table + (head as u64 + idx as u64) * 16;
+        assert!(bus.write(desc, &addr.to_le_bytes()));
+        assert!(bus.write(desc + 8, &len.to_le_bytes()));
+        assert!(bus.write(desc + 12, &flags.to_le_bytes()));
+        assert!(bus.write(desc + 14, &(head + idx as u16 + 1).to_le_bytes()));
+    }
+
+    assert!(bus.write(avail + 4 + (avail_idx % QUEUE_SIZE) as u64 * 2, &head.to_le_bytes()));
+    assert!(bus.write(avail + 2, &(avail_idx + 1).to_le_bytes()));
+}
+
+/// Number of chains used by the device in a queue
+fn virtio_used_idx(bus: &mut Bus, queue: u32) -> u16 {
+    read_u16(bus, queue_addrs(queue).2 + 2)
+}
+
+/// Length written by the device to the `idx`th used chain
+fn virtio_used_len(bus: &mut Bus, queue: u32, idx: u16) -> u32 {
+    read_u32(bus, queue_addrs(queue).2 + 4 + (idx % QUEUE_SIZE) as u64 * 8 + 4)
+}
+
+/// Make a descriptor chain available in a queue and notify the device, returns the length reported in the used ring
+fn virtio_submit(bus: &mut Bus, queue: u32, descriptors: &[(u64, u32, bool)]) -> u32 {
+    let used_idx = virtio_used_idx(bus, queue);
+    virtio_make_available(bus, queue, descriptors);
+    write_u32(bus, VIRTIO_BASE + 0x50, queue);
+    assert_eq!(virtio_used_idx(bus, queue), used_idx + 1);
+    virtio_used_len(bus, queue, used_idx)
+}
+
+fn virtio_block_request(bus: &mut Bus, req_type: u32, sector: u64, len: u32) -> u8 {
+    let mut header = [0; 16];
+    header[0..4].copy_from_slice(&req_type.to_le_bytes());
+    header[8..16].copy_from_slice(&sector.to_le_bytes());
+    assert!(bus.write(0x8000_1000, &header));
+
+    let data_write = req_type != 1;
+    virtio_submit(bus, 0, &[(0x8000_1000, 16, false), (0x8000_2000, len, data_write), (0x8000_3000, 1, true)]);
+    read_u8(bus, 0x8000_3000)
+}
+
+#[test]
+fn test_virtio_block() {
+    let path = std::env::temp_dir().join(format!("emu_virtio_block_{}.img", std::process::id()));
+    let image = (0..4 * 512).map(|idx| (idx / 512) as u8 + 1).collect::<Vec<u8>>();
+    std::fs::write(&path, &image).unwrap();
+
+    // Copy-on-write
+    let mut bus = virtio_bus(Box::new(VirtioMmio::new(VirtioBlock::open(&path, DiskMode::CopyOnWrite).unwrap())), 1);
+    assert_eq!(read_u32(&mut bus, VIRTIO_BASE + 0x8), 2);
+    assert_eq!(read_u32(&mut bus, VIRTIO_BASE + 0x100), 4);
+
+    assert_eq!(virtio_block_request(&mut bus, 0, 1, 512), 0);
+    assert_eq!(&bus.ram[0x2000..0x2200], &image[512..1024]);
+    assert_eq!(read_u32(&mut bus, VIRTIO_BASE + 0x60), 1);
+    write_u32(&mut bus, VIRTIO_BASE + 0x64, 1);
+    assert_eq!(read_u32(&mut bus, VIRTIO_BASE + 0x60), 0);
+
+    bus.ram[0x2000..0x2200].fill(0xAA);
+    assert_eq!(virtio_block_request(&mut bus, 1, 2, 512), 0);
+    bus.ram[0x2000..0x2400].fill(0);
+    assert_eq!(virtio_block_request(&mut bus, 0, 2, 1024), 0);
+    assert_eq!(&bus.ram[0x2000..0x2200], &[0xAA; 512]);
+    assert_eq!(&bus.ram[0x2200..0x2400], &image[1536..2048]);
+    assert_eq!(std::fs::read(&path).unwrap(), image);
+
+    // Out of bounds and unsupported requests
+    assert_eq!(virtio_block_request(&mut bus, 0, 4, 512), 1);
+    assert_eq!(virtio_block_request(&mut bus, 0x1234, 0, 512), 2);
+
+    // Read-only
+    let mut bus = virtio_bus(Box::new(VirtioMmio::new(VirtioBlock::open(&path, DiskMode::ReadOnly).unwrap())), 1);
+    write_u32(&mut bus, VIRTIO_BASE + 0x14, 0);
+    assert_eq!(read_u32(&mut bus, VIRTIO_BASE + 0x10) & (1 << 5), 1 << 5);
+    assert_eq!(virtio_block_request(&mut bus, 1, 0, 512), 1);
+
+    // Read-write
+    let mut bus = virtio_bus(Box::new(VirtioMmio::new(VirtioBlock::open(&path, DiskMode::ReadWrite).unwrap())), 1);
+    bus.ram[0x2000..0x2200].fill(0x55);
+    assert_eq!(virtio_block_request(&mut bus, 1, 3, 512), 0);
+    assert_eq!(virtio_block_request(&mut bus, 4, 0, 0), 0);
+    assert_eq!(&std::fs::read(&path).unwrap()[1536..], &[0x55; 512]);
+
+    // Reset
+    write_u32(&mut bus, VIRTIO_BASE + 0x70, 0);
+    assert_eq!(read_u32(&mut bus, VIRTIO_BASE + 0x70), 0);
+    assert_eq!(read_u32(&mut bus, VIRTIO_BASE + 0x44), 0);
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+fn control_message(bus: &mut Bus, addr: u64) -> (u32, u16, u16) {
+    (read_u32(bus, addr), read_u16(bus, addr + 4), read_u16(bus, addr + 6))
+}
+
+#[test]
+fn test_virtio_console() {
+    let console = BufferBackend::new();
+    let port1 = BufferBackend::new();
+    let mut bus = virtio_bus(Box::new(VirtioMmio::new(VirtioConsole::new(vec![Box::new(console.clone()), Box::new(port1.clone())]))), 6);
+    assert_eq!(read_u32(&mut bus, VIRTIO_BASE + 0x8), 3);
+    assert_eq!(read_u32(&mut bus, VIRTIO_BASE + 0x104), 2);
+
+    // Port discovery over the control queues
+    for idx in 0..2 {
+        virtio_make_available(&mut bus, 2, &[(0x8000_4000 + idx * 8, 8, true)]);
+    }
+    assert!(bus.write(0x8000_5000, &[0, 0, 0, 0, 0, 0, 1, 0]));
+    virtio_submit(&mut bus, 3, &[(0x8000_5000, 8, false)]);
+    bus.tick(false);
+    assert_eq!(virtio_used_idx(&mut bus, 2), 2);
+    assert_eq!(control_message(&mut bus, 0x8000_4000), (0, 1, 0));
+    assert_eq!(control_message(&mut bus, 0x8000_4008), (1, 1, 0));
+
+    for idx in 0..2 {
+        virtio_make_available(&mut bus, 2, &[(0x8000_4000 + idx * 8, 8, true)]);
+    }
+    assert!(bus.write(0x8000_5000, &[0, 0, 0, 0, 3, 0, 1, 0]));
+    virtio_submit(&mut bus, 3, &[(0x8000_5000, 8, false)]);
+    bus.tick(false);
+    assert_eq!(virtio_used_idx(&mut bus, 2), 4);
+    assert_eq!(control_message(&mut bus, 0x8000_4000), (0, 4, 1));
+    assert_eq!(control_message(&mut bus, 0x8000_4008), (0, 6, 1));
+
+    // Transmit on port 1
+    assert!(bus.write(0x8000_6000, b"hey"));
+    assert_eq!(virtio_submit(&mut bus, 5, &[(0x8000_6000, 3, false)]), 0);
+    assert_eq!(port1.take_output(), b"hey");
+    assert!(console.take_output().is_empty());
+
+    // Receive on port 0
+    virtio_make_available(&mut bus, 0, &[(0x8000_7000, 16, true)]);
+    bus.tick(false);
+    assert_eq!(virtio_used_idx(&mut bus, 0), 0);
+    console.push_input(b"in");
+    bus.tick(false);
+    assert_eq!(virtio_used_idx(&mut bus, 0), 1);
+    assert_eq!(virtio_used_len(&mut bus, 0, 0), 2);
+    assert_eq!(&bus.ram[0x7000..0x7002], b"in");
+    assert_eq!(read_u32(&mut bus, VIRTIO_BASE + 0x60), 1);
+
+    // Emergency write
+    write_u32(&mut bus, VIRTIO_BASE + 0x108, b'!' as u32);
+    assert_eq!(console.take_output(), b"!");
+}
+
+#[test]
+fn test_virtio_rng() {
+    let mut outputs = Vec::new();
+    for _ in 0..2 {
+        let mut bus = virtio_bus(Box::new(VirtioMmio::new(VirtioRng::new(1234))), 1);
+        assert_eq!(read_u32(&mut bus, VIRTIO_BASE + 0x8), 4);
+        assert_eq!(virtio_submit(&mut bus, 0, &[(0x8000_1000, 20, true), (0x8000_2000, 12, true)]), 32);
+        outputs.push([bus.ram[0x1000..0x1014].to_vec(), bus.ram[0x2000..0x200C].to_vec()].concat());
+    }
+    assert_eq!(outputs[0], outputs[1]);
+    assert!(outputs[0].iter().any(|&byte| byte != 0));
+
+    let mut bus = virtio_bus(Box::new(VirtioMmio::new(VirtioRng::new(5678))), 1);
+    virtio_submit(&mut bus, 0, &[(0x8000_1000, 32, true)]);
+    assert_ne!(&bus.ram[0x1000..0x1020], &outputs[0][..]);
+}
+
+/// Transmit a frame on a virtio-net device, prefixed by an empty header
+fn virtio_net_send(bus: &mut Bus, frame: &[u8]) {
+    let mut data = vec![0; 12];
+    data.extend(frame);
+    assert!(bus.write(0x8000_1000, &data));
+    virtio_submit(bus, 1, &[(0x8000_1000, data.len() as u32, false)]);
+}
+
+#[test]
+fn test_virtio_net() {
+    let mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+    let frame = (0..64).collect::<Vec<u8>>();
+
+    // Loopback switch
+    let switch = LoopbackSwitch::new();
+    let mut bus_a = virtio_bus(Box::new(VirtioMmio::new(VirtioNet::new(mac, Box::new(switch.connect())))), 2);
+    let mut bus_b = virtio_bus(Box::new(VirtioMmio::new(VirtioNet::new(mac, Box::new(switch.connect())))), 2);
+    assert_eq!(read_u32(&mut bus_a, VIRTIO_BASE + 0x8), 1);
+    let mut config = [0; 8];
+    assert!(bus_a.read(VIRTIO_BASE + 0x100, &mut config));
+    assert_eq!(config, [0x52, 0x54, 0x00, 0x12, 0x34, 0x56, 1, 0]);
+
+    virtio_net_send(&mut bus_a, &frame);
+    virtio_make_available(&mut bus_b, 0, &[(0x8000_2000, 1526, true)]);
+    bus_b.tick(false);
+    assert_eq!(virtio_used_idx(&mut bus_b, 0), 1);
+    assert_eq!(virtio_used_len(&mut bus_b, 0, 0), 12 + 64);
+    assert_eq!(read_u16(&mut bus_b, 0x8000_2000 + 10), 1);
+    assert_eq!(&bus_b.ram[0x200C..0x204C], &frame[..]);
+
+    // Frames are not sent back to the sender
+    virtio_make_available(&mut bus_a, 0, &[(0x8000_2000, 1526, true)]);
+    bus_a.tick(false);
+    assert_eq!(virtio_used_idx(&mut bus_a, 0), 0);
+
+    // Pcap
+    let path = std::env::temp_dir().join(format!("emu_virtio_net_{}.pcap", std::process::id()));
+    let mut bus = virtio_bus(Box::new(VirtioMmio::new(VirtioNet::new(mac, Box::new(PcapBackend::new(Some(&path), None).unwrap())))), 2);
+    virtio_net_send(&mut bus, &frame);
+    virtio_net_send(&mut bus, &frame[..32]);
+    let pcap = std::fs::read(&path).unwrap();
+    assert_eq!(pcap.len(), 24 + 16 + 64 + 16 + 32);
+    assert_eq!(&pcap[0..4], &[0xD4, 0xC3, 0xB2, 0xA1]);
+
+    let mut bus = virtio_bus(Box::new(VirtioMmio::new(VirtioNet::new(mac, Box::new(PcapBackend::new(None, Some(&path)).unwrap())))), 2);
+    virtio_make_available(&mut bus, 0, &[(0x8000_2000, 1526, true)]);
+    virtio_make_available(&mut bus, 0, &[(0x8000_3000, 1526, true)]);
+    bus.tick(false);
+    assert_eq!(virtio_used_idx(&mut bus, 0), 2);
+    assert_eq!(&bus.ram[0x200C..0x204C], &frame[..]);
+    assert_eq!(virtio_used_len(&mut bus, 0, 1), 12 + 32);
+    assert_eq!(&bus.ram[0x300C..0x302C], &frame[..32]);
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_framebuffer() {
+    let framebuffer = Framebuffer::new(2, 2, PixelFormat::X8R8G8B8);
+    assert_eq!(framebuffer.stride(), 8);
+    assert_eq!(framebuffer.size(), 16);
+    assert_eq!(framebuffer.format().name(), "x8r8g8b8");
+
+    let mut bus = Bus::new();
+    bus.add_device(0x5000_0000, framebuffer.size(), Box::new(framebuffer.clone()));
+    assert!(bus.write(0x5000_0000, &0x00FF_0000u32.to_le_bytes()));
+    assert!(bus.write(0x5000_0004, &0xFF00_FF00u32.to_le_bytes()));
+    assert!(bus.write(0x5000_0008, &0x0000_00FFu32.to_le_bytes()));
+    assert!(bus.write(0x5000_000C, &[0x40, 0x80, 0xC0, 0x00]));
+    assert!(!bus.write(0x5000_000E, &[0; 4]));
+    assert_eq!(framebuffer.to_rgb(), [0xFF, 0, 0, 0, 0xFF, 0, 0, 0, 0xFF, 0xC0, 0x80, 0x40]);
+
+    let mut ppm = Vec::new();
+    framebuffer.write_ppm(&mut ppm).unwrap();
+    assert_eq!(&ppm[..11], b"P6\n2 2\n255\n");
+    assert_eq!(&ppm[11..], &framebuffer.to_rgb()[..]);
+
+    let mut png = Vec::new();
+    framebuffer.write_png(&mut png).unwrap();
+    assert_eq!(&png[..8], &[0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n']);
+    // IHDR, with its CRC
+    assert_eq!(&png[8..16], &[0, 0, 0, 13, b'I', b'H', b'D', b'R']);
+    assert_eq!(&png[16..29], &[0, 0, 0, 2, 0, 0, 0, 2, 8, 2, 0, 0, 0]);
+    assert_eq!(&png[29..33], &0xFD_D4_9A_73u32.to_be_bytes());
+    // IDAT contains the unfiltered rows in a single stored block
+    let idat_len = u32::from_be_bytes(png[33..37].try_into().unwrap()) as usize;
+    assert_eq!(&png[37..41], b"IDAT");
+    assert_eq!(idat_len, 2 + 5 + 14 + 4);
+    assert_eq!(&png[41..48], &[0x78, 0x01, 1, 14, 0, !14, 0xFF]);
+    assert_eq!(&png[48..55], &[0, 0xFF, 0, 0, 0, 0xFF, 0]);
+    assert_eq!(&png[png.len() - 12..], &[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]);
+
+    // 16-bit pixels are expanded to 8-bit channels
+    let framebuffer = Framebuffer::new(1, 1, PixelFormat::R5G6B5);
+    assert!(framebuffer.clone().write(0, &0xF81Fu16.to_le_bytes()));
+    assert_eq!(framebuffer.to_rgb(), [0xFF, 0, 0xFF]);
+}
+
+#[test]
+fn test_rtc() {
+    // 1 µs per tick
+    let mut rtc = Rtc::new(RtcClock::Virtual { start: 0x1_0000_0000, ns_per_tick: 1000 });
+    let mut buf = [0; 4];
+    assert!(rtc.read(0x00, &mut buf));
+    assert_eq!(u32::from_le_bytes(buf), 0);
+    assert!(rtc.read(0x04, &mut buf));
+    assert_eq!(u32::from_le_bytes(buf), 1);
+
+    rtc.tick(false);
+    assert_eq!(rtc.time(), 0x1_0000_0000 + 1000);
+
+    // Setting the time
+    assert!(rtc.write(0x04, &2u32.to_le_bytes()));
+    assert!(rtc.write(0x00, &0u32.to_le_bytes()));
+    assert_eq!(rtc.time(), 0x2_0000_0000);
+    rtc.tick(true);
+    assert_eq!(rtc.time(), 0x2_0000_0000 + 1000);
+
+    // Alarm 10 µs from now
+    assert!(rtc.write(0x10, &1u32.to_le_bytes()));
+    assert!(rtc.write(0x0C, &2u32.to_le_bytes()));
+    assert!(rtc.write(0x08, &11000u32.to_le_bytes()));
+    assert!(rtc.read(0x18, &mut buf));
+    assert_eq!(u32::from_le_bytes(buf), 1);
+    assert_eq!(rtc.next_event(), Some(10));
+    rtc.skip(9);
+    assert!(!rtc.interrupt_level());
+    rtc.tick(false);
+    assert!(rtc.interrupt_level());
+    assert_eq!(rtc.next_event(), None);
+    assert!(rtc.read(0x18, &mut buf));
+    assert_eq!(u32::from_le_bytes(buf), 0);
+    assert!(rtc.write(0x1C, &1u32.to_le_bytes()));
+    assert!(!rtc.interrupt_level());
+
+    // An alarm in the past fires immediately, and cleared alarms don't fire
+    assert!(rtc.write(0x08, &0u32.to_le_bytes()));
+    assert!(rtc.interrupt_level());
+    assert!(rtc.write(0x1C, &1u32.to_le_bytes()));
+    assert!(rtc.write(0x08, &20000u32.to_le_bytes()));
+    assert!(rtc.write(0x14, &1u32.to_le_bytes()));
+    rtc.skip(100);
+    assert!(!rtc.interrupt_level());
+
+    assert!(!rtc.read(0x00, &mut [0; 8]));
+    assert!(!rtc.write(0x20, &[0; 4]));
+
+    // Host clock
+    let rtc = Rtc::new(RtcClock::Host);
+    assert!(rtc.time() > 1_600_000_000 * 1_000_000_000);
+}
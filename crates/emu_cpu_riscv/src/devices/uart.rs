@@ -0,0 +1,217 @@
+use std::collections::VecDeque;
+
+use crate::fdt::{FdtContext, FdtNode};
+
+use super::{Device, SerialBackend};
+
+
+// Registers (NS16550A, byte-wide with a register stride of 1)
+//
+// | offset | read              | write             | DLAB = 1        |
+// |--------|-------------------|-------------------|-----------------|
+// | 0      | RBR               | THR               | DLL             |
+// | 1      | IER               | IER               | DLM             |
+// | 2      | IIR               | FCR               |                 |
+// | 3      | LCR               | LCR               |                 |
+// | 4      | MCR               | MCR               |                 |
+// | 5      | LSR               |                   |                 |
+// | 6      | MSR               |                   |                 |
+// | 7      | SCR               | SCR               |                 |
+
+const RBR_THR: u64 = 0;
+const IER: u64 = 1;
+const IIR_FCR: u64 = 2;
+const LCR: u64 = 3;
+const MCR: u64 = 4;
+const LSR: u64 = 5;
+const MSR: u64 = 6;
+const SCR: u64 = 7;
+
+const IER_RX_AVAILABLE: u8 = 0x01;
+const IER_THR_EMPTY: u8 = 0x02;
+const IER_MASK: u8 = 0x0F;
+
+const IIR_NONE: u8 = 0x01;
+const IIR_THR_EMPTY: u8 = 0x02;
+const IIR_RX_AVAILABLE: u8 = 0x04;
+const IIR_FIFO_ENABLED: u8 = 0xC0;
+
+const FCR_FIFO_ENABLE: u8 = 0x01;
+const FCR_CLEAR_RX: u8 = 0x02;
+
+const LCR_DLAB: u8 = 0x80;
+
+const MCR_LOOPBACK: u8 = 0x10;
+
+const LSR_DATA_READY: u8 = 0x01;
+const LSR_THR_EMPTY: u8 = 0x20;
+const LSR_TRANSMITTER_EMPTY: u8 = 0x40;
+
+/// Depth of the receive FIFO
+const FIFO_SIZE: usize = 16;
+
+/// Size of the UART region on the bus
+pub const UART_SIZE: u64 = 0x100;
+
+/// Input clock reported in the devicetree, the emulated UART doesn't depend on the baud rate
+const UART_CLOCK_FREQUENCY: u32 = 3_686_400;
+
+/// NS16550A compatible UART, transmitted data is sent to the backend immediately
+pub struct Uart {
+    backend: Box<dyn SerialBackend>,
+
+    rx_fifo: VecDeque<u8>,
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    divisor: u16,
+    /// Set when the THR becomes empty, cleared by reading the IIR or writing the THR
+    thr_empty_interrupt: bool,
+}
+
+impl Uart {
+    pub fn new(backend: Box<dyn SerialBackend>) -> Self {
+        Self {
+            backend,
+            rx_fifo: VecDeque::new(),
+            ier: 0,
+            fcr: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            divisor: 0,
+            thr_empty_interrupt: false,
+        }
+    }
+
+    fn rx_capacity(&self) -> usize {
+        if self.fcr & FCR_FIFO_ENABLE != 0 { FIFO_SIZE } else { 1 }
+    }
+
+    fn transmit(&mut self, byte: u8) {
+        if self.mcr & MCR_LOOPBACK != 0 {
+            if self.rx_fifo.len() < self.rx_capacity() {
+                self.rx_fifo.push_back(byte);
+            }
+        } else {
+            self.backend.transmit(byte);
+        }
+        self.thr_empty_interrupt = true;
+    }
+
+    /// Highest priority interrupt that is pending
+    fn interrupt_id(&self) -> u8 {
+        if self.ier & IER_RX_AVAILABLE != 0 && !self.rx_fifo.is_empty() {
+            IIR_RX_AVAILABLE
+        } else if self.ier & IER_THR_EMPTY != 0 && self.thr_empty_interrupt {
+            IIR_THR_EMPTY
+        } else {
+            IIR_NONE
+        }
+    }
+
+    fn read_register(&mut self, offset: u64) -> u8 {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            RBR_THR if dlab => self.divisor as u8,
+            RBR_THR => self.rx_fifo.pop_front().unwrap_or(0),
+            IER if dlab => (self.divisor >> 8) as u8,
+            IER => self.ier,
+            IIR_FCR => {
+                let id = self.interrupt_id();
+                if id == IIR_THR_EMPTY {
+                    self.thr_empty_interrupt = false;
+                }
+                let fifo = if self.fcr & FCR_FIFO_ENABLE != 0 { IIR_FIFO_ENABLED } else { 0 };
+                id | fifo
+            },
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                let data_ready = if self.rx_fifo.is_empty() { 0 } else { LSR_DATA_READY };
+                data_ready | LSR_THR_EMPTY | LSR_TRANSMITTER_EMPTY
+            },
+            // Report DCD, RI, DSR and CTS as asserted, as there is no modem
+            MSR => 0xF0,
+            SCR => self.scr,
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: u64, value: u8) {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            RBR_THR if dlab => self.divisor = (self.divisor & 0xFF00) | value as u16,
+            RBR_THR => self.transmit(value),
+            IER if dlab => self.divisor = (self.divisor & 0x00FF) | ((value as u16) << 8),
+            IER => {
+                // Enabling the THR empty interrupt while the THR is empty raises it immediately
+                if value & IER_THR_EMPTY != 0 && self.ier & IER_THR_EMPTY == 0 {
+                    self.thr_empty_interrupt = true;
+                }
+                self.ier = value & IER_MASK;
+            },
+            IIR_FCR => {
+                if value & FCR_CLEAR_RX != 0 {
+                    self.rx_fifo.clear();
+                }
+                self.fcr = value & 0xC9;
+            },
+            LCR => self.lcr = value,
+            MCR => self.mcr = value & 0x1F,
+            SCR => self.scr = value,
+            _ => {},
+        }
+    }
+}
+
+impl Device for Uart {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> bool {
+        if buf.len() != 1 {
+            return false;
+        }
+        buf[0] = self.read_register(offset);
+        true
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> bool {
+        if buf.len() != 1 {
+            return false;
+        }
+        self.write_register(offset, buf[0]);
+        true
+    }
+
+    fn tick(&mut self, _retired: bool) {
+        // Loopback disconnects the receiver from the host
+        if self.mcr & MCR_LOOPBACK == 0 && self.rx_fifo.len() < self.rx_capacity() {
+            if let Some(byte) = self.backend.receive() {
+                self.rx_fifo.push_back(byte);
+            }
+        }
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        // Keep polling the host while the guest waits for input
+        (self.ier & IER_RX_AVAILABLE != 0 && self.rx_fifo.is_empty() && self.backend.may_receive()).then_some(1)
+    }
+
+    fn skip(&mut self, ticks: u64) {
+        if ticks != 0 {
+            self.tick(false);
+        }
+    }
+
+    fn interrupt_level(&self) -> bool {
+        self.interrupt_id() != IIR_NONE
+    }
+
+    fn devicetree_node(&self, base: u64, _fdt: &FdtContext) -> Option<FdtNode> {
+        let mut node = FdtNode::new(format!("serial@{base:x}"));
+        node.property_string("compatible", "ns16550a")
+            .property_u32("clock-frequency", UART_CLOCK_FREQUENCY);
+        Some(node)
+    }
+}
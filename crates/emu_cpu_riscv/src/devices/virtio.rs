@@ -0,0 +1,445 @@
+use crate::fdt::{FdtContext, FdtNode};
+use crate::memory::MemoryBus;
+
+use super::{Device, read_register};
+
+mod block;
+pub use block::*;
+mod console;
+pub use console::*;
+mod net;
+pub use net::*;
+mod rng;
+pub use rng::*;
+
+
+// Memory map (virtio-mmio, version 2)
+//
+// | offset | register                          |
+// |--------|-----------------------------------|
+// | 0x000  | MagicValue                        |
+// | 0x004  | Version                           |
+// | 0x008  | DeviceID                          |
+// | 0x00C  | VendorID                          |
+// | 0x010  | DeviceFeatures                    |
+// | 0x014  | DeviceFeaturesSel                 |
+// | 0x020  | DriverFeatures                    |
+// | 0x024  | DriverFeaturesSel                 |
+// | 0x030  | QueueSel                          |
+// | 0x034  | QueueNumMax                       |
+// | 0x038  | QueueNum                          |
+// | 0x044  | QueueReady                        |
+// | 0x050  | QueueNotify                       |
+// | 0x060  | InterruptStatus                   |
+// | 0x064  | InterruptACK                      |
+// | 0x070  | Status                            |
+// | 0x080  | QueueDescLow/High                 |
+// | 0x090  | QueueDriverLow/High               |
+// | 0x0A0  | QueueDeviceLow/High               |
+// | 0x0FC  | ConfigGeneration                  |
+// | 0x100  | device specific configuration     |
+
+const MAGIC_VALUE: u64 = 0x000;
+const VERSION: u64 = 0x004;
+const DEVICE_ID: u64 = 0x008;
+const VENDOR_ID: u64 = 0x00C;
+const DEVICE_FEATURES: u64 = 0x010;
+const DEVICE_FEATURES_SEL: u64 = 0x014;
+const DRIVER_FEATURES: u64 = 0x020;
+const DRIVER_FEATURES_SEL: u64 = 0x024;
+const QUEUE_SEL: u64 = 0x030;
+const QUEUE_NUM_MAX: u64 = 0x034;
+const QUEUE_NUM: u64 = 0x038;
+const QUEUE_READY: u64 = 0x044;
+const QUEUE_NOTIFY: u64 = 0x050;
+const INTERRUPT_STATUS: u64 = 0x060;
+const INTERRUPT_ACK: u64 = 0x064;
+const STATUS: u64 = 0x070;
+const QUEUE_DESC_LOW: u64 = 0x080;
+const QUEUE_DESC_HIGH: u64 = 0x084;
+const QUEUE_DRIVER_LOW: u64 = 0x090;
+const QUEUE_DRIVER_HIGH: u64 = 0x094;
+const QUEUE_DEVICE_LOW: u64 = 0x0A0;
+const QUEUE_DEVICE_HIGH: u64 = 0x0A4;
+const CONFIG: u64 = 0x100;
+
+/// "virt" in little-endian
+const MAGIC: u32 = 0x7472_6976;
+/// "QEMU" in little-endian, so guests apply the same quirks as for qemu
+const VENDOR: u32 = 0x554D_4551;
+
+const STATUS_DRIVER_OK: u32 = 0x04;
+const STATUS_FEATURES_OK: u32 = 0x08;
+
+const INTERRUPT_USED_BUFFER: u32 = 0x1;
+
+/// Feature bit indicating compliance with version 1 of the virtio spec, required for the version 2 mmio transport
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// Size of the virtio-mmio region on the bus
+pub const VIRTIO_MMIO_SIZE: u64 = 0x1000;
+
+/// Max number of descriptors in a virtqueue
+pub const VIRTQUEUE_MAX_SIZE: u16 = 256;
+
+const VIRTQ_DESC_F_NEXT: u16 = 0x1;
+const VIRTQ_DESC_F_WRITE: u16 = 0x2;
+
+/// Device behind a virtio transport
+pub trait VirtioDevice: Send {
+    /// Virtio device type
+    fn device_id(&self) -> u32;
+
+    /// Device specific feature bits
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn num_queues(&self) -> usize;
+
+    /// Read from the device specific configuration space
+    fn read_config(&mut self, _offset: u64, _buf: &mut [u8]) -> bool {
+        false
+    }
+
+    /// Write to the device specific configuration space
+    fn write_config(&mut self, _offset: u64, _buf: &[u8]) -> bool {
+        false
+    }
+
+    /// Handle the driver making buffers available in `queue`, returns `true` if any buffers were used
+    fn notify(&mut self, queue: usize, queues: &mut [Virtqueue], memory: &mut dyn MemoryBus) -> bool;
+
+    /// Handle events from the host, called every tick once the driver is ready, returns `true` if any buffers were used
+    fn poll(&mut self, _queues: &mut [Virtqueue], _memory: &mut dyn MemoryBus) -> bool {
+        false
+    }
+
+    /// Number of ticks after which `poll` may use buffers, `None` if that only happens after the driver makes buffers available
+    fn next_event(&self) -> Option<u64> {
+        None
+    }
+
+    /// Reset the device state
+    fn reset(&mut self) {}
+}
+
+/// Buffer described by a descriptor
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Descriptor {
+    pub addr: u64,
+    pub len: u32,
+    /// Set if the buffer is written by the device, otherwise it is read by the device
+    pub write: bool,
+}
+
+/// Chain of descriptors making up a single request
+pub struct DescriptorChain {
+    /// Index of the first descriptor, identifying the chain when it is used
+    pub head: u16,
+    pub descriptors: Vec<Descriptor>,
+}
+
+impl DescriptorChain {
+    /// Read the contents of all device-readable buffers
+    pub fn read_all(&self, memory: &mut dyn MemoryBus) -> Option<Vec<u8>> {
+        let mut data = Vec::new();
+        for desc in self.descriptors.iter().filter(|desc| !desc.write) {
+            let start = data.len();
+            data.resize(start + desc.len as usize, 0);
+            if !memory.read(desc.addr, &mut data[start..]) {
+                return None;
+            }
+        }
+        Some(data)
+    }
+
+    /// Total size of the device-writable buffers
+    pub fn writable_len(&self) -> usize {
+        self.descriptors.iter().filter(|desc| desc.write).map(|desc| desc.len as usize).sum()
+    }
+
+    /// Write `data` to the device-writable buffers, returns the number of bytes written
+    pub fn write_all(&self, memory: &mut dyn MemoryBus, mut data: &[u8]) -> u32 {
+        let mut written = 0;
+        for desc in self.descriptors.iter().filter(|desc| desc.write) {
+            if data.is_empty() {
+                break;
+            }
+            let len = data.len().min(desc.len as usize);
+            if !memory.write(desc.addr, &data[..len]) {
+                break;
+            }
+            written += len as u32;
+            data = &data[len..];
+        }
+        written
+    }
+}
+
+/// Split virtqueue, i.e. a descriptor table with separate available (driver) and used (device) rings
+#[derive(Clone, Default)]
+pub struct Virtqueue {
+    size: u16,
+    ready: bool,
+    desc: u64,
+    avail: u64,
+    used: u64,
+    /// Index in the available ring of the next buffer to handle
+    last_avail: u16,
+    /// Index in the used ring of the next used buffer
+    next_used: u16,
+}
+
+impl Virtqueue {
+    pub fn is_ready(&self) -> bool {
+        self.ready && self.size != 0
+    }
+
+    /// Take the next available descriptor chain, returns `None` if the driver didn't make any buffers available or the chain is malformed
+    pub fn pop(&mut self, memory: &mut dyn MemoryBus) -> Option<DescriptorChain> {
+        if !self.is_ready() {
+            return None;
+        }
+
+        let avail_idx = read_u16(memory, self.avail + 2)?;
+        if avail_idx == self.last_avail {
+            return None;
+        }
+        let head = read_u16(memory, self.avail + 4 + (self.last_avail % self.size) as u64 * 2)?;
+        self.last_avail = self.last_avail.wrapping_add(1);
+
+        let mut descriptors = Vec::new();
+        let mut idx = head;
+        // Chains can't be longer than the queue, which also protects against loops
+        for _ in 0..self.size {
+            if idx >= self.size {
+                return None;
+            }
+            let mut raw = [0; 16];
+            if !memory.read(self.desc + idx as u64 * 16, &mut raw) {
+                return None;
+            }
+            let flags = u16::from_le_bytes([raw[12], raw[13]]);
+            descriptors.push(Descriptor {
+                addr: u64::from_le_bytes(raw[0..8].try_into().unwrap()),
+                len: u32::from_le_bytes(raw[8..12].try_into().unwrap()),
+                write: flags & VIRTQ_DESC_F_WRITE != 0,
+            });
+
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                return Some(DescriptorChain { head, descriptors });
+            }
+            idx = u16::from_le_bytes([raw[14], raw[15]]);
+        }
+        None
+    }
+
+    /// Return a descriptor chain to the driver, with `len` bytes written to its buffers
+    pub fn push_used(&mut self, memory: &mut dyn MemoryBus, head: u16, len: u32) {
+        let elem = self.used + 4 + (self.next_used % self.size) as u64 * 8;
+        memory.write(elem, &(head as u32).to_le_bytes());
+        memory.write(elem + 4, &len.to_le_bytes());
+        self.next_used = self.next_used.wrapping_add(1);
+        memory.write(self.used + 2, &self.next_used.to_le_bytes());
+    }
+}
+
+fn read_u16(memory: &mut dyn MemoryBus, addr: u64) -> Option<u16> {
+    let mut buf = [0; 2];
+    memory.read(addr, &mut buf).then(|| u16::from_le_bytes(buf))
+}
+
+/// Set the low or high half of a 64-bit value
+fn set_half(value: u64, high: bool, half: u32) -> u64 {
+    if high {
+        (value & 0xFFFF_FFFF) | ((half as u64) << 32)
+    } else {
+        (value & !0xFFFF_FFFF) | half as u64
+    }
+}
+
+/// Virtio-mmio transport, exposing a virtio device on the bus
+pub struct VirtioMmio<D: VirtioDevice> {
+    device: D,
+
+    device_features_sel: u32,
+    driver_features: u64,
+    driver_features_sel: u32,
+    queue_sel: u32,
+    queues: Vec<Virtqueue>,
+    /// Queues notified by the driver, which still need to be processed
+    notified: u64,
+    interrupt_status: u32,
+    status: u32,
+}
+
+impl<D: VirtioDevice> VirtioMmio<D> {
+    pub fn new(device: D) -> Self {
+        let num_queues = device.num_queues();
+        assert!(num_queues <= 64, "virtio devices support at most 64 queues");
+        Self {
+            device,
+            device_features_sel: 0,
+            driver_features: 0,
+            driver_features_sel: 0,
+            queue_sel: 0,
+            queues: vec![Virtqueue::default(); num_queues],
+            notified: 0,
+            interrupt_status: 0,
+            status: 0,
+        }
+    }
+
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    pub fn device_mut(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    fn device_features(&self) -> u64 {
+        VIRTIO_F_VERSION_1 | self.device.features()
+    }
+
+    fn reset(&mut self) {
+        self.device_features_sel = 0;
+        self.driver_features = 0;
+        self.driver_features_sel = 0;
+        self.queue_sel = 0;
+        self.queues.fill(Virtqueue::default());
+        self.notified = 0;
+        self.interrupt_status = 0;
+        self.status = 0;
+        self.device.reset();
+    }
+
+    fn selected_queue(&mut self) -> Option<&mut Virtqueue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    fn read_register(&mut self, offset: u64) -> u32 {
+        match offset {
+            MAGIC_VALUE         => MAGIC,
+            VERSION             => 2,
+            DEVICE_ID           => self.device.device_id(),
+            VENDOR_ID           => VENDOR,
+            DEVICE_FEATURES     => match self.device_features_sel {
+                0 => self.device_features() as u32,
+                1 => (self.device_features() >> 32) as u32,
+                _ => 0,
+            },
+            QUEUE_NUM_MAX if self.selected_queue().is_some() => VIRTQUEUE_MAX_SIZE as u32,
+            QUEUE_READY         => self.selected_queue().is_some_and(|queue| queue.ready) as u32,
+            INTERRUPT_STATUS    => self.interrupt_status,
+            STATUS              => self.status,
+            // Write-only and reserved registers read as 0
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: u64, value: u32) {
+        match offset {
+            DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            DRIVER_FEATURES     => self.driver_features = match self.driver_features_sel {
+                0 => set_half(self.driver_features, false, value),
+                1 => set_half(self.driver_features, true, value),
+                _ => self.driver_features,
+            },
+            DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            QUEUE_SEL           => self.queue_sel = value,
+            QUEUE_NOTIFY if (value as usize) < self.queues.len() => self.notified |= 1 << value,
+            INTERRUPT_ACK       => self.interrupt_status &= !value,
+            STATUS              => {
+                if value == 0 {
+                    self.reset();
+                    return;
+                }
+                // Features can only be accepted if the driver doesn't use any feature the device doesn't offer
+                let mut value = value;
+                if value & STATUS_FEATURES_OK != 0 && self.driver_features & !self.device_features() != 0 {
+                    value &= !STATUS_FEATURES_OK;
+                }
+                self.status = value;
+            },
+            QUEUE_NUM | QUEUE_READY | QUEUE_DESC_LOW | QUEUE_DESC_HIGH | QUEUE_DRIVER_LOW | QUEUE_DRIVER_HIGH | QUEUE_DEVICE_LOW | QUEUE_DEVICE_HIGH => {
+                let Some(queue) = self.selected_queue() else { return; };
+                match offset {
+                    QUEUE_NUM         => if value <= VIRTQUEUE_MAX_SIZE as u32 && value.is_power_of_two() {
+                        queue.size = value as u16;
+                    },
+                    QUEUE_READY       => queue.ready = value & 1 != 0,
+                    QUEUE_DESC_LOW    => queue.desc = set_half(queue.desc, false, value),
+                    QUEUE_DESC_HIGH   => queue.desc = set_half(queue.desc, true, value),
+                    QUEUE_DRIVER_LOW  => queue.avail = set_half(queue.avail, false, value),
+                    QUEUE_DRIVER_HIGH => queue.avail = set_half(queue.avail, true, value),
+                    QUEUE_DEVICE_LOW  => queue.used = set_half(queue.used, false, value),
+                    _                 => queue.used = set_half(queue.used, true, value),
+                }
+            },
+            // Read-only and reserved registers ignore writes
+            _ => {},
+        }
+    }
+}
+
+impl<D: VirtioDevice> Device for VirtioMmio<D> {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> bool {
+        if offset >= CONFIG {
+            return self.device.read_config(offset - CONFIG, buf);
+        }
+        // Transport registers only support aligned 32-bit accesses
+        if buf.len() != 4 || !offset.is_multiple_of(4) {
+            return false;
+        }
+        let value = self.read_register(offset);
+        read_register(value as u64, 0, buf)
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> bool {
+        if offset >= CONFIG {
+            return self.device.write_config(offset - CONFIG, buf);
+        }
+        if buf.len() != 4 || !offset.is_multiple_of(4) {
+            return false;
+        }
+        self.write_register(offset, u32::from_le_bytes(buf.try_into().unwrap()));
+        true
+    }
+
+    fn process_dma(&mut self, memory: &mut dyn MemoryBus) {
+        if self.status & STATUS_DRIVER_OK == 0 {
+            return;
+        }
+
+        let mut used = false;
+        while self.notified != 0 {
+            let queue = self.notified.trailing_zeros() as usize;
+            self.notified &= !(1 << queue);
+            used |= self.device.notify(queue, &mut self.queues, memory);
+        }
+        used |= self.device.poll(&mut self.queues, memory);
+
+        if used {
+            self.interrupt_status |= INTERRUPT_USED_BUFFER;
+        }
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        if self.status & STATUS_DRIVER_OK == 0 {
+            return None;
+        }
+        self.device.next_event()
+    }
+
+    fn interrupt_level(&self) -> bool {
+        self.interrupt_status != 0
+    }
+
+    fn devicetree_node(&self, base: u64, _fdt: &FdtContext) -> Option<FdtNode> {
+        let mut node = FdtNode::new(format!("virtio_mmio@{base:x}"));
+        node.property_string("compatible", "virtio,mmio");
+        Some(node)
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::memory::MemoryBus;
+
+use super::{VirtioDevice, Virtqueue, DescriptorChain};
+use super::super::read_register;
+
+
+const DEVICE_ID_BLOCK: u32 = 2;
+
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// Size of the request header, i.e. the type, a reserved field and the sector
+const REQUEST_HEADER_SIZE: usize = 16;
+/// Size of the device id returned by `VIRTIO_BLK_T_GET_ID`
+const DEVICE_ID_SIZE: usize = 20;
+
+pub const SECTOR_SIZE: usize = 512;
+
+/// How the disk image is accessed
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DiskMode {
+    /// Writes go to the image
+    ReadWrite,
+    /// The guest can't write to the disk
+    ReadOnly,
+    /// Writes are kept in memory, leaving the image unmodified
+    CopyOnWrite,
+}
+
+/// Virtio block device backed by a disk image on the host
+pub struct VirtioBlock {
+    file: File,
+    mode: DiskMode,
+    num_sectors: u64,
+    /// Sectors written in copy-on-write mode
+    overlay: HashMap<u64, Box<[u8; SECTOR_SIZE]>>,
+}
+
+impl VirtioBlock {
+    /// Open the disk image at `path`, any partial sector at the end of the image is not accessible
+    pub fn open(path: &Path, mode: DiskMode) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(mode == DiskMode::ReadWrite).open(path)?;
+        let num_sectors = file.metadata()?.len() / SECTOR_SIZE as u64;
+        Ok(Self { file, mode, num_sectors, overlay: HashMap::new() })
+    }
+
+    pub fn num_sectors(&self) -> u64 {
+        self.num_sectors
+    }
+
+    fn in_bounds(&self, sector: u64, len: usize) -> bool {
+        len.is_multiple_of(SECTOR_SIZE) && sector.checked_add((len / SECTOR_SIZE) as u64).is_some_and(|end| end <= self.num_sectors)
+    }
+
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> io::Result<()> {
+        for (idx, chunk) in buf.chunks_mut(SECTOR_SIZE).enumerate() {
+            let sector = sector + idx as u64;
+            match self.overlay.get(&sector) {
+                Some(data) => chunk.copy_from_slice(&data[..]),
+                None => {
+                    self.file.seek(SeekFrom::Start(sector * SECTOR_SIZE as u64))?;
+                    self.file.read_exact(chunk)?;
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, sector: u64, data: &[u8]) -> io::Result<()> {
+        match self.mode {
+            DiskMode::ReadWrite => {
+                self.file.seek(SeekFrom::Start(sector * SECTOR_SIZE as u64))?;
+                self.file.write_all(data)
+            },
+            DiskMode::ReadOnly => Err(io::ErrorKind::PermissionDenied.into()),
+            DiskMode::CopyOnWrite => {
+                for (idx, chunk) in data.chunks(SECTOR_SIZE).enumerate() {
+                    self.overlay.insert(sector + idx as u64, Box::new(chunk.try_into().unwrap()));
+                }
+                Ok(())
+            },
+        }
+    }
+
+    /// Handle a request, returns the number of bytes written to the request's buffers
+    fn handle_request(&mut self, chain: &DescriptorChain, memory: &mut dyn MemoryBus) -> u32 {
+        // The last byte of the writable buffers holds the status
+        let writable = chain.writable_len();
+        if writable == 0 {
+            return 0;
+        }
+        let Some(request) = chain.read_all(memory).filter(|request| request.len() >= REQUEST_HEADER_SIZE) else {
+            return chain.write_all(memory, &[VIRTIO_BLK_S_IOERR]);
+        };
+
+        let req_type = u32::from_le_bytes(request[0..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(request[8..16].try_into().unwrap());
+        let data = &request[REQUEST_HEADER_SIZE..];
+
+        let mut response = Vec::new();
+        let status = match req_type {
+            VIRTIO_BLK_T_IN => {
+                response.resize(writable - 1, 0);
+                if self.in_bounds(sector, response.len()) && self.read_sectors(sector, &mut response).is_ok() {
+                    VIRTIO_BLK_S_OK
+                } else {
+                    VIRTIO_BLK_S_IOERR
+                }
+            },
+            VIRTIO_BLK_T_OUT => {
+                if self.in_bounds(sector, data.len()) && self.write_sectors(sector, data).is_ok() {
+                    VIRTIO_BLK_S_OK
+                } else {
+                    VIRTIO_BLK_S_IOERR
+                }
+            },
+            VIRTIO_BLK_T_FLUSH => match self.mode {
+                DiskMode::ReadWrite => if self.file.sync_data().is_ok() { VIRTIO_BLK_S_OK } else { VIRTIO_BLK_S_IOERR },
+                _ => VIRTIO_BLK_S_OK,
+            },
+            VIRTIO_BLK_T_GET_ID => {
+                let mut id = [0; DEVICE_ID_SIZE];
+                id[..14].copy_from_slice(b"emu-virtio-blk");
+                response.extend(&id[..DEVICE_ID_SIZE.min(writable - 1)]);
+                VIRTIO_BLK_S_OK
+            },
+            _ => VIRTIO_BLK_S_UNSUPP,
+        };
+
+        // Pad the response, so the status ends up in the last byte
+        response.resize(writable - 1, 0);
+        response.push(status);
+        chain.write_all(memory, &response)
+    }
+}
+
+impl VirtioDevice for VirtioBlock {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID_BLOCK
+    }
+
+    fn features(&self) -> u64 {
+        let ro = if self.mode == DiskMode::ReadOnly { VIRTIO_BLK_F_RO } else { 0 };
+        VIRTIO_BLK_F_FLUSH | ro
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    fn read_config(&mut self, offset: u64, buf: &mut [u8]) -> bool {
+        // Only `capacity` is implemented
+        match offset {
+            0..=7 => read_register(self.num_sectors, offset, buf),
+            _ => { buf.fill(0); true },
+        }
+    }
+
+    fn notify(&mut self, _queue: usize, queues: &mut [Virtqueue], memory: &mut dyn MemoryBus) -> bool {
+        let mut used = false;
+        while let Some(chain) = queues[0].pop(memory) {
+            let len = self.handle_request(&chain, memory);
+            queues[0].push_used(memory, chain.head, len);
+            used = true;
+        }
+        used
+    }
+}
@@ -0,0 +1,192 @@
+use std::collections::VecDeque;
+
+use crate::memory::MemoryBus;
+
+use super::{VirtioDevice, Virtqueue};
+use super::super::{SerialBackend, read_register};
+
+
+const DEVICE_ID_CONSOLE: u32 = 3;
+
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+const VIRTIO_CONSOLE_F_EMERG_WRITE: u64 = 1 << 2;
+
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+
+const CONTROL_RX: usize = 2;
+const CONTROL_TX: usize = 3;
+
+/// Max number of bytes buffered per port while the driver has no receive buffers available
+const INPUT_BUFFER_SIZE: usize = 4096;
+
+struct Port {
+    backend: Box<dyn SerialBackend>,
+    input: VecDeque<u8>,
+}
+
+/// Virtio console, with every port connected to a serial backend on the host.
+///
+/// With more than 1 port, the multiport feature is offered, with port 0 being the console, i.e. `hvc0` on Linux
+pub struct VirtioConsole {
+    ports: Vec<Port>,
+    /// Control messages waiting for a buffer in the control receive queue
+    control: VecDeque<[u8; 8]>,
+}
+
+impl VirtioConsole {
+    pub fn new(ports: Vec<Box<dyn SerialBackend>>) -> Self {
+        assert!(!ports.is_empty(), "virtio console needs at least 1 port");
+        Self {
+            ports: ports.into_iter().map(|backend| Port { backend, input: VecDeque::new() }).collect(),
+            control: VecDeque::new(),
+        }
+    }
+
+    fn is_multiport(&self) -> bool {
+        self.ports.len() > 1
+    }
+
+    /// Receive queue of a port, the transmit queue directly follows it
+    fn receive_queue(port: usize) -> usize {
+        // Queues 2 and 3 are the control queues
+        if port == 0 { 0 } else { 2 + 2 * port }
+    }
+
+    fn send_control(&mut self, id: u32, event: u16, value: u16) {
+        let mut msg = [0; 8];
+        msg[0..4].copy_from_slice(&id.to_le_bytes());
+        msg[4..6].copy_from_slice(&event.to_le_bytes());
+        msg[6..8].copy_from_slice(&value.to_le_bytes());
+        self.control.push_back(msg);
+    }
+
+    fn handle_control(&mut self, msg: &[u8]) {
+        if msg.len() < 8 {
+            return;
+        }
+        let id = u32::from_le_bytes(msg[0..4].try_into().unwrap());
+        let event = u16::from_le_bytes([msg[4], msg[5]]);
+        let value = u16::from_le_bytes([msg[6], msg[7]]);
+
+        match event {
+            VIRTIO_CONSOLE_DEVICE_READY if value == 1 => {
+                for port in 0..self.ports.len() {
+                    self.send_control(port as u32, VIRTIO_CONSOLE_DEVICE_ADD, 0);
+                }
+            },
+            VIRTIO_CONSOLE_PORT_READY if value == 1 && (id as usize) < self.ports.len() => {
+                if id == 0 {
+                    self.send_control(id, VIRTIO_CONSOLE_CONSOLE_PORT, 1);
+                }
+                // Host side of the ports is always connected
+                self.send_control(id, VIRTIO_CONSOLE_PORT_OPEN, 1);
+            },
+            _ => {},
+        }
+    }
+}
+
+impl VirtioDevice for VirtioConsole {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID_CONSOLE
+    }
+
+    fn features(&self) -> u64 {
+        let multiport = if self.is_multiport() { VIRTIO_CONSOLE_F_MULTIPORT } else { 0 };
+        VIRTIO_CONSOLE_F_EMERG_WRITE | multiport
+    }
+
+    fn num_queues(&self) -> usize {
+        if self.is_multiport() { 2 * (self.ports.len() + 1) } else { 2 }
+    }
+
+    fn read_config(&mut self, offset: u64, buf: &mut [u8]) -> bool {
+        // cols and rows are not supported and read as 0, followed by `max_nr_ports` and `emerg_wr`
+        match offset {
+            4..=7 => read_register(self.ports.len() as u64, offset - 4, buf),
+            _ => { buf.fill(0); true },
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, buf: &[u8]) -> bool {
+        // Emergency write to port 0
+        if offset == 8 && buf.len() == 4 {
+            self.ports[0].backend.transmit(buf[0]);
+        }
+        true
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Virtqueue], memory: &mut dyn MemoryBus) -> bool {
+        let mut used = false;
+        if queue == CONTROL_TX && self.is_multiport() {
+            while let Some(chain) = queues[queue].pop(memory) {
+                if let Some(msg) = chain.read_all(memory) {
+                    self.handle_control(&msg);
+                }
+                queues[queue].push_used(memory, chain.head, 0);
+                used = true;
+            }
+        } else if queue % 2 == 1 {
+            let port = if queue == 1 { 0 } else { (queue - 3) / 2 };
+            let Some(port) = self.ports.get_mut(port) else { return false; };
+            while let Some(chain) = queues[queue].pop(memory) {
+                for byte in chain.read_all(memory).unwrap_or_default() {
+                    port.backend.transmit(byte);
+                }
+                queues[queue].push_used(memory, chain.head, 0);
+                used = true;
+            }
+        }
+        used
+    }
+
+    fn poll(&mut self, queues: &mut [Virtqueue], memory: &mut dyn MemoryBus) -> bool {
+        let mut used = false;
+
+        if self.is_multiport() {
+            while !self.control.is_empty() {
+                let Some(chain) = queues[CONTROL_RX].pop(memory) else { break; };
+                let msg = self.control.pop_front().unwrap();
+                let len = chain.write_all(memory, &msg);
+                queues[CONTROL_RX].push_used(memory, chain.head, len);
+                used = true;
+            }
+        }
+
+        for (idx, port) in self.ports.iter_mut().enumerate() {
+            while port.input.len() < INPUT_BUFFER_SIZE {
+                match port.backend.receive() {
+                    Some(byte) => port.input.push_back(byte),
+                    None => break,
+                }
+            }
+            if port.input.is_empty() {
+                continue;
+            }
+
+            let queue = &mut queues[Self::receive_queue(idx)];
+            while !port.input.is_empty() {
+                let Some(chain) = queue.pop(memory) else { break; };
+                let len = chain.writable_len().min(port.input.len());
+                let data = port.input.drain(..len).collect::<Vec<_>>();
+                let written = chain.write_all(memory, &data);
+                queue.push_used(memory, chain.head, written);
+                used = true;
+            }
+        }
+        used
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        // Keep polling the host while input may arrive
+        self.ports.iter().any(|port| port.backend.may_receive()).then_some(1)
+    }
+
+    fn reset(&mut self) {
+        self.control.clear();
+    }
+}
@@ -0,0 +1,108 @@
+use crate::memory::MemoryBus;
+
+use super::{VirtioDevice, Virtqueue};
+use super::super::NetBackend;
+
+
+const DEVICE_ID_NET: u32 = 1;
+
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+/// Size of `virtio_net_hdr` with `VIRTIO_F_VERSION_1`, which prefixes every frame
+const NET_HEADER_SIZE: usize = 12;
+/// Offset of `num_buffers` in the header
+const NET_HEADER_NUM_BUFFERS: usize = 10;
+
+const RECEIVE_QUEUE: usize = 0;
+const TRANSMIT_QUEUE: usize = 1;
+
+/// Virtio network device, exchanging frames with a network backend on the host
+pub struct VirtioNet {
+    mac: [u8; 6],
+    backend: Box<dyn NetBackend>,
+    /// Frame received from the backend, waiting for a receive buffer
+    pending: Option<Vec<u8>>,
+}
+
+impl VirtioNet {
+    pub fn new(mac: [u8; 6], backend: Box<dyn NetBackend>) -> Self {
+        Self { mac, backend, pending: None }
+    }
+}
+
+impl VirtioDevice for VirtioNet {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID_NET
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS
+    }
+
+    fn num_queues(&self) -> usize {
+        2
+    }
+
+    fn read_config(&mut self, offset: u64, buf: &mut [u8]) -> bool {
+        // `mac`, followed by `status`
+        let mut config = [0; 8];
+        config[..6].copy_from_slice(&self.mac);
+        config[6..].copy_from_slice(&VIRTIO_NET_S_LINK_UP.to_le_bytes());
+
+        buf.fill(0);
+        let start = (offset as usize).min(config.len());
+        let end = (start + buf.len()).min(config.len());
+        buf[..end - start].copy_from_slice(&config[start..end]);
+        true
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Virtqueue], memory: &mut dyn MemoryBus) -> bool {
+        // Receive buffers are filled when polling
+        if queue != TRANSMIT_QUEUE {
+            return false;
+        }
+
+        let mut used = false;
+        while let Some(chain) = queues[TRANSMIT_QUEUE].pop(memory) {
+            if let Some(data) = chain.read_all(memory).filter(|data| data.len() > NET_HEADER_SIZE) {
+                self.backend.send(&data[NET_HEADER_SIZE..]);
+            }
+            queues[TRANSMIT_QUEUE].push_used(memory, chain.head, 0);
+            used = true;
+        }
+        used
+    }
+
+    fn poll(&mut self, queues: &mut [Virtqueue], memory: &mut dyn MemoryBus) -> bool {
+        let mut used = false;
+        loop {
+            if self.pending.is_none() {
+                self.pending = self.backend.receive();
+            }
+            let Some(frame) = &self.pending else { break; };
+            let Some(chain) = queues[RECEIVE_QUEUE].pop(memory) else { break; };
+
+            // Without mergeable receive buffers, each frame is placed in a single chain, frames that don't fit are truncated
+            let mut data = vec![0; NET_HEADER_SIZE];
+            data[NET_HEADER_NUM_BUFFERS..].copy_from_slice(&1u16.to_le_bytes());
+            data.extend(frame);
+            let len = chain.write_all(memory, &data);
+            queues[RECEIVE_QUEUE].push_used(memory, chain.head, len);
+
+            self.pending = None;
+            used = true;
+        }
+        used
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        (self.pending.is_some() || self.backend.may_receive()).then_some(1)
+    }
+
+    fn reset(&mut self) {
+        self.pending = None;
+    }
+}
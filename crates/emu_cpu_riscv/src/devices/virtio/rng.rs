@@ -0,0 +1,52 @@
+use crate::memory::MemoryBus;
+
+use super::{VirtioDevice, Virtqueue};
+
+
+const DEVICE_ID_RNG: u32 = 4;
+
+/// Virtio entropy device, backed by a seeded PRNG, so runs using the same seed are reproducible.
+///
+/// The generated numbers are not suitable for cryptographic use
+pub struct VirtioRng {
+    /// SplitMix64 state
+    state: u64,
+}
+
+impl VirtioRng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+impl VirtioDevice for VirtioRng {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID_RNG
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    fn notify(&mut self, _queue: usize, queues: &mut [Virtqueue], memory: &mut dyn MemoryBus) -> bool {
+        let mut used = false;
+        while let Some(chain) = queues[0].pop(memory) {
+            let mut data = vec![0; chain.writable_len()];
+            for chunk in data.chunks_mut(8) {
+                chunk.copy_from_slice(&self.next_u64().to_le_bytes()[..chunk.len()]);
+            }
+            let len = chain.write_all(memory, &data);
+            queues[0].push_used(memory, chain.head, len);
+            used = true;
+        }
+        used
+    }
+}
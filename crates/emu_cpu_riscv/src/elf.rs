@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+use std::fmt;
+
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELFCLASS32: u8 = 1;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_RISCV: u16 = 243;
+
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+
+/// Maximum size of RAM allocated by `RiscvEmulator::load_elf` when no RAM is configured
+pub const ELF_MAX_RAM_SIZE: u64 = 1 << 30;
+
+/// Error while parsing an ELF file
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ElfError {
+    /// The file is not an ELF file
+    InvalidMagic,
+    /// The file is not a little-endian RISC-V ELF file
+    Unsupported,
+    /// A header or section points outside of the file
+    Truncated,
+    /// A segment is loaded to an address outside of RAM
+    SegmentOutsideRam(u64),
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ElfError::InvalidMagic            => write!(f, "not an ELF file"),
+            ElfError::Unsupported             => write!(f, "not a little-endian RISC-V ELF file"),
+            ElfError::Truncated               => write!(f, "ELF file is truncated"),
+            ElfError::SegmentOutsideRam(addr) => write!(f, "segment at {addr:#X} is outside of RAM"),
+        }
+    }
+}
+
+/// Segment to be loaded into memory
+pub struct Segment<'a> {
+    /// Physical address the segment is loaded at
+    pub addr: u64,
+    pub data: &'a [u8],
+    /// Size of the segment in memory, the bytes after `data` are zeroed
+    pub mem_size: u64,
+}
+
+/// Parsed ELF file
+pub struct Elf<'a> {
+    pub is_64_bit: bool,
+    pub entry: u64,
+    pub segments: Vec<Segment<'a>>,
+    /// Values of the symbols in the symbol table
+    pub symbols: HashMap<String, u64>,
+}
+
+/// Little-endian reader of the fields of the ELF file, with the size of the `addr`/`off`/`xword` fields depending on the ELF class
+struct Reader<'a> {
+    data: &'a [u8],
+    is_64_bit: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&self, offset: u64, len: u64) -> Result<&'a [u8], ElfError> {
+        let end = offset.checked_add(len).ok_or(ElfError::Truncated)?;
+        self.data.get(offset as usize..end as usize).ok_or(ElfError::Truncated)
+    }
+
+    fn u8(&self, offset: u64) -> Result<u8, ElfError> {
+        Ok(self.bytes(offset, 1)?[0])
+    }
+
+    fn u16(&self, offset: u64) -> Result<u16, ElfError> {
+        Ok(u16::from_le_bytes(self.bytes(offset, 2)?.try_into().unwrap()))
+    }
+
+    fn u32(&self, offset: u64) -> Result<u32, ElfError> {
+        Ok(u32::from_le_bytes(self.bytes(offset, 4)?.try_into().unwrap()))
+    }
+
+    fn u64(&self, offset: u64) -> Result<u64, ElfError> {
+        Ok(u64::from_le_bytes(self.bytes(offset, 8)?.try_into().unwrap()))
+    }
+
+    /// Read a field that is 4 bytes in ELF32 and 8 bytes in ELF64
+    fn word(&self, offset: u64) -> Result<u64, ElfError> {
+        if self.is_64_bit { self.u64(offset) } else { self.u32(offset).map(|val| val as u64) }
+    }
+
+    fn str(&self, offset: u64) -> Result<&'a str, ElfError> {
+        let bytes = self.data.get(offset as usize..).ok_or(ElfError::Truncated)?;
+        let len = bytes.iter().position(|&byte| byte == 0).ok_or(ElfError::Truncated)?;
+        std::str::from_utf8(&bytes[..len]).map_err(|_| ElfError::Truncated)
+    }
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.get(..4) != Some(&ELF_MAGIC) {
+            return Err(ElfError::InvalidMagic);
+        }
+
+        let mut reader = Reader { data, is_64_bit: false };
+        reader.is_64_bit = match reader.u8(4)? {
+            ELFCLASS32 => false,
+            ELFCLASS64 => true,
+            _ => return Err(ElfError::Unsupported),
+        };
+        if reader.u8(5)? != ELFDATA2LSB || reader.u16(18)? != EM_RISCV {
+            return Err(ElfError::Unsupported);
+        }
+
+        // Offsets of the fields following `e_entry`, which depend on the class
+        let (phoff, shoff, sizes) = if reader.is_64_bit { (32, 40, 54) } else { (28, 32, 42) };
+        let entry = reader.word(24)?;
+        let phoff = reader.word(phoff)?;
+        let shoff = reader.word(shoff)?;
+        let phentsize = reader.u16(sizes)? as u64;
+        let phnum = reader.u16(sizes + 2)? as u64;
+        let shentsize = reader.u16(sizes + 4)? as u64;
+        let shnum = reader.u16(sizes + 6)? as u64;
+
+        let mut segments = Vec::new();
+        for idx in 0..phnum {
+            let header = phoff + idx * phentsize;
+            if reader.u32(header)? != PT_LOAD {
+                continue;
+            }
+
+            // ELF64 moves `p_flags` to after `p_type`
+            let (offset, paddr, filesz, memsz) = if reader.is_64_bit {
+                (reader.u64(header + 8)?, reader.u64(header + 24)?, reader.u64(header + 32)?, reader.u64(header + 40)?)
+            } else {
+                (reader.u32(header + 4)? as u64, reader.u32(header + 12)? as u64, reader.u32(header + 16)? as u64, reader.u32(header + 20)? as u64)
+            };
+            segments.push(Segment { addr: paddr, data: reader.bytes(offset, filesz)?, mem_size: memsz });
+        }
+
+        let mut symbols = HashMap::new();
+        let section = |idx: u64| -> Result<(u32, u64, u64, u32, u64), ElfError> {
+            let header = shoff + idx * shentsize;
+            let word_size = if reader.is_64_bit { 8 } else { 4 };
+            let sh_type = reader.u32(header + 4)?;
+            let offset = reader.word(header + 8 + 2 * word_size)?;
+            let size = reader.word(header + 8 + 3 * word_size)?;
+            let link = reader.u32(header + 8 + 4 * word_size)?;
+            let entsize = reader.word(header + 16 + 5 * word_size)?;
+            Ok((sh_type, offset, size, link, entsize))
+        };
+        for idx in 0..shnum {
+            let (sh_type, offset, size, link, entsize) = section(idx)?;
+            if sh_type != SHT_SYMTAB || entsize == 0 {
+                continue;
+            }
+            let (_, strtab, _, _, _) = section(link as u64)?;
+
+            for sym in 1..size / entsize {
+                let sym = offset + sym * entsize;
+                // ELF64 moves `st_value` to after `st_info`, `st_other` and `st_shndx`
+                let value = if reader.is_64_bit { reader.u64(sym + 8)? } else { reader.u32(sym + 4)? as u64 };
+                let name = reader.str(strtab + reader.u32(sym)? as u64)?;
+                if !name.is_empty() {
+                    symbols.insert(name.to_string(), value);
+                }
+            }
+        }
+
+        Ok(Self { is_64_bit: reader.is_64_bit, entry, segments, symbols })
+    }
+}
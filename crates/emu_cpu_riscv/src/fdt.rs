@@ -0,0 +1,172 @@
+// Flattened devicetree (DTB) layout, all values are big-endian
+//
+// | offset           | content                                              |
+// |------------------|------------------------------------------------------|
+// | 0x00             | header                                               |
+// | 0x28             | memory reservation block, only the terminating entry |
+// | 0x38             | structure block                                      |
+// | after structure  | strings block                                        |
+
+const FDT_MAGIC: u32 = 0xD00D_FEED;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: usize = 0x28;
+const FDT_RESERVE_MAP_SIZE: usize = 0x10;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+
+/// Phandles used to reference the interrupt controllers, which are assigned when the devicetree is generated
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct FdtContext {
+    pub num_harts: usize,
+}
+
+impl FdtContext {
+    /// Phandle of the local interrupt controller of a hart
+    pub fn cpu_intc_phandle(&self, hart: usize) -> u32 {
+        1 + hart as u32
+    }
+
+    /// Phandle of the interrupt controller the `irq` of the devices on the bus refers to
+    pub fn interrupt_parent(&self) -> u32 {
+        1 + self.num_harts as u32
+    }
+}
+
+/// Node of a devicetree
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct FdtNode {
+    pub name: String,
+    pub properties: Vec<(String, Vec<u8>)>,
+    pub children: Vec<FdtNode>,
+}
+
+impl FdtNode {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self { name: name.into(), properties: Vec::new(), children: Vec::new() }
+    }
+
+    pub fn property(&mut self, name: &str, value: Vec<u8>) -> &mut Self {
+        self.properties.push((name.to_string(), value));
+        self
+    }
+
+    pub fn property_empty(&mut self, name: &str) -> &mut Self {
+        self.property(name, Vec::new())
+    }
+
+    pub fn property_u32(&mut self, name: &str, value: u32) -> &mut Self {
+        self.property_cells(name, &[value])
+    }
+
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) -> &mut Self {
+        self.property(name, cells.iter().flat_map(|cell| cell.to_be_bytes()).collect())
+    }
+
+    /// Add a property consisting of 64-bit values, each encoded as 2 cells
+    pub fn property_u64s(&mut self, name: &str, values: &[u64]) -> &mut Self {
+        self.property(name, values.iter().flat_map(|value| value.to_be_bytes()).collect())
+    }
+
+    pub fn property_string(&mut self, name: &str, value: &str) -> &mut Self {
+        self.property_strings(name, &[value])
+    }
+
+    pub fn property_strings(&mut self, name: &str, values: &[&str]) -> &mut Self {
+        self.property(name, values.iter().flat_map(|value| value.bytes().chain([0])).collect())
+    }
+
+    pub fn child(&mut self, node: FdtNode) -> &mut Self {
+        self.children.push(node);
+        self
+    }
+
+    /// Get a child node by name
+    pub fn child_mut(&mut self, name: &str) -> Option<&mut FdtNode> {
+        self.children.iter_mut().find(|child| child.name == name)
+    }
+
+    /// Get the value of a property
+    pub fn get_property(&self, name: &str) -> Option<&[u8]> {
+        self.properties.iter().find(|(prop, _)| prop == name).map(|(_, value)| value.as_slice())
+    }
+
+    /// Serialize the tree with this node as the root into a flattened devicetree blob
+    pub fn to_dtb(&self, boot_cpuid: u32) -> Vec<u8> {
+        let mut structure = Vec::new();
+        let mut strings = Vec::new();
+        self.write_structure(&mut structure, &mut strings);
+        structure.extend(FDT_END.to_be_bytes());
+
+        let off_struct = FDT_HEADER_SIZE + FDT_RESERVE_MAP_SIZE;
+        let off_strings = off_struct + structure.len();
+        let total_size = off_strings + strings.len();
+
+        let mut dtb = Vec::with_capacity(total_size);
+        for value in [
+            FDT_MAGIC,
+            total_size as u32,
+            off_struct as u32,
+            off_strings as u32,
+            FDT_HEADER_SIZE as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            boot_cpuid,
+            strings.len() as u32,
+            structure.len() as u32,
+        ] {
+            dtb.extend(value.to_be_bytes());
+        }
+        // The reservation map only contains the terminating entry
+        dtb.extend([0; FDT_RESERVE_MAP_SIZE]);
+        dtb.extend(structure);
+        dtb.extend(strings);
+        dtb
+    }
+
+    fn write_structure(&self, structure: &mut Vec<u8>, strings: &mut Vec<u8>) {
+        structure.extend(FDT_BEGIN_NODE.to_be_bytes());
+        structure.extend(self.name.as_bytes());
+        structure.push(0);
+        pad_to_cell(structure);
+
+        for (name, value) in &self.properties {
+            structure.extend(FDT_PROP.to_be_bytes());
+            structure.extend((value.len() as u32).to_be_bytes());
+            structure.extend((string_offset(strings, name) as u32).to_be_bytes());
+            structure.extend(value);
+            pad_to_cell(structure);
+        }
+        for child in &self.children {
+            child.write_structure(structure, strings);
+        }
+
+        structure.extend(FDT_END_NODE.to_be_bytes());
+    }
+}
+
+fn pad_to_cell(data: &mut Vec<u8>) {
+    data.resize(data.len().next_multiple_of(4), 0);
+}
+
+/// Offset of a string in the strings block, adding it if it isn't present yet
+fn string_offset(strings: &mut Vec<u8>, name: &str) -> usize {
+    let mut offset = 0;
+    for string in strings.split(|&byte| byte == 0) {
+        if string == name.as_bytes() && offset < strings.len() {
+            return offset;
+        }
+        offset += string.len() + 1;
+    }
+
+    let offset = strings.len();
+    strings.extend(name.as_bytes());
+    strings.push(0);
+    offset
+}
+
+#[cfg(test)]
+pub(crate) mod tests;
@@ -0,0 +1,180 @@
+use emu_cpu::{CpuEmulator, EmulationSettings, MisalignedPolicy};
+
+use crate::devices::*;
+use crate::{RiscvEmulator, ExtensionIsa};
+
+use super::*;
+
+/// Parse a devicetree blob back into a tree of nodes
+pub(crate) fn parse_dtb(dtb: &[u8]) -> FdtNode {
+    let read_u32 = |offset: usize| u32::from_be_bytes(dtb[offset..offset + 4].try_into().unwrap());
+    assert_eq!(read_u32(0), FDT_MAGIC);
+    assert_eq!(read_u32(4) as usize, dtb.len());
+    let off_struct = read_u32(8) as usize;
+    let off_strings = read_u32(12) as usize;
+
+    let read_str = |offset: usize| {
+        let len = dtb[offset..].iter().position(|&byte| byte == 0).unwrap();
+        String::from_utf8(dtb[offset..offset + len].to_vec()).unwrap()
+    };
+
+    let mut stack: Vec<FdtNode> = Vec::new();
+    let mut offset = off_struct;
+    loop {
+        let token = read_u32(offset);
+        offset += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let name = read_str(offset);
+                offset += (name.len() + 1).next_multiple_of(4);
+                stack.push(FdtNode::new(name));
+            },
+            FDT_END_NODE => {
+                let node = stack.pop().unwrap();
+                match stack.last_mut() {
+                    Some(parent) => { parent.child(node); },
+                    None => {
+                        assert_eq!(read_u32(offset), FDT_END);
+                        return node;
+                    },
+                }
+            },
+            FDT_PROP => {
+                let len = read_u32(offset) as usize;
+                let name = read_str(off_strings + read_u32(offset + 4) as usize);
+                let value = dtb[offset + 8..offset + 8 + len].to_vec();
+                stack.last_mut().unwrap().property(&name, value);
+                offset += 8 + len.next_multiple_of(4);
+            },
+            _ => panic!("unexpected token {token}"),
+        }
+    }
+}
+
+/// Parse the devicetree blob loaded at `addr` in the RAM of `emulator`
+pub(crate) fn read_dtb(emulator: &RiscvEmulator, addr: u64) -> FdtNode {
+    let offset = (addr - emulator.memory.ram_base) as usize;
+    let size = u32::from_be_bytes(emulator.memory.ram[offset + 4..offset + 8].try_into().unwrap()) as usize;
+    parse_dtb(&emulator.memory.ram[offset..offset + size])
+}
+
+pub(crate) fn find_node<'a>(node: &'a FdtNode, path: &str) -> &'a FdtNode {
+    path.split('/').filter(|name| !name.is_empty()).fold(node, |node, name| {
+        node.children.iter().find(|child| child.name == name).unwrap_or_else(|| panic!("missing node {name}"))
+    })
+}
+
+pub(crate) fn cells(value: &[u8]) -> Vec<u32> {
+    value.chunks(4).map(|cell| u32::from_be_bytes(cell.try_into().unwrap())).collect()
+}
+
+/// Device without a devicetree description
+struct Undescribed;
+
+impl Device for Undescribed {
+    fn read(&mut self, _offset: u64, _buf: &mut [u8]) -> bool {
+        false
+    }
+
+    fn write(&mut self, _offset: u64, _buf: &[u8]) -> bool {
+        false
+    }
+}
+
+/// Load the devicetree of a single hart machine with a CLINT, PLIC, UART and virtio device, returns the emulator, the address of the devicetree and the parsed devicetree
+fn load_devicetree() -> (RiscvEmulator, u64, FdtNode) {
+    let mut emulator = RiscvEmulator::new(EmulationSettings { print_instructions: false, misaligned: MisalignedPolicy::Trap });
+    emulator.set_base_isa("RV64I");
+    emulator.set_extensions(ExtensionIsa::M | ExtensionIsa::A | ExtensionIsa::C | ExtensionIsa::Zicsr | ExtensionIsa::Zifencei | ExtensionIsa::S);
+    assert_eq!(emulator.isa_string(), "rv64imac_zicsr_zifencei");
+
+    emulator.memory.ram = vec![0; 0x10_0000];
+    emulator.memory.ram_base = 0x8000_0000;
+    emulator.memory.add_device(0x0200_0000, CLINT_SIZE, Box::new(Clint::new(1, Timebase::InstructionsRetired)));
+    emulator.memory.add_device(0x0C00_0000, PLIC_SIZE, Box::new(Plic::new(8, 1)));
+    emulator.memory.add_device_with_irq(0x1000_0000, UART_SIZE, 1, Box::new(Uart::new(Box::new(BufferBackend::new()))));
+    emulator.memory.add_device_with_irq(0x1000_1000, VIRTIO_MMIO_SIZE, 2, Box::new(VirtioMmio::new(VirtioRng::new(0))));
+    emulator.memory.add_device(0x2000_0000, 0x1000, Box::new(Undescribed));
+
+    let devicetree = emulator.devicetree(10_000_000, Some("console=ttyS0"));
+    let addr = emulator.load_devicetree(&devicetree);
+    let tree = read_dtb(&emulator, addr);
+    (emulator, addr, tree)
+}
+
+#[test]
+fn test_fdt_to_dtb() {
+    let mut root = FdtNode::new("");
+    let mut child = FdtNode::new("child@0");
+    child.property_u32("reg", 0).property_strings("compatible", &["a", "b"]);
+    root.property_u32("#address-cells", 1).property_u32("reg", 5).child(child);
+    let dtb = root.to_dtb(0);
+    assert_eq!(parse_dtb(&dtb), root);
+    // `reg` is only stored once in the strings block
+    assert_eq!(&dtb[dtb.len() - 30..], b"#address-cells\0reg\0compatible\0");
+}
+
+#[test]
+fn test_fdt_load_devicetree() {
+    let (emulator, addr, _) = load_devicetree();
+    assert_eq!(addr & 0xFFF, 0);
+    assert_eq!(emulator.harts[0].read_x_register(10), 0);
+    assert_eq!(emulator.harts[0].read_x_register(11), addr);
+}
+
+#[test]
+fn test_fdt_chosen() {
+    let (_, _, tree) = load_devicetree();
+    let chosen = find_node(&tree, "/chosen");
+    assert_eq!(chosen.get_property("bootargs"), Some(&b"console=ttyS0\0"[..]));
+    assert_eq!(chosen.get_property("stdout-path"), Some(&b"/soc/serial@10000000\0"[..]));
+}
+
+#[test]
+fn test_fdt_cpus() {
+    let (_, _, tree) = load_devicetree();
+    let cpus = find_node(&tree, "/cpus");
+    assert_eq!(cells(cpus.get_property("timebase-frequency").unwrap()), [10_000_000]);
+    let cpu = find_node(cpus, "cpu@0");
+    assert_eq!(cpu.get_property("riscv,isa"), Some(&b"rv64imac_zicsr_zifencei\0"[..]));
+    assert_eq!(cpu.get_property("mmu-type"), Some(&b"riscv,sv48\0"[..]));
+    assert_eq!(cells(find_node(cpu, "interrupt-controller").get_property("phandle").unwrap()), [1]);
+}
+
+#[test]
+fn test_fdt_memory() {
+    let (_, _, tree) = load_devicetree();
+    let memory = find_node(&tree, "/memory@80000000");
+    assert_eq!(cells(memory.get_property("reg").unwrap()), [0, 0x8000_0000, 0, 0x10_0000]);
+}
+
+#[test]
+fn test_fdt_interrupt_controllers() {
+    let (_, _, tree) = load_devicetree();
+    let clint = find_node(&tree, "/soc/clint@2000000");
+    assert_eq!(cells(clint.get_property("interrupts-extended").unwrap()), [1, 3, 1, 7]);
+    assert_eq!(cells(clint.get_property("reg").unwrap()), [0, 0x0200_0000, 0, 0x10000]);
+    assert_eq!(clint.get_property("interrupts"), None);
+
+    let plic = find_node(&tree, "/soc/plic@c000000");
+    assert_eq!(cells(plic.get_property("interrupts-extended").unwrap()), [1, 11, 1, 9]);
+    assert_eq!(cells(plic.get_property("riscv,ndev").unwrap()), [8]);
+    assert_eq!(cells(plic.get_property("phandle").unwrap()), [2]);
+}
+
+#[test]
+fn test_fdt_devices() {
+    let (_, _, tree) = load_devicetree();
+    // Devices without a description are left out
+    let soc = find_node(&tree, "/soc");
+    assert_eq!(soc.children.len(), 4);
+
+    let uart = find_node(soc, "serial@10000000");
+    assert_eq!(uart.get_property("compatible"), Some(&b"ns16550a\0"[..]));
+    assert_eq!(cells(uart.get_property("interrupts").unwrap()), [1]);
+    assert_eq!(cells(uart.get_property("interrupt-parent").unwrap()), [2]);
+
+    let virtio = find_node(soc, "virtio_mmio@10001000");
+    assert_eq!(virtio.get_property("compatible"), Some(&b"virtio,mmio\0"[..]));
+    assert_eq!(cells(virtio.get_property("interrupts").unwrap()), [2]);
+}
@@ -0,0 +1,212 @@
+use std::fmt;
+
+use emu_cpu::{InstructionInfo, MisalignedPolicy};
+use emu_macros::EnumCount;
+use emu_utils::*;
+
+use crate::instructions::{Disassembler, Instruction};
+use crate::memory::{self, MemoryBus};
+use crate::mmu::{self, AccessType, AccessMode};
+use crate::registers::RegisterFile;
+use crate::trap::Trap;
+
+instruction_set! {
+    #[derive(Clone, Copy, PartialEq, Eq, Debug, EnumCount)]
+    pub enum AInstructions : A_INSTUCTION_INFO {
+        LrW      { rd: u8, rs1: u8, aq: bool, rl: bool },
+        ScW      { rd: u8, rs1: u8, rs2: u8, aq: bool, rl: bool },
+        AmoswapW { rd: u8, rs1: u8, rs2: u8, aq: bool, rl: bool },
+        AmoaddW  { rd: u8, rs1: u8, rs2: u8, aq: bool, rl: bool },
+        AmoxorW  { rd: u8, rs1: u8, rs2: u8, aq: bool, rl: bool },
+        AmoandW  { rd: u8, rs1: u8, rs2: u8, aq: bool, rl: bool },
+        AmoorW   { rd: u8, rs1: u8, rs2: u8, aq: bool, rl: bool },
+        AmominW  { rd: u8, rs1: u8, rs2: u8, aq: bool, rl: bool },
+        AmomaxW  { rd: u8, rs1: u8, rs2: u8, aq: bool, rl: bool },
+        AmominuW { rd: u8, rs1: u8, rs2: u8, aq: bool, rl: bool },
+        AmomaxuW { rd: u8, rs1: u8, rs2: u8, aq: bool, rl: bool },
+
+        LrD      { rd: u8, rs1: u8, aq: bool, rl: bool },
+        ScD      { rd: u8, rs1: u8, rs2: u8, aq: bool, rl: bool },
+        AmoswapD { rd: u8, rs1: u8, rs2: u8, aq: bool, rl: bool },
+        AmoaddD  { rd: u8, rs1: u8, rs2: u8, aq: bool, rl: bool },
+        AmoxorD  { rd: u8, rs1: u8, rs2: u8, aq: bool, rl: bool },
+        AmoandD  { rd: u8, rs1: u8, rs2: u8, aq: bool, rl: bool },
+        AmoorD   { rd: u8, rs1: u8, rs2: u8, aq: bool, rl: bool },
+        AmominD  { rd: u8, rs1: u8, rs2: u8, aq: bool, rl: bool },
+        AmomaxD  { rd: u8, rs1: u8, rs2: u8, aq: bool, rl: bool },
+        AmominuD { rd: u8, rs1: u8, rs2: u8, aq: bool, rl: bool },
+        AmomaxuD { rd: u8, rs1: u8, rs2: u8, aq: bool, rl: bool },
+    }
+}
+
+/// Operation of an atomic instruction, independent of its width
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AtomicOp {
+    Lr,
+    Sc,
+    Swap,
+    Add,
+    Xor,
+    And,
+    Or,
+    Min,
+    Max,
+    Minu,
+    Maxu,
+}
+
+impl AtomicOp {
+    /// Value written to memory by an AMO of `size` bytes, 32-bit values are sign-extended for the comparisons
+    pub fn apply(self, old: u64, src: u64, size: usize) -> u64 {
+        let (old, src) = if size == 4 { (sign_extend_64(old, 31), sign_extend_64(src, 31)) } else { (old, src) };
+        match self {
+            AtomicOp::Swap => src,
+            AtomicOp::Add  => old.wrapping_add(src),
+            AtomicOp::Xor  => old ^ src,
+            AtomicOp::And  => old & src,
+            AtomicOp::Or   => old | src,
+            AtomicOp::Min  => (old as i64).min(src as i64) as u64,
+            AtomicOp::Max  => (old as i64).max(src as i64) as u64,
+            AtomicOp::Minu => old.min(src),
+            AtomicOp::Maxu => old.max(src),
+            AtomicOp::Lr | AtomicOp::Sc => unreachable!("LR and SC are not AMOs"),
+        }
+    }
+}
+
+/// Decoded fields of an atomic instruction
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct AtomicFields {
+    pub op: AtomicOp,
+    /// Access size in bytes
+    pub size: usize,
+    pub rd: u8,
+    pub rs1: u8,
+    /// Always 0 for LR
+    pub rs2: u8,
+    pub aq: bool,
+    pub rl: bool,
+}
+
+impl AInstructions {
+    pub fn fields(&self) -> AtomicFields {
+        let (op, size, rd, rs1, rs2, aq, rl) = match *self {
+            Self::LrW      { rd, rs1, aq, rl }      => (AtomicOp::Lr  , 4, rd, rs1, 0  , aq, rl),
+            Self::ScW      { rd, rs1, rs2, aq, rl } => (AtomicOp::Sc  , 4, rd, rs1, rs2, aq, rl),
+            Self::AmoswapW { rd, rs1, rs2, aq, rl } => (AtomicOp::Swap, 4, rd, rs1, rs2, aq, rl),
+            Self::AmoaddW  { rd, rs1, rs2, aq, rl } => (AtomicOp::Add , 4, rd, rs1, rs2, aq, rl),
+            Self::AmoxorW  { rd, rs1, rs2, aq, rl } => (AtomicOp::Xor , 4, rd, rs1, rs2, aq, rl),
+            Self::AmoandW  { rd, rs1, rs2, aq, rl } => (AtomicOp::And , 4, rd, rs1, rs2, aq, rl),
+            Self::AmoorW   { rd, rs1, rs2, aq, rl } => (AtomicOp::Or  , 4, rd, rs1, rs2, aq, rl),
+            Self::AmominW  { rd, rs1, rs2, aq, rl } => (AtomicOp::Min , 4, rd, rs1, rs2, aq, rl),
+            Self::AmomaxW  { rd, rs1, rs2, aq, rl } => (AtomicOp::Max , 4, rd, rs1, rs2, aq, rl),
+            Self::AmominuW { rd, rs1, rs2, aq, rl } => (AtomicOp::Minu, 4, rd, rs1, rs2, aq, rl),
+            Self::AmomaxuW { rd, rs1, rs2, aq, rl } => (AtomicOp::Maxu, 4, rd, rs1, rs2, aq, rl),
+            Self::LrD      { rd, rs1, aq, rl }      => (AtomicOp::Lr  , 8, rd, rs1, 0  , aq, rl),
+            Self::ScD      { rd, rs1, rs2, aq, rl } => (AtomicOp::Sc  , 8, rd, rs1, rs2, aq, rl),
+            Self::AmoswapD { rd, rs1, rs2, aq, rl } => (AtomicOp::Swap, 8, rd, rs1, rs2, aq, rl),
+            Self::AmoaddD  { rd, rs1, rs2, aq, rl } => (AtomicOp::Add , 8, rd, rs1, rs2, aq, rl),
+            Self::AmoxorD  { rd, rs1, rs2, aq, rl } => (AtomicOp::Xor , 8, rd, rs1, rs2, aq, rl),
+            Self::AmoandD  { rd, rs1, rs2, aq, rl } => (AtomicOp::And , 8, rd, rs1, rs2, aq, rl),
+            Self::AmoorD   { rd, rs1, rs2, aq, rl } => (AtomicOp::Or  , 8, rd, rs1, rs2, aq, rl),
+            Self::AmominD  { rd, rs1, rs2, aq, rl } => (AtomicOp::Min , 8, rd, rs1, rs2, aq, rl),
+            Self::AmomaxD  { rd, rs1, rs2, aq, rl } => (AtomicOp::Max , 8, rd, rs1, rs2, aq, rl),
+            Self::AmominuD { rd, rs1, rs2, aq, rl } => (AtomicOp::Minu, 8, rd, rs1, rs2, aq, rl),
+            Self::AmomaxuD { rd, rs1, rs2, aq, rl } => (AtomicOp::Maxu, 8, rd, rs1, rs2, aq, rl),
+        };
+        AtomicFields { op, size, rd, rs1, rs2, aq, rl }
+    }
+
+    pub fn exec(&self, register_file: &mut RegisterFile, memory: &mut dyn MemoryBus) {
+        if let Err(trap) = self.try_exec(register_file, memory) {
+            register_file.raise_trap(trap);
+        }
+    }
+
+    fn try_exec(&self, register_file: &mut RegisterFile, memory: &mut dyn MemoryBus) -> Result<(), Trap> {
+        let AtomicFields { op, size, rd, rs1, rs2, aq, rl } = self.fields();
+        if !register_file.csr().has_extension('A') || (size == 8 && register_file.is_32_bit()) {
+            return Err(Trap::illegal_instruction());
+        }
+
+        // Reservations are tracked on physical addresses, as different virtual addresses can map to the same memory
+        let addr = register_file.read_x_register(rs1);
+        let access = if op == AtomicOp::Lr { AccessType::Load } else { AccessType::Store };
+        let mode = AccessMode::effective(register_file, access);
+        let misaligned = addr & (size as u64 - 1) != 0;
+        // Misaligned LR/SC can't be emulated, as reservation sets only cover naturally aligned memory
+        if misaligned && matches!(op, AtomicOp::Lr | AtomicOp::Sc) && matches!(register_file.misaligned_policy(), MisalignedPolicy::Emulate { .. }) {
+            return Err(Trap { gva: mode.virtualized, ..Trap::new(access.misaligned(), addr) });
+        }
+        memory::check_alignment(register_file, addr, size, access, mode)?;
+
+        let hart = register_file.csr().mhartid as usize;
+        let src = register_file.read_x_register(rs2);
+        if rl {
+            memory.release();
+        }
+        let result = if misaligned {
+            // Emulated misaligned AMOs are a load followed by a store, which are only atomic when the harts are executed round-robin
+            let old = memory::read_virtual(register_file, memory, addr, size, AccessType::Store, mode)?;
+            let old = memory::to_data_endianness(register_file, old, size, mode);
+            let new = memory::to_data_endianness(register_file, op.apply(old, src, size), size, mode);
+            memory::write_virtual(register_file, memory, addr, size, new, mode)?;
+            old
+        } else {
+            let paddr = mmu::translate(register_file, memory, addr, access, mode)?;
+            let access_fault = Trap { gva: mode.virtualized, ..Trap::new(access.access_fault(), addr) };
+            match op {
+                AtomicOp::Lr => {
+                    let value = memory.load_reserved(hart, paddr, size).ok_or(access_fault)?;
+                    memory::to_data_endianness(register_file, value, size, mode)
+                },
+                // The reservation is released whether or not the store succeeds
+                AtomicOp::Sc => {
+                    let value = memory::to_data_endianness(register_file, src, size, mode);
+                    if memory.store_conditional(hart, paddr, size, value).ok_or(access_fault)? { 0 } else { 1 }
+                },
+                _ => memory.amo(paddr, size, op, src, memory::is_big_endian(register_file, mode)).ok_or(access_fault)?,
+            }
+        };
+        if aq {
+            memory.acquire();
+        }
+
+        if rd != 0 {
+            let result = if size == 4 { sign_extend_64(result, 31) } else { result };
+            register_file.write_x_register(rd, result);
+        }
+        register_file.inc_pc(4);
+        Ok(())
+    }
+
+    #[deprecated(note = "use `Disassembler::format` instead")]
+    pub fn log(&self, f: &mut dyn fmt::Write) -> fmt::Result {
+        write!(f, "{}", Disassembler::new(false).format(0, &Instruction::A(*self)))
+    }
+}
+
+pub const A_INSTUCTION_INFO: [InstructionInfo; AInstructions::COUNT] = [
+    InstructionInfo { name: "LR.W"     , mnemonic: "lr.w rd, (rs1)"           , encoding: "R-Type: 00010qr_00000_aaaaa_010_ddddd_0101111", desc: "Loads a 32-bit value from the address in `rs1`, sign-extends it into `rd` and registers a reservation set containing the address. `aq` and `rl` set the ordering of the access." },
+    InstructionInfo { name: "SC.W"     , mnemonic: "sc.w rd, rs2, (rs1)"      , encoding: "R-Type: 00011qr_bbbbb_aaaaa_010_ddddd_0101111", desc: "Conditionally stores the lower 32 bits of `rs2` to the address in `rs1`, only if the hart holds a valid reservation on the address. Writes 0 to `rd` on success and 1 on failure. The reservation is invalidated whether or not the store succeeds." },
+    InstructionInfo { name: "AMOSWAP.W", mnemonic: "amoswap.w rd, rs2, (rs1)" , encoding: "R-Type: 00001qr_bbbbb_aaaaa_010_ddddd_0101111", desc: "Atomically loads a 32-bit value from the address in `rs1` into `rd` and stores the lower 32 bits of `rs2` to the address." },
+    InstructionInfo { name: "AMOADD.W" , mnemonic: "amoadd.w rd, rs2, (rs1)"  , encoding: "R-Type: 00000qr_bbbbb_aaaaa_010_ddddd_0101111", desc: "Atomically loads a 32-bit value from the address in `rs1` into `rd` and stores the sum of the value and `rs2` to the address." },
+    InstructionInfo { name: "AMOXOR.W" , mnemonic: "amoxor.w rd, rs2, (rs1)"  , encoding: "R-Type: 00100qr_bbbbb_aaaaa_010_ddddd_0101111", desc: "Atomically loads a 32-bit value from the address in `rs1` into `rd` and stores the bitwise XOR of the value and `rs2` to the address." },
+    InstructionInfo { name: "AMOAND.W" , mnemonic: "amoand.w rd, rs2, (rs1)"  , encoding: "R-Type: 01100qr_bbbbb_aaaaa_010_ddddd_0101111", desc: "Atomically loads a 32-bit value from the address in `rs1` into `rd` and stores the bitwise AND of the value and `rs2` to the address." },
+    InstructionInfo { name: "AMOOR.W"  , mnemonic: "amoor.w rd, rs2, (rs1)"   , encoding: "R-Type: 01000qr_bbbbb_aaaaa_010_ddddd_0101111", desc: "Atomically loads a 32-bit value from the address in `rs1` into `rd` and stores the bitwise OR of the value and `rs2` to the address." },
+    InstructionInfo { name: "AMOMIN.W" , mnemonic: "amomin.w rd, rs2, (rs1)"  , encoding: "R-Type: 10000qr_bbbbb_aaaaa_010_ddddd_0101111", desc: "Atomically loads a 32-bit value from the address in `rs1` into `rd` and stores the signed minimum of the value and `rs2` to the address." },
+    InstructionInfo { name: "AMOMAX.W" , mnemonic: "amomax.w rd, rs2, (rs1)"  , encoding: "R-Type: 10100qr_bbbbb_aaaaa_010_ddddd_0101111", desc: "Atomically loads a 32-bit value from the address in `rs1` into `rd` and stores the signed maximum of the value and `rs2` to the address." },
+    InstructionInfo { name: "AMOMINU.W", mnemonic: "amominu.w rd, rs2, (rs1)" , encoding: "R-Type: 11000qr_bbbbb_aaaaa_010_ddddd_0101111", desc: "Atomically loads a 32-bit value from the address in `rs1` into `rd` and stores the unsigned minimum of the value and `rs2` to the address." },
+    InstructionInfo { name: "AMOMAXU.W", mnemonic: "amomaxu.w rd, rs2, (rs1)" , encoding: "R-Type: 11100qr_bbbbb_aaaaa_010_ddddd_0101111", desc: "Atomically loads a 32-bit value from the address in `rs1` into `rd` and stores the unsigned maximum of the value and `rs2` to the address." },
+    InstructionInfo { name: "LR.D"     , mnemonic: "lr.d rd, (rs1)"           , encoding: "R-Type: 00010qr_00000_aaaaa_011_ddddd_0101111", desc: "RV64 only. Loads a 64-bit value from the address in `rs1` into `rd` and registers a reservation set containing the address." },
+    InstructionInfo { name: "SC.D"     , mnemonic: "sc.d rd, rs2, (rs1)"      , encoding: "R-Type: 00011qr_bbbbb_aaaaa_011_ddddd_0101111", desc: "RV64 only. Conditionally stores `rs2` to the address in `rs1`, only if the hart holds a valid reservation on the address. Writes 0 to `rd` on success and 1 on failure." },
+    InstructionInfo { name: "AMOSWAP.D", mnemonic: "amoswap.d rd, rs2, (rs1)" , encoding: "R-Type: 00001qr_bbbbb_aaaaa_011_ddddd_0101111", desc: "RV64 only. Atomically loads a 64-bit value from the address in `rs1` into `rd` and stores `rs2` to the address." },
+    InstructionInfo { name: "AMOADD.D" , mnemonic: "amoadd.d rd, rs2, (rs1)"  , encoding: "R-Type: 00000qr_bbbbb_aaaaa_011_ddddd_0101111", desc: "RV64 only. Atomically loads a 64-bit value from the address in `rs1` into `rd` and stores the sum of the value and `rs2` to the address." },
+    InstructionInfo { name: "AMOXOR.D" , mnemonic: "amoxor.d rd, rs2, (rs1)"  , encoding: "R-Type: 00100qr_bbbbb_aaaaa_011_ddddd_0101111", desc: "RV64 only. Atomically loads a 64-bit value from the address in `rs1` into `rd` and stores the bitwise XOR of the value and `rs2` to the address." },
+    InstructionInfo { name: "AMOAND.D" , mnemonic: "amoand.d rd, rs2, (rs1)"  , encoding: "R-Type: 01100qr_bbbbb_aaaaa_011_ddddd_0101111", desc: "RV64 only. Atomically loads a 64-bit value from the address in `rs1` into `rd` and stores the bitwise AND of the value and `rs2` to the address." },
+    InstructionInfo { name: "AMOOR.D"  , mnemonic: "amoor.d rd, rs2, (rs1)"   , encoding: "R-Type: 01000qr_bbbbb_aaaaa_011_ddddd_0101111", desc: "RV64 only. Atomically loads a 64-bit value from the address in `rs1` into `rd` and stores the bitwise OR of the value and `rs2` to the address." },
+    InstructionInfo { name: "AMOMIN.D" , mnemonic: "amomin.d rd, rs2, (rs1)"  , encoding: "R-Type: 10000qr_bbbbb_aaaaa_011_ddddd_0101111", desc: "RV64 only. Atomically loads a 64-bit value from the address in `rs1` into `rd` and stores the signed minimum of the value and `rs2` to the address." },
+    InstructionInfo { name: "AMOMAX.D" , mnemonic: "amomax.d rd, rs2, (rs1)"  , encoding: "R-Type: 10100qr_bbbbb_aaaaa_011_ddddd_0101111", desc: "RV64 only. Atomically loads a 64-bit value from the address in `rs1` into `rd` and stores the signed maximum of the value and `rs2` to the address." },
+    InstructionInfo { name: "AMOMINU.D", mnemonic: "amominu.d rd, rs2, (rs1)" , encoding: "R-Type: 11000qr_bbbbb_aaaaa_011_ddddd_0101111", desc: "RV64 only. Atomically loads a 64-bit value from the address in `rs1` into `rd` and stores the unsigned minimum of the value and `rs2` to the address." },
+    InstructionInfo { name: "AMOMAXU.D", mnemonic: "amomaxu.d rd, rs2, (rs1)" , encoding: "R-Type: 11100qr_bbbbb_aaaaa_011_ddddd_0101111", desc: "RV64 only. Atomically loads a 64-bit value from the address in `rs1` into `rd` and stores the unsigned maximum of the value and `rs2` to the address." },
+];
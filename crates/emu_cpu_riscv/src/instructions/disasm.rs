@@ -0,0 +1,253 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+use emu_utils::*;
+
+use crate::csr;
+use crate::elf::Elf;
+use crate::memory_model::FENCE_FM_TSO;
+
+use super::*;
+
+/// ABI names of the integer registers
+const ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+    "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+    "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+/// FENCE predecessor and successor sets, indexed by the value of the field
+const FENCE_SETS: [&str; 16] = ["0", "w", "r", "rw", "o", "ow", "or", "orw", "i", "iw", "ir", "irw", "io", "iow", "ior", "iorw"];
+
+/// Disassembler rendering instructions in the syntax of GNU objdump
+pub struct Disassembler {
+    /// Print the ABI names of the registers (`a0`, `sp`, `ra`) instead of `x10`, `x2`, `x1`, like objdump without `-M numeric`
+    pub abi_names: bool,
+    /// Print pseudo-instructions (`li`, `mv`, `ret`, `j`, `nop`, `beqz`, ...) where they apply, like objdump without `-M no-aliases`
+    pub pseudo_instructions: bool,
+    /// Addresses wrap around at 32 bits
+    pub is_32_bit: bool,
+    /// Symbols by address, used to label branch targets and the addresses of a disassembled range
+    symbols: BTreeMap<u64, String>,
+}
+
+impl Disassembler {
+    pub fn new(is_32_bit: bool) -> Self {
+        Self { abi_names: true, pseudo_instructions: true, is_32_bit, symbols: BTreeMap::new() }
+    }
+
+    /// Create a disassembler for the code of an ELF file, using the symbols in its symbol table
+    pub fn for_elf(elf: &Elf) -> Self {
+        let mut disassembler = Self::new(!elf.is_64_bit);
+        disassembler.add_symbols(&elf.symbols);
+        disassembler
+    }
+
+    /// Add symbols to label addresses with, when symbols share an address, the first name in alphabetical order is used
+    pub fn add_symbols(&mut self, symbols: &HashMap<String, u64>) {
+        for (name, &addr) in symbols {
+            let addr = self.wrap(addr);
+            if self.symbols.get(&addr).is_none_or(|existing| name < existing) {
+                self.symbols.insert(addr, name.clone());
+            }
+        }
+    }
+
+    /// Disassemble an encoded instruction located at `addr`, e.g. `addi\tsp,sp,-32`.
+    ///
+    /// Encodings that don't decode to an instruction are printed as data
+    pub fn instruction(&self, addr: u64, encoded: u32) -> String {
+        match InstructionEncoding32(encoded).decode() {
+            Some(instr) => self.format(addr, &instr),
+            None => format!(".4byte\t0x{encoded:x}"),
+        }
+    }
+
+    /// Disassemble a decoded instruction located at `addr`
+    pub fn format(&self, addr: u64, instr: &Instruction) -> String {
+        let (mnemonic, operands) = self.pseudo_instruction(addr, instr).unwrap_or_else(|| self.base_instruction(addr, instr));
+        if operands.is_empty() {
+            mnemonic
+        } else {
+            format!("{mnemonic}\t{}", operands.join(","))
+        }
+    }
+
+    /// Disassemble the instructions in `data`, located at `addr`, in the format of `objdump -d`.
+    ///
+    /// Addresses with a symbol start with a label, trailing bytes that don't form a whole instruction are left out
+    pub fn range(&self, addr: u64, data: &[u8]) -> String {
+        let digits = if self.is_32_bit { 8 } else { 16 };
+        // Like objdump, leading zeroes of the addresses are dropped in groups of 4, based on the end of the range, keeping at least 4 of them
+        let end = self.wrap(addr.wrapping_add(data.len() as u64));
+        let leading_zeros = (end.leading_zeros() as usize / 4).saturating_sub(16 - digits);
+        let width = digits - leading_zeros.saturating_sub(1) / 4 * 4;
+
+        let mut out = String::new();
+        for (offset, bytes) in data.chunks_exact(4).enumerate() {
+            let addr = self.wrap(addr.wrapping_add(offset as u64 * 4));
+            if let Some(name) = self.symbols.get(&addr) {
+                if !out.is_empty() {
+                    out.push('\n');
+                }
+                let _ = writeln!(out, "{addr:0digits$x} <{name}>:");
+            }
+            let encoded = u32::from_le_bytes(bytes.try_into().unwrap());
+            let _ = writeln!(out, "{addr:>width$x}:\t{encoded:08x}          \t{}", self.instruction(addr, encoded));
+        }
+        out
+    }
+
+    fn wrap(&self, addr: u64) -> u64 {
+        if self.is_32_bit { addr & u32::MAX as u64 } else { addr }
+    }
+
+    fn register(&self, reg: u32) -> String {
+        if self.abi_names {
+            ABI_NAMES[reg as usize].to_string()
+        } else {
+            format!("x{reg}")
+        }
+    }
+
+    fn csr(&self, addr: u32) -> String {
+        csr::name(addr as u16).map_or_else(|| format!("0x{addr:x}"), str::to_string)
+    }
+
+    /// Format the target of a branch or jump, followed by the symbol it is in
+    fn target(&self, addr: u64, offset: u64) -> String {
+        let target = self.wrap(addr.wrapping_add(offset));
+        match self.symbols.range(..=target).next_back() {
+            Some((&base, name)) if base == target => format!("{target:x} <{name}>"),
+            Some((&base, name)) => format!("{target:x} <{name}+0x{:x}>", target - base),
+            None => format!("{target:x}"),
+        }
+    }
+
+    /// Disassemble an instruction using its mnemonic in the instruction info table
+    fn base_instruction(&self, addr: u64, instr: &Instruction) -> (String, Vec<String>) {
+        if let Instruction::RV32I(RV32IInstuction::FENCE { fm: FENCE_FM_TSO, pred: 0b0011, succ: 0b0011, .. }) = instr {
+            return ("fence.tso".to_string(), Vec::new());
+        }
+
+        let (pattern, operands) = instr.encoding();
+        let mnemonic = instr.info().mnemonic;
+        let (mnemonic, template) = mnemonic.split_once(' ').unwrap_or((mnemonic, ""));
+        let mut mnemonic = mnemonic.to_string();
+        if pattern.has_field(Field::Aq) {
+            mnemonic += match (operands.get(Field::Aq), operands.get(Field::Rl)) {
+                (0, 0) => "",
+                (_, 0) => ".aq",
+                (0, _) => ".rl",
+                _ => ".aqrl",
+            };
+        }
+
+        // Operands like `imm(rs1)` consist of multiple fields
+        let operands = template.split(", ").filter(|operand| !operand.is_empty()).map(|operand| {
+            let mut rendered = String::new();
+            let mut rest = operand;
+            while !rest.is_empty() {
+                let len = rest.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(rest.len()).max(1);
+                let (token, tail) = rest.split_at(len);
+                rendered += &self.field(addr, pattern, &operands, token);
+                rest = tail;
+            }
+            rendered
+        }).collect();
+        (mnemonic, operands)
+    }
+
+    /// Format the operand field named `token` in an instruction mnemonic, other tokens are kept as-is
+    fn field(&self, addr: u64, pattern: &EncodingPattern, operands: &Operands, token: &str) -> String {
+        match token {
+            "rd"   => self.register(operands.get(Field::Rd)),
+            "rs1"  => self.register(operands.get(Field::Rs1)),
+            "rs2"  => self.register(operands.get(Field::Rs2)),
+            "csr"  => self.csr(operands.get(Field::Csr)),
+            "uimm" => operands.get(Field::Uimm).to_string(),
+            "pred" => FENCE_SETS[operands.get(Field::Pred) as usize].to_string(),
+            "succ" => FENCE_SETS[operands.get(Field::Succ) as usize].to_string(),
+            "imm"  => {
+                let imm = operands.get(Field::Imm) as u64;
+                let width = pattern.field_width(Field::Imm) as u8;
+                match pattern.format {
+                    // The immediate is a multiple of 2, its lowest bit isn't encoded
+                    Format::B | Format::J => self.target(addr, sign_extend_64(imm, width)),
+                    Format::U => format!("0x{imm:x}"),
+                    // Shift amounts
+                    _ if width < 12 => format!("0x{imm:x}"),
+                    _ => (sign_extend_64(imm, width - 1) as i64).to_string(),
+                }
+            },
+            _ => token.to_string(),
+        }
+    }
+
+    /// Recognize the pseudo-instructions objdump prints instead of the base instruction
+    fn pseudo_instruction(&self, addr: u64, instr: &Instruction) -> Option<(String, Vec<String>)> {
+        if !self.pseudo_instructions {
+            return None;
+        }
+
+        let reg = |reg: u8| self.register(reg as u32);
+        let csr_name = |csr: u16| self.csr(csr as u32);
+        let signed = |imm: u16| (sign_extend_64(imm as u64, 11) as i64).to_string();
+        let branch = |imm: u16| self.target(addr, sign_extend_64(imm as u64, 12));
+        let jump = |imm: u32| self.target(addr, sign_extend_64(imm as u64, 20));
+
+        let (mnemonic, operands) = match *instr {
+            Instruction::RV32I(instr) => match instr {
+                RV32IInstuction::ADDI  { rd: 0, rs1: 0, imm: 0 }  => ("nop"   , vec![]),
+                RV32IInstuction::ADDI  { rd, rs1: 0, imm }        => ("li"    , vec![reg(rd), signed(imm)]),
+                RV32IInstuction::ADDI  { rd, rs1, imm: 0 }        => ("mv"    , vec![reg(rd), reg(rs1)]),
+                RV32IInstuction::XORI  { rd, rs1, imm: 0xFFF }    => ("not"   , vec![reg(rd), reg(rs1)]),
+                RV32IInstuction::ANDI  { rd, rs1, imm: 0xFF }     => ("zext.b", vec![reg(rd), reg(rs1)]),
+                RV32IInstuction::SLTIU { rd, rs1, imm: 1 }        => ("seqz"  , vec![reg(rd), reg(rs1)]),
+                RV32IInstuction::SUB   { rd, rs1: 0, rs2 }        => ("neg"   , vec![reg(rd), reg(rs2)]),
+                RV32IInstuction::SLTU  { rd, rs1: 0, rs2 }        => ("snez"  , vec![reg(rd), reg(rs2)]),
+                RV32IInstuction::SLT   { rd, rs1, rs2: 0 }        => ("sltz"  , vec![reg(rd), reg(rs1)]),
+                RV32IInstuction::SLT   { rd, rs1: 0, rs2 }        => ("sgtz"  , vec![reg(rd), reg(rs2)]),
+                RV32IInstuction::BEQ   { rs1, rs2: 0, imm }       => ("beqz"  , vec![reg(rs1), branch(imm)]),
+                RV32IInstuction::BNE   { rs1, rs2: 0, imm }       => ("bnez"  , vec![reg(rs1), branch(imm)]),
+                RV32IInstuction::BLT   { rs1, rs2: 0, imm }       => ("bltz"  , vec![reg(rs1), branch(imm)]),
+                RV32IInstuction::BLT   { rs1: 0, rs2, imm }       => ("bgtz"  , vec![reg(rs2), branch(imm)]),
+                RV32IInstuction::BGE   { rs1, rs2: 0, imm }       => ("bgez"  , vec![reg(rs1), branch(imm)]),
+                RV32IInstuction::BGE   { rs1: 0, rs2, imm }       => ("blez"  , vec![reg(rs2), branch(imm)]),
+                RV32IInstuction::JAL   { rd: 0, imm }             => ("j"     , vec![jump(imm)]),
+                RV32IInstuction::JAL   { rd: 1, imm }             => ("jal"   , vec![jump(imm)]),
+                RV32IInstuction::JALR  { rd: 0, rs1: 1, imm: 0 }  => ("ret"   , vec![]),
+                RV32IInstuction::JALR  { rd: 0, rs1, imm: 0 }     => ("jr"    , vec![reg(rs1)]),
+                RV32IInstuction::JALR  { rd: 1, rs1, imm: 0 }     => ("jalr"  , vec![reg(rs1)]),
+                RV32IInstuction::FENCE { rd: 0, rs1: 0, succ: 0b1111, pred: 0b1111, fm: 0 } => ("fence", vec![]),
+                _ => return None,
+            },
+            Instruction::Zicsr(instr) => match instr {
+                ZicsrInstructions::CSRRS  { rd, rs1: 0, csr: csr::CYCLE }    => ("rdcycle"   , vec![reg(rd)]),
+                ZicsrInstructions::CSRRS  { rd, rs1: 0, csr: csr::TIME }     => ("rdtime"    , vec![reg(rd)]),
+                ZicsrInstructions::CSRRS  { rd, rs1: 0, csr: csr::INSTRET }  => ("rdinstret" , vec![reg(rd)]),
+                ZicsrInstructions::CSRRS  { rd, rs1: 0, csr: csr::CYCLEH }   => ("rdcycleh"  , vec![reg(rd)]),
+                ZicsrInstructions::CSRRS  { rd, rs1: 0, csr: csr::TIMEH }    => ("rdtimeh"   , vec![reg(rd)]),
+                ZicsrInstructions::CSRRS  { rd, rs1: 0, csr: csr::INSTRETH } => ("rdinstreth", vec![reg(rd)]),
+                ZicsrInstructions::CSRRS  { rd, rs1: 0, csr }  => ("csrr" , vec![reg(rd), csr_name(csr)]),
+                ZicsrInstructions::CSRRW  { rd: 0, rs1, csr }  => ("csrw" , vec![csr_name(csr), reg(rs1)]),
+                ZicsrInstructions::CSRRS  { rd: 0, rs1, csr }  => ("csrs" , vec![csr_name(csr), reg(rs1)]),
+                ZicsrInstructions::CSRRC  { rd: 0, rs1, csr }  => ("csrc" , vec![csr_name(csr), reg(rs1)]),
+                ZicsrInstructions::CSRRWI { rd: 0, uimm, csr } => ("csrwi", vec![csr_name(csr), uimm.to_string()]),
+                ZicsrInstructions::CSRRSI { rd: 0, uimm, csr } => ("csrsi", vec![csr_name(csr), uimm.to_string()]),
+                ZicsrInstructions::CSRRCI { rd: 0, uimm, csr } => ("csrci", vec![csr_name(csr), uimm.to_string()]),
+                _ => return None,
+            },
+            // The address space and address operands are optional
+            Instruction::Privileged(PrivilegedInstructions::SfenceVma { rs1: 0, rs2: 0 }) => ("sfence.vma", vec![]),
+            Instruction::Privileged(PrivilegedInstructions::SfenceVma { rs1, rs2: 0 })    => ("sfence.vma", vec![reg(rs1)]),
+            Instruction::H(HInstructions::HfenceVvma { rs1: 0, rs2: 0 })                  => ("hfence.vvma", vec![]),
+            Instruction::H(HInstructions::HfenceVvma { rs1, rs2: 0 })                     => ("hfence.vvma", vec![reg(rs1)]),
+            Instruction::H(HInstructions::HfenceGvma { rs1: 0, rs2: 0 })                  => ("hfence.gvma", vec![]),
+            Instruction::H(HInstructions::HfenceGvma { rs1, rs2: 0 })                     => ("hfence.gvma", vec![reg(rs1)]),
+            _ => return None,
+        };
+        Some((mnemonic.to_string(), operands))
+    }
+}
@@ -0,0 +1,425 @@
+use emu_cpu::InstructionInfo;
+use emu_macros::EnumCount;
+use emu_utils::*;
+
+// Encoding patterns
+//
+// The `encoding` of an `InstructionInfo` is the single description of how an instruction is encoded, the decoder and encoder are derived from it.
+// A pattern starts with the format, e.g. `I-Type:`, followed by 32 characters describing bits 31 down to 0, `_` and spaces are ignored:
+//
+// - `0` or `1`: fixed bit, used to match the instruction
+// - `-`: reserved bit, ignored when decoding and encoded as 0
+// - a field letter: bit of an operand, see `Field`, the bits of an operand are ordered from most to least significant
+//
+// The bits of the B-type and J-type immediates are scrambled in the encoding, they are unscrambled using the format of the instruction.
+
+/// Instruction format
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    R,
+    I,
+    S,
+    B,
+    U,
+    J,
+}
+
+impl Format {
+    const fn parse(letter: u8) -> Format {
+        match letter {
+            b'R' => Format::R,
+            b'I' => Format::I,
+            b'S' => Format::S,
+            b'B' => Format::B,
+            b'U' => Format::U,
+            b'J' => Format::J,
+            _ => panic!("unknown instruction format"),
+        }
+    }
+
+    /// Convert the immediate bits, in the order they appear in the encoding, to the value of the immediate
+    fn unscramble_imm(self, bits: u32) -> u32 {
+        match self {
+            // imm[12|10:5|4:1|11]
+            Format::B => ((bits >> 11) & 0x1) << 12 | (bits & 0x1) << 11 | ((bits >> 1) & 0x3FF) << 1,
+            // imm[20|10:1|11|19:12]
+            Format::J => ((bits >> 19) & 0x1) << 20 | (bits & 0xFF) << 12 | ((bits >> 8) & 0x1) << 11 | ((bits >> 9) & 0x3FF) << 1,
+            _ => bits,
+        }
+    }
+
+    /// Convert the value of the immediate to the immediate bits, in the order they appear in the encoding
+    fn scramble_imm(self, imm: u32) -> u32 {
+        match self {
+            Format::B => ((imm >> 12) & 0x1) << 11 | ((imm >> 1) & 0x3FF) << 1 | ((imm >> 11) & 0x1),
+            Format::J => ((imm >> 20) & 0x1) << 19 | ((imm >> 1) & 0x3FF) << 9 | ((imm >> 11) & 0x1) << 8 | ((imm >> 12) & 0xFF),
+            _ => imm,
+        }
+    }
+}
+
+/// Instruction operand field
+#[derive(Clone, Copy, PartialEq, Eq, Debug, EnumCount)]
+pub enum Field {
+    /// Destination register, `d`
+    Rd,
+    /// First source register, `a`
+    Rs1,
+    /// Second source register, `b`
+    Rs2,
+    /// Immediate, `i`
+    Imm,
+    /// CSR address, `c`
+    Csr,
+    /// Unsigned immediate in the rs1 field, `u`
+    Uimm,
+    /// FENCE predecessor set, `p`
+    Pred,
+    /// FENCE successor set, `s`
+    Succ,
+    /// FENCE mode, `f`
+    Fm,
+    /// Acquire bit, `q`
+    Aq,
+    /// Release bit, `r`
+    Rl,
+}
+
+impl Field {
+    const fn from_letter(letter: u8) -> Option<Field> {
+        match letter {
+            b'd' => Some(Field::Rd),
+            b'a' => Some(Field::Rs1),
+            b'b' => Some(Field::Rs2),
+            b'i' => Some(Field::Imm),
+            b'c' => Some(Field::Csr),
+            b'u' => Some(Field::Uimm),
+            b'p' => Some(Field::Pred),
+            b's' => Some(Field::Succ),
+            b'f' => Some(Field::Fm),
+            b'q' => Some(Field::Aq),
+            b'r' => Some(Field::Rl),
+            _ => None,
+        }
+    }
+}
+
+/// Operand values of an instruction, indexed by `Field`
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Operands([u32; Field::COUNT]);
+
+impl Operands {
+    pub fn get(&self, field: Field) -> u32 {
+        self.0[field as usize]
+    }
+
+    pub fn set(&mut self, field: Field, value: u32) {
+        self.0[field as usize] = value;
+    }
+}
+
+/// Conversion between an operand value and the type of the field in an instruction
+pub trait OperandValue {
+    fn from_operand(value: u32) -> Self;
+    fn to_operand(self) -> u32;
+}
+
+impl OperandValue for u8 {
+    fn from_operand(value: u32) -> Self {
+        value as u8
+    }
+
+    fn to_operand(self) -> u32 {
+        self as u32
+    }
+}
+
+impl OperandValue for u16 {
+    fn from_operand(value: u32) -> Self {
+        value as u16
+    }
+
+    fn to_operand(self) -> u32 {
+        self as u32
+    }
+}
+
+impl OperandValue for u32 {
+    fn from_operand(value: u32) -> Self {
+        value
+    }
+
+    fn to_operand(self) -> u32 {
+        self
+    }
+}
+
+impl OperandValue for bool {
+    fn from_operand(value: u32) -> Self {
+        value != 0
+    }
+
+    fn to_operand(self) -> u32 {
+        self as u32
+    }
+}
+
+/// Mask/match pattern and operand layout of an instruction, parsed from its encoding pattern
+#[derive(Clone, Copy, Debug)]
+pub struct EncodingPattern {
+    pub format: Format,
+    /// Bits that identify the instruction
+    pub mask: u32,
+    /// Value of the bits in `mask`
+    pub value: u32,
+    /// Bits of each operand field
+    fields: [u32; Field::COUNT],
+}
+
+impl EncodingPattern {
+    const EMPTY: EncodingPattern = EncodingPattern { format: Format::R, mask: 0, value: 0, fields: [0; Field::COUNT] };
+
+    /// Parse an encoding pattern, panics if the pattern is malformed, so invalid tables fail to compile
+    pub const fn parse(encoding: &str) -> EncodingPattern {
+        let bytes = encoding.as_bytes();
+        if bytes.len() < 7 || bytes[1] != b'-' {
+            panic!("encoding pattern is missing the instruction format");
+        }
+        let mut pattern = EncodingPattern { format: Format::parse(bytes[0]), ..Self::EMPTY };
+
+        let mut idx = 0;
+        while bytes[idx] != b':' {
+            idx += 1;
+        }
+        idx += 1;
+
+        let mut bit = 32;
+        while idx < bytes.len() {
+            let letter = bytes[idx];
+            idx += 1;
+            if letter == b'_' || letter == b' ' {
+                continue;
+            }
+            if bit == 0 {
+                panic!("encoding pattern has more than 32 bits");
+            }
+            bit -= 1;
+
+            match letter {
+                b'0' => pattern.mask |= 1 << bit,
+                b'1' => {
+                    pattern.mask |= 1 << bit;
+                    pattern.value |= 1 << bit;
+                },
+                b'-' => {},
+                _ => match Field::from_letter(letter) {
+                    Some(field) => pattern.fields[field as usize] |= 1 << bit,
+                    None => panic!("unknown field in encoding pattern"),
+                },
+            }
+        }
+        if bit != 0 {
+            panic!("encoding pattern has less than 32 bits");
+        }
+        pattern
+    }
+
+    /// Check if the encoded instruction matches the pattern
+    pub fn matches(&self, encoded: u32) -> bool {
+        encoded & self.mask == self.value
+    }
+
+    /// Check if the pattern contains an operand field
+    pub fn has_field(&self, field: Field) -> bool {
+        self.fields[field as usize] != 0
+    }
+
+    /// Get the number of bits of an operand field
+    pub fn field_width(&self, field: Field) -> u32 {
+        self.fields[field as usize].count_ones()
+    }
+
+    /// Extract the operands from an encoded instruction
+    pub fn extract(&self, encoded: u32) -> Operands {
+        let mut operands = Operands::default();
+        for field in 0..Field::COUNT {
+            let mut bits = 0;
+            let mut mask = self.fields[field];
+            while mask != 0 {
+                let bit = 31 - mask.leading_zeros();
+                bits = (bits << 1) | ((encoded >> bit) & 0x1);
+                mask &= !(1 << bit);
+            }
+            operands.0[field] = if field == Field::Imm as usize { self.format.unscramble_imm(bits) } else { bits };
+        }
+        operands
+    }
+
+    /// Encode an instruction with the given operands, bits of an operand that don't fit in its field are dropped
+    pub fn encode(&self, operands: &Operands) -> u32 {
+        let mut encoded = self.value;
+        for field in 0..Field::COUNT {
+            let mut bits = if field == Field::Imm as usize { self.format.scramble_imm(operands.0[field]) } else { operands.0[field] };
+            let mut mask = self.fields[field];
+            // The lowest bit of the operand goes in the lowest bit of the field
+            while mask != 0 {
+                let bit = mask.trailing_zeros();
+                encoded |= (bits & 0x1) << bit;
+                bits >>= 1;
+                mask &= !(1 << bit);
+            }
+        }
+        encoded
+    }
+}
+
+/// Parse the encoding patterns of an instruction info table
+pub const fn parse_encodings<const N: usize>(info: &[InstructionInfo; N]) -> [EncodingPattern; N] {
+    let mut patterns = [EncodingPattern::EMPTY; N];
+    let mut idx = 0;
+    while idx < N {
+        patterns[idx] = EncodingPattern::parse(info[idx].encoding);
+        idx += 1;
+    }
+    patterns
+}
+
+/// Patterns of an instruction group that can match each combination of the opcode and funct3 bits, so decoding doesn't have to try every pattern
+pub struct DecodeTable {
+    /// Pattern indices, in the order of the group, for each value of opcode[6:2] and funct3
+    buckets: Vec<Vec<u16>>,
+}
+
+impl DecodeTable {
+    /// Bits of the opcode and funct3 used to select a bucket, opcode[1:0] is `11` for all 32-bit instructions
+    const KEY_MASK: u32 = 0x0000_707C;
+
+    pub fn new(encodings: &[EncodingPattern]) -> Self {
+        let buckets = (0..256).map(|bucket| {
+            let key = ((bucket as u32 & 0x1F) << 2) | ((bucket as u32 >> 5) << 12);
+            (0..encodings.len() as u16).filter(|&index| {
+                let pattern = &encodings[index as usize];
+                (key ^ pattern.value) & pattern.mask & Self::KEY_MASK == 0
+            }).collect()
+        }).collect();
+        DecodeTable { buckets }
+    }
+
+    fn bucket(encoded: u32) -> usize {
+        (((encoded >> 2) & 0x1F) | (((encoded >> 12) & 0x7) << 5)) as usize
+    }
+
+    /// Find the index of the first pattern in `encodings` matching the encoded instruction
+    pub fn find(&self, encodings: &[EncodingPattern], encoded: u32) -> Option<usize> {
+        self.buckets[Self::bucket(encoded)].iter()
+            .map(|&index| index as usize)
+            .find(|&index| encodings[index].matches(encoded))
+    }
+}
+
+/// Group of instructions described by an instruction info table
+pub trait InstructionSet: Sized {
+    /// Instruction info table of the group
+    const INFO: &'static [InstructionInfo];
+
+    /// Encoding pattern of each instruction, in the order of the variants
+    const ENCODINGS: &'static [EncodingPattern];
+
+    /// Decode table of `ENCODINGS`, built on first use
+    fn decode_table() -> &'static DecodeTable;
+
+    /// Create the instruction at `index` from its operands
+    fn from_operands(index: usize, operands: &Operands) -> Self;
+
+    /// Get the index of the instruction and its operands
+    fn operands(&self) -> (usize, Operands);
+
+    /// Decode an instruction in the group
+    fn decode(encoded: u32) -> Option<Self> {
+        let index = Self::decode_table().find(Self::ENCODINGS, encoded)?;
+        Some(Self::from_operands(index, &Self::ENCODINGS[index].extract(encoded)))
+    }
+
+    /// Get the info of the instruction
+    fn info(&self) -> &'static InstructionInfo {
+        &Self::INFO[self.operands().0]
+    }
+
+    /// Get the encoding pattern of the instruction and its operands
+    fn encoding(&self) -> (&'static EncodingPattern, Operands) {
+        let (index, operands) = self.operands();
+        (&Self::ENCODINGS[index], operands)
+    }
+}
+
+/// Map the name of an instruction field to its operand `Field`
+macro_rules! instruction_field {
+    (rd) => { $crate::instructions::Field::Rd };
+    (rs1) => { $crate::instructions::Field::Rs1 };
+    (rs2) => { $crate::instructions::Field::Rs2 };
+    (imm) => { $crate::instructions::Field::Imm };
+    (csr) => { $crate::instructions::Field::Csr };
+    (uimm) => { $crate::instructions::Field::Uimm };
+    (pred) => { $crate::instructions::Field::Pred };
+    (succ) => { $crate::instructions::Field::Succ };
+    (fm) => { $crate::instructions::Field::Fm };
+    (aq) => { $crate::instructions::Field::Aq };
+    (rl) => { $crate::instructions::Field::Rl };
+}
+
+/// Define an instruction enum and implement `InstructionSet` for it, using the encoding patterns of its instruction info table.
+/// The variants must be in the same order as the table, their fields are named after the operand they hold.
+macro_rules! instruction_set {
+    (
+        $(#[$meta:meta])*
+        $vis:vis enum $name:ident : $info:ident {
+            $($variant:ident $({ $($field:ident : $ty:ty),* $(,)? })?),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        #[allow(clippy::upper_case_acronyms)]
+        $vis enum $name {
+            $($variant $({ $($field: $ty),* })?),*
+        }
+
+        impl $crate::instructions::InstructionSet for $name {
+            const INFO: &'static [::emu_cpu::InstructionInfo] = &$info;
+            const ENCODINGS: &'static [$crate::instructions::EncodingPattern] = &$crate::instructions::parse_encodings(&$info);
+
+            fn decode_table() -> &'static $crate::instructions::DecodeTable {
+                static TABLE: ::std::sync::OnceLock<$crate::instructions::DecodeTable> = ::std::sync::OnceLock::new();
+                TABLE.get_or_init(|| $crate::instructions::DecodeTable::new(Self::ENCODINGS))
+            }
+
+            fn from_operands(index: usize, _operands: &$crate::instructions::Operands) -> Self {
+                #[allow(unused_imports)]
+                use $crate::instructions::OperandValue;
+
+                const CONSTRUCTORS: [fn(&$crate::instructions::Operands) -> $name; <$name as ::emu_utils::EnumCountT>::COUNT] = [
+                    $(|_operands| $name::$variant { $($($field: OperandValue::from_operand(_operands.get(instruction_field!($field)))),*)? }),*
+                ];
+                CONSTRUCTORS[index](_operands)
+            }
+
+            fn operands(&self) -> (usize, $crate::instructions::Operands) {
+                #[allow(unused_imports)]
+                use $crate::instructions::OperandValue;
+
+                #[allow(clippy::upper_case_acronyms)]
+                enum Index {
+                    $($variant),*
+                }
+
+                #[allow(unused_mut)]
+                let mut operands = $crate::instructions::Operands::default();
+                let index = match *self {
+                    $($name::$variant { $($($field),*)? } => {
+                        $($(operands.set(instruction_field!($field), $field.to_operand());)*)?
+                        Index::$variant
+                    }),*
+                };
+                (index as usize, operands)
+            }
+        }
+    };
+}
@@ -0,0 +1,139 @@
+use std::fmt;
+
+use emu_cpu::InstructionInfo;
+use emu_macros::EnumCount;
+use emu_utils::*;
+
+use crate::csr::*;
+use crate::instructions::{Disassembler, Instruction};
+use crate::memory::{self, MemoryBus};
+use crate::mmu::{AccessType, AccessMode};
+use crate::registers::{RegisterFile, PrivilegeMode};
+use crate::trap::Trap;
+
+instruction_set! {
+    #[derive(Clone, Copy, PartialEq, Eq, Debug, EnumCount)]
+    pub enum HInstructions : H_INSTUCTION_INFO {
+        HlvB   { rd: u8, rs1: u8 },
+        HlvBu  { rd: u8, rs1: u8 },
+        HlvH   { rd: u8, rs1: u8 },
+        HlvHu  { rd: u8, rs1: u8 },
+        HlvxHu { rd: u8, rs1: u8 },
+        HlvW   { rd: u8, rs1: u8 },
+        HlvxWu { rd: u8, rs1: u8 },
+        HlvWu  { rd: u8, rs1: u8 },
+        HlvD   { rd: u8, rs1: u8 },
+
+        HsvB   { rs1: u8, rs2: u8 },
+        HsvH   { rs1: u8, rs2: u8 },
+        HsvW   { rs1: u8, rs2: u8 },
+        HsvD   { rs1: u8, rs2: u8 },
+
+        HfenceVvma { rs1: u8, rs2: u8 },
+        HfenceGvma { rs1: u8, rs2: u8 },
+    }
+}
+
+impl HInstructions {
+    pub fn exec(&self, register_file: &mut RegisterFile, memory: &mut dyn MemoryBus) {
+        if let Err(trap) = self.try_exec(register_file, memory) {
+            register_file.raise_trap(trap);
+        }
+    }
+
+    fn try_exec(&self, register_file: &mut RegisterFile, memory: &mut dyn MemoryBus) -> Result<(), Trap> {
+        let privilege = register_file.privilege();
+        let virtualized = register_file.is_virtualized();
+        let csr = register_file.csr();
+
+        if !csr.has_extension('H') {
+            return Err(Trap::illegal_instruction());
+        }
+        // Hypervisor instructions are never allowed in VS/VU-mode, but would be allowed in HS-mode
+        if virtualized {
+            return Err(Trap::virtual_instruction());
+        }
+
+        match *self {
+            Self::HfenceVvma { .. } | Self::HfenceGvma { .. } => {
+                if privilege == PrivilegeMode::User {
+                    return Err(Trap::illegal_instruction());
+                }
+                if matches!(self, Self::HfenceGvma { .. }) && privilege == PrivilegeMode::Supervisor && csr.mstatus & MSTATUS_TVM != 0 {
+                    return Err(Trap::illegal_instruction());
+                }
+                // Dummy, address translations are not cached
+                register_file.inc_pc(4);
+                return Ok(());
+            },
+            _ => {},
+        }
+
+        // Virtual-machine loads and stores are allowed in U-mode when `hstatus.HU` is set
+        if privilege == PrivilegeMode::User && csr.hstatus & HSTATUS_HU == 0 {
+            return Err(Trap::illegal_instruction());
+        }
+
+        let mode = AccessMode::guest(register_file);
+        let is_32_bit = register_file.is_32_bit();
+
+        let (rd, rs1, size, signed, access) = match *self {
+            Self::HlvB   { rd, rs1 } => (rd, rs1, 1, true , AccessType::Load),
+            Self::HlvBu  { rd, rs1 } => (rd, rs1, 1, false, AccessType::Load),
+            Self::HlvH   { rd, rs1 } => (rd, rs1, 2, true , AccessType::Load),
+            Self::HlvHu  { rd, rs1 } => (rd, rs1, 2, false, AccessType::Load),
+            Self::HlvxHu { rd, rs1 } => (rd, rs1, 2, false, AccessType::LoadExecutable),
+            Self::HlvW   { rd, rs1 } => (rd, rs1, 4, true , AccessType::Load),
+            Self::HlvxWu { rd, rs1 } => (rd, rs1, 4, false, AccessType::LoadExecutable),
+            Self::HlvWu  { rd, rs1 } if !is_32_bit => (rd, rs1, 4, false, AccessType::Load),
+            Self::HlvD   { rd, rs1 } if !is_32_bit => (rd, rs1, 8, false, AccessType::Load),
+
+            Self::HsvB { rs1, rs2 } => return Self::store(register_file, memory, rs1, rs2, 1, mode),
+            Self::HsvH { rs1, rs2 } => return Self::store(register_file, memory, rs1, rs2, 2, mode),
+            Self::HsvW { rs1, rs2 } => return Self::store(register_file, memory, rs1, rs2, 4, mode),
+            Self::HsvD { rs1, rs2 } if !is_32_bit => return Self::store(register_file, memory, rs1, rs2, 8, mode),
+
+            _ => return Err(Trap::illegal_instruction()),
+        };
+
+        let addr = register_file.read_x_register(rs1);
+        let val = memory::load_with_mode(register_file, memory, addr, size, access, mode)?;
+        let val = if signed { sign_extend_64(val, size as u8 * 8 - 1) } else { val };
+        if rd != 0 {
+            register_file.write_x_register(rd, val);
+        }
+        register_file.inc_pc(4);
+        Ok(())
+    }
+
+    fn store(register_file: &mut RegisterFile, memory: &mut dyn MemoryBus, rs1: u8, rs2: u8, size: usize, mode: AccessMode) -> Result<(), Trap> {
+        let addr = register_file.read_x_register(rs1);
+        let val = register_file.read_x_register(rs2);
+        memory::store_with_mode(register_file, memory, addr, size, val, mode)?;
+        register_file.inc_pc(4);
+        Ok(())
+    }
+
+    #[deprecated(note = "use `Disassembler::format` instead")]
+    pub fn log(&self, f: &mut dyn fmt::Write) -> fmt::Result {
+        write!(f, "{}", Disassembler::new(false).format(0, &Instruction::H(*self)))
+    }
+}
+
+pub const H_INSTUCTION_INFO: [InstructionInfo; HInstructions::COUNT] = [
+    InstructionInfo { name: "HLV.B"      , mnemonic: "hlv.b rd, (rs1)"     , encoding: "R-Type:  0110000_00000_aaaaa_100_ddddd_1110011", desc: "Loads an 8-bit value from the guest virtual address in `rs1` and sign-extends it into `rd`. The address is translated and protected as if the load was executed in VS- or VU-mode, depending on `hstatus.SPVP`." },
+    InstructionInfo { name: "HLV.BU"     , mnemonic: "hlv.bu rd, (rs1)"    , encoding: "R-Type:  0110000_00001_aaaaa_100_ddddd_1110011", desc: "Loads an 8-bit value from the guest virtual address in `rs1` and zero-extends it into `rd`. The address is translated and protected as if the load was executed in VS- or VU-mode, depending on `hstatus.SPVP`." },
+    InstructionInfo { name: "HLV.H"      , mnemonic: "hlv.h rd, (rs1)"     , encoding: "R-Type:  0110010_00000_aaaaa_100_ddddd_1110011", desc: "Loads a 16-bit value from the guest virtual address in `rs1` and sign-extends it into `rd`. The address is translated and protected as if the load was executed in VS- or VU-mode, depending on `hstatus.SPVP`." },
+    InstructionInfo { name: "HLV.HU"     , mnemonic: "hlv.hu rd, (rs1)"    , encoding: "R-Type:  0110010_00001_aaaaa_100_ddddd_1110011", desc: "Loads a 16-bit value from the guest virtual address in `rs1` and zero-extends it into `rd`. The address is translated and protected as if the load was executed in VS- or VU-mode, depending on `hstatus.SPVP`." },
+    InstructionInfo { name: "HLVX.HU"    , mnemonic: "hlvx.hu rd, (rs1)"   , encoding: "R-Type:  0110010_00011_aaaaa_100_ddddd_1110011", desc: "Same as HLV.HU, but requires execute permission instead of read permission at both stages of address translation." },
+    InstructionInfo { name: "HLV.W"      , mnemonic: "hlv.w rd, (rs1)"     , encoding: "R-Type:  0110100_00000_aaaaa_100_ddddd_1110011", desc: "Loads a 32-bit value from the guest virtual address in `rs1` and sign-extends it into `rd`. The address is translated and protected as if the load was executed in VS- or VU-mode, depending on `hstatus.SPVP`." },
+    InstructionInfo { name: "HLVX.WU"    , mnemonic: "hlvx.wu rd, (rs1)"   , encoding: "R-Type:  0110100_00011_aaaaa_100_ddddd_1110011", desc: "Loads a 32-bit value from the guest virtual address in `rs1` and zero-extends it into `rd`, requiring execute permission instead of read permission at both stages of address translation." },
+    InstructionInfo { name: "HLV.WU"     , mnemonic: "hlv.wu rd, (rs1)"    , encoding: "R-Type:  0110100_00001_aaaaa_100_ddddd_1110011", desc: "RV64 only. Loads a 32-bit value from the guest virtual address in `rs1` and zero-extends it into `rd`." },
+    InstructionInfo { name: "HLV.D"      , mnemonic: "hlv.d rd, (rs1)"     , encoding: "R-Type:  0110110_00000_aaaaa_100_ddddd_1110011", desc: "RV64 only. Loads a 64-bit value from the guest virtual address in `rs1` into `rd`." },
+    InstructionInfo { name: "HSV.B"      , mnemonic: "hsv.b rs2, (rs1)"    , encoding: "R-Type:  0110001_bbbbb_aaaaa_100_00000_1110011", desc: "Stores the lower 8 bits of `rs2` to the guest virtual address in `rs1`. The address is translated and protected as if the store was executed in VS- or VU-mode, depending on `hstatus.SPVP`." },
+    InstructionInfo { name: "HSV.H"      , mnemonic: "hsv.h rs2, (rs1)"    , encoding: "R-Type:  0110011_bbbbb_aaaaa_100_00000_1110011", desc: "Stores the lower 16 bits of `rs2` to the guest virtual address in `rs1`. The address is translated and protected as if the store was executed in VS- or VU-mode, depending on `hstatus.SPVP`." },
+    InstructionInfo { name: "HSV.W"      , mnemonic: "hsv.w rs2, (rs1)"    , encoding: "R-Type:  0110101_bbbbb_aaaaa_100_00000_1110011", desc: "Stores the lower 32 bits of `rs2` to the guest virtual address in `rs1`. The address is translated and protected as if the store was executed in VS- or VU-mode, depending on `hstatus.SPVP`." },
+    InstructionInfo { name: "HSV.D"      , mnemonic: "hsv.d rs2, (rs1)"    , encoding: "R-Type:  0110111_bbbbb_aaaaa_100_00000_1110011", desc: "RV64 only. Stores `rs2` to the guest virtual address in `rs1`." },
+    InstructionInfo { name: "HFENCE.VVMA", mnemonic: "hfence.vvma rs1, rs2", encoding: "R-Type:  0010001_bbbbb_aaaaa_000_00000_1110011", desc: "Same as SFENCE.VMA, but applies to the VS-stage address translation of the current virtual machine, as selected by `hgatp.VMID`." },
+    InstructionInfo { name: "HFENCE.GVMA", mnemonic: "hfence.gvma rs1, rs2", encoding: "R-Type:  0110001_bbbbb_aaaaa_000_00000_1110011", desc: "Synchronizes updates to the G-stage page tables with current execution. `rs1` optionally selects a guest physical address (shifted right by 2) and `rs2` optionally selects a virtual machine ID." },
+];
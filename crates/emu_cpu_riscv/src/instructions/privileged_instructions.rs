@@ -0,0 +1,141 @@
+use std::fmt;
+
+use emu_cpu::InstructionInfo;
+use emu_macros::EnumCount;
+use emu_utils::*;
+
+use crate::csr::*;
+use crate::instructions::{Disassembler, Instruction};
+use crate::memory::MemoryBus;
+use crate::registers::{RegisterFile, PrivilegeMode};
+use crate::trap::Trap;
+
+instruction_set! {
+    #[derive(Clone, Copy, PartialEq, Eq, Debug, EnumCount)]
+    pub enum PrivilegedInstructions : PRIVILEGED_INSTUCTION_INFO {
+        MRET,
+        SRET,
+        WFI,
+        SfenceVma { rs1: u8, rs2: u8 },
+    }
+}
+
+impl PrivilegedInstructions {
+    pub fn exec(&self, register_file: &mut RegisterFile, _memory: &mut dyn MemoryBus) {
+        if let Err(trap) = self.try_exec(register_file) {
+            register_file.raise_trap(trap);
+        }
+    }
+
+    fn try_exec(&self, register_file: &mut RegisterFile) -> Result<(), Trap> {
+        let privilege = register_file.privilege();
+        let virtualized = register_file.is_virtualized();
+
+        match *self {
+            Self::MRET => {
+                if privilege != PrivilegeMode::Machine {
+                    return Err(Trap::illegal_instruction());
+                }
+
+                let csr = register_file.csr_mut();
+                let mstatus = csr.mstatus;
+                let mpp = PrivilegeMode::from_bits(mstatus >> MSTATUS_MPP_SHIFT).unwrap_or(PrivilegeMode::User);
+                let mpv = mpp != PrivilegeMode::Machine && mstatus & MSTATUS_MPV != 0;
+
+                let mut mstatus = set_bits(mstatus, MSTATUS_MIE, mstatus & MSTATUS_MPIE != 0);
+                mstatus |= MSTATUS_MPIE;
+                mstatus &= !(MSTATUS_MPP | MSTATUS_MPV);
+                if mpp != PrivilegeMode::Machine {
+                    mstatus &= !MSTATUS_MPRV;
+                }
+                csr.mstatus = mstatus;
+
+                let epc = csr.mepc;
+                register_file.set_privilege(mpp);
+                register_file.set_virtualized(mpv);
+                register_file.write_pc(epc);
+            },
+            Self::SRET => match (privilege, virtualized) {
+                (PrivilegeMode::User, false) => return Err(Trap::illegal_instruction()),
+                (PrivilegeMode::User, true ) => return Err(Trap::virtual_instruction()),
+                (PrivilegeMode::Supervisor, true) => {
+                    let csr = register_file.csr_mut();
+                    if csr.hstatus & HSTATUS_VTSR != 0 {
+                        return Err(Trap::virtual_instruction());
+                    }
+
+                    let vsstatus = csr.vsstatus;
+                    let spp = if vsstatus & MSTATUS_SPP != 0 { PrivilegeMode::Supervisor } else { PrivilegeMode::User };
+                    let mut vsstatus = set_bits(vsstatus, MSTATUS_SIE, vsstatus & MSTATUS_SPIE != 0);
+                    vsstatus |= MSTATUS_SPIE;
+                    vsstatus &= !MSTATUS_SPP;
+                    csr.vsstatus = vsstatus;
+
+                    let epc = csr.vsepc;
+                    register_file.set_privilege(spp);
+                    register_file.write_pc(epc);
+                },
+                _ => {
+                    let csr = register_file.csr_mut();
+                    if privilege == PrivilegeMode::Supervisor && csr.mstatus & MSTATUS_TSR != 0 {
+                        return Err(Trap::illegal_instruction());
+                    }
+
+                    let mstatus = csr.mstatus;
+                    let spp = if mstatus & MSTATUS_SPP != 0 { PrivilegeMode::Supervisor } else { PrivilegeMode::User };
+                    let spv = csr.has_extension('H') && csr.hstatus & HSTATUS_SPV != 0;
+
+                    let mut mstatus = set_bits(mstatus, MSTATUS_SIE, mstatus & MSTATUS_SPIE != 0);
+                    mstatus |= MSTATUS_SPIE;
+                    mstatus &= !(MSTATUS_SPP | MSTATUS_MPRV);
+                    csr.mstatus = mstatus;
+                    csr.hstatus &= !HSTATUS_SPV;
+
+                    let epc = csr.sepc;
+                    register_file.set_privilege(spp);
+                    register_file.set_virtualized(spv);
+                    register_file.write_pc(epc);
+                },
+            },
+            Self::WFI => {
+                let csr = register_file.csr();
+                // The time limit of `mstatus.TW` and `hstatus.VTW` is 0, so WFI traps immediately
+                match (privilege, virtualized) {
+                    (PrivilegeMode::Machine, _) => {},
+                    _ if csr.mstatus & MSTATUS_TW != 0 => return Err(Trap::illegal_instruction()),
+                    (PrivilegeMode::User, false) if csr.has_extension('S') => return Err(Trap::illegal_instruction()),
+                    (PrivilegeMode::User, true ) => return Err(Trap::virtual_instruction()),
+                    (PrivilegeMode::Supervisor, true) if csr.hstatus & HSTATUS_VTW != 0 => return Err(Trap::virtual_instruction()),
+                    _ => {},
+                }
+                register_file.set_waiting(true);
+                register_file.inc_pc(4);
+            },
+            Self::SfenceVma { .. } => {
+                let csr = register_file.csr();
+                match (privilege, virtualized) {
+                    (PrivilegeMode::User, false) => return Err(Trap::illegal_instruction()),
+                    (PrivilegeMode::User, true ) => return Err(Trap::virtual_instruction()),
+                    (PrivilegeMode::Supervisor, false) if csr.mstatus & MSTATUS_TVM != 0 => return Err(Trap::illegal_instruction()),
+                    (PrivilegeMode::Supervisor, true ) if csr.hstatus & HSTATUS_VTVM != 0 => return Err(Trap::virtual_instruction()),
+                    _ => {},
+                }
+                // Dummy, address translations are not cached
+                register_file.inc_pc(4);
+            },
+        }
+        Ok(())
+    }
+
+    #[deprecated(note = "use `Disassembler::format` instead")]
+    pub fn log(&self, f: &mut dyn fmt::Write) -> fmt::Result {
+        write!(f, "{}", Disassembler::new(false).format(0, &Instruction::Privileged(*self)))
+    }
+}
+
+pub const PRIVILEGED_INSTUCTION_INFO: [InstructionInfo; PrivilegedInstructions::COUNT] = [
+    InstructionInfo { name: "MRET"      , mnemonic: "mret"               , encoding: "R-Type:  0011000_00010_00000_000_00000_1110011", desc: "Return from a trap taken into M-mode. Sets the privilege mode to `mstatus.MPP` (and the virtualization mode to `mstatus.MPV`), `mstatus.MIE` to `mstatus.MPIE`, `mstatus.MPIE` to 1 and jumps to the address in `mepc`." },
+    InstructionInfo { name: "SRET"      , mnemonic: "sret"               , encoding: "R-Type:  0001000_00010_00000_000_00000_1110011", desc: "Return from a trap taken into S-mode. Sets the privilege mode to `sstatus.SPP` (and the virtualization mode to `hstatus.SPV`), `sstatus.SIE` to `sstatus.SPIE`, `sstatus.SPIE` to 1 and jumps to the address in `sepc`. When executed in VS-mode, `vsstatus` and `vsepc` are used instead." },
+    InstructionInfo { name: "WFI"       , mnemonic: "wfi"                , encoding: "R-Type:  0001000_00101_00000_000_00000_1110011", desc: "Wait for interrupt. Provides a hint that the current hart can be stalled until an interrupt might need servicing. Execution resumes after the WFI once an enabled interrupt is pending, even when interrupts are globally disabled." },
+    InstructionInfo { name: "SFENCE.VMA", mnemonic: "sfence.vma rs1, rs2", encoding: "R-Type:  0001001_bbbbb_aaaaa_000_00000_1110011", desc: "Synchronizes updates to in-memory memory-management data structures with current execution. `rs1` optionally selects a virtual address and `rs2` optionally selects an address space." },
+];
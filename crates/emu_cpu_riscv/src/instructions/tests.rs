@@ -1,4 +1,8 @@
-use crate::registers::RegisterFile;
+use emu_cpu::MisalignedPolicy;
+
+use crate::csr::*;
+use crate::registers::{RegisterFile, PrivilegeMode};
+use crate::trap::{self, Trap, Exception, Interrupt};
 
 use super::*;
 
@@ -257,6 +261,16 @@ fn test_rv32i_lui() {
     instr.exec(&mut register_file, &mut memory);
     assert_eq!(register_file.read_x_register(1), 0x1234_5000);
     assert_eq!(register_file.read_pc(), 4);
+
+    // The immediate is sign extended to 64 bits
+    let instr = RV32IInstuction::LUI { rd: 1, imm: 0x8_0000 };
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.read_x_register(1), 0xFFFF_FFFF_8000_0000);
+
+    // RV32 registers only hold the low 32 bits
+    let mut register_file = RegisterFile::new(true);
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.read_x_register(1), 0x8000_0000);
 }
 
 #[test]
@@ -276,6 +290,17 @@ fn test_rv32i_auipc() {
     instr.exec(&mut register_file, &mut memory);
     assert_eq!(register_file.read_x_register(1), 0x1234_5000 + 0x67890);
     assert_eq!(register_file.read_pc(), 0x67894);
+
+    // A negative offset is subtracted from the pc
+    register_file.write_pc(0x8000_1000);
+    let instr = RV32IInstuction::AUIPC { rd: 1, imm: 0xF_FFFF };
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.read_x_register(1), 0x8000_0000);
+
+    register_file.write_pc(0x1000);
+    let instr = RV32IInstuction::AUIPC { rd: 1, imm: 0x8_0000 };
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.read_x_register(1), 0xFFFF_FFFF_8000_1000);
 }
 
 
@@ -763,6 +788,46 @@ fn test_rv32i_bgtu() {
     assert_eq!(register_file.read_pc(), 196);
 }
 
+#[test]
+fn test_rv32i_branch_offset() {
+    let mut register_file = RegisterFile::new(false);
+
+    let mut memory = [0];
+
+    // The offset is a 13-bit signed value, bit 11 is not the sign bit
+    let instr = RV32IInstuction::BEQ { rs1: 0, rs2: 0, imm: 0x800 };
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.read_pc(), 0x800);
+
+    let instr = RV32IInstuction::BEQ { rs1: 0, rs2: 0, imm: 0x1FF0 };
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.read_pc(), 0x7F0);
+}
+
+#[test]
+fn test_encode_decode() {
+    // jal ra, 8: the opcode uses all 7 low bits
+    let instr = InstructionEncoding32(0x008000EF).decode();
+    assert!(matches!(instr, Some(Instruction::RV32I(RV32IInstuction::JAL { rd: 1, imm: 8 }))));
+
+    // add ra, sp, gp
+    assert_eq!(InstructionEncoding32::encode(Instruction::RV32I(RV32IInstuction::ADD { rd: 1, rs1: 2, rs2: 3 })).0, 0x003100B3);
+    assert!(matches!(InstructionEncoding32(0x003100B3).decode(), Some(Instruction::RV32I(RV32IInstuction::ADD { rd: 1, rs1: 2, rs2: 3 }))));
+
+    // bne ra, sp, 8
+    assert_eq!(InstructionEncoding32::encode(Instruction::RV32I(RV32IInstuction::BNE { rs1: 1, rs2: 2, imm: 8 })).0, 0x00209463);
+    assert!(matches!(InstructionEncoding32(0x00209463).decode(), Some(Instruction::RV32I(RV32IInstuction::BNE { rs1: 1, rs2: 2, imm: 8 }))));
+}
+
+#[test]
+fn test_rv32i_info() {
+    // The mnemonic starts with the name of the instruction
+    for info in &RV32I_INSTUCTION_INFO {
+        assert_eq!(info.mnemonic.split(' ').next().unwrap(), info.name.to_lowercase(), "{}", info.name);
+        assert_eq!(&info.encoding[1..7], "-Type:", "{}", info.name);
+    }
+}
+
 #[test]
 fn test_rv32i_lb() {
     let mut register_file = RegisterFile::new(false);
@@ -1018,3 +1083,676 @@ fn test_rv32i_sw() {
     assert_eq!(memory[7], 45);
     assert_eq!(register_file.read_pc(), 12);
 }
+
+
+#[test]
+fn test_zicsr() {
+    let mut register_file = RegisterFile::new(false);
+    register_file.write_x_register(2, 0xF0);
+
+    let mut memory = [0];
+
+    let instr = ZicsrInstructions::CSRRW { rd: 1, rs1: 2, csr: MSCRATCH };
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.read_x_register(1), 0);
+    assert_eq!(register_file.csr().mscratch, 0xF0);
+    assert_eq!(register_file.read_pc(), 4);
+
+    let instr = ZicsrInstructions::CSRRSI { rd: 1, uimm: 0xF, csr: MSCRATCH };
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.read_x_register(1), 0xF0);
+    assert_eq!(register_file.csr().mscratch, 0xFF);
+
+    let instr = ZicsrInstructions::CSRRC { rd: 1, rs1: 2, csr: MSCRATCH };
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.read_x_register(1), 0xFF);
+    assert_eq!(register_file.csr().mscratch, 0x0F);
+    assert_eq!(register_file.read_pc(), 12);
+
+    // M-mode CSRs are not accessible from S-mode
+    register_file.set_privilege(PrivilegeMode::Supervisor);
+    let instr = ZicsrInstructions::CSRRS { rd: 1, rs1: 0, csr: MSCRATCH };
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.take_trap().map(|trap| trap.cause), Some(Exception::IllegalInstruction));
+    assert_eq!(register_file.read_pc(), 12);
+}
+
+#[test]
+fn test_zicntr() {
+    let mut register_file = RegisterFile::new(false);
+    register_file.csr_mut().misa |= misa_bit('S') | misa_bit('H');
+    register_file.csr_mut().mtime = 1000;
+    register_file.csr_mut().htimedelta = (-100i64) as u64;
+
+    let mut memory = [0];
+
+    let mut rdtime = |register_file: &mut RegisterFile| {
+        let instr = ZicsrInstructions::CSRRS { rd: 1, rs1: 0, csr: TIME };
+        instr.exec(register_file, &mut memory);
+        match register_file.take_trap() {
+            Some(trap) => Err(trap.cause),
+            None => Ok(register_file.read_x_register(1)),
+        }
+    };
+
+    assert_eq!(rdtime(&mut register_file), Ok(1000));
+
+    // Below M-mode, the counter needs to be enabled in `mcounteren`
+    register_file.set_privilege(PrivilegeMode::Supervisor);
+    assert_eq!(rdtime(&mut register_file), Err(Exception::IllegalInstruction));
+    register_file.csr_mut().mcounteren = COUNTEREN_TM;
+    assert_eq!(rdtime(&mut register_file), Ok(1000));
+
+    // U-mode also needs it enabled in `scounteren`
+    register_file.set_privilege(PrivilegeMode::User);
+    assert_eq!(rdtime(&mut register_file), Err(Exception::IllegalInstruction));
+    register_file.csr_mut().scounteren = COUNTEREN_TM;
+    assert_eq!(rdtime(&mut register_file), Ok(1000));
+
+    // VS-mode and VU-mode also need it enabled in `hcounteren`, and read the time offset by `htimedelta`
+    register_file.set_privilege(PrivilegeMode::Supervisor);
+    register_file.set_virtualized(true);
+    assert_eq!(rdtime(&mut register_file), Err(Exception::VirtualInstruction));
+    register_file.csr_mut().hcounteren = COUNTEREN_TM;
+    assert_eq!(rdtime(&mut register_file), Ok(900));
+    register_file.set_privilege(PrivilegeMode::User);
+    assert_eq!(rdtime(&mut register_file), Ok(900));
+    register_file.csr_mut().scounteren = 0;
+    assert_eq!(rdtime(&mut register_file), Err(Exception::VirtualInstruction));
+
+    // The counters are read-only, `mcycle` and `minstret` are written instead
+    register_file.set_privilege(PrivilegeMode::Machine);
+    register_file.set_virtualized(false);
+    register_file.write_x_register(2, 42);
+    let instr = ZicsrInstructions::CSRRW { rd: 0, rs1: 2, csr: INSTRET };
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.take_trap().map(|trap| trap.cause), Some(Exception::IllegalInstruction));
+    let instr = ZicsrInstructions::CSRRW { rd: 0, rs1: 2, csr: MINSTRET };
+    instr.exec(&mut register_file, &mut memory);
+    let instr = ZicsrInstructions::CSRRS { rd: 1, rs1: 0, csr: INSTRET };
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.read_x_register(1), 42);
+
+    // RV32 reads the upper half through `timeh`
+    let mut register_file = RegisterFile::new(true);
+    register_file.csr_mut().mtime = 0x1_0000_0002;
+    let instr = ZicsrInstructions::CSRRS { rd: 1, rs1: 0, csr: TIMEH };
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.read_x_register(1), 1);
+}
+
+#[test]
+fn test_zifencei() {
+    let mut register_file = RegisterFile::new(false);
+
+    let mut memory = [0];
+
+    let instr = ZifenceiInstructions::FenceI;
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.read_pc(), 4);
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.read_pc(), 8);
+}
+
+#[test]
+fn test_mret_sret() {
+    let mut register_file = RegisterFile::new(false);
+    register_file.csr_mut().misa |= misa_bit('S') | misa_bit('H');
+
+    let mut memory = [0];
+
+    // M-mode -> VS-mode
+    register_file.csr_mut().mstatus = (1 << MSTATUS_MPP_SHIFT) | MSTATUS_MPV | MSTATUS_MPIE;
+    register_file.csr_mut().mepc = 0x100;
+    PrivilegedInstructions::MRET.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.privilege(), PrivilegeMode::Supervisor);
+    assert!(register_file.is_virtualized());
+    assert_eq!(register_file.csr().mstatus & (MSTATUS_MIE | MSTATUS_MPV | MSTATUS_MPP), MSTATUS_MIE);
+    assert_eq!(register_file.read_pc(), 0x100);
+
+    // MRET is illegal outside of M-mode
+    PrivilegedInstructions::MRET.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.take_trap().map(|trap| trap.cause), Some(Exception::IllegalInstruction));
+
+    // SRET in VS-mode uses the VS CSRs
+    register_file.csr_mut().vsepc = 0x200;
+    PrivilegedInstructions::SRET.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.privilege(), PrivilegeMode::User);
+    assert!(register_file.is_virtualized());
+    assert_eq!(register_file.read_pc(), 0x200);
+
+    // SRET in VU-mode raises a virtual instruction exception
+    PrivilegedInstructions::SRET.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.take_trap().map(|trap| trap.cause), Some(Exception::VirtualInstruction));
+}
+
+// G-stage page table (Sv39x4) mapping guest physical page 0x1000 to physical page 0x2000
+fn setup_g_stage(register_file: &mut RegisterFile, memory: &mut [u8]) {
+    let write_pte = |memory: &mut [u8], addr: usize, pte: u64| memory[addr..addr + 8].copy_from_slice(&pte.to_le_bytes());
+    // Root table at 0x4000 -> 0x8000 -> 0x9000
+    write_pte(memory, 0x4000, (0x8 << 10) | 0x01);
+    write_pte(memory, 0x8000, (0x9 << 10) | 0x01);
+    // Leaf: V | R | W | U | A | D
+    write_pte(memory, 0x9008, (0x2 << 10) | 0xD7);
+
+    let csr = register_file.csr_mut();
+    csr.misa |= misa_bit('S') | misa_bit('H');
+    csr.hgatp = (8 << 60) | 0x4;
+}
+
+#[test]
+fn test_h_load_store() {
+    let mut register_file = RegisterFile::new(false);
+    let mut memory = vec![0u8; 0xA000];
+    setup_g_stage(&mut register_file, &mut memory);
+    register_file.set_privilege(PrivilegeMode::Supervisor);
+    register_file.write_x_register(1, 0x1008);
+    register_file.write_x_register(2, 0x8877_6655_4433_2211);
+
+    let instr = HInstructions::HsvD { rs1: 1, rs2: 2 };
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.pending_trap(), None);
+    assert_eq!(&memory[0x2008..0x2010], &0x8877_6655_4433_2211u64.to_le_bytes());
+
+    let instr = HInstructions::HlvB { rd: 3, rs1: 1 };
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.read_x_register(3), 0x11);
+
+    let instr = HInstructions::HlvW { rd: 3, rs1: 1 };
+    register_file.write_x_register(1, 0x100C);
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.read_x_register(3), 0xFFFF_FFFF_8877_6655);
+    assert_eq!(register_file.read_pc(), 12);
+
+    // Unmapped guest physical address
+    register_file.write_x_register(1, 0x3004);
+    let instr = HInstructions::HlvHu { rd: 3, rs1: 1 };
+    instr.exec(&mut register_file, &mut memory);
+    let trap = register_file.take_trap().unwrap();
+    assert_eq!(trap.cause, Exception::LoadGuestPageFault);
+    assert_eq!(trap.tval, 0x3004);
+    assert_eq!(trap.tval2, 0x3004 >> 2);
+    assert_eq!(register_file.read_pc(), 12);
+
+    // Guest-page faults are taken into HS-mode when delegated
+    register_file.csr_mut().medeleg = 1 << Exception::LoadGuestPageFault.code();
+    register_file.csr_mut().stvec = 0x400;
+    trap::take_trap(&mut register_file, trap);
+    assert_eq!(register_file.csr().scause, Exception::LoadGuestPageFault.code());
+    assert_eq!(register_file.csr().htval, 0x3004 >> 2);
+    assert_ne!(register_file.csr().hstatus & HSTATUS_GVA, 0);
+    assert_eq!(register_file.read_pc(), 0x400);
+}
+
+#[test]
+fn test_h_virtual_instruction() {
+    let mut register_file = RegisterFile::new(false);
+    let mut memory = vec![0u8; 0xA000];
+    setup_g_stage(&mut register_file, &mut memory);
+    register_file.set_privilege(PrivilegeMode::Supervisor);
+    register_file.set_virtualized(true);
+
+    let instr = HInstructions::HlvB { rd: 3, rs1: 1 };
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.take_trap().map(|trap| trap.cause), Some(Exception::VirtualInstruction));
+
+    // Hypervisor CSRs are not accessible from VS-mode
+    let instr = ZicsrInstructions::CSRRS { rd: 1, rs1: 0, csr: HSTATUS };
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.take_trap().map(|trap| trap.cause), Some(Exception::VirtualInstruction));
+
+    // Supervisor CSRs are redirected to their VS counterparts
+    register_file.write_x_register(2, 0x1234);
+    let instr = ZicsrInstructions::CSRRW { rd: 0, rs1: 2, csr: SSCRATCH };
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.csr().vsscratch, 0x1234);
+    assert_eq!(register_file.csr().sscratch, 0);
+
+    // Loads and stores in VS-mode go through both translation stages
+    register_file.write_x_register(1, 0x1010);
+    let instr = RV32IInstuction::SW { rs1: 1, rs2: 2, imm: 0 };
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.pending_trap(), None);
+    assert_eq!(&memory[0x2010..0x2014], &0x1234u32.to_le_bytes());
+}
+
+#[test]
+fn test_a_amo() {
+    let mut register_file = RegisterFile::new(false);
+    let mut memory = [0u8; 16];
+    register_file.write_x_register(1, 8);
+    register_file.write_x_register(2, (-5i64) as u64);
+
+    // The A extension needs to be enabled in `misa`
+    let instr = AInstructions::AmoaddW { rd: 3, rs1: 1, rs2: 2, aq: false, rl: false };
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.take_trap().map(|trap| trap.cause), Some(Exception::IllegalInstruction));
+
+    register_file.csr_mut().misa |= misa_bit('A');
+    memory[8..12].copy_from_slice(&3u32.to_le_bytes());
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.pending_trap(), None);
+    assert_eq!(register_file.read_x_register(3), 3);
+    assert_eq!(&memory[8..12], &(-2i32).to_le_bytes());
+    assert_eq!(register_file.read_pc(), 4);
+
+    // Values are sign-extended for the comparisons and the result
+    let instr = AInstructions::AmominuW { rd: 3, rs1: 1, rs2: 2, aq: true, rl: true };
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.read_x_register(3), (-2i64) as u64);
+    assert_eq!(&memory[8..12], &(-5i32).to_le_bytes());
+
+    let instr = AInstructions::AmomaxD { rd: 3, rs1: 1, rs2: 0, aq: false, rl: false };
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.read_x_register(3), 0xFFFF_FFFB);
+    assert_eq!(&memory[8..16], &0xFFFF_FFFBu64.to_le_bytes());
+
+    // Memory without reservations never lets SC succeed
+    let instr = AInstructions::LrW { rd: 3, rs1: 1, aq: false, rl: false };
+    instr.exec(&mut register_file, &mut memory);
+    let instr = AInstructions::ScW { rd: 3, rs1: 1, rs2: 2, aq: false, rl: false };
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.read_x_register(3), 1);
+    assert_eq!(&memory[8..12], &0xFFFF_FFFBu32.to_le_bytes());
+
+    // Misaligned addresses, LR/SC can't be emulated
+    register_file.write_x_register(1, 4);
+    let instr = AInstructions::LrD { rd: 3, rs1: 1, aq: false, rl: false };
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.take_trap().map(|trap| trap.cause), Some(Exception::LoadAddressMisaligned));
+    register_file.set_misaligned_policy(MisalignedPolicy::Trap);
+    let instr = AInstructions::AmoswapD { rd: 3, rs1: 1, rs2: 2, aq: false, rl: false };
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.take_trap().map(|trap| trap.cause), Some(Exception::StoreAddressMisaligned));
+    register_file.set_misaligned_policy(MisalignedPolicy::AccessFault);
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.take_trap().map(|trap| trap.cause), Some(Exception::StoreAccessFault));
+    register_file.set_misaligned_policy(MisalignedPolicy::Emulate { count: true });
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.pending_trap(), None);
+    assert_eq!(register_file.read_x_register(3), 0xFFFF_FFFB_0000_0000);
+    assert_eq!(&memory[4..12], &(-5i64).to_le_bytes());
+    assert_eq!(register_file.misaligned_accesses(), 1);
+
+    // RV64 only
+    register_file.set_32_bit(true);
+    register_file.write_x_register(1, 8);
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.take_trap().map(|trap| trap.cause), Some(Exception::IllegalInstruction));
+
+    for instr in [
+        AInstructions::LrW { rd: 1, rs1: 2, aq: true, rl: false },
+        AInstructions::ScD { rd: 1, rs1: 2, rs2: 3, aq: false, rl: true },
+        AInstructions::AmoorW { rd: 4, rs1: 5, rs2: 6, aq: true, rl: true },
+    ] {
+        assert!(matches!(InstructionEncoding32::encode(Instruction::A(instr)).decode(), Some(Instruction::A(decoded)) if decoded == instr));
+    }
+}
+
+#[test]
+fn test_misaligned() {
+    let mut register_file = RegisterFile::new(false);
+    let mut memory = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+    register_file.write_x_register(2, 1);
+
+    let load = RV32IInstuction::LW { rd: 1, rs1: 2, imm: 4 };
+    let store = RV32IInstuction::SH { rs1: 2, rs2: 2, imm: 8 };
+
+    register_file.set_misaligned_policy(MisalignedPolicy::Trap);
+    load.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.take_trap(), Some(Trap::new(Exception::LoadAddressMisaligned, 5)));
+    store.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.take_trap(), Some(Trap::new(Exception::StoreAddressMisaligned, 9)));
+    assert_eq!(register_file.read_pc(), 0);
+
+    register_file.set_misaligned_policy(MisalignedPolicy::AccessFault);
+    load.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.take_trap(), Some(Trap::new(Exception::LoadAccessFault, 5)));
+    store.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.take_trap(), Some(Trap::new(Exception::StoreAccessFault, 9)));
+
+    register_file.set_misaligned_policy(MisalignedPolicy::Emulate { count: false });
+    load.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.read_x_register(1), 0x08070605);
+    assert_eq!(register_file.misaligned_accesses(), 0);
+
+    register_file.set_misaligned_policy(MisalignedPolicy::Emulate { count: true });
+    store.exec(&mut register_file, &mut memory);
+    assert_eq!(&memory[9..11], &[1, 0]);
+    // Aligned accesses aren't counted
+    let instr = RV32IInstuction::LW { rd: 1, rs1: 2, imm: 3 };
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.misaligned_accesses(), 1);
+
+    // Instruction fetch, which only needs to be aligned to 2 bytes with the C extension
+    register_file.set_misaligned_policy(MisalignedPolicy::Trap);
+    register_file.write_pc(2);
+    assert_eq!(crate::execute_instruction(&mut register_file, &mut memory, false), Err(Trap::new(Exception::InstructionAddressMisaligned, 2)));
+    register_file.set_misaligned_policy(MisalignedPolicy::AccessFault);
+    assert_eq!(crate::execute_instruction(&mut register_file, &mut memory, false), Err(Trap::new(Exception::InstructionAccessFault, 2)));
+    register_file.set_misaligned_policy(MisalignedPolicy::Emulate { count: true });
+    // The fetch is counted whether or not the fetched word decodes
+    let _ = crate::execute_instruction(&mut register_file, &mut memory, false);
+    assert_eq!(register_file.misaligned_accesses(), 2);
+}
+
+#[test]
+fn test_big_endian() {
+    let mut register_file = RegisterFile::new(false);
+    register_file.csr_mut().misa |= misa_bit('A') | misa_bit('S');
+    let mut memory = [0x12, 0x34, 0x56, 0x78, 0, 0, 0, 0];
+    register_file.write_x_register(2, 0);
+
+    // The `*BE` bits are read-only while the endianness is fixed
+    write(&mut register_file, MSTATUS, MSTATUS_MBE | MSTATUS_SBE | MSTATUS_UBE).unwrap();
+    assert_eq!(register_file.csr().mstatus & (MSTATUS_MBE | MSTATUS_SBE | MSTATUS_UBE), 0);
+
+    register_file.csr_mut().set_endianness(PrivilegeMode::Machine, Endianness::Bi);
+    write(&mut register_file, MSTATUS, MSTATUS_MBE | MSTATUS_SBE | MSTATUS_UBE).unwrap();
+    assert_eq!(register_file.csr().mstatus & (MSTATUS_MBE | MSTATUS_SBE | MSTATUS_UBE), MSTATUS_MBE);
+
+    let instr = RV32IInstuction::LW { rd: 1, rs1: 2, imm: 0 };
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.read_x_register(1), 0x12345678);
+
+    register_file.write_x_register(3, 0xAABB);
+    let instr = RV32IInstuction::SH { rs1: 2, rs2: 3, imm: 4 };
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(&memory[4..6], &[0xAA, 0xBB]);
+
+    // AMOs operate on the big-endian value
+    register_file.write_x_register(3, 0x100);
+    let instr = AInstructions::AmoaddW { rd: 4, rs1: 2, rs2: 3, aq: false, rl: false };
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.read_x_register(4), 0x12345678);
+    assert_eq!(&memory[0..4], &[0x12, 0x34, 0x57, 0x78]);
+
+    // Other modes keep their own endianness
+    register_file.csr_mut().set_endianness(PrivilegeMode::Supervisor, Endianness::Big);
+    assert_eq!(register_file.csr().hstatus & HSTATUS_VSBE, HSTATUS_VSBE);
+    register_file.set_privilege(PrivilegeMode::User);
+    let instr = RV32IInstuction::LW { rd: 1, rs1: 2, imm: 0 };
+    instr.exec(&mut register_file, &mut memory);
+    assert_eq!(register_file.read_x_register(1), 0x78573412);
+
+    // Instruction fetch is always little-endian
+    register_file.set_privilege(PrivilegeMode::Machine);
+    let mut memory = 0x02A00093u32.to_le_bytes(); // addi x1, x0, 42
+    register_file.write_pc(0);
+    crate::execute_instruction(&mut register_file, &mut memory, false).unwrap();
+    assert_eq!(register_file.read_x_register(1), 42);
+}
+
+#[test]
+fn test_extension_gating() {
+    let mut register_file = RegisterFile::new(false);
+    let fence_i = InstructionEncoding32(0x0000100F);
+    let csrrs = InstructionEncoding32(0x300020F3);  // csrrs x1, mstatus, x0
+    let amoadd_w = InstructionEncoding32(0x0021A0AF); // amoadd.w x1, x2, (x3)
+    let amoadd_d = InstructionEncoding32(0x0021B0AF); // amoadd.d x1, x2, (x3)
+    let sret = InstructionEncoding32(0x10200073);
+
+    // Zicsr and Zifencei are enabled by default
+    assert!(fence_i.decode_for(&register_file).is_some());
+    assert!(csrrs.decode_for(&register_file).is_some());
+    register_file.set_extensions(ExtensionIsa::Zicsr);
+    assert!(fence_i.decode().is_some());
+    assert!(fence_i.decode_for(&register_file).is_none());
+    assert!(csrrs.decode_for(&register_file).is_some());
+
+    // Single-letter extensions are checked in `misa`
+    assert!(amoadd_w.decode_for(&register_file).is_none());
+    assert!(sret.decode_for(&register_file).is_none());
+    register_file.csr_mut().misa |= misa_bit('A') | misa_bit('S');
+    assert!(amoadd_w.decode_for(&register_file).is_some());
+    assert!(amoadd_d.decode_for(&register_file).is_some());
+    assert!(sret.decode_for(&register_file).is_some());
+    register_file.set_32_bit(true);
+    assert!(amoadd_d.decode_for(&register_file).is_none());
+
+    // The E base ISA only has 16 registers
+    register_file.csr_mut().misa = (register_file.csr().misa & !misa_bit('I')) | misa_bit('E');
+    assert!(InstructionEncoding32(0x00100793).decode_for(&register_file).is_some()); // addi x15, x0, 1
+    assert!(InstructionEncoding32(0x00100813).decode_for(&register_file).is_none()); // addi x16, x0, 1
+    assert!(InstructionEncoding32(0x00180093).decode_for(&register_file).is_none()); // addi x1, x16, 1
+    assert!(InstructionEncoding32(0x340FD0F3).decode_for(&register_file).is_some()); // csrrwi x1, mscratch, 31
+
+    // Disabled instructions raise an illegal instruction exception when executed
+    let mut memory = fence_i.0.to_le_bytes();
+    register_file.write_pc(0);
+    assert_eq!(crate::execute_instruction(&mut register_file, &mut memory, false), Err(Trap::new(Exception::IllegalInstruction, fence_i.0 as u64)));
+}
+
+#[test]
+fn test_wfi() {
+    let mut register_file = RegisterFile::new(false);
+    register_file.csr_mut().misa |= misa_bit('S');
+
+    let mut memory = [0];
+
+    PrivilegedInstructions::WFI.exec(&mut register_file, &mut memory);
+    assert!(register_file.is_waiting());
+    assert_eq!(register_file.read_pc(), 4);
+
+    // WFI in S-mode traps when `mstatus.TW` is set
+    register_file.set_waiting(false);
+    register_file.set_privilege(PrivilegeMode::Supervisor);
+    register_file.csr_mut().mstatus |= MSTATUS_TW;
+    PrivilegedInstructions::WFI.exec(&mut register_file, &mut memory);
+    assert!(!register_file.is_waiting());
+    assert_eq!(register_file.take_trap().map(|trap| trap.cause), Some(Exception::IllegalInstruction));
+    assert_eq!(register_file.read_pc(), 4);
+}
+
+#[test]
+fn test_interrupts() {
+    let mut register_file = RegisterFile::new(false);
+    register_file.csr_mut().misa |= misa_bit('S');
+    register_file.write_pc(0x1000);
+
+    // Pending, but not enabled
+    register_file.csr_mut().interrupt_lines = Interrupt::MachineTimer.mask() | Interrupt::SupervisorExternal.mask();
+    assert_eq!(trap::pending_interrupt(&register_file), None);
+
+    // Enabled, but globally disabled in M-mode
+    register_file.csr_mut().mie = Interrupt::MachineTimer.mask() | Interrupt::SupervisorExternal.mask();
+    assert_eq!(trap::pending_interrupt(&register_file), None);
+
+    // Machine interrupts have priority over supervisor interrupts
+    register_file.csr_mut().mstatus |= MSTATUS_MIE;
+    assert_eq!(trap::pending_interrupt(&register_file), Some(Interrupt::MachineTimer));
+
+    // Interrupts delegated to S-mode depend on `sstatus.SIE` in S-mode
+    register_file.csr_mut().mideleg = Interrupt::SupervisorExternal.mask();
+    register_file.csr_mut().interrupt_lines = Interrupt::SupervisorExternal.mask();
+    register_file.set_privilege(PrivilegeMode::Supervisor);
+    assert_eq!(trap::pending_interrupt(&register_file), None);
+    register_file.csr_mut().mstatus |= MSTATUS_SIE;
+    assert_eq!(trap::pending_interrupt(&register_file), Some(Interrupt::SupervisorExternal));
+
+    // Interrupts taken into M-mode are always enabled in S-mode, regardless of `mstatus.MIE`
+    register_file.csr_mut().mstatus &= !MSTATUS_MIE;
+    register_file.csr_mut().interrupt_lines |= Interrupt::MachineTimer.mask();
+    assert_eq!(trap::pending_interrupt(&register_file), Some(Interrupt::MachineTimer));
+
+    // Vectored mode
+    register_file.csr_mut().mtvec = 0x200 | 1;
+    trap::take_interrupt(&mut register_file, Interrupt::MachineTimer);
+    assert_eq!(register_file.privilege(), PrivilegeMode::Machine);
+    assert_eq!(register_file.csr().mcause, (1 << 63) | 7);
+    assert_eq!(register_file.csr().mepc, 0x1000);
+    assert_eq!(register_file.csr().mstatus & (MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP), (1 << MSTATUS_MPP_SHIFT));
+    assert_eq!(register_file.read_pc(), 0x200 + 4 * 7);
+
+    // Delegated interrupts are not taken in M-mode
+    register_file.csr_mut().interrupt_lines = Interrupt::SupervisorExternal.mask();
+    register_file.csr_mut().mstatus |= MSTATUS_MIE;
+    assert_eq!(trap::pending_interrupt(&register_file), None);
+}
+
+#[test]
+fn test_encoding_tables() {
+    let groups: [&[EncodingPattern]; 6] = [
+        RV32IInstuction::ENCODINGS,
+        PrivilegedInstructions::ENCODINGS,
+        ZicsrInstructions::ENCODINGS,
+        ZifenceiInstructions::ENCODINGS,
+        HInstructions::ENCODINGS,
+        AInstructions::ENCODINGS,
+    ];
+    let patterns: Vec<_> = groups.iter().flat_map(|group| group.iter()).collect();
+
+    // No encoded instruction may match more than 1 pattern
+    for (idx, a) in patterns.iter().enumerate() {
+        for b in &patterns[idx + 1..] {
+            let common = a.mask & b.mask;
+            assert_ne!(a.value & common, b.value & common, "overlapping patterns {:08X}/{:08X} and {:08X}/{:08X}", a.value, a.mask, b.value, b.mask);
+        }
+    }
+
+    // Every pattern decodes to an instruction that encodes back to the same bits, reserved bits are not encoded
+    for pattern in patterns {
+        let field_bits = pattern.encode(&pattern.extract(u32::MAX)) & !pattern.mask;
+        for operand_bits in [0, 0xFFFF_FFFF, 0xA5A5_5A5A] {
+            let encoded = InstructionEncoding32(pattern.value | (operand_bits & field_bits));
+            let instr = encoded.decode().expect("pattern does not decode");
+            assert_eq!(InstructionEncoding32::encode(instr).0, encoded.0);
+        }
+    }
+}
+
+#[test]
+fn test_instruction_tables() {
+    // Every variant encodes to its own info table row and decodes back to itself, so a variant and a row in the wrong order are caught
+    fn check<T: InstructionSet + Copy + PartialEq + std::fmt::Debug>(wrap: fn(T) -> Instruction, unwrap: fn(Instruction) -> Option<T>) {
+        for (index, pattern) in T::ENCODINGS.iter().enumerate() {
+            for operand_bits in [0, 0xFFFF_FFFF, 0xA5A5_5A5A] {
+                let instr = T::from_operands(index, &pattern.extract(pattern.value | (operand_bits & !pattern.mask)));
+                let decoded = InstructionEncoding32::encode(wrap(instr)).decode().and_then(unwrap);
+                assert_eq!(decoded, Some(instr));
+
+                let variant = format!("{instr:?}");
+                let variant = variant.split(' ').next().unwrap();
+                assert_eq!(instr.info().name.replace('.', ""), variant.to_uppercase(), "{variant} has the info of {}", instr.info().name);
+            }
+        }
+    }
+
+    check(Instruction::RV32I, |instr| if let Instruction::RV32I(instr) = instr { Some(instr) } else { None });
+    check(Instruction::Privileged, |instr| if let Instruction::Privileged(instr) = instr { Some(instr) } else { None });
+    check(Instruction::Zicsr, |instr| if let Instruction::Zicsr(instr) = instr { Some(instr) } else { None });
+    check(Instruction::Zifencei, |instr| if let Instruction::Zifencei(instr) = instr { Some(instr) } else { None });
+    check(Instruction::H, |instr| if let Instruction::H(instr) = instr { Some(instr) } else { None });
+    check(Instruction::A, |instr| if let Instruction::A(instr) = instr { Some(instr) } else { None });
+}
+
+#[test]
+fn test_decode_table() {
+    fn check<T: InstructionSet>() {
+        let table = T::decode_table();
+        // Every pattern with any operand bits, and encodings matching no pattern, are found the same way as by trying every pattern
+        let encodings = T::ENCODINGS.iter().flat_map(|pattern| [0, 0xFFFF_FFFF, 0xA5A5_5A5A].map(|bits| pattern.value | (bits & !pattern.mask)));
+        for encoded in encodings.chain([0x0000_0000, 0xFFFF_FFFF, 0x0000_707F, 0x1234_5678]) {
+            assert_eq!(table.find(T::ENCODINGS, encoded), T::ENCODINGS.iter().position(|pattern| pattern.matches(encoded)), "{encoded:08X}");
+        }
+    }
+
+    check::<RV32IInstuction>();
+    check::<PrivilegedInstructions>();
+    check::<ZicsrInstructions>();
+    check::<ZifenceiInstructions>();
+    check::<HInstructions>();
+    check::<AInstructions>();
+}
+
+#[test]
+fn test_decode() {
+    let decode = |encoded| InstructionEncoding32(encoded).decode();
+
+    assert!(matches!(decode(0xFE010113), Some(Instruction::RV32I(RV32IInstuction::ADDI { rd: 2, rs1: 2, imm: 0xFE0 })))); // addi sp, sp, -32
+    assert!(matches!(decode(0xFFF54513), Some(Instruction::RV32I(RV32IInstuction::XORI { rd: 10, rs1: 10, imm: 0xFFF })))); // xori a0, a0, -1
+    assert!(matches!(decode(0x00153513), Some(Instruction::RV32I(RV32IInstuction::SLTIU { rd: 10, rs1: 10, imm: 1 })))); // sltiu a0, a0, 1
+    assert!(matches!(decode(0x40355513), Some(Instruction::RV32I(RV32IInstuction::SRAI { rd: 10, rs1: 10, imm: 3 })))); // srai a0, a0, 3
+    assert!(matches!(decode(0x12345537), Some(Instruction::RV32I(RV32IInstuction::LUI { rd: 10, imm: 0x12345 })))); // lui a0, 0x12345
+    assert!(matches!(decode(0x00112E23), Some(Instruction::RV32I(RV32IInstuction::SW { rs1: 2, rs2: 1, imm: 28 })))); // sw ra, 28(sp)
+    assert!(matches!(decode(0xFEA59F23), Some(Instruction::RV32I(RV32IInstuction::SH { rs1: 11, rs2: 10, imm: 0xFFE })))); // sh a0, -2(a1)
+    assert!(matches!(decode(0xFE0798E3), Some(Instruction::RV32I(RV32IInstuction::BNE { rs1: 15, rs2: 0, imm: 0x1FF0 })))); // bnez a5, -16
+    assert!(matches!(decode(0x00B550E3), Some(Instruction::RV32I(RV32IInstuction::BGE { rs1: 10, rs2: 11, imm: 0x800 })))); // bge a0, a1, 2048
+    assert!(matches!(decode(0x800FF0EF), Some(Instruction::RV32I(RV32IInstuction::JAL { rd: 1, imm: 0x1FF000 })))); // jal ra, -4096
+    assert!(matches!(decode(0x7FFFF06F), Some(Instruction::RV32I(RV32IInstuction::JAL { rd: 0, imm: 0xFFFFE })))); // j 0xFFFFE
+    assert!(matches!(decode(0x8330000F), Some(Instruction::RV32I(RV32IInstuction::FENCE { rd: 0, rs1: 0, succ: 0b0011, pred: 0b0011, fm: 0b1000 })))); // fence.tso
+    assert!(matches!(decode(0x340FD0F3), Some(Instruction::Zicsr(ZicsrInstructions::CSRRWI { rd: 1, uimm: 31, csr: 0x340 })))); // csrrwi ra, mscratch, 31
+    assert!(matches!(decode(0x0EB6252F), Some(Instruction::A(AInstructions::AmoswapW { rd: 10, rs1: 12, rs2: 11, aq: true, rl: true })))); // amoswap.w.aqrl a0, a1, (a2)
+
+    // The reserved fields of FENCE.I are ignored
+    assert!(matches!(decode(0x0010100F), Some(Instruction::Zifencei(ZifenceiInstructions::FenceI))));
+    // Reserved encodings
+    assert!(decode(0x00007003).is_none()); // LOAD with funct3 = 111
+    assert!(decode(0x10208073).is_none()); // SRET with rs1 != 0
+}
+
+#[test]
+fn test_disassembler() {
+    let mut disassembler = Disassembler::new(false);
+    disassembler.add_symbols(&[("_start".to_string(), 0x8000_0000), ("main".to_string(), 0x8000_0008)].into());
+
+    let disasm = |disassembler: &Disassembler, encoded| disassembler.instruction(0x8000_0010, encoded);
+    assert_eq!(disasm(&disassembler, 0xFE010113), "addi\tsp,sp,-32");
+    assert_eq!(disasm(&disassembler, 0xFFF00513), "li\ta0,-1");
+    assert_eq!(disasm(&disassembler, 0x00058513), "mv\ta0,a1");
+    assert_eq!(disasm(&disassembler, 0x00000013), "nop");
+    assert_eq!(disasm(&disassembler, 0x00008067), "ret");
+    assert_eq!(disasm(&disassembler, 0xFE0798E3), "bnez\ta5,80000000 <_start>");
+    assert_eq!(disasm(&disassembler, 0xFF9FF06F), "j\t80000008 <main>");
+    assert_eq!(disasm(&disassembler, 0xFFDFF0EF), "jal\t8000000c <main+0x4>");
+    assert_eq!(disasm(&disassembler, 0x00112E23), "sw\tra,28(sp)");
+    assert_eq!(disasm(&disassembler, 0x00C12083), "lw\tra,12(sp)");
+    assert_eq!(disasm(&disassembler, 0x12345537), "lui\ta0,0x12345");
+    assert_eq!(disasm(&disassembler, 0x40355513), "srai\ta0,a0,0x3");
+    assert_eq!(disasm(&disassembler, 0x340FD0F3), "csrrwi\tra,mscratch,31");
+    assert_eq!(disasm(&disassembler, 0xF1402573), "csrr\ta0,mhartid");
+    assert_eq!(disasm(&disassembler, 0x8330000F), "fence.tso");
+    assert_eq!(disasm(&disassembler, 0x0FF0000F), "fence");
+    assert_eq!(disasm(&disassembler, 0x0310000F), "fence\trw,w");
+    assert_eq!(disasm(&disassembler, 0x0EB6252F), "amoswap.w.aqrl\ta0,a1,(a2)");
+    assert_eq!(disasm(&disassembler, 0x12000073), "sfence.vma");
+    assert_eq!(disasm(&disassembler, 0x00007003), ".4byte\t0x7003");
+
+    disassembler.abi_names = false;
+    disassembler.pseudo_instructions = false;
+    assert_eq!(disasm(&disassembler, 0xFE010113), "addi\tx2,x2,-32");
+    assert_eq!(disasm(&disassembler, 0xFE0798E3), "bne\tx15,x0,80000000 <_start>");
+    assert_eq!(disasm(&disassembler, 0xF1402573), "csrrs\tx10,mhartid,x0");
+    assert_eq!(disasm(&disassembler, 0x0FF0000F), "fence\tiorw,iorw");
+    assert_eq!(disasm(&disassembler, 0x8330000F), "fence.tso");
+
+    // Trailing bytes that don't form an instruction are left out
+    let mut disassembler = Disassembler::new(false);
+    disassembler.add_symbols(&[("_start".to_string(), 0x8000_0000), ("_end".to_string(), 0x8000_0008)].into());
+    let mut data = Vec::new();
+    for encoded in [0xFE010113u32, 0x00008067, 0x00000013] {
+        data.extend_from_slice(&encoded.to_le_bytes());
+    }
+    data.push(0);
+    assert_eq!(disassembler.range(0x8000_0000, &data), concat!(
+        "0000000080000000 <_start>:\n",
+        "    80000000:\tfe010113          \taddi\tsp,sp,-32\n",
+        "    80000004:\t00008067          \tret\n",
+        "\n",
+        "0000000080000008 <_end>:\n",
+        "    80000008:\t00000013          \tnop\n",
+    ));
+}
+
+#[test]
+#[allow(deprecated)]
+fn test_log() {
+    // `log` is kept for compatibility and prints the disassembly, prefixed with the encoding for a decoded instruction
+    let mut out = String::new();
+    InstructionEncoding32(0xFE010113).decode().unwrap().log(&mut out).unwrap();
+    assert_eq!(out, "FE010113 | addi\tsp,sp,-32");
+
+    let mut out = String::new();
+    ZicsrInstructions::CSRRS { rd: 10, rs1: 0, csr: 0xF14 }.log(&mut out).unwrap();
+    assert_eq!(out, "csrr\ta0,mhartid");
+}
@@ -0,0 +1,91 @@
+use std::fmt;
+
+use emu_cpu::InstructionInfo;
+use emu_macros::EnumCount;
+use emu_utils::*;
+
+use crate::csr;
+use crate::instructions::{Disassembler, Instruction};
+use crate::memory::MemoryBus;
+use crate::registers::RegisterFile;
+use crate::trap::Trap;
+
+instruction_set! {
+    #[derive(Clone, Copy, PartialEq, Eq, Debug, EnumCount)]
+    pub enum ZicsrInstructions : ZICSR_INSTUCTION_INFO {
+        CSRRW  { rd: u8, rs1: u8, csr: u16 },
+        CSRRS  { rd: u8, rs1: u8, csr: u16 },
+        CSRRC  { rd: u8, rs1: u8, csr: u16 },
+        CSRRWI { rd: u8, uimm: u8, csr: u16 },
+        CSRRSI { rd: u8, uimm: u8, csr: u16 },
+        CSRRCI { rd: u8, uimm: u8, csr: u16 },
+    }
+}
+
+impl ZicsrInstructions {
+    pub fn exec(&self, register_file: &mut RegisterFile, _memory: &mut dyn MemoryBus) {
+        if let Err(trap) = self.try_exec(register_file) {
+            register_file.raise_trap(trap);
+        }
+    }
+
+    fn try_exec(&self, register_file: &mut RegisterFile) -> Result<(), Trap> {
+        let (rd, old) = match *self {
+            Self::CSRRW { rd, rs1, csr } => {
+                let src = register_file.read_x_register(rs1);
+                // CSRRW with rd = x0 shall not read the CSR
+                if rd == 0 {
+                    csr::write(register_file, csr, src)?;
+                    (rd, 0)
+                } else {
+                    (rd, csr::read_write(register_file, csr, |_| src)?)
+                }
+            },
+            Self::CSRRWI { rd, uimm, csr } => {
+                if rd == 0 {
+                    csr::write(register_file, csr, uimm as u64)?;
+                    (rd, 0)
+                } else {
+                    (rd, csr::read_write(register_file, csr, |_| uimm as u64)?)
+                }
+            },
+            // CSRRS and CSRRC with rs1 = x0 (or uimm = 0) shall not write the CSR
+            Self::CSRRS { rd, rs1, csr } => {
+                let mask = register_file.read_x_register(rs1);
+                if rs1 == 0 { (rd, csr::read(register_file, csr)?) } else { (rd, csr::read_write(register_file, csr, |old| old | mask)?) }
+            },
+            Self::CSRRC { rd, rs1, csr } => {
+                let mask = register_file.read_x_register(rs1);
+                if rs1 == 0 { (rd, csr::read(register_file, csr)?) } else { (rd, csr::read_write(register_file, csr, |old| old & !mask)?) }
+            },
+            Self::CSRRSI { rd, uimm, csr } => {
+                let mask = uimm as u64;
+                if uimm == 0 { (rd, csr::read(register_file, csr)?) } else { (rd, csr::read_write(register_file, csr, |old| old | mask)?) }
+            },
+            Self::CSRRCI { rd, uimm, csr } => {
+                let mask = uimm as u64;
+                if uimm == 0 { (rd, csr::read(register_file, csr)?) } else { (rd, csr::read_write(register_file, csr, |old| old & !mask)?) }
+            },
+        };
+
+        if rd != 0 {
+            register_file.write_x_register(rd, old);
+        }
+        register_file.inc_pc(4);
+        Ok(())
+    }
+
+    #[deprecated(note = "use `Disassembler::format` instead")]
+    pub fn log(&self, f: &mut dyn fmt::Write) -> fmt::Result {
+        write!(f, "{}", Disassembler::new(false).format(0, &Instruction::Zicsr(*self)))
+    }
+}
+
+pub const ZICSR_INSTUCTION_INFO: [InstructionInfo; ZicsrInstructions::COUNT] = [
+    InstructionInfo { name: "CSRRW" , mnemonic: "csrrw rd, csr, rs1"  , encoding: "I-Type:   cccccccccccc_aaaaa_001_ddddd_1110011", desc: "Atomically swaps values in the CSRs and integer registers. Reads the old value of the CSR, zero-extends the value to XLEN bits, then writes it to integer register `rd`. The initial value in `rs1` is written to the CSR. If `rd = x0`, then the instruction shall not read the CSR and shall not cause any of the side effects that might occur on a CSR read." },
+    InstructionInfo { name: "CSRRS" , mnemonic: "csrrs rd, csr, rs1"  , encoding: "I-Type:   cccccccccccc_aaaaa_010_ddddd_1110011", desc: "Atomic read and set bits in CSR. Reads the value of the CSR, zero-extends the value to XLEN bits, and writes it to integer register `rd`. The initial value in integer register `rs1` is treated as a bit mask that specifies bit positions to be set in the CSR. If `rs1 = x0`, then the instruction will not write to the CSR at all. Note: `CSRRS rd, csr, x0` is used to implement the `CSRR rd, csr` assembler pseudo-instruction." },
+    InstructionInfo { name: "CSRRC" , mnemonic: "csrrc rd, csr, rs1"  , encoding: "I-Type:   cccccccccccc_aaaaa_011_ddddd_1110011", desc: "Atomic read and clear bits in CSR. Reads the value of the CSR, zero-extends the value to XLEN bits, and writes it to integer register `rd`. The initial value in integer register `rs1` is treated as a bit mask that specifies bit positions to be cleared in the CSR. If `rs1 = x0`, then the instruction will not write to the CSR at all." },
+    InstructionInfo { name: "CSRRWI", mnemonic: "csrrwi rd, csr, uimm", encoding: "I-Type:   cccccccccccc_uuuuu_101_ddddd_1110011", desc: "Same as CSRRW, except that it updates the CSR using the 5-bit zero-extended immediate encoded in the `rs1` field." },
+    InstructionInfo { name: "CSRRSI", mnemonic: "csrrsi rd, csr, uimm", encoding: "I-Type:   cccccccccccc_uuuuu_110_ddddd_1110011", desc: "Same as CSRRS, except that it updates the CSR using the 5-bit zero-extended immediate encoded in the `rs1` field. If `uimm = 0`, then the instruction will not write to the CSR at all." },
+    InstructionInfo { name: "CSRRCI", mnemonic: "csrrci rd, csr, uimm", encoding: "I-Type:   cccccccccccc_uuuuu_111_ddddd_1110011", desc: "Same as CSRRC, except that it updates the CSR using the 5-bit zero-extended immediate encoded in the `rs1` field. If `uimm = 0`, then the instruction will not write to the CSR at all." },
+];
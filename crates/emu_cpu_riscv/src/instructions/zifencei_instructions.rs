@@ -4,25 +4,31 @@ use emu_cpu::InstructionInfo;
 use emu_macros::EnumCount;
 use emu_utils::*;
 
+use crate::instructions::{Disassembler, Instruction};
+use crate::memory::MemoryBus;
 use crate::registers::RegisterFile;
 
-#[derive(Clone, Copy, PartialEq, Eq, Debug, EnumCount)]
-pub enum ZifenceiInstructions {
-    FenceI,
+instruction_set! {
+    #[derive(Clone, Copy, PartialEq, Eq, Debug, EnumCount)]
+    pub enum ZifenceiInstructions : ZIFENCEI_INSTUCTION_INFO {
+        FenceI,
+    }
 }
 
 impl ZifenceiInstructions {
-    pub fn exec(&self, register_file: &mut RegisterFile, memory: &mut [u8]) {
+    pub fn exec(&self, register_file: &mut RegisterFile, _memory: &mut dyn MemoryBus) {
         match *self {
-            ZifenceiInstructions::FenceI => {} // Dummy, we don't have an i$,
+            // We don't have an i$, so instruction fetches already see all stores
+            ZifenceiInstructions::FenceI => register_file.inc_pc(4),
         }
     }
 
+    #[deprecated(note = "use `Disassembler::format` instead")]
     pub fn log(&self, f: &mut dyn fmt::Write) -> fmt::Result {
-        write!(f, "ifencei")
+        write!(f, "{}", Disassembler::new(false).format(0, &Instruction::Zifencei(*self)))
     }
 }
 
 pub const ZIFENCEI_INSTUCTION_INFO: [InstructionInfo; ZifenceiInstructions::COUNT] = [
-    InstructionInfo { name: "FENCE.I", mnemonic: "fence.i", encoding: "I-Type: 000000000000_00000_001_00000_0001111", desc: "Used to synchronized the instruction and data streams. RISC-V does not guarantee that stores to instruciton memory will be made visible to instruction fetches on a RISC-V hart until thta hard executes a FENCE.I instcruction." },
+    InstructionInfo { name: "FENCE.I", mnemonic: "fence.i", encoding: "I-Type: ------------_-----_001_-----_0001111", desc: "Used to synchronized the instruction and data streams. RISC-V does not guarantee that stores to instruciton memory will be made visible to instruction fetches on a RISC-V hart until thta hard executes a FENCE.I instcruction." },
 ];
\ No newline at end of file
@@ -1,8 +1,11 @@
+use std::fmt;
+use std::str::FromStr;
+
 use emu_cpu::{BaseIsaInfo, IsaStatus, IsaSize, ExtensionIsaInfo};
 use emu_macros::{EnumCount, flags};
 use emu_utils::EnumCountT;
 
-use crate::instructions::{RV32I_INSTUCTION_INFO, ZIFENCEI_INSTUCTION_INFO};
+use crate::instructions::{RV32I_INSTUCTION_INFO, PRIVILEGED_INSTUCTION_INFO, ZICSR_INSTUCTION_INFO, ZIFENCEI_INSTUCTION_INFO, H_INSTUCTION_INFO, A_INSTUCTION_INFO};
 
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug, EnumCount)]
@@ -28,7 +31,11 @@ pub enum ExtensionIsa {
     Q,
     L,
     C,
-    B,
+    Zba,
+    Zbb,
+    Zbc,
+    Zbs,
+    B = Zba | Zbb | Zbs,
     J,
     T,
     P,
@@ -62,23 +69,27 @@ pub const BASE_ISA_INFO: [BaseIsaInfo; BaseIsa::COUNT] = [
 
 pub const EXT_ISA_INFO: [ExtensionIsaInfo; ExtensionIsa::COUNT] = [
     ExtensionIsaInfo { name: "M"          , desc: "Standard extension for integer multiplication and division", version: "2.0"   , status: IsaStatus::Ratified, instructions: [None, None, None, None, None,] },
-    ExtensionIsaInfo { name: "A"          , desc: "Standard extension for atomic instructions"                , version: "2.1"   , status: IsaStatus::Ratified, instructions: [None, None, None, None, None,] },
+    ExtensionIsaInfo { name: "A"          , desc: "Standard extension for atomic instructions"                , version: "2.1"   , status: IsaStatus::Ratified, instructions: [None, None, Some(&A_INSTUCTION_INFO), Some(&A_INSTUCTION_INFO), None,] },
     ExtensionIsaInfo { name: "F"          , desc: "Standard extension for single-precision floating-point"    , version: "2.2"   , status: IsaStatus::Ratified, instructions: [None, None, None, None, None,] },
     ExtensionIsaInfo { name: "D"          , desc: "Standard extension for double-precision floating-point"    , version: "2.2"   , status: IsaStatus::Ratified, instructions: [None, None, None, None, None,] },
-    ExtensionIsaInfo { name: "Zicsr"      , desc: "Control and Status Register (CSR) instructions"            , version: "2.0"   , status: IsaStatus::Ratified, instructions: [None, None, None, None, None,] },
+    ExtensionIsaInfo { name: "Zicsr"      , desc: "Control and Status Register (CSR) instructions"            , version: "2.0"   , status: IsaStatus::Ratified, instructions: [None, None, Some(&ZICSR_INSTUCTION_INFO), Some(&ZICSR_INSTUCTION_INFO), None,] },
     ExtensionIsaInfo { name: "Zifencei"   , desc: "Instuction-fetch fence"                                    , version: "2.0"   , status: IsaStatus::Ratified, instructions: [None, None, Some(&ZIFENCEI_INSTUCTION_INFO), Some(&ZIFENCEI_INSTUCTION_INFO), None,] },
     ExtensionIsaInfo { name: "G"          , desc: "Shorthand for the IMAFD_Zicrt_Zifencei base and extensions", version: "n/a"   , status: IsaStatus::Ratified, instructions: [None, None, None, None, None,] },
     ExtensionIsaInfo { name: "Q"          , desc: "Standard extension for quad-precision floating-point"      , version: "2.2"   , status: IsaStatus::Ratified, instructions: [None, None, None, None, None,] },
     ExtensionIsaInfo { name: "L"          , desc: "Standard extension for decimal floating-point"             , version: "0.0"   , status: IsaStatus::Draft   , instructions: [None, None, None, None, None,] },
     ExtensionIsaInfo { name: "C"          , desc: "Standard extension for compressed instructions"            , version: "2.0"   , status: IsaStatus::Ratified, instructions: [None, None, None, None, None,] },
-    ExtensionIsaInfo { name: "B"          , desc: "Standard extension for bit manipulation"                   , version: "1.0"   , status: IsaStatus::Ratified, instructions: [None, None, None, None, None,] },
+    ExtensionIsaInfo { name: "Zba"        , desc: "Address generation"                                        , version: "1.0"   , status: IsaStatus::Ratified, instructions: [None, None, None, None, None,] },
+    ExtensionIsaInfo { name: "Zbb"        , desc: "Basic bit manipulation"                                    , version: "1.0"   , status: IsaStatus::Ratified, instructions: [None, None, None, None, None,] },
+    ExtensionIsaInfo { name: "Zbc"        , desc: "Carry-less multiplication"                                 , version: "1.0"   , status: IsaStatus::Ratified, instructions: [None, None, None, None, None,] },
+    ExtensionIsaInfo { name: "Zbs"        , desc: "Single-bit instructions"                                   , version: "1.0"   , status: IsaStatus::Ratified, instructions: [None, None, None, None, None,] },
+    ExtensionIsaInfo { name: "B"          , desc: "Shorthand for the Zba_Zbb_Zbs extensions"                  , version: "1.0"   , status: IsaStatus::Ratified, instructions: [None, None, None, None, None,] },
     ExtensionIsaInfo { name: "J"          , desc: "Standard extension for dynamically translated languages"   , version: "0.0"   , status: IsaStatus::Draft   , instructions: [None, None, None, None, None,] },
     ExtensionIsaInfo { name: "T"          , desc: "Standard extension for transactional memory"               , version: "0.0"   , status: IsaStatus::Draft   , instructions: [None, None, None, None, None,] },
     ExtensionIsaInfo { name: "P"          , desc: "Standard extension for packed SIMD instructions"           , version: "0.9.10", status: IsaStatus::Draft   , instructions: [None, None, None, None, None,] },
     ExtensionIsaInfo { name: "V"          , desc: "Standard extension for vector instructions"                , version: "1.0"   , status: IsaStatus::Ratified, instructions: [None, None, None, None, None,] },
     ExtensionIsaInfo { name: "Zk"         , desc: "Standard extension for scalar cryptography"                , version: "1.0.1" , status: IsaStatus::Ratified, instructions: [None, None, None, None, None,] },
-    ExtensionIsaInfo { name: "H"          , desc: "Standard extension for hypervisor"                         , version: "1.0"   , status: IsaStatus::Ratified, instructions: [None, None, None, None, None,] },
-    ExtensionIsaInfo { name: "S"          , desc: "Standard extension for supervisor-level instructions"      , version: "1.12"  , status: IsaStatus::Ratified, instructions: [None, None, None, None, None,] },
+    ExtensionIsaInfo { name: "H"          , desc: "Standard extension for hypervisor"                         , version: "1.0"   , status: IsaStatus::Ratified, instructions: [None, None, Some(&H_INSTUCTION_INFO), Some(&H_INSTUCTION_INFO), None,] },
+    ExtensionIsaInfo { name: "S"          , desc: "Standard extension for supervisor-level instructions"      , version: "1.12"  , status: IsaStatus::Ratified, instructions: [None, None, Some(&PRIVILEGED_INSTUCTION_INFO), Some(&PRIVILEGED_INSTUCTION_INFO), None,] },
     ExtensionIsaInfo { name: "Zam"        , desc: "Misaligned atomics"                                        , version: "0.1"   , status: IsaStatus::Draft   , instructions: [None, None, None, None, None,] },
     ExtensionIsaInfo { name: "Zihintpause", desc: "Pause hint"                                                , version: "2.0"   , status: IsaStatus::Ratified, instructions: [None, None, None, None, None,] },
     ExtensionIsaInfo { name: "Zihintntl"  , desc: "Non-temporal locality hints"                               , version: "0.2"   , status: IsaStatus::Draft   , instructions: [None, None, None, None, None,] },
@@ -91,4 +102,257 @@ pub const EXT_ISA_INFO: [ExtensionIsaInfo; ExtensionIsa::COUNT] = [
     ExtensionIsaInfo { name: "Zhinxmin"   , desc: "Minimum half-precision floating-point in integer registers", version: "1.0"   , status: IsaStatus::Ratified, instructions: [None, None, None, None, None,] },
     ExtensionIsaInfo { name: "Zmmul"      , desc: "Multiplication subset of the M extension"                  , version: "1.0"   , status: IsaStatus::Ratified, instructions: [None, None, None, None, None,] },
     ExtensionIsaInfo { name: "Zlso"       , desc: "Total store ordering"                                      , version: "1.0"   , status: IsaStatus::Ratified, instructions: [None, None, None, None, None,] },
-];
\ No newline at end of file
+];
+
+/// Name of the extensions in an ISA string, in canonical order.
+///
+/// Multi-letter extensions are ordered by the category of their second letter, following the order of the single-letter extensions, and then alphabetically
+const ISA_STRING_EXTENSIONS: [(ExtensionIsa, &str); 32] = [
+    (ExtensionIsa::M          , "m"),
+    (ExtensionIsa::A          , "a"),
+    (ExtensionIsa::F          , "f"),
+    (ExtensionIsa::D          , "d"),
+    (ExtensionIsa::Q          , "q"),
+    (ExtensionIsa::L          , "l"),
+    (ExtensionIsa::C          , "c"),
+    (ExtensionIsa::B          , "b"),
+    (ExtensionIsa::J          , "j"),
+    (ExtensionIsa::T          , "t"),
+    (ExtensionIsa::P          , "p"),
+    (ExtensionIsa::V          , "v"),
+    (ExtensionIsa::H          , "h"),
+    (ExtensionIsa::Zicsr      , "zicsr"),
+    (ExtensionIsa::Zifencei   , "zifencei"),
+    (ExtensionIsa::Zihintntl  , "zihintntl"),
+    (ExtensionIsa::Zihintpause, "zihintpause"),
+    (ExtensionIsa::Zmmul      , "zmmul"),
+    (ExtensionIsa::Zam        , "zam"),
+    (ExtensionIsa::Zfa        , "zfa"),
+    (ExtensionIsa::Zfh        , "zfh"),
+    (ExtensionIsa::Zfhmin     , "zfhmin"),
+    (ExtensionIsa::Zfinx      , "zfinx"),
+    (ExtensionIsa::Zdinx      , "zdinx"),
+    (ExtensionIsa::Zba        , "zba"),
+    (ExtensionIsa::Zbb        , "zbb"),
+    (ExtensionIsa::Zbc        , "zbc"),
+    (ExtensionIsa::Zbs        , "zbs"),
+    (ExtensionIsa::Zk         , "zk"),
+    (ExtensionIsa::Zlso       , "ztso"),
+    (ExtensionIsa::Zhinx      , "zhinx"),
+    (ExtensionIsa::Zhinxmin   , "zhinxmin"),
+];
+
+/// Extensions enabled along with another extension
+const IMPLIED_EXTENSIONS: [(ExtensionIsa, ExtensionIsa); 3] = [
+    (ExtensionIsa::F    , ExtensionIsa::Zicsr),
+    (ExtensionIsa::Zfinx, ExtensionIsa::Zicsr),
+    // The hypervisor extension extends S-mode
+    (ExtensionIsa::H    , ExtensionIsa::S),
+];
+
+/// Extensions that require another extension to be enabled as well, by their ISA string name
+const EXTENSION_DEPENDENCIES: [(&str, &str); 9] = [
+    ("d"       , "f"),
+    ("q"       , "d"),
+    ("v"       , "d"),
+    ("zfa"     , "f"),
+    ("zfh"     , "f"),
+    ("zfhmin"  , "f"),
+    ("zdinx"   , "zfinx"),
+    ("zhinx"   , "zfinx"),
+    ("zhinxmin", "zfinx"),
+];
+
+/// Extensions that can't be enabled together, by their ISA string name
+const EXTENSION_CONFLICTS: [(&str, &str); 1] = [
+    ("f", "zfinx"),
+];
+
+/// Error while parsing an ISA string
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum IsaError {
+    /// The string doesn't start with a supported base ISA, e.g. `rv64i`
+    InvalidBase,
+    /// An extension is unknown to the emulator
+    UnknownExtension(String),
+    /// An extension has a version that isn't implemented by the emulator, as `major.minor`
+    UnsupportedVersion(&'static str, String),
+    /// An extension is given more than once
+    Duplicate(&'static str),
+    /// The first extension requires the second extension
+    MissingDependency(&'static str, &'static str),
+    /// The extensions can't be enabled together
+    Conflict(&'static str, &'static str),
+}
+
+impl fmt::Display for IsaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IsaError::InvalidBase                       => write!(f, "ISA string doesn't start with a base ISA"),
+            IsaError::UnknownExtension(ext)             => write!(f, "unknown extension '{ext}'"),
+            IsaError::UnsupportedVersion(ext, version)  => write!(f, "version {version} of extension '{ext}' is not supported"),
+            IsaError::Duplicate(ext)                    => write!(f, "extension '{ext}' is given more than once"),
+            IsaError::MissingDependency(ext, requires)  => write!(f, "extension '{ext}' requires extension '{requires}'"),
+            IsaError::Conflict(first, second)           => write!(f, "extensions '{first}' and '{second}' can't be enabled together"),
+        }
+    }
+}
+
+/// Base ISA and extensions of a hart, which can be parsed from and formatted as an ISA string, e.g. `rv64imafdc_zicsr_zifencei`
+#[derive(Clone, Copy)]
+pub struct IsaConfig {
+    pub base: BaseIsa,
+    pub extensions: ExtensionIsa,
+}
+
+/// Split a version, e.g. `2p0` or `2`, off the start of `s`, returning the major and minor version
+fn split_version(s: &str) -> (Option<(u32, u32)>, &str) {
+    let digits = |s: &str| s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+
+    let major_len = digits(s);
+    if major_len == 0 {
+        return (None, s);
+    }
+    let major = s[..major_len].parse().unwrap_or(u32::MAX);
+    let rest = &s[major_len..];
+
+    // A `p` is only a separator when followed by the minor version, otherwise it is the P extension
+    let minor_len = rest.strip_prefix('p').map_or(0, digits);
+    if minor_len == 0 {
+        return (Some((major, 0)), rest);
+    }
+    let minor = rest[1..1 + minor_len].parse().unwrap_or(u32::MAX);
+    (Some((major, minor)), &rest[1 + minor_len..])
+}
+
+/// Check if a version given in an ISA string matches the implemented version, e.g. `2.1`
+fn check_version(name: &'static str, version: Option<(u32, u32)>, implemented: &str) -> Result<(), IsaError> {
+    let Some((major, minor)) = version else {
+        return Ok(());
+    };
+    let mut parts = implemented.split('.').map(|part| part.parse::<u32>().ok());
+    if parts.next().flatten() == Some(major) && parts.next().flatten().unwrap_or(0) == minor {
+        Ok(())
+    } else {
+        Err(IsaError::UnsupportedVersion(name, format!("{major}.{minor}")))
+    }
+}
+
+/// Get an extension by its name in an ISA string
+fn extension(name: &str) -> Option<ExtensionIsa> {
+    ISA_STRING_EXTENSIONS.iter().find(|(_, known)| *known == name).map(|&(ext, _)| ext)
+}
+
+impl FromStr for IsaConfig {
+    type Err = IsaError;
+
+    /// Parse an ISA string, ignoring case.
+    ///
+    /// Extensions can have a version (e.g. `m2p0`), which needs to match the implemented version.
+    /// `g` expands to `imafd_zicsr_zifencei` and `b` to `zba_zbb_zbs`, extensions that depend on `Zicsr` enable it
+    fn from_str(isa: &str) -> Result<Self, IsaError> {
+        let isa = isa.to_ascii_lowercase();
+        let rest = isa.strip_prefix("rv").ok_or(IsaError::InvalidBase)?;
+        let xlen_len = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let (xlen, rest) = rest.split_at(xlen_len);
+        let (base, general) = match (xlen, rest.chars().next()) {
+            ("32" , Some('i')) => (BaseIsa::RV32I , false),
+            ("32" , Some('e')) => (BaseIsa::RV32E , false),
+            ("32" , Some('g')) => (BaseIsa::RV32I , true ),
+            ("64" , Some('i')) => (BaseIsa::RV64I , false),
+            ("64" , Some('e')) => (BaseIsa::RV64E , false),
+            ("64" , Some('g')) => (BaseIsa::RV64I , true ),
+            ("128", Some('i')) => (BaseIsa::RV128I, false),
+            ("128", Some('g')) => (BaseIsa::RV128I, true ),
+            _ => return Err(IsaError::InvalidBase),
+        };
+        let (version, rest) = split_version(&rest[1..]);
+        let info = &BASE_ISA_INFO[base as usize];
+        // `g` is a shorthand, which has no version
+        let (name, implemented) = if general { ("g", "") } else { (info.name, info.version) };
+        check_version(name, version, implemented)?;
+
+        let mut seen = Vec::new();
+        let mut add = |name: &str, version| {
+            let Some(&(_, name)) = ISA_STRING_EXTENSIONS.iter().find(|(_, known)| *known == name) else {
+                return Err(IsaError::UnknownExtension(name.to_string()));
+            };
+            if seen.contains(&name) {
+                return Err(IsaError::Duplicate(name));
+            }
+            seen.push(name);
+
+            // Ztso is the ISA string name of the Zlso extension
+            let info_name = if name == "ztso" { "zlso" } else { name };
+            let info = EXT_ISA_INFO.iter().find(|info| info.name.eq_ignore_ascii_case(info_name)).unwrap();
+            check_version(name, version, info.version)
+        };
+
+        // Single-letter extensions directly follow the base, multi-letter extensions are separated by underscores
+        for (i, part) in rest.split('_').enumerate() {
+            if i != 0 && part.starts_with(['z', 's', 'x']) {
+                let name_len = part.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(part.len());
+                let (version, rest) = split_version(&part[name_len..]);
+                if !rest.is_empty() {
+                    return Err(IsaError::UnknownExtension(part.to_string()));
+                }
+                add(&part[..name_len], version)?;
+                continue;
+            }
+
+            let mut rest = part;
+            while let Some(letter) = rest.chars().next() {
+                let name = &rest[..letter.len_utf8()];
+                if letter == 'z' || letter == 's' || letter == 'x' {
+                    return Err(IsaError::UnknownExtension(rest.to_string()));
+                }
+                let (version, after) = split_version(&rest[name.len()..]);
+                add(name, version)?;
+                rest = after;
+            }
+        }
+
+        let extensions = seen.iter().filter_map(|&name| extension(name))
+            .fold(if general { ExtensionIsa::G } else { ExtensionIsa::None }, |extensions, ext| extensions | ext);
+        let extensions = IMPLIED_EXTENSIONS.iter()
+            .fold(extensions, |extensions, &(ext, implied)| if extensions.is_set(ext) { extensions | implied } else { extensions });
+        let is_set = |name| extension(name).is_some_and(|ext| extensions.is_set(ext));
+        for (ext, required) in EXTENSION_DEPENDENCIES {
+            if is_set(ext) && !is_set(required) {
+                return Err(IsaError::MissingDependency(ext, required));
+            }
+        }
+        for (first, second) in EXTENSION_CONFLICTS {
+            if is_set(first) && is_set(second) {
+                return Err(IsaError::Conflict(first, second));
+            }
+        }
+
+        Ok(IsaConfig { base, extensions })
+    }
+}
+
+impl fmt::Display for IsaConfig {
+    /// Format the canonical ISA string, with `g` and `b` expanded, S-mode isn't part of the ISA string
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let base = match self.base {
+            BaseIsa::RV32I  => "rv32i",
+            BaseIsa::RV32E  => "rv32e",
+            BaseIsa::RV64E  => "rv64e",
+            BaseIsa::RV128I => "rv128i",
+            BaseIsa::RVWMO | BaseIsa::RV64I => "rv64i",
+        };
+        write!(f, "{base}")?;
+        // `b` is only a shorthand when parsing, its extensions are formatted instead
+        for (ext, name) in ISA_STRING_EXTENSIONS.into_iter().filter(|&(_, name)| name != "b") {
+            if self.extensions.is_set(ext) {
+                let separator = if name.len() > 1 { "_" } else { "" };
+                write!(f, "{separator}{name}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests;
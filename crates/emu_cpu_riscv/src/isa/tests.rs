@@ -0,0 +1,88 @@
+use emu_cpu::{EmulationSettings, MisalignedPolicy};
+
+use crate::csr::misa_bit;
+use crate::{RiscvEmulator, MemoryModel};
+
+use super::*;
+
+fn parse(isa: &str) -> Result<String, IsaError> {
+    isa.parse::<IsaConfig>().map(|config| config.to_string())
+}
+
+fn new_emulator() -> RiscvEmulator {
+    RiscvEmulator::new(EmulationSettings { print_instructions: false, misaligned: MisalignedPolicy::Trap })
+}
+
+#[test]
+fn test_isa_string_set_isa() {
+    let mut emulator = new_emulator();
+    emulator.set_isa("rv64imafdcv_zicsr_zifencei_zba_zbb").unwrap();
+    assert_eq!(emulator.isa_string(), "rv64imafdcv_zicsr_zifencei_zba_zbb");
+    assert!(!emulator.harts[0].is_32_bit());
+    assert_eq!(emulator.harts[0].csr().misa & (misa_bit('C') | misa_bit('V') | misa_bit('B')), misa_bit('C') | misa_bit('V'));
+
+    emulator.set_isa("RV32GC").unwrap();
+    assert_eq!(emulator.isa_string(), "rv32imafdc_zicsr_zifencei");
+    assert!(emulator.harts[0].is_32_bit());
+}
+
+#[test]
+fn test_isa_string_canonical_order() {
+    // Shorthands are expanded and the canonical order is restored
+    assert_eq!(parse("RV32GC"), Ok("rv32imafdc_zicsr_zifencei".to_string()));
+    assert_eq!(parse("rv64i_zbs_zba_zbb_c"), Ok("rv64ic_zba_zbb_zbs".to_string()));
+}
+
+#[test]
+fn test_isa_string_b() {
+    // `b` is formatted as its extensions, which parse back to the same configuration
+    let isa = parse("rv64ib").unwrap();
+    assert_eq!(isa, "rv64i_zba_zbb_zbs");
+    assert!(isa.parse::<IsaConfig>().unwrap().extensions.is_set(ExtensionIsa::B));
+    assert_eq!(parse(&isa), Ok(isa));
+}
+
+#[test]
+fn test_isa_string_versions() {
+    // Versions need to match the implemented version
+    assert_eq!(parse("rv64i2p1m2a2p1_zicsr2p0_ztso1p0"), Ok("rv64ima_zicsr_ztso".to_string()));
+    assert_eq!(parse("rv32ip"), Ok("rv32ip".to_string()));
+    assert_eq!(parse("rv64im3p0"), Err(IsaError::UnsupportedVersion("m", "3.0".to_string())));
+    assert_eq!(parse("rv64g2p0"), Err(IsaError::UnsupportedVersion("g", "2.0".to_string())));
+}
+
+#[test]
+fn test_isa_string_implied_extensions() {
+    assert_eq!(parse("rv64if"), Ok("rv64if_zicsr".to_string()));
+
+    let mut emulator = new_emulator();
+    emulator.set_isa("rv64ih").unwrap();
+    assert!(emulator.harts[0].csr().has_extension('S'));
+}
+
+#[test]
+fn test_isa_string_errors() {
+    assert_eq!(parse("rv64id"), Err(IsaError::MissingDependency("d", "f")));
+    assert_eq!(parse("rv64imm"), Err(IsaError::Duplicate("m")));
+    assert_eq!(parse("rv64i_zfoo"), Err(IsaError::UnknownExtension("zfoo".to_string())));
+    assert_eq!(parse("rv64izicsr"), Err(IsaError::UnknownExtension("zicsr".to_string())));
+    assert_eq!(parse("rv64if_zfinx"), Err(IsaError::Conflict("f", "zfinx")));
+    assert_eq!(parse("rv48i"), Err(IsaError::InvalidBase));
+
+    // Invalid strings leave the configuration unchanged
+    let mut emulator = new_emulator();
+    emulator.set_isa("rv64ih").unwrap();
+    assert_eq!(emulator.set_isa("rv64id"), Err(IsaError::MissingDependency("d", "f")));
+    assert_eq!(emulator.isa_string(), "rv64ih");
+}
+
+#[test]
+fn test_isa_string_ztso() {
+    // Ztso selects the RVTSO memory model
+    let mut emulator = new_emulator();
+    emulator.set_isa("rv64ia_ztso").unwrap();
+    assert_eq!(emulator.memory_model, MemoryModel::Tso);
+    emulator.set_isa("rv64ia").unwrap();
+    assert_eq!(emulator.memory_model, MemoryModel::Sequential);
+    assert_eq!(emulator.isa_string(), "rv64ia");
+}
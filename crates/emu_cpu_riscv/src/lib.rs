@@ -1,61 +1,502 @@
-use std::fmt::Write;
-
-use emu_cpu::{ CpuEmulator, BaseIsaInfo, ExtensionIsaInfo, EmulationSettings };
-use isa::{BASE_ISA_INFO, BaseIsa, EXT_ISA_INFO, ExtensionIsa};
+use emu_cpu::{ CpuEmulator, BaseIsaInfo, ExtensionIsaInfo, EmulationSettings, HaltReason };
+use isa::{BASE_ISA_INFO, EXT_ISA_INFO};
 use registers::RegisterFile;
 
-use crate::instructions::InstructionEncoding32;
-
+use crate::csr::{misa_bit, set_bits};
+use crate::memory::MemoryBus;
+use crate::memory_model::{BufferedBus, StoreBuffer};
+use crate::mmu::{AccessType, AccessMode};
+use crate::trap::Trap;
 
+pub use isa::{BaseIsa, ExtensionIsa, IsaConfig, IsaError};
+pub use instructions::{Disassembler, Instruction, InstructionEncoding32};
+pub use trap::{Interrupt, Exception};
+pub use registers::PrivilegeMode;
+pub use csr::Endianness;
+pub use bus::Bus;
+pub use elf::{Elf, ElfError, Segment, ELF_MAX_RAM_SIZE};
+pub use fdt::{FdtNode, FdtContext};
+pub use sbi::Sbi;
+pub use memory_model::{MemoryModel, LitmusTest, LitmusError, LITMUS_RAM_SIZE, LITMUS_CODE_BASE, LITMUS_CODE_SIZE};
+pub use virt::{
+    VirtConfig, BootError, VIRT_TEST_BASE, VIRT_RTC_BASE, VIRT_CLINT_BASE, VIRT_PLIC_BASE, VIRT_UART_BASE, VIRT_VIRTIO_BASE, VIRT_RAM_BASE, VIRT_MAX_VIRTIO,
+};
+pub use devices::{
+    Device, TestFinisher, FINISHER_SIZE, Htif, Clint, Timebase, CLINT_SIZE, Plic, PlicContext, PLIC_SIZE, Uart, UART_SIZE,
+    SerialBackend, StdioBackend, FileBackend, BufferBackend,
+    VirtioMmio, VirtioDevice, Virtqueue, Descriptor, DescriptorChain, VIRTIO_MMIO_SIZE, VirtioBlock, DiskMode, VirtioConsole, VirtioRng, VirtioNet,
+    NetBackend, LoopbackSwitch, SwitchPort, PcapBackend,
+    Framebuffer, PixelFormat, Rtc, RtcClock, RTC_SIZE,
+};
 
 mod isa;
 mod registers;
 mod instructions;
+mod csr;
+mod trap;
+mod mmu;
+mod memory;
+mod memory_model;
+mod bus;
+mod devices;
+mod elf;
+mod fdt;
+mod virt;
+mod sbi;
+mod parallel;
+
+#[cfg(test)]
+mod tests;
 
 
 
-// TODO:
-// Setting to allow misaligned load/stores, i.e. 32-bit load/store not aligned to 4-bytes, and 16-bit load/store not aligned to 2-bytes
 pub struct RiscvEmulator {
     pub settings: EmulationSettings,
     pub base_isa: BaseIsa,
     pub extensions: ExtensionIsa,
 
-    pub register_file: RegisterFile,
+    /// Harts sharing the bus, the index of a hart is its `mhartid`
+    pub harts: Vec<RegisterFile>,
+    /// Number of consecutive ticks a hart is scheduled for, before the next hart is scheduled
+    pub quantum: u64,
+    /// Execute the harts in parallel on host threads instead of round-robin, see `execute_parallel` for the determinism that is lost.
+    /// The harts are still executed round-robin when the built-in SBI or a memory model other than `MemoryModel::Sequential` is used
+    pub parallel: bool,
+    /// Hart executing the current tick
+    current_hart: usize,
+    /// Number of ticks the current hart has been scheduled for
+    quantum_ticks: u64,
+
+    pub memory: Bus,
+    /// Host-target interface, polled after every tick
+    pub htif: Option<Htif>,
+    /// Interrupt lines of every hart raised using `set_interrupt`
+    interrupt_lines: Vec<u64>,
+    /// Memory model of the accesses to RAM, derived from the ISA by `update_memory_model`
+    pub memory_model: MemoryModel,
+    /// Set when the RVWMO base ISA was selected using `set_base_isa`, the default base ISA doesn't select a memory model
+    rvwmo_selected: bool,
+    /// Number of ticks after which a store buffered by the memory model becomes visible to the other harts
+    pub store_buffer_latency: u64,
+    /// Stores of every hart buffered by the memory model
+    store_buffers: Vec<StoreBuffer>,
+    /// Harts stopped using the SBI HSM extension, which don't execute until they are started again
+    stopped: Vec<bool>,
+    /// Halt requested by a device, returned by the next call to `execute`
+    halt: Option<HaltReason>,
+    /// Built-in SBI implementation handling the `ecall`s from S-mode, if used instead of M-mode firmware
+    sbi: Option<Sbi>,
 
-    pub machine_code: Vec<u8>,
-    pub memory: Vec<u8>,
+    /// Virtual clock, advanced by 1 for every tick, including the ticks the hart is waiting for an interrupt
+    pub cycles: u64,
 }
 
 impl RiscvEmulator {
     pub fn new(settings: EmulationSettings) -> Self {
+        Self::new_smp(settings, 1)
+    }
+
+    /// Create an emulator with `num_harts` harts sharing the bus.
+    ///
+    /// The harts are scheduled round-robin, each executing `quantum` ticks in turn, which keeps the execution deterministic
+    pub fn new_smp(settings: EmulationSettings, num_harts: usize) -> Self {
+        assert!(num_harts != 0, "at least 1 hart is required");
+        let harts = (0..num_harts).map(|hart| {
+            let mut register_file = RegisterFile::new(false);
+            register_file.csr_mut().mhartid = hart as u64;
+            register_file.set_misaligned_policy(settings.misaligned);
+            register_file
+        }).collect();
+
         RiscvEmulator { 
             settings,
             base_isa: BaseIsa::RVWMO,
-            extensions: ExtensionIsa::None,
-            register_file: RegisterFile::new(false),
-            machine_code: Vec::new(),
-            memory: Vec::new(),
+            extensions: ExtensionIsa::Zicsr | ExtensionIsa::Zifencei,
+            harts,
+            quantum: 1,
+            parallel: false,
+            current_hart: 0,
+            quantum_ticks: 0,
+            memory: Bus::new(),
+            htif: None,
+            interrupt_lines: vec![0; num_harts],
+            memory_model: MemoryModel::Sequential,
+            rvwmo_selected: false,
+            store_buffer_latency: 16,
+            store_buffers: vec![StoreBuffer::default(); num_harts],
+            stopped: vec![false; num_harts],
+            halt: None,
+            sbi: None,
+            cycles: 0,
+        }
+    }
+
+    /// Hart executing the current tick, or the last hart that was scheduled between ticks
+    pub fn current_hart(&self) -> usize {
+        self.current_hart
+    }
+
+    /// Raise or lower an interrupt line of a hart, the interrupt stays pending in `mip` as long as the line is raised
+    pub fn set_interrupt(&mut self, hart: usize, interrupt: Interrupt, raised: bool) {
+        self.interrupt_lines[hart] = set_bits(self.interrupt_lines[hart], interrupt.mask(), raised);
+        self.update_interrupt_lines();
+    }
+
+    /// Update the interrupt lines of the harts, combining the lines raised using `set_interrupt` and by the devices on the bus, and the time read by the harts
+    fn update_interrupt_lines(&mut self) {
+        let time = self.memory.time();
+        for hart in 0..self.harts.len() {
+            let lines = self.forward_sbi_interrupts(hart, self.interrupt_lines[hart] | self.memory.interrupt_lines(hart));
+            let csr = self.harts[hart].csr_mut();
+            csr.interrupt_lines = lines;
+            csr.mtime = time;
+        }
+    }
+
+    /// Check if a hart is stopped, or waiting for an interrupt and no interrupt is pending to wake it up
+    fn is_hart_idle(&self, hart: usize) -> bool {
+        self.stopped[hart] || is_waiting_for_interrupt(&self.harts[hart])
+    }
+
+    /// Check if all harts are idle
+    fn is_idle(&self) -> bool {
+        (0..self.harts.len()).all(|hart| self.is_hart_idle(hart))
+    }
+
+    /// Select the hart executing the next tick, switching to the next hart that isn't idle when the quantum of the current hart expires or it becomes idle
+    fn schedule(&mut self) {
+        if self.quantum_ticks >= self.quantum || self.is_hart_idle(self.current_hart) {
+            let num_harts = self.harts.len();
+            if let Some(next) = (1..=num_harts).map(|offset| (self.current_hart + offset) % num_harts).find(|&hart| !self.is_hart_idle(hart)) {
+                self.current_hart = next;
+            }
+            self.quantum_ticks = 0;
+        }
+        self.quantum_ticks += 1;
+    }
+
+    /// Number of misaligned accesses emulated by all harts, which are only counted when using `MisalignedPolicy::Emulate { count: true }`
+    pub fn misaligned_accesses(&self) -> u64 {
+        self.harts.iter().map(RegisterFile::misaligned_accesses).sum()
+    }
+
+    /// Set the data endianness of a privilege mode on all harts, instruction fetches are always little-endian.
+    ///
+    /// The endianness of U-mode and S-mode also applies to VU-mode and VS-mode
+    pub fn set_data_endianness(&mut self, mode: PrivilegeMode, endianness: Endianness) {
+        for register_file in &mut self.harts {
+            register_file.csr_mut().set_endianness(mode, endianness);
+        }
+    }
+
+    /// Make the stores buffered by the memory model visible to all harts
+    pub fn drain_store_buffers(&mut self) {
+        for buffer in &mut self.store_buffers {
+            buffer.drain_all(&mut self.memory);
+        }
+    }
+
+    /// Derive the memory model from the ISA: Ztso selects `MemoryModel::Tso`, otherwise selecting the RVWMO base ISA selects `MemoryModel::Rvwmo`, and all other ISAs use `MemoryModel::Sequential`
+    fn update_memory_model(&mut self) {
+        self.memory_model = if self.extensions.is_set(ExtensionIsa::Zlso) {
+            MemoryModel::Tso
+        } else if self.rvwmo_selected {
+            MemoryModel::Rvwmo
+        } else {
+            MemoryModel::Sequential
+        };
+    }
+
+    /// Set the enabled extensions, this also updates `misa` and the memory model, see `update_memory_model`.
+    ///
+    /// Instructions of disabled extensions raise an illegal instruction exception
+    pub fn set_extensions(&mut self, extensions: ExtensionIsa) {
+        self.extensions = extensions;
+        self.update_memory_model();
+
+        const MISA_EXTENSIONS: [(ExtensionIsa, char); 10] = [
+            (ExtensionIsa::M, 'M'),
+            (ExtensionIsa::A, 'A'),
+            (ExtensionIsa::F, 'F'),
+            (ExtensionIsa::D, 'D'),
+            (ExtensionIsa::Q, 'Q'),
+            (ExtensionIsa::C, 'C'),
+            (ExtensionIsa::B, 'B'),
+            (ExtensionIsa::V, 'V'),
+            (ExtensionIsa::H, 'H'),
+            (ExtensionIsa::S, 'S'),
+        ];
+
+        let base = if matches!(self.base_isa, BaseIsa::RV32E | BaseIsa::RV64E) { 'E' } else { 'I' };
+        let mut misa = misa_bit(base) | misa_bit('U');
+        for (ext, letter) in MISA_EXTENSIONS {
+            if extensions.is_set(ext) {
+                misa |= misa_bit(letter);
+            }
+        }
+
+        for register_file in &mut self.harts {
+            register_file.set_extensions(extensions);
+            let csr = register_file.csr_mut();
+            csr.misa = (csr.misa & !0x3FF_FFFF) | misa;
+        }
+    }
+
+    /// Base ISA and extensions of the harts
+    pub fn isa(&self) -> IsaConfig {
+        IsaConfig { base: self.base_isa, extensions: self.extensions }
+    }
+
+    /// Configure the base ISA and extensions from an ISA string, e.g. `rv64imafdc_zicsr_zifencei` or `rv64gc`.
+    ///
+    /// S-mode isn't part of the ISA string, so it stays enabled if it was, and is enabled by the H extension.
+    /// Neither is the RVWMO base ISA, which stays selected for `rv64i`
+    pub fn set_isa(&mut self, isa: &str) -> Result<(), IsaError> {
+        let IsaConfig { base, extensions } = isa.parse()?;
+        let extensions = if self.extensions.is_set(ExtensionIsa::S) { extensions | ExtensionIsa::S } else { extensions };
+        if !(base == BaseIsa::RV64I && self.base_isa == BaseIsa::RVWMO) {
+            self.set_base_isa(BASE_ISA_INFO[base as usize].name);
+        }
+        self.set_extensions(extensions);
+        Ok(())
+    }
+
+    /// Canonical ISA string of the harts, as used in the devicetree, e.g. `rv64imac_zicsr_zifencei`
+    pub fn isa_string(&self) -> String {
+        self.isa().to_string()
+    }
+
+    /// Generate the devicetree describing the harts, RAM and the devices on the bus, `timebase_frequency` is the frequency of `mtime`
+    pub fn devicetree(&self, timebase_frequency: u32, bootargs: Option<&str>) -> FdtNode {
+        let fdt = FdtContext { num_harts: self.harts.len() };
+        let isa = self.isa_string();
+
+        let mut cpus = FdtNode::new("cpus");
+        cpus.property_u32("#address-cells", 1)
+            .property_u32("#size-cells", 0)
+            .property_u32("timebase-frequency", timebase_frequency);
+        for (hart, register_file) in self.harts.iter().enumerate() {
+            let mut intc = FdtNode::new("interrupt-controller");
+            intc.property_u32("#interrupt-cells", 1)
+                .property_empty("interrupt-controller")
+                .property_string("compatible", "riscv,cpu-intc")
+                .property_u32("phandle", fdt.cpu_intc_phandle(hart));
+
+            let mut cpu = FdtNode::new(format!("cpu@{hart:x}"));
+            cpu.property_string("device_type", "cpu")
+                .property_u32("reg", hart as u32)
+                .property_string("status", "okay")
+                .property_string("compatible", "riscv")
+                .property_string("riscv,isa", &isa);
+            if self.extensions.is_set(ExtensionIsa::S) {
+                cpu.property_string("mmu-type", if register_file.is_32_bit() { "riscv,sv32" } else { "riscv,sv48" });
+            }
+            cpu.child(intc);
+            cpus.child(cpu);
+        }
+
+        let mut memory = FdtNode::new(format!("memory@{:x}", self.memory.ram_base));
+        memory.property_string("device_type", "memory")
+            .property_u64s("reg", &[self.memory.ram_base, self.memory.ram.len() as u64]);
+
+        let mut soc = FdtNode::new("soc");
+        soc.property_u32("#address-cells", 2)
+            .property_u32("#size-cells", 2)
+            .property_string("compatible", "simple-bus")
+            .property_empty("ranges");
+        soc.children = self.memory.devicetree_nodes(&fdt);
+
+        let mut chosen = FdtNode::new("chosen");
+        if let Some(bootargs) = bootargs {
+            chosen.property_string("bootargs", bootargs);
         }
+        if let Some(serial) = soc.children.iter().find(|node| node.name.starts_with("serial@")) {
+            chosen.property_string("stdout-path", &format!("/soc/{}", serial.name));
+        }
+
+        let mut root = FdtNode::new("");
+        root.property_u32("#address-cells", 2)
+            .property_u32("#size-cells", 2)
+            .property_string("compatible", "riscv-virtio")
+            .property_string("model", "riscv-virtio,emu")
+            .child(chosen)
+            .child(cpus)
+            .child(memory)
+            .child(soc);
+        root
+    }
+
+    /// Place the flattened devicetree at the end of RAM and pass its address in `a1` to every hart, with the hart id in `a0`, as expected by firmware and kernels at reset.
+    ///
+    /// Returns the address of the devicetree
+    pub fn load_devicetree(&mut self, devicetree: &FdtNode) -> u64 {
+        let dtb = devicetree.to_dtb(self.current_hart as u32);
+        assert!(dtb.len() <= self.memory.ram.len(), "RAM is too small to hold the devicetree");
+
+        let offset = (self.memory.ram.len() - dtb.len()) & !0xFFF;
+        self.memory.ram[offset..offset + dtb.len()].copy_from_slice(&dtb);
+
+        let addr = self.memory.ram_base + offset as u64;
+        for (hart, register_file) in self.harts.iter_mut().enumerate() {
+            register_file.write_x_register(10, hart as u64);
+            register_file.write_x_register(11, addr);
+        }
+        addr
+    }
+
+    /// Load the segments of an ELF file into RAM and jump to its entry point on every hart, this also selects RV32I or RV64I depending on the ELF class.
+    ///
+    /// When RAM is empty, it is placed at the lowest segment and sized to fit all segments, up to `ELF_MAX_RAM_SIZE`, otherwise all segments need to fit in RAM.
+    /// Nothing is loaded if any segment doesn't fit
+    pub fn load_elf<'a>(&mut self, data: &'a [u8]) -> Result<Elf<'a>, ElfError> {
+        let elf = Elf::parse(data)?;
+
+        let (ram_base, ram_size) = if self.memory.ram.is_empty() {
+            let base = elf.segments.iter().map(|segment| segment.addr).min().map_or(self.memory.ram_base, |base| base & !0xFFF);
+            (base, ELF_MAX_RAM_SIZE)
+        } else {
+            (self.memory.ram_base, self.memory.ram.len() as u64)
+        };
+
+        let mut ranges = Vec::with_capacity(elf.segments.len());
+        for segment in &elf.segments {
+            let size = segment.mem_size.max(segment.data.len() as u64);
+            let range = segment.addr.checked_sub(ram_base)
+                .and_then(|offset| Some(offset..offset.checked_add(size)?))
+                .filter(|range| range.end <= ram_size)
+                .ok_or(ElfError::SegmentOutsideRam(segment.addr))?;
+            ranges.push(range.start as usize..range.end as usize);
+        }
+
+        self.memory.ram_base = ram_base;
+        let end = ranges.iter().map(|range| range.end).max().unwrap_or(0);
+        if self.memory.ram.len() < end {
+            self.memory.ram.resize(end, 0);
+        }
+        for (segment, range) in elf.segments.iter().zip(ranges) {
+            self.memory.ram[range.start..range.start + segment.data.len()].copy_from_slice(segment.data);
+            self.memory.ram[range.start + segment.data.len()..range.end].fill(0);
+        }
+
+        self.set_base_isa(if elf.is_64_bit { "RV64I" } else { "RV32I" });
+        self.set_instruction_pointer(elf.entry as usize);
+        Ok(elf)
+    }
+
+    /// Disassemble the memory in `start..end` in the format of `objdump -d`, e.g. to inspect code loaded using `load_elf`.
+    ///
+    /// Returns `None` if any part of the range can't be read from the bus
+    pub fn disassemble(&mut self, disassembler: &Disassembler, start: u64, end: u64) -> Option<String> {
+        let mut data = vec![0; end.checked_sub(start)? as usize];
+        self.memory.read(start, &mut data).then(|| disassembler.range(start, &data))
+    }
+
+    /// Execute a single instruction on the current hart, taking any pending interrupt first. Returns `true` if the instruction was retired
+    fn step(&mut self) -> bool {
+        if self.is_hart_idle(self.current_hart) {
+            return false;
+        }
+        let register_file = &mut self.harts[self.current_hart];
+        let result = match self.memory_model {
+            MemoryModel::Sequential => execute_instruction(register_file, &mut self.memory, self.settings.print_instructions),
+            model => {
+                let ram = self.memory.ram_range();
+                let buffer = &mut self.store_buffers[self.current_hart];
+                execute_instruction(register_file, &mut BufferedBus { memory: &mut self.memory, buffer, model, ram, cycle: self.cycles }, self.settings.print_instructions)
+            },
+        };
+        match result {
+            Ok(()) => true,
+            // SBI calls handled by the emulator complete like any other instruction
+            Err(trap) if trap.cause == Exception::SupervisorEnvironmentCall && self.handle_sbi_call() => {
+                let csr = self.harts[self.current_hart].csr_mut();
+                csr.minstret = csr.minstret.wrapping_add(1);
+                true
+            },
+            Err(trap) => {
+                trap::take_trap(&mut self.harts[self.current_hart], trap);
+                false
+            },
+        }
+    }
+}
+
+/// Check if a hart is waiting for an interrupt and no interrupt is pending to wake it up
+fn is_waiting_for_interrupt(register_file: &RegisterFile) -> bool {
+    // WFI also resumes for interrupts that are pending and enabled in `mie`, but globally disabled
+    let csr = register_file.csr();
+    register_file.is_waiting() && csr.mip() & csr.mie == 0
+}
+
+/// Fetch the instruction at the pc of a hart
+fn fetch(register_file: &mut RegisterFile, memory: &mut dyn MemoryBus) -> Result<InstructionEncoding32, Trap> {
+    let pc = register_file.read_pc();
+    let mode = AccessMode::effective(register_file, AccessType::Fetch);
+    // Instructions are aligned to 2 bytes when compressed instructions are supported
+    let align = if register_file.csr().has_extension('C') { 2 } else { 4 };
+    memory::check_alignment(register_file, pc, align, AccessType::Fetch, mode)?;
+
+    let raw = memory::read_virtual(register_file, memory, pc, 4, AccessType::Fetch, mode)?;
+    Ok(InstructionEncoding32(raw as u32))
+}
+
+/// Execute a single instruction on a hart, taking any pending interrupt first.
+///
+/// Returns the trap raised by the instruction, which still needs to be taken, if the instruction wasn't retired
+fn execute_instruction(register_file: &mut RegisterFile, memory: &mut dyn MemoryBus, print_instructions: bool) -> Result<(), Trap> {
+    register_file.set_waiting(false);
+    let csr = register_file.csr_mut();
+    csr.mcycle = csr.mcycle.wrapping_add(1);
+
+    // Interrupts are only taken between instructions
+    if let Some(interrupt) = trap::pending_interrupt(register_file) {
+        trap::take_interrupt(register_file, interrupt);
     }
+
+    let encoded = fetch(register_file, memory)?;
+    let instr = encoded.decode_for(register_file).ok_or(Trap::new(Exception::IllegalInstruction, encoded.0 as u64))?;
+
+    if print_instructions {
+        let pc = register_file.read_pc();
+        println!("{pc:x}:\t{:08x}\t{}", encoded.0, Disassembler::new(register_file.is_32_bit()).format(pc, &instr));
+    }
+
+    instr.exec(register_file, memory);
+    if let Some(trap) = register_file.take_trap() {
+        return Err(trap);
+    }
+    let csr = register_file.csr_mut();
+    csr.minstret = csr.minstret.wrapping_add(1);
+    Ok(())
 }
 
 impl CpuEmulator for RiscvEmulator {
 
     fn set_settings(&mut self, settings: EmulationSettings) {
+        for register_file in &mut self.harts {
+            register_file.set_misaligned_policy(settings.misaligned);
+        }
         self.settings = settings;
     }
 
     fn set_base_isa(&mut self, isa: &str) -> bool {
-        match isa {
-            "RVWMO"  => { self.base_isa = BaseIsa::RVWMO;  self.register_file.set_32_bit(false); true },
-            "RV32I"  => { self.base_isa = BaseIsa::RV32I;  self.register_file.set_32_bit(true ); true },
-            "RV32E"  => { self.base_isa = BaseIsa::RV32E;  self.register_file.set_32_bit(true ); true },
-            "RV64I"  => { self.base_isa = BaseIsa::RV64I;  self.register_file.set_32_bit(false); true },
-            "RV64E"  => { self.base_isa = BaseIsa::RV64E;  self.register_file.set_32_bit(false); true },
-            "RV128I" => { self.base_isa = BaseIsa::RV128I; self.register_file.set_32_bit(false); true },
-            _ => false
+        let (base_isa, is_32_bit) = match isa {
+            "RVWMO"  => (BaseIsa::RVWMO , false),
+            "RV32I"  => (BaseIsa::RV32I , true ),
+            "RV32E"  => (BaseIsa::RV32E , true ),
+            "RV64I"  => (BaseIsa::RV64I , false),
+            "RV64E"  => (BaseIsa::RV64E , false),
+            "RV128I" => (BaseIsa::RV128I, false),
+            _ => return false
+        };
+        self.base_isa = base_isa;
+        self.rvwmo_selected = base_isa == BaseIsa::RVWMO;
+        for register_file in &mut self.harts {
+            register_file.set_32_bit(is_32_bit);
         }
+        // `misa` reports the E base ISA as an extension, this also updates the memory model
+        self.set_extensions(self.extensions);
+        true
     }
 
 
@@ -68,45 +509,83 @@ impl CpuEmulator for RiscvEmulator {
     }
 
     fn format_register_file(&self, f: &mut dyn std::fmt::Write) -> std::fmt::Result {
-        write!(f, "{}", self.register_file)
+        if let [register_file] = self.harts.as_slice() {
+            return write!(f, "{register_file}");
+        }
+        for (hart, register_file) in self.harts.iter().enumerate() {
+            writeln!(f, "hart {hart}:")?;
+            write!(f, "{register_file}")?;
+        }
+        Ok(())
     }
 
     fn set_code(&mut self, code: Vec<u8>) {
-        self.machine_code = code;
+        // The code is placed at the start of RAM
+        let ram = &mut self.memory.ram;
+        if ram.len() < code.len() {
+            ram.resize(code.len(), 0);
+        }
+        ram[..code.len()].copy_from_slice(&code);
     }
 
     fn set_instruction_pointer(&mut self, pointer: usize) {
         assert!(pointer & 1 == 0);
-        self.register_file.write_pc(pointer as u64);
+        for register_file in &mut self.harts {
+            register_file.write_pc(pointer as u64);
+        }
     }
 
     fn tick(&mut self) {
-        let pc = self.register_file.read_pc() as usize;
-        assert!(pc + 3 < self.machine_code.len());
-
-        let raw = unsafe { *(self.machine_code.as_ptr().add(pc) as *const u32) };
-        let encoded = InstructionEncoding32(raw);
-        let instr = encoded.decode().unwrap();
+        self.cycles += 1;
 
-        if self.settings.print_instructions {
-            let mut buf = String::new();
-            instr.log(&mut buf);
-            println!("{}", &buf);
+        self.schedule();
+        let retired = self.step();
+        for buffer in &mut self.store_buffers {
+            buffer.drain_expired(self.cycles, self.store_buffer_latency, &mut self.memory);
         }
+        self.memory.tick(retired);
+        self.update_interrupt_lines();
 
-        instr.exec(&mut self.register_file, &mut self.memory);
+        if let Some(halt) = self.memory.take_halt_request() {
+            self.halt = Some(halt);
+        }
+        if let Some(halt) = self.htif.as_mut().and_then(|htif| htif.poll(&mut self.memory)) {
+            self.halt = Some(halt);
+        }
     }
 
-    fn execute(&mut self, num_instructions: Option<u32>) {
-        match num_instructions {
-            Some(count) => {
-                for _ in 0..count {
-                    self.tick();
-                }
-            },
-            None => loop {
-                self.tick();
-            },
+    fn execute(&mut self, num_instructions: Option<u32>) -> HaltReason {
+        if self.parallel && self.harts.len() > 1 && self.sbi.is_none() && self.memory_model == MemoryModel::Sequential {
+            return self.execute_parallel(num_instructions);
+        }
+
+        let mut remaining = num_instructions.map(|count| count as u64);
+        while remaining != Some(0) {
+            if let Some(halt) = self.halt.take() {
+                return halt;
+            }
+
+            // While all harts are idle, only the devices can wake them up, so the idle time up to the next device event can be skipped
+            if self.is_idle() {
+                // Buffered stores become visible while the harts are idle
+                self.drain_store_buffers();
+                let ticks = match (self.memory.next_event(), remaining) {
+                    (Some(event), Some(count)) => event.min(count),
+                    (Some(event), None       ) => event,
+                    (None       , Some(count)) => count,
+                    (None       , None       ) => return HaltReason::Idle,
+                };
+
+                self.cycles += ticks;
+                self.memory.skip(ticks);
+                self.update_interrupt_lines();
+                remaining = remaining.map(|count| count - ticks);
+                continue;
+            }
+
+            self.tick();
+            remaining = remaining.map(|count| count - 1);
         }
+        self.halt.take().unwrap_or(HaltReason::InstructionLimit)
     }
 }
\ No newline at end of file
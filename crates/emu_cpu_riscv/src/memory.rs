@@ -0,0 +1,404 @@
+use std::sync::atomic::{AtomicU8, AtomicU16, AtomicU32, AtomicU64, Ordering};
+
+use emu_cpu::MisalignedPolicy;
+
+use crate::csr::{MSTATUS_UBE, MSTATUS_SBE, MSTATUS_MBE, HSTATUS_VSBE};
+use crate::instructions::AtomicOp;
+use crate::mmu::{self, AccessType, AccessMode};
+use crate::registers::{RegisterFile, PrivilegeMode};
+use crate::trap::{Trap, Exception};
+
+
+/// Physical memory, as seen by a hart
+pub trait MemoryBus {
+    /// Read `buf.len()` bytes at the physical address `addr`, returns `false` if the access failed
+    fn read(&mut self, addr: u64, buf: &mut [u8]) -> bool;
+
+    /// Write `buf.len()` bytes at the physical address `addr`, returns `false` if the access failed
+    fn write(&mut self, addr: u64, buf: &[u8]) -> bool;
+
+    /// Register a reservation of `hart` on the reservation set containing the physical address `addr`, as done by LR
+    fn reserve(&mut self, _hart: usize, _addr: u64) {}
+
+    /// Release the reservation of `hart`, returns `true` if it was still valid and covered `addr`, as checked by SC.
+    ///
+    /// Memory that doesn't track reservations never holds a valid reservation
+    fn release_reservation(&mut self, _hart: usize, _addr: u64) -> bool {
+        false
+    }
+
+    /// Load `size` bytes at the physical address `addr` and register a reservation of `hart` on it, as done by LR.
+    ///
+    /// Returns `None` if the access failed
+    fn load_reserved(&mut self, hart: usize, addr: u64, size: usize) -> Option<u64> {
+        let mut buf = [0u8; 8];
+        if !self.read(addr, &mut buf[..size]) {
+            return None;
+        }
+        self.reserve(hart, addr);
+        Some(u64::from_le_bytes(buf))
+    }
+
+    /// Store `size` bytes of `value` at the physical address `addr` if `hart` still holds its reservation on it, as done by SC.
+    ///
+    /// Returns if the store succeeded, or `None` if the access failed
+    fn store_conditional(&mut self, hart: usize, addr: u64, size: usize, value: u64) -> Option<bool> {
+        if !self.release_reservation(hart, addr) {
+            return Some(false);
+        }
+        self.write(addr, &value.to_le_bytes()[..size]).then_some(true)
+    }
+
+    /// Atomically perform the read-modify-write of an AMO of `size` bytes at the physical address `addr`, with the value stored big-endian if `big_endian` is set.
+    ///
+    /// Returns the old value, or `None` if the access failed
+    fn amo(&mut self, addr: u64, size: usize, op: AtomicOp, src: u64, big_endian: bool) -> Option<u64> {
+        let mut buf = [0u8; 8];
+        if !self.read(addr, &mut buf[..size]) {
+            return None;
+        }
+        let old = byte_order(u64::from_le_bytes(buf), size, big_endian);
+        let new = byte_order(op.apply(old, src, size), size, big_endian);
+        self.write(addr, &new.to_le_bytes()[..size]).then_some(old)
+    }
+
+    /// Order the accesses in the predecessor set before the accesses in the successor set, as done by FENCE.
+    ///
+    /// The sets are combinations of `FENCE_I`, `FENCE_O`, `FENCE_R` and `FENCE_W`
+    fn fence(&mut self, _pred: u8, _succ: u8) {}
+
+    /// Order an atomic access before all later accesses, as done by its `aq` bit, called after the access
+    fn acquire(&mut self) {}
+
+    /// Order all earlier accesses before an atomic access, as done by its `rl` bit, called before the access
+    fn release(&mut self) {}
+}
+
+impl MemoryBus for [u8] {
+    fn read(&mut self, addr: u64, buf: &mut [u8]) -> bool {
+        let start = addr as usize;
+        match start.checked_add(buf.len()).and_then(|end| self.get(start..end)) {
+            Some(src) => { buf.copy_from_slice(src); true },
+            None => false,
+        }
+    }
+
+    fn write(&mut self, addr: u64, buf: &[u8]) -> bool {
+        let start = addr as usize;
+        match start.checked_add(buf.len()).and_then(|end| self.get_mut(start..end)) {
+            Some(dst) => { dst.copy_from_slice(buf); true },
+            None => false,
+        }
+    }
+}
+
+impl<const N: usize> MemoryBus for [u8; N] {
+    fn read(&mut self, addr: u64, buf: &mut [u8]) -> bool {
+        self.as_mut_slice().read(addr, buf)
+    }
+
+    fn write(&mut self, addr: u64, buf: &[u8]) -> bool {
+        self.as_mut_slice().write(addr, buf)
+    }
+}
+
+impl MemoryBus for Vec<u8> {
+    fn read(&mut self, addr: u64, buf: &mut [u8]) -> bool {
+        self.as_mut_slice().read(addr, buf)
+    }
+
+    fn write(&mut self, addr: u64, buf: &[u8]) -> bool {
+        self.as_mut_slice().write(addr, buf)
+    }
+}
+
+
+
+/// RAM shared by harts executing on separate host threads, accessed using host atomics.
+///
+/// Naturally aligned accesses of up to 8 bytes are single-copy atomic, other accesses are split into bytes.
+/// AMOs are performed using the host atomic of the same size
+pub struct SharedRam {
+    /// Backing storage, using 64-bit words starting at the 8-byte aligned address below `base`, so every naturally aligned access is aligned on the host
+    words: Box<[AtomicU64]>,
+    len: usize,
+    base: u64,
+    /// Offset of `base` in the storage
+    pad: usize,
+}
+
+impl SharedRam {
+    /// Move RAM mapped at `base` into shared storage
+    pub fn new(ram: Vec<u8>, base: u64) -> Self {
+        let pad = (base % 8) as usize;
+        // The bytes before the first 8-byte aligned address share a word with the padding
+        let head = ram.len().min((8 - pad) % 8);
+        let mut first = [0u8; 8];
+        first[pad..pad + head].copy_from_slice(&ram[..head]);
+        let words = (head > 0).then_some(first).into_iter().chain(ram[head..].chunks(8).map(|chunk| {
+            let mut word = [0u8; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            word
+        })).map(|word| AtomicU64::new(u64::from_ne_bytes(word))).collect();
+        Self { words, len: ram.len(), base, pad }
+    }
+
+    /// Copy the contents back into unshared RAM
+    pub fn into_vec(self) -> Vec<u8> {
+        self.words.iter().flat_map(|word| word.load(Ordering::Relaxed).to_ne_bytes()).skip(self.pad).take(self.len).collect()
+    }
+
+    /// Offset in the storage of an access of `len` bytes at `addr`, if it is fully within RAM
+    fn offset(&self, addr: u64, len: usize) -> Option<usize> {
+        let offset = usize::try_from(addr.checked_sub(self.base)?).ok()?;
+        (offset.checked_add(len)? <= self.len).then_some(offset + self.pad)
+    }
+
+    fn atomic_u8(&self, offset: usize) -> &AtomicU8 {
+        // SAFETY: the offset is within the storage, and the atomics have the same representation as the integers
+        unsafe { &*self.words.as_ptr().cast::<AtomicU8>().add(offset) }
+    }
+
+    /// Atomic at `offset` in the storage, `None` if the offset isn't aligned to 2 bytes
+    fn atomic_u16(&self, offset: usize) -> Option<&AtomicU16> {
+        // SAFETY: the storage is 8-byte aligned, so an even offset within the storage is aligned to 2 bytes
+        offset.is_multiple_of(2).then(|| unsafe { &*self.words.as_ptr().cast::<u8>().add(offset).cast::<AtomicU16>() })
+    }
+
+    /// Atomic at `offset` in the storage, `None` if the offset isn't aligned to 4 bytes
+    fn atomic_u32(&self, offset: usize) -> Option<&AtomicU32> {
+        // SAFETY: the storage is 8-byte aligned, so an offset within the storage that is a multiple of 4 is aligned to 4 bytes
+        offset.is_multiple_of(4).then(|| unsafe { &*self.words.as_ptr().cast::<u8>().add(offset).cast::<AtomicU32>() })
+    }
+
+    /// Atomic at `offset` in the storage, `None` if the offset isn't aligned to 8 bytes
+    fn atomic_u64(&self, offset: usize) -> Option<&AtomicU64> {
+        offset.is_multiple_of(8).then(|| &self.words[offset / 8])
+    }
+
+    /// Read `buf.len()` bytes at the physical address `addr`, returns `false` if the access is outside RAM
+    pub fn read(&self, addr: u64, buf: &mut [u8]) -> bool {
+        let Some(offset) = self.offset(addr, buf.len()) else { return false; };
+        // Accesses that aren't naturally aligned are split into bytes
+        let loaded = match buf.len() {
+            2 => self.atomic_u16(offset).map(|atomic| buf.copy_from_slice(&atomic.load(Ordering::Relaxed).to_ne_bytes())),
+            4 => self.atomic_u32(offset).map(|atomic| buf.copy_from_slice(&atomic.load(Ordering::Relaxed).to_ne_bytes())),
+            8 => self.atomic_u64(offset).map(|atomic| buf.copy_from_slice(&atomic.load(Ordering::Relaxed).to_ne_bytes())),
+            _ => None,
+        };
+        if loaded.is_none() {
+            for (idx, byte) in buf.iter_mut().enumerate() {
+                *byte = self.atomic_u8(offset + idx).load(Ordering::Relaxed);
+            }
+        }
+        true
+    }
+
+    /// Write `buf.len()` bytes at the physical address `addr`, returns `false` if the access is outside RAM
+    pub fn write(&self, addr: u64, buf: &[u8]) -> bool {
+        let Some(offset) = self.offset(addr, buf.len()) else { return false; };
+        // Accesses that aren't naturally aligned are split into bytes
+        let stored = match buf.len() {
+            2 => self.atomic_u16(offset).map(|atomic| atomic.store(u16::from_ne_bytes(buf.try_into().unwrap()), Ordering::Relaxed)),
+            4 => self.atomic_u32(offset).map(|atomic| atomic.store(u32::from_ne_bytes(buf.try_into().unwrap()), Ordering::Relaxed)),
+            8 => self.atomic_u64(offset).map(|atomic| atomic.store(u64::from_ne_bytes(buf.try_into().unwrap()), Ordering::Relaxed)),
+            _ => None,
+        };
+        if stored.is_none() {
+            for (idx, &byte) in buf.iter().enumerate() {
+                self.atomic_u8(offset + idx).store(byte, Ordering::Relaxed);
+            }
+        }
+        true
+    }
+
+    /// Atomically load a naturally aligned value of 4 or 8 bytes, returns `None` if the access is outside RAM or not naturally aligned
+    pub fn load(&self, addr: u64, size: usize) -> Option<u64> {
+        let offset = self.offset(addr, size)?;
+        match size {
+            4 => Some(u32::from_le(self.atomic_u32(offset)?.load(Ordering::SeqCst)) as u64),
+            8 => Some(u64::from_le(self.atomic_u64(offset)?.load(Ordering::SeqCst))),
+            _ => unreachable!("atomic accesses are 4 or 8 bytes"),
+        }
+    }
+
+    /// Atomically store `new` if the value in memory is still `expected`, returns if the store succeeded, or `None` if the access is outside RAM or not naturally aligned
+    pub fn compare_exchange(&self, addr: u64, size: usize, expected: u64, new: u64) -> Option<bool> {
+        let offset = self.offset(addr, size)?;
+        match size {
+            4 => Some(self.atomic_u32(offset)?.compare_exchange((expected as u32).to_le(), (new as u32).to_le(), Ordering::SeqCst, Ordering::SeqCst).is_ok()),
+            8 => Some(self.atomic_u64(offset)?.compare_exchange(expected.to_le(), new.to_le(), Ordering::SeqCst, Ordering::SeqCst).is_ok()),
+            _ => unreachable!("atomic accesses are 4 or 8 bytes"),
+        }
+    }
+
+    /// Atomically perform an AMO on a naturally aligned value of 4 or 8 bytes, stored big-endian if `big_endian` is set.
+    ///
+    /// Returns the old value, or `None` if the access is outside RAM or not naturally aligned
+    pub fn amo(&self, addr: u64, size: usize, op: AtomicOp, src: u64, big_endian: bool) -> Option<u64> {
+        let offset = self.offset(addr, size)?;
+        let order = |value| byte_order(value, size, big_endian);
+        // Bitwise operations don't depend on the byte order of the host, other operations use a compare-and-swap loop on the little-endian value
+        let old = match size {
+            4 => {
+                let atomic = self.atomic_u32(offset)?;
+                let src_le = (order(src) as u32).to_le();
+                let old = match op {
+                    AtomicOp::Swap => atomic.swap(src_le, Ordering::SeqCst),
+                    AtomicOp::And  => atomic.fetch_and(src_le, Ordering::SeqCst),
+                    AtomicOp::Or   => atomic.fetch_or(src_le, Ordering::SeqCst),
+                    AtomicOp::Xor  => atomic.fetch_xor(src_le, Ordering::SeqCst),
+                    _ => atomic.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |old| {
+                        Some((order(op.apply(order(u32::from_le(old) as u64), src, size)) as u32).to_le())
+                    }).unwrap(),
+                };
+                u32::from_le(old) as u64
+            },
+            8 => {
+                let atomic = self.atomic_u64(offset)?;
+                let src_le = order(src).to_le();
+                let old = match op {
+                    AtomicOp::Swap => atomic.swap(src_le, Ordering::SeqCst),
+                    AtomicOp::And  => atomic.fetch_and(src_le, Ordering::SeqCst),
+                    AtomicOp::Or   => atomic.fetch_or(src_le, Ordering::SeqCst),
+                    AtomicOp::Xor  => atomic.fetch_xor(src_le, Ordering::SeqCst),
+                    _ => atomic.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |old| {
+                        Some(order(op.apply(order(u64::from_le(old)), src, size)).to_le())
+                    }).unwrap(),
+                };
+                u64::from_le(old)
+            },
+            _ => unreachable!("atomic accesses are 4 or 8 bytes"),
+        };
+        Some(order(old))
+    }
+}
+
+/// Read a little-endian value of `size` bytes from physical memory
+pub fn read_physical(memory: &mut dyn MemoryBus, addr: u64, size: usize) -> Option<u64> {
+    debug_assert!(size <= 8);
+    let mut buf = [0u8; 8];
+    if memory.read(addr, &mut buf[..size]) {
+        Some(u64::from_le_bytes(buf))
+    } else {
+        None
+    }
+}
+
+/// Reverse the order of the low `size` bytes of `value`, converting it between little-endian and big-endian
+pub fn swap_endianness(value: u64, size: usize) -> u64 {
+    value.swap_bytes() >> (64 - size * 8)
+}
+
+/// Convert a value of `size` bytes between little-endian and big-endian if `big_endian` is set
+fn byte_order(value: u64, size: usize, big_endian: bool) -> u64 {
+    if big_endian {
+        swap_endianness(value, size)
+    } else {
+        value
+    }
+}
+
+/// Check if data accesses made with the given access mode are big-endian, according to the `*BE` bit of the mode
+pub fn is_big_endian(register_file: &RegisterFile, mode: AccessMode) -> bool {
+    let csr = register_file.csr();
+    match (mode.privilege, mode.virtualized) {
+        (PrivilegeMode::Machine   , _    ) => csr.mstatus & MSTATUS_MBE != 0,
+        (PrivilegeMode::Supervisor, false) => csr.mstatus & MSTATUS_SBE != 0,
+        (PrivilegeMode::User      , false) => csr.mstatus & MSTATUS_UBE != 0,
+        (PrivilegeMode::Supervisor, true ) => csr.hstatus & HSTATUS_VSBE != 0,
+        (PrivilegeMode::User      , true ) => csr.vsstatus & MSTATUS_UBE != 0,
+    }
+}
+
+/// Convert a value of `size` bytes between little-endian and the data endianness of the access mode
+pub fn to_data_endianness(register_file: &RegisterFile, value: u64, size: usize, mode: AccessMode) -> u64 {
+    byte_order(value, size, is_big_endian(register_file, mode))
+}
+
+/// Translate an access of `size` bytes, splitting it in 2 physical accesses when it crosses a page boundary
+fn translate_access(register_file: &RegisterFile, memory: &mut dyn MemoryBus, addr: u64, size: usize, access: AccessType, mode: AccessMode) -> Result<[(u64, usize); 2], Trap> {
+    let page_offset = (addr & 0xFFF) as usize;
+    if page_offset + size <= 0x1000 {
+        let paddr = mmu::translate(register_file, memory, addr, access, mode)?;
+        Ok([(paddr, size), (0, 0)])
+    } else {
+        let first_size = 0x1000 - page_offset;
+        let first = mmu::translate(register_file, memory, addr, access, mode)?;
+        let second = mmu::translate(register_file, memory, addr.wrapping_add(first_size as u64), access, mode)?;
+        Ok([(first, first_size), (second, size - first_size)])
+    }
+}
+
+/// Check if an access at `addr` is aligned to `align` bytes, handling it according to the misaligned access policy of the hart if it isn't
+pub fn check_alignment(register_file: &mut RegisterFile, addr: u64, align: usize, access: AccessType, mode: AccessMode) -> Result<(), Trap> {
+    if addr & (align as u64 - 1) == 0 {
+        return Ok(());
+    }
+    match register_file.misaligned_policy() {
+        MisalignedPolicy::Trap => Err(Trap { gva: mode.virtualized, ..Trap::new(access.misaligned(), addr) }),
+        MisalignedPolicy::AccessFault => Err(Trap { gva: mode.virtualized, ..Trap::new(access.access_fault(), addr) }),
+        MisalignedPolicy::Emulate { count } => {
+            if count {
+                register_file.count_misaligned_access();
+            }
+            Ok(())
+        },
+    }
+}
+
+/// Read a little-endian value of `size` bytes from the virtual address `addr`, without checking its alignment
+pub fn read_virtual(register_file: &RegisterFile, memory: &mut dyn MemoryBus, addr: u64, size: usize, access: AccessType, mode: AccessMode) -> Result<u64, Trap> {
+    let parts = translate_access(register_file, memory, addr, size, access, mode)?;
+
+    let mut buf = [0u8; 8];
+    let mut offset = 0;
+    for (paddr, part_size) in parts {
+        if part_size != 0 && !memory.read(paddr, &mut buf[offset..offset + part_size]) {
+            return Err(Trap { gva: mode.virtualized, ..Trap::new(access.access_fault(), addr) });
+        }
+        offset += part_size;
+    }
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// Write a little-endian value of `size` bytes to the virtual address `addr`, without checking its alignment
+pub fn write_virtual(register_file: &RegisterFile, memory: &mut dyn MemoryBus, addr: u64, size: usize, value: u64, mode: AccessMode) -> Result<(), Trap> {
+    let parts = translate_access(register_file, memory, addr, size, AccessType::Store, mode)?;
+
+    let buf = value.to_le_bytes();
+    let mut offset = 0;
+    for (paddr, part_size) in parts {
+        if part_size != 0 && !memory.write(paddr, &buf[offset..offset + part_size]) {
+            return Err(Trap { gva: mode.virtualized, ..Trap::new(Exception::StoreAccessFault, addr) });
+        }
+        offset += part_size;
+    }
+    Ok(())
+}
+
+/// Load a value of `size` bytes from the virtual address `addr`, using the data endianness of the access mode
+pub fn load_with_mode(register_file: &mut RegisterFile, memory: &mut dyn MemoryBus, addr: u64, size: usize, access: AccessType, mode: AccessMode) -> Result<u64, Trap> {
+    check_alignment(register_file, addr, size, access, mode)?;
+    let value = read_virtual(register_file, memory, addr, size, access, mode)?;
+    Ok(to_data_endianness(register_file, value, size, mode))
+}
+
+/// Store a value of `size` bytes to the virtual address `addr`, using the data endianness of the access mode
+pub fn store_with_mode(register_file: &mut RegisterFile, memory: &mut dyn MemoryBus, addr: u64, size: usize, value: u64, mode: AccessMode) -> Result<(), Trap> {
+    check_alignment(register_file, addr, size, AccessType::Store, mode)?;
+    let value = to_data_endianness(register_file, value, size, mode);
+    write_virtual(register_file, memory, addr, size, value, mode)
+}
+
+/// Load a value of `size` bytes from the virtual address `addr`, using the current translation and protection mode
+pub fn load(register_file: &mut RegisterFile, memory: &mut dyn MemoryBus, addr: u64, size: usize) -> Result<u64, Trap> {
+    let mode = AccessMode::effective(register_file, AccessType::Load);
+    load_with_mode(register_file, memory, addr, size, AccessType::Load, mode)
+}
+
+/// Store a value of `size` bytes to the virtual address `addr`, using the current translation and protection mode
+pub fn store(register_file: &mut RegisterFile, memory: &mut dyn MemoryBus, addr: u64, size: usize, value: u64) -> Result<(), Trap> {
+    let mode = AccessMode::effective(register_file, AccessType::Store);
+    store_with_mode(register_file, memory, addr, size, value, mode)
+}
@@ -0,0 +1,378 @@
+use std::collections::{BTreeSet, HashSet, VecDeque};
+use std::fmt;
+use std::ops::Range;
+
+use crate::bus::Ram;
+use crate::csr::misa_bit;
+use crate::instructions::AtomicOp;
+use crate::memory::MemoryBus;
+use crate::registers::RegisterFile;
+use crate::trap::Exception;
+use crate::execute_instruction;
+
+
+// Access sets of FENCE
+pub const FENCE_I: u8 = 0b1000;
+pub const FENCE_O: u8 = 0b0100;
+pub const FENCE_R: u8 = 0b0010;
+pub const FENCE_W: u8 = 0b0001;
+/// Fence mode of FENCE.TSO
+pub const FENCE_FM_TSO: u8 = 0b1000;
+
+/// Memory model used for accesses to RAM when the harts are executed round-robin
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum MemoryModel {
+    /// Every access is performed immediately, in program order
+    #[default]
+    Sequential,
+    /// RVWMO, modelled using a store buffer per hart.
+    ///
+    /// Stores to RAM are buffered and become visible to other harts once drained, loads are performed immediately and forward from the buffer of the hart.
+    /// Buffered stores to different addresses can drain in any order, unless separated by a fence ordering stores.
+    /// Loads are performed in program order, so outcomes that are only possible by reordering loads aren't modelled
+    Rvwmo,
+    /// RVTSO, as required by Ztso, modelled using a FIFO store buffer per hart.
+    ///
+    /// Stores drain in program order and atomic accesses drain all buffered stores first, so only loads can be performed before earlier stores, like on x86
+    Tso,
+}
+
+/// Store to RAM that isn't visible to other harts yet
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+struct BufferedStore {
+    addr: u64,
+    len: usize,
+    data: [u8; 8],
+    /// Fences ordering stores executed before the store, which need all stores of earlier epochs to drain first
+    epoch: u64,
+    /// Value of the virtual clock when the store was executed
+    cycle: u64,
+}
+
+impl BufferedStore {
+    fn overlaps(&self, addr: u64, len: usize) -> bool {
+        self.addr < addr + len as u64 && addr < self.addr + self.len as u64
+    }
+}
+
+/// Stores of a hart that aren't visible to other harts yet, in program order
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub struct StoreBuffer {
+    stores: VecDeque<BufferedStore>,
+    epoch: u64,
+}
+
+impl StoreBuffer {
+    fn push(&mut self, addr: u64, buf: &[u8], cycle: u64) {
+        let mut data = [0; 8];
+        data[..buf.len()].copy_from_slice(buf);
+        self.stores.push_back(BufferedStore { addr, len: buf.len(), data, epoch: self.epoch, cycle });
+    }
+
+    /// Overwrite the bytes read from memory with the youngest buffered stores to them
+    fn forward(&self, addr: u64, buf: &mut [u8]) {
+        let len = buf.len();
+        for store in self.stores.iter().filter(|store| store.overlaps(addr, len)) {
+            for (byte_addr, byte) in (addr..).zip(buf.iter_mut()) {
+                if let Some(offset) = byte_addr.checked_sub(store.addr).filter(|&offset| offset < store.len as u64) {
+                    *byte = store.data[offset as usize];
+                }
+            }
+        }
+    }
+
+    /// Order the stores executed after this call after all buffered stores
+    fn barrier(&mut self) {
+        self.epoch += 1;
+    }
+
+    /// Indices of the stores that are allowed to drain next under `model`
+    fn drainable(&self, model: MemoryModel) -> Vec<usize> {
+        (0..self.stores.len()).filter(|&idx| {
+            let store = &self.stores[idx];
+            match model {
+                MemoryModel::Sequential | MemoryModel::Tso => idx == 0,
+                // Stores to the same address drain in order, and stores can't pass a fence
+                MemoryModel::Rvwmo => self.stores.range(..idx).all(|older| older.epoch == store.epoch && !older.overlaps(store.addr, store.len)),
+            }
+        }).collect()
+    }
+
+    /// Make the store at `idx` visible to all harts
+    fn drain(&mut self, idx: usize, memory: &mut dyn MemoryBus) {
+        if let Some(store) = self.stores.remove(idx) {
+            memory.write(store.addr, &store.data[..store.len]);
+        }
+    }
+
+    /// Make all buffered stores visible to all harts, in program order
+    pub fn drain_all(&mut self, memory: &mut dyn MemoryBus) {
+        while !self.stores.is_empty() {
+            self.drain(0, memory);
+        }
+    }
+
+    /// Drain the stores in program order until no store overlapping `addr` is buffered, as needed before an access that bypasses the buffer
+    fn drain_overlapping(&mut self, addr: u64, len: usize, memory: &mut dyn MemoryBus) {
+        if let Some(youngest) = self.stores.iter().rposition(|store| store.overlaps(addr, len)) {
+            for _ in 0..=youngest {
+                self.drain(0, memory);
+            }
+        }
+    }
+
+    /// Drain the stores that have been buffered for at least `latency` ticks at `cycle`
+    pub fn drain_expired(&mut self, cycle: u64, latency: u64, memory: &mut dyn MemoryBus) {
+        while self.stores.front().is_some_and(|store| cycle - store.cycle >= latency) {
+            self.drain(0, memory);
+        }
+    }
+}
+
+/// View of memory used by a hart, buffering the stores of the hart to RAM according to the memory model
+pub(crate) struct BufferedBus<'a> {
+    pub memory: &'a mut dyn MemoryBus,
+    pub buffer: &'a mut StoreBuffer,
+    pub model: MemoryModel,
+    /// Physical addresses of RAM, accesses to other addresses are device accesses, which aren't buffered
+    pub ram: Range<u64>,
+    /// Value of the virtual clock
+    pub cycle: u64,
+}
+
+impl BufferedBus<'_> {
+    fn is_ram(&self, addr: u64, len: usize) -> bool {
+        self.ram.start <= addr && addr.saturating_add(len as u64) <= self.ram.end
+    }
+
+    /// Drain the buffered stores that need to be visible before an atomic access, under RVTSO atomic accesses are ordered after all earlier stores
+    fn drain_for_atomic(&mut self, addr: u64, size: usize) {
+        match self.model {
+            MemoryModel::Tso => self.buffer.drain_all(self.memory),
+            _ => self.buffer.drain_overlapping(addr, size, self.memory),
+        }
+    }
+}
+
+impl MemoryBus for BufferedBus<'_> {
+    fn read(&mut self, addr: u64, buf: &mut [u8]) -> bool {
+        if !self.memory.read(addr, buf) {
+            return false;
+        }
+        if self.is_ram(addr, buf.len()) {
+            self.buffer.forward(addr, buf);
+        }
+        true
+    }
+
+    fn write(&mut self, addr: u64, buf: &[u8]) -> bool {
+        if self.model == MemoryModel::Sequential || !self.is_ram(addr, buf.len()) {
+            return self.memory.write(addr, buf);
+        }
+        self.buffer.push(addr, buf, self.cycle);
+        true
+    }
+
+    fn reserve(&mut self, hart: usize, addr: u64) {
+        self.memory.reserve(hart, addr);
+    }
+
+    fn release_reservation(&mut self, hart: usize, addr: u64) -> bool {
+        self.memory.release_reservation(hart, addr)
+    }
+
+    // Atomic accesses are performed directly on memory, after the buffered stores to the same address
+
+    fn load_reserved(&mut self, hart: usize, addr: u64, size: usize) -> Option<u64> {
+        self.drain_for_atomic(addr, size);
+        self.memory.load_reserved(hart, addr, size)
+    }
+
+    fn store_conditional(&mut self, hart: usize, addr: u64, size: usize, value: u64) -> Option<bool> {
+        self.drain_for_atomic(addr, size);
+        self.memory.store_conditional(hart, addr, size, value)
+    }
+
+    fn amo(&mut self, addr: u64, size: usize, op: AtomicOp, src: u64, big_endian: bool) -> Option<u64> {
+        self.drain_for_atomic(addr, size);
+        self.memory.amo(addr, size, op, src, big_endian)
+    }
+
+    fn fence(&mut self, pred: u8, succ: u8) {
+        // Loads and device accesses are performed immediately, so only buffered stores need to be ordered
+        if pred & FENCE_W == 0 {
+            return;
+        }
+        if succ & (FENCE_I | FENCE_O | FENCE_R) != 0 {
+            self.buffer.drain_all(self.memory);
+        } else if succ & FENCE_W != 0 {
+            self.buffer.barrier();
+        }
+    }
+
+    // Later accesses can't be performed before an atomic access, as loads are performed in order and later stores are buffered after it
+
+    fn release(&mut self) {
+        self.buffer.drain_all(self.memory);
+    }
+}
+
+/// Size of RAM used by litmus tests
+pub const LITMUS_RAM_SIZE: u64 = 0x1000;
+/// Address of the code of the first hart of a litmus test, the addresses below are available for data
+pub const LITMUS_CODE_BASE: u64 = 0x800;
+/// Space reserved for the code of every hart of a litmus test
+pub const LITMUS_CODE_SIZE: u64 = 0x100;
+
+/// Error while enumerating the outcomes of a litmus test
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LitmusError {
+    /// There is no space for the code of more than `(LITMUS_RAM_SIZE - LITMUS_CODE_BASE) / LITMUS_CODE_SIZE` harts
+    TooManyHarts,
+    /// The code of a hart is larger than `LITMUS_CODE_SIZE`
+    CodeTooLarge(usize),
+    /// A 64-bit value in memory or a location in the outcome is outside RAM
+    OutsideRam(u64),
+    /// A register in the outcome doesn't exist, as hart and register
+    InvalidRegister(usize, u8),
+    /// A hart raised an exception in one of the interleavings
+    Trap { hart: usize, pc: u64, cause: Exception },
+}
+
+impl fmt::Display for LitmusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LitmusError::TooManyHarts               => write!(f, "at most {} harts are supported", (LITMUS_RAM_SIZE - LITMUS_CODE_BASE) / LITMUS_CODE_SIZE),
+            LitmusError::CodeTooLarge(hart)         => write!(f, "code of hart {hart} is larger than {LITMUS_CODE_SIZE:#X} bytes"),
+            LitmusError::OutsideRam(addr)           => write!(f, "location {addr:#X} is outside RAM"),
+            LitmusError::InvalidRegister(hart, reg) => write!(f, "register x{reg} of hart {hart} does not exist"),
+            LitmusError::Trap { hart, pc, cause }   => write!(f, "hart {hart} raised {cause} at {pc:#X}"),
+        }
+    }
+}
+
+/// Check that a 64-bit value at `addr` is within RAM, returning its offset
+fn litmus_location(addr: u64) -> Result<usize, LitmusError> {
+    match addr.checked_add(8) {
+        Some(end) if end <= LITMUS_RAM_SIZE => Ok(addr as usize),
+        _ => Err(LitmusError::OutsideRam(addr)),
+    }
+}
+
+/// Small multi-hart program of which all outcomes allowed by a memory model can be enumerated
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct LitmusTest {
+    /// Code of every hart, which is placed at `LITMUS_CODE_BASE + hart * LITMUS_CODE_SIZE`, a hart finishes when it executed its last instruction
+    pub harts: Vec<Vec<u32>>,
+    /// Initial 64-bit values in memory, all other memory is zero
+    pub memory: Vec<(u64, u64)>,
+    /// Registers in the outcome, as hart and register
+    pub registers: Vec<(usize, u8)>,
+    /// Memory locations in the outcome, as 64-bit values once all stores are drained
+    pub locations: Vec<u64>,
+}
+
+/// State of a litmus test between two steps
+#[derive(Clone)]
+struct LitmusState {
+    harts: Vec<RegisterFile>,
+    buffers: Vec<StoreBuffer>,
+    ram: Vec<u8>,
+    reservations: Vec<Option<u64>>,
+}
+
+impl LitmusState {
+    /// Key identifying the state, the harts only use their integer registers
+    fn key(&self) -> (Vec<u64>, Vec<StoreBuffer>, Vec<u8>, Vec<Option<u64>>) {
+        let registers = self.harts.iter().flat_map(|register_file| (1..32).map(|reg| register_file.read_x_register(reg)).chain([register_file.read_pc()])).collect();
+        (registers, self.buffers.clone(), self.ram.clone(), self.reservations.clone())
+    }
+
+    fn step(&mut self, hart: usize, model: MemoryModel) -> Result<(), LitmusError> {
+        let mut ram = Ram::new(&mut self.ram, 0, &mut self.reservations);
+        let mut memory = BufferedBus { memory: &mut ram, buffer: &mut self.buffers[hart], model, ram: 0..LITMUS_RAM_SIZE, cycle: 0 };
+        let register_file = &mut self.harts[hart];
+        let pc = register_file.read_pc();
+        execute_instruction(register_file, &mut memory, false).map_err(|trap| LitmusError::Trap { hart, pc, cause: trap.cause })
+    }
+
+    fn drain(&mut self, hart: usize, idx: usize) {
+        self.buffers[hart].drain(idx, &mut Ram::new(&mut self.ram, 0, &mut self.reservations));
+    }
+}
+
+impl LitmusTest {
+    /// Enumerate all outcomes allowed by `model`, as the values of `registers` followed by the values of `locations`.
+    ///
+    /// Every interleaving of the instructions of the harts and the draining of their buffered stores is explored, harts execute in M-mode as RV64IA.
+    /// Fails if the test doesn't fit in the RAM of the harts, or if a hart raises an exception in any interleaving
+    pub fn outcomes(&self, model: MemoryModel) -> Result<BTreeSet<Vec<u64>>, LitmusError> {
+        if self.harts.len() as u64 > (LITMUS_RAM_SIZE - LITMUS_CODE_BASE) / LITMUS_CODE_SIZE {
+            return Err(LitmusError::TooManyHarts);
+        }
+        if let Some(&(hart, reg)) = self.registers.iter().find(|&&(hart, reg)| hart >= self.harts.len() || reg >= 32) {
+            return Err(LitmusError::InvalidRegister(hart, reg));
+        }
+        let locations = self.locations.iter().map(|&addr| litmus_location(addr)).collect::<Result<Vec<_>, _>>()?;
+
+        let mut ram = vec![0; LITMUS_RAM_SIZE as usize];
+        for &(addr, value) in &self.memory {
+            let offset = litmus_location(addr)?;
+            ram[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+        }
+        let mut harts = Vec::new();
+        let mut code_ends = Vec::new();
+        for (hart, code) in self.harts.iter().enumerate() {
+            if code.len() as u64 * 4 > LITMUS_CODE_SIZE {
+                return Err(LitmusError::CodeTooLarge(hart));
+            }
+            let base = LITMUS_CODE_BASE + hart as u64 * LITMUS_CODE_SIZE;
+            for (addr, instr) in (base..).step_by(4).zip(code) {
+                ram[addr as usize..addr as usize + 4].copy_from_slice(&instr.to_le_bytes());
+            }
+
+            let mut register_file = RegisterFile::new(false);
+            register_file.csr_mut().mhartid = hart as u64;
+            register_file.csr_mut().misa |= misa_bit('A');
+            register_file.write_pc(base);
+            harts.push(register_file);
+            code_ends.push(base + code.len() as u64 * 4);
+        }
+
+        let num_harts = harts.len();
+        let mut outcomes = BTreeSet::new();
+        let mut visited = HashSet::new();
+        let mut states = vec![LitmusState { harts, buffers: vec![StoreBuffer::default(); num_harts], ram, reservations: vec![None; num_harts] }];
+        while let Some(state) = states.pop() {
+            if !visited.insert(state.key()) {
+                continue;
+            }
+
+            let mut finished = true;
+            for (hart, &code_end) in code_ends.iter().enumerate() {
+                if state.harts[hart].read_pc() != code_end {
+                    let mut next = state.clone();
+                    next.step(hart, model)?;
+                    states.push(next);
+                    finished = false;
+                }
+                for idx in state.buffers[hart].drainable(model) {
+                    let mut next = state.clone();
+                    next.drain(hart, idx);
+                    states.push(next);
+                    finished = false;
+                }
+            }
+
+            if finished {
+                let registers = self.registers.iter().map(|&(hart, reg)| state.harts[hart].read_x_register(reg));
+                let locations = locations.iter().map(|&offset| u64::from_le_bytes(state.ram[offset..offset + 8].try_into().unwrap()));
+                outcomes.insert(registers.chain(locations).collect());
+            }
+        }
+        Ok(outcomes)
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,233 @@
+use emu_cpu::{CpuEmulator, EmulationSettings, HaltReason, MisalignedPolicy};
+
+use crate::{RiscvEmulator, BaseIsa, ExtensionIsa};
+
+use super::*;
+
+// Store buffering: each hart stores 1 to its own location and loads the location of the other hart
+//
+//         addi  a0, x0, 0x100
+//         addi  a1, x0, 0x108
+//         addi  t0, x0, 1
+//         sw    t0, 0(a0)       ; sw t0, 0(a1) on hart 1
+//         lw    a2, 0(a1)       ; lw a2, 0(a0) on hart 1
+const STORE_BUFFERING: [[u32; 5]; 2] = [
+    [0x10000513, 0x10800593, 0x00100293, 0x00552023, 0x0005A603],
+    [0x10000513, 0x10800593, 0x00100293, 0x0055A023, 0x00052603],
+];
+
+fn store_buffering() -> LitmusTest {
+    LitmusTest {
+        harts: STORE_BUFFERING.iter().map(|code| code.to_vec()).collect(),
+        registers: vec![(0, 12), (1, 12)],
+        ..LitmusTest::default()
+    }
+}
+
+// Message passing: hart 0 stores the data at 0x100 and then the flag at 0x108, hart 1 loads the flag and then the data
+//
+//         addi  a0, x0, 0x100
+//         addi  a1, x0, 0x108
+//         addi  t0, x0, 1
+//         sw    t0, 0(a0)
+//         sw    t0, 0(a1)
+//
+//         addi  a0, x0, 0x100
+//         addi  a1, x0, 0x108
+//         lw    a2, 0(a1)
+//         lw    a3, 0(a0)
+fn message_passing() -> LitmusTest {
+    LitmusTest {
+        harts: vec![
+            vec![0x10000513, 0x10800593, 0x00100293, 0x00552023, 0x0055A023],
+            vec![0x10000513, 0x10800593, 0x0005A603, 0x00052683],
+        ],
+        memory: vec![(0x100, 0), (0x108, 0)],
+        registers: vec![(1, 12), (1, 13)],
+        locations: vec![0x100, 0x108],
+    }
+}
+
+fn outcomes(outcomes: &[&[u64]]) -> BTreeSet<Vec<u64>> {
+    outcomes.iter().map(|outcome| outcome.to_vec()).collect()
+}
+
+#[test]
+fn test_litmus_store_buffering() {
+    let test = store_buffering();
+    assert_eq!(test.outcomes(MemoryModel::Sequential).unwrap(), outcomes(&[&[0, 1], &[1, 0], &[1, 1]]));
+    assert_eq!(test.outcomes(MemoryModel::Rvwmo).unwrap(), outcomes(&[&[0, 0], &[0, 1], &[1, 0], &[1, 1]]));
+    assert_eq!(test.outcomes(MemoryModel::Tso).unwrap(), outcomes(&[&[0, 0], &[0, 1], &[1, 0], &[1, 1]]));
+}
+
+#[test]
+fn test_litmus_store_buffering_fence() {
+    // fence rw, rw between the store and the load
+    let mut test = store_buffering();
+    for code in &mut test.harts {
+        code.insert(4, 0x0330000F);
+    }
+    assert_eq!(test.outcomes(MemoryModel::Rvwmo).unwrap(), test.outcomes(MemoryModel::Sequential).unwrap());
+}
+
+#[test]
+fn test_litmus_message_passing() {
+    let test = message_passing();
+    let sequential = outcomes(&[&[0, 0, 1, 1], &[0, 1, 1, 1], &[1, 1, 1, 1]]);
+    assert_eq!(test.outcomes(MemoryModel::Sequential).unwrap(), sequential);
+    assert!(test.outcomes(MemoryModel::Rvwmo).unwrap().contains(&vec![1, 0, 1, 1]));
+    // Stores drain in program order under RVTSO
+    assert_eq!(test.outcomes(MemoryModel::Tso).unwrap(), sequential);
+}
+
+#[test]
+fn test_litmus_message_passing_fence() {
+    // fence w, w between the stores
+    let mut test = message_passing();
+    test.harts[0].insert(4, 0x0110000F);
+    assert_eq!(test.outcomes(MemoryModel::Rvwmo).unwrap(), test.outcomes(MemoryModel::Sequential).unwrap());
+}
+
+#[test]
+fn test_litmus_message_passing_release() {
+    // amoswap.w.rl x0, t0, (a1) as the store of the flag
+    let mut test = message_passing();
+    test.harts[0][4] = 0x0A55A02F;
+    assert_eq!(test.outcomes(MemoryModel::Rvwmo).unwrap(), test.outcomes(MemoryModel::Sequential).unwrap());
+}
+
+/// Emulator executing the store buffering test on 2 harts, followed by a WFI
+fn store_buffering_emulator(isa: &str) -> RiscvEmulator {
+    let mut emulator = RiscvEmulator::new_smp(EmulationSettings { print_instructions: false, misaligned: MisalignedPolicy::Trap }, 2);
+    emulator.set_base_isa(isa);
+    emulator.memory.ram = vec![0; 0x1000];
+    for (hart, code) in STORE_BUFFERING.iter().enumerate() {
+        let base = hart * 0x40;
+        for (idx, instr) in code.iter().chain(&[0x10500073]).enumerate() {
+            emulator.memory.ram[base + idx * 4..base + idx * 4 + 4].copy_from_slice(&instr.to_le_bytes());
+        }
+        emulator.harts[hart].write_pc(base as u64);
+    }
+    emulator
+}
+
+#[test]
+fn test_rvwmo_store_buffer() {
+    // When executing, the stores stay buffered until they expire, so both loads miss the store of the other hart
+    let mut emulator = store_buffering_emulator("RVWMO");
+    assert_eq!(emulator.memory_model, MemoryModel::Rvwmo);
+    assert_eq!(emulator.execute(Some(10)), HaltReason::InstructionLimit);
+    assert_eq!(emulator.harts[0].read_x_register(12), 0);
+    assert_eq!(emulator.harts[1].read_x_register(12), 0);
+    let mut value = [0; 4];
+    assert!(emulator.memory.read(0x100, &mut value));
+    assert_eq!(value, [0; 4]);
+
+    // The buffered stores are drained once the harts wait for an interrupt
+    assert_eq!(emulator.execute(None), HaltReason::Idle);
+    assert!(emulator.memory.read(0x100, &mut value));
+    assert_eq!(u32::from_le_bytes(value), 1);
+}
+
+#[test]
+fn test_tso_store_buffer() {
+    // Ztso selects RVTSO, which also buffers the stores
+    let mut emulator = store_buffering_emulator("RV64I");
+    emulator.set_extensions(ExtensionIsa::A | ExtensionIsa::Zlso);
+    assert_eq!(emulator.memory_model, MemoryModel::Tso);
+    assert_eq!(emulator.isa_string(), "rv64ia_ztso");
+    assert_eq!(emulator.execute(None), HaltReason::Idle);
+    assert_eq!(emulator.harts[0].read_x_register(12), 0);
+    assert_eq!(emulator.harts[1].read_x_register(12), 0);
+}
+
+#[test]
+fn test_sequential_execution() {
+    // Without a memory model, the interleaved harts both see the store of the other hart
+    let mut emulator = store_buffering_emulator("RV64I");
+    assert_eq!(emulator.memory_model, MemoryModel::Sequential);
+    assert_eq!(emulator.execute(None), HaltReason::Idle);
+    assert_eq!(emulator.harts[0].read_x_register(12), 1);
+    assert_eq!(emulator.harts[1].read_x_register(12), 1);
+}
+
+#[test]
+fn test_memory_model_base_isa() {
+    // The default base ISA is RVWMO, but it only selects the memory model once it is set explicitly
+    let mut emulator = RiscvEmulator::new_smp(EmulationSettings { print_instructions: false, misaligned: MisalignedPolicy::Trap }, 2);
+    assert_eq!(emulator.base_isa, BaseIsa::RVWMO);
+    assert_eq!(emulator.memory_model, MemoryModel::Sequential);
+    emulator.set_extensions(ExtensionIsa::A);
+    assert_eq!(emulator.memory_model, MemoryModel::Sequential);
+
+    emulator.set_base_isa("RVWMO");
+    assert_eq!(emulator.memory_model, MemoryModel::Rvwmo);
+    emulator.set_base_isa("RV64I");
+    assert_eq!(emulator.memory_model, MemoryModel::Sequential);
+    emulator.set_base_isa("RVWMO");
+    emulator.set_base_isa("RV32I");
+    assert_eq!(emulator.memory_model, MemoryModel::Sequential);
+}
+
+#[test]
+fn test_memory_model_extensions() {
+    // Ztso selects RVTSO, clearing it returns to the memory model of the base ISA
+    let mut emulator = RiscvEmulator::new(EmulationSettings { print_instructions: false, misaligned: MisalignedPolicy::Trap });
+    emulator.set_base_isa("RV64I");
+    emulator.set_extensions(ExtensionIsa::A | ExtensionIsa::Zlso);
+    assert_eq!(emulator.memory_model, MemoryModel::Tso);
+    emulator.set_extensions(ExtensionIsa::A);
+    assert_eq!(emulator.memory_model, MemoryModel::Sequential);
+
+    emulator.set_base_isa("RVWMO");
+    emulator.set_extensions(ExtensionIsa::A | ExtensionIsa::Zlso);
+    assert_eq!(emulator.memory_model, MemoryModel::Tso);
+    emulator.set_extensions(ExtensionIsa::A);
+    assert_eq!(emulator.memory_model, MemoryModel::Rvwmo);
+}
+
+#[test]
+fn test_memory_model_isa_string() {
+    // RVWMO isn't part of the ISA string, so it stays selected for rv64i
+    let mut emulator = RiscvEmulator::new(EmulationSettings { print_instructions: false, misaligned: MisalignedPolicy::Trap });
+    emulator.set_base_isa("RVWMO");
+    emulator.set_isa("rv64ia_ztso").unwrap();
+    assert_eq!(emulator.memory_model, MemoryModel::Tso);
+    assert_eq!(emulator.isa_string(), "rv64ia_ztso");
+    emulator.set_isa("rv64ia").unwrap();
+    assert_eq!(emulator.memory_model, MemoryModel::Rvwmo);
+    assert_eq!(emulator.base_isa, BaseIsa::RVWMO);
+
+    emulator.set_isa("rv32ia").unwrap();
+    assert_eq!(emulator.memory_model, MemoryModel::Sequential);
+    emulator.set_isa("rv64ia_ztso").unwrap();
+    assert_eq!(emulator.memory_model, MemoryModel::Tso);
+    emulator.set_isa("rv64ia").unwrap();
+    assert_eq!(emulator.memory_model, MemoryModel::Sequential);
+    assert_eq!(emulator.base_isa, BaseIsa::RV64I);
+}
+
+#[test]
+fn test_litmus_errors() {
+    // Traps of any interleaving are reported, here the store of hart 1 outside RAM
+    let mut test = store_buffering();
+    test.harts[1][1] = 0x00001597; // auipc a1, 0x1
+    assert_eq!(test.outcomes(MemoryModel::Sequential), Err(LitmusError::Trap { hart: 1, pc: 0x90C, cause: Exception::StoreAccessFault }));
+
+    let mut test = store_buffering();
+    test.locations.push(LITMUS_RAM_SIZE - 4);
+    assert_eq!(test.outcomes(MemoryModel::Sequential), Err(LitmusError::OutsideRam(LITMUS_RAM_SIZE - 4)));
+    let mut test = store_buffering();
+    test.memory.push((u64::MAX - 4, 0));
+    assert_eq!(test.outcomes(MemoryModel::Sequential), Err(LitmusError::OutsideRam(u64::MAX - 4)));
+
+    let mut test = store_buffering();
+    test.registers.push((2, 10));
+    assert_eq!(test.outcomes(MemoryModel::Sequential), Err(LitmusError::InvalidRegister(2, 10)));
+
+    let mut test = store_buffering();
+    test.harts[0] = vec![0x00000013; LITMUS_CODE_SIZE as usize / 4 + 1];
+    assert_eq!(test.outcomes(MemoryModel::Sequential), Err(LitmusError::CodeTooLarge(0)));
+    let test = LitmusTest { harts: vec![vec![]; 9], ..LitmusTest::default() };
+    assert_eq!(test.outcomes(MemoryModel::Sequential), Err(LitmusError::TooManyHarts));
+}
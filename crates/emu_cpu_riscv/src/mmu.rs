@@ -0,0 +1,301 @@
+use crate::csr::*;
+use crate::memory::{self, MemoryBus, read_physical};
+use crate::registers::{RegisterFile, PrivilegeMode};
+use crate::trap::{Trap, Exception};
+
+
+// Page table entry
+//
+// Sv32:
+// | 31                      20 | 19                 10 | 9 - 8 | 7 | 6 | 5 | 4 | 3 | 2 | 1 | 0 |
+// |           PPN[1]           |        PPN[0]        |  RSW  | D | A | G | U | X | W | R | V |
+//
+// Sv39/Sv48:
+// | 63 - 54  | 53                                   10 | 9 - 8 | 7 | 6 | 5 | 4 | 3 | 2 | 1 | 0 |
+// | reserved |                  PPN                  |  RSW  | D | A | G | U | X | W | R | V |
+
+const PTE_V: u64 = 1 << 0;
+const PTE_R: u64 = 1 << 1;
+const PTE_W: u64 = 1 << 2;
+const PTE_X: u64 = 1 << 3;
+const PTE_U: u64 = 1 << 4;
+const PTE_A: u64 = 1 << 6;
+const PTE_D: u64 = 1 << 7;
+
+/// Type of memory access being translated
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AccessType {
+    Fetch,
+    Load,
+    Store,
+    /// Load requiring execute permission instead of read permission (`HLVX.*`)
+    LoadExecutable,
+}
+
+impl AccessType {
+    fn page_fault(self) -> Exception {
+        match self {
+            AccessType::Fetch                             => Exception::InstructionPageFault,
+            AccessType::Load | AccessType::LoadExecutable => Exception::LoadPageFault,
+            AccessType::Store                             => Exception::StorePageFault,
+        }
+    }
+
+    fn guest_page_fault(self) -> Exception {
+        match self {
+            AccessType::Fetch                             => Exception::InstructionGuestPageFault,
+            AccessType::Load | AccessType::LoadExecutable => Exception::LoadGuestPageFault,
+            AccessType::Store                             => Exception::StoreGuestPageFault,
+        }
+    }
+
+    pub fn misaligned(self) -> Exception {
+        match self {
+            AccessType::Fetch                             => Exception::InstructionAddressMisaligned,
+            AccessType::Load | AccessType::LoadExecutable => Exception::LoadAddressMisaligned,
+            AccessType::Store                             => Exception::StoreAddressMisaligned,
+        }
+    }
+
+    pub fn access_fault(self) -> Exception {
+        match self {
+            AccessType::Fetch                             => Exception::InstructionAccessFault,
+            AccessType::Load | AccessType::LoadExecutable => Exception::LoadAccessFault,
+            AccessType::Store                             => Exception::StoreAccessFault,
+        }
+    }
+}
+
+/// Privilege and virtualization mode an access is translated and checked with
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct AccessMode {
+    pub privilege: PrivilegeMode,
+    pub virtualized: bool,
+}
+
+impl AccessMode {
+    /// Mode used for accesses made by the hart itself, loads and stores take `mstatus.MPRV` into account
+    pub fn effective(register_file: &RegisterFile, access: AccessType) -> Self {
+        let mstatus = register_file.csr().mstatus;
+        if access != AccessType::Fetch && register_file.privilege() == PrivilegeMode::Machine && mstatus & MSTATUS_MPRV != 0 {
+            let privilege = PrivilegeMode::from_bits(mstatus >> MSTATUS_MPP_SHIFT).unwrap_or(PrivilegeMode::User);
+            let virtualized = privilege != PrivilegeMode::Machine && mstatus & MSTATUS_MPV != 0;
+            Self { privilege, virtualized }
+        } else {
+            Self { privilege: register_file.privilege(), virtualized: register_file.is_virtualized() }
+        }
+    }
+
+    /// Mode used by the hypervisor virtual-machine load and store instructions, as if executed in VS- or VU-mode depending on `hstatus.SPVP`
+    pub fn guest(register_file: &RegisterFile) -> Self {
+        let privilege = if register_file.csr().hstatus & HSTATUS_SPVP != 0 { PrivilegeMode::Supervisor } else { PrivilegeMode::User };
+        Self { privilege, virtualized: true }
+    }
+}
+
+/// Layout of a page table
+#[derive(Clone, Copy)]
+struct PagingMode {
+    levels: u32,
+    pte_size: usize,
+    vpn_bits: u32,
+    va_bits: u32,
+}
+
+const SV32: PagingMode = PagingMode { levels: 2, pte_size: 4, vpn_bits: 10, va_bits: 32 };
+const SV39: PagingMode = PagingMode { levels: 3, pte_size: 8, vpn_bits: 9 , va_bits: 39 };
+const SV48: PagingMode = PagingMode { levels: 4, pte_size: 8, vpn_bits: 9 , va_bits: 48 };
+
+/// Decode `satp`, `vsatp` or `hgatp` into the paging mode and root page table address, `None` means bare (no translation)
+fn decode_atp(atp: u64, is_32_bit: bool) -> Option<(PagingMode, u64)> {
+    if is_32_bit {
+        match atp >> 31 {
+            1 => Some((SV32, (atp & 0x3F_FFFF) << 12)),
+            _ => None,
+        }
+    } else {
+        let root = (atp & 0xFFF_FFFF_FFFF) << 12;
+        match atp >> 60 {
+            8 => Some((SV39, root)),
+            9 => Some((SV48, root)),
+            _ => None,
+        }
+    }
+}
+
+fn pte_ppn(pte: u64, is_32_bit: bool) -> u64 {
+    if is_32_bit { (pte >> 10) & 0x3F_FFFF } else { (pte >> 10) & 0xFFF_FFFF_FFFF }
+}
+
+/// Result of a successful page table walk
+struct Leaf {
+    pte: u64,
+    level: u32,
+}
+
+/// Walk a page table, `translate_pte` translates the address of each page table entry before it gets read.
+///
+/// `big_endian` selects the endianness of the page table entries, given by `mstatus.SBE` or `hstatus.VSBE` for the VS-stage
+fn walk(memory: &mut dyn MemoryBus, mode: PagingMode, root: u64, addr: u64, root_extra_bits: u32, big_endian: bool, translate_pte: &mut dyn FnMut(&mut dyn MemoryBus, u64) -> Result<u64, Trap>) -> Result<Option<Leaf>, Trap> {
+    // Sv32 is the only paging mode of RV32, and the only one using 32-bit PTEs
+    let is_32_bit = mode.pte_size == 4;
+    let mut table = root;
+    for level in (0..mode.levels).rev() {
+        let vpn_bits = if level == mode.levels - 1 { mode.vpn_bits + root_extra_bits } else { mode.vpn_bits };
+        let vpn = (addr >> (12 + level * mode.vpn_bits)) & ((1 << vpn_bits) - 1);
+        let pte_addr = translate_pte(memory, table + vpn * mode.pte_size as u64)?;
+
+        let pte = match read_physical(memory, pte_addr, mode.pte_size) {
+            Some(pte) if big_endian => memory::swap_endianness(pte, mode.pte_size),
+            Some(pte) => pte,
+            None => return Err(Trap::new(Exception::LoadAccessFault, addr)),
+        };
+
+        let reserved = !is_32_bit && pte >> 54 != 0;
+        if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) || reserved {
+            return Ok(None);
+        }
+
+        if pte & (PTE_R | PTE_X) == 0 {
+            // Pointer to the next level
+            table = pte_ppn(pte, is_32_bit) << 12;
+            continue;
+        }
+
+        // Superpages need to be aligned
+        let low_ppn_mask = (1u64 << (level * mode.vpn_bits)) - 1;
+        if pte_ppn(pte, is_32_bit) & low_ppn_mask != 0 {
+            return Ok(None);
+        }
+        return Ok(Some(Leaf { pte, level }));
+    }
+    Ok(None)
+}
+
+/// Calculate the physical address of a leaf PTE
+fn leaf_address(mode: PagingMode, leaf: &Leaf, addr: u64, is_32_bit: bool) -> u64 {
+    let page_bits = 12 + leaf.level * mode.vpn_bits;
+    let page_mask = (1u64 << page_bits) - 1;
+    ((pte_ppn(leaf.pte, is_32_bit) << 12) & !page_mask) | (addr & page_mask)
+}
+
+fn check_permissions(pte: u64, access: AccessType, user: bool, sum: bool, mxr: bool) -> bool {
+    let allowed = match access {
+        AccessType::Fetch          => pte & PTE_X != 0,
+        AccessType::Load           => pte & PTE_R != 0 || (mxr && pte & PTE_X != 0),
+        AccessType::LoadExecutable => pte & PTE_X != 0,
+        AccessType::Store          => pte & PTE_W != 0,
+    };
+    let privilege_ok = match (user, pte & PTE_U != 0) {
+        (true , u) => u,
+        (false, true) => sum && access != AccessType::Fetch,
+        (false, false) => true,
+    };
+    // Accessed and dirty bits are not updated by the emulator, software is required to set them (Svade)
+    let flags_ok = pte & PTE_A != 0 && (access != AccessType::Store || pte & PTE_D != 0);
+
+    allowed && privilege_ok && flags_ok
+}
+
+/// G-stage translation of a guest physical address.
+///
+/// `gva` is the guest virtual address that caused the access and `implicit` indicates that the access is a VS-stage page table access.
+fn translate_g_stage(register_file: &RegisterFile, memory: &mut dyn MemoryBus, gpa: u64, gva: u64, access: AccessType, implicit: bool) -> Result<u64, Trap> {
+    let is_32_bit = register_file.is_32_bit();
+    let csr = register_file.csr();
+
+    let guest_page_fault = || {
+        let tinst = match (implicit, is_32_bit) {
+            (false, _)     => 0,
+            (true , true ) => 0x2000,
+            (true , false) => 0x3000,
+        };
+        Trap { cause: access.guest_page_fault(), tval: gva, tval2: gpa >> 2, tinst, gva: true }
+    };
+
+    let (mode, root) = match decode_atp(csr.hgatp, is_32_bit) {
+        Some(atp) => atp,
+        None => return Ok(gpa),
+    };
+
+    // The guest physical address space is 2 bits wider than the corresponding virtual address space
+    if gpa >> (mode.va_bits + 2) != 0 {
+        return Err(guest_page_fault());
+    }
+
+    let big_endian = csr.mstatus & MSTATUS_SBE != 0;
+    let leaf = walk(memory, mode, root, gpa, 2, big_endian, &mut |_, pte_addr| Ok(pte_addr))
+        .map_err(|trap| Trap { cause: access.access_fault(), tval: gva, gva: true, ..trap })?;
+    let leaf = match leaf {
+        Some(leaf) => leaf,
+        None => return Err(guest_page_fault()),
+    };
+
+    // Implicit accesses of the VS-stage page table are treated as loads, all G-stage accesses are treated as U-mode accesses
+    let pte_access = if implicit { AccessType::Load } else { access };
+    let mxr = csr.mstatus & MSTATUS_MXR != 0;
+    if !check_permissions(leaf.pte, pte_access, true, false, mxr) {
+        return Err(guest_page_fault());
+    }
+
+    Ok(leaf_address(mode, &leaf, gpa, is_32_bit))
+}
+
+/// Translate a virtual address to a physical address using the given access mode
+pub fn translate(register_file: &RegisterFile, memory: &mut dyn MemoryBus, addr: u64, access: AccessType, mode: AccessMode) -> Result<u64, Trap> {
+    if mode.privilege == PrivilegeMode::Machine {
+        return Ok(addr);
+    }
+
+    let is_32_bit = register_file.is_32_bit();
+    let csr = register_file.csr();
+    let atp = if mode.virtualized { csr.vsatp } else { csr.satp };
+
+    let page_fault = Trap { cause: access.page_fault(), tval: addr, tval2: 0, tinst: 0, gva: mode.virtualized };
+
+    // VS-stage or single stage translation
+    let addr_after_first = match decode_atp(atp, is_32_bit) {
+        None => addr,
+        Some((paging, root)) => {
+            // Virtual addresses need to be sign-extended
+            if !is_32_bit {
+                let shift = 64 - paging.va_bits;
+                if (((addr << shift) as i64) >> shift) as u64 != addr {
+                    return Err(page_fault);
+                }
+            }
+
+            let leaf = if mode.virtualized {
+                let big_endian = csr.hstatus & HSTATUS_VSBE != 0;
+                walk(memory, paging, root, addr, 0, big_endian, &mut |memory, pte_addr| translate_g_stage(register_file, memory, pte_addr, addr, access, true))
+            } else {
+                let big_endian = csr.mstatus & MSTATUS_SBE != 0;
+                walk(memory, paging, root, addr, 0, big_endian, &mut |_, pte_addr| Ok(pte_addr))
+            };
+            let leaf = leaf.map_err(|trap| if trap.cause == Exception::LoadAccessFault {
+                Trap { cause: access.access_fault(), tval: addr, gva: mode.virtualized, ..trap }
+            } else {
+                trap
+            })?;
+            let leaf = match leaf {
+                Some(leaf) => leaf,
+                None => return Err(page_fault),
+            };
+
+            let status = if mode.virtualized { csr.vsstatus } else { csr.mstatus };
+            let sum = status & MSTATUS_SUM != 0;
+            let mxr = status & MSTATUS_MXR != 0 || csr.mstatus & MSTATUS_MXR != 0;
+            if !check_permissions(leaf.pte, access, mode.privilege == PrivilegeMode::User, sum, mxr) {
+                return Err(page_fault);
+            }
+
+            leaf_address(paging, &leaf, addr, is_32_bit)
+        },
+    };
+
+    if mode.virtualized {
+        translate_g_stage(register_file, memory, addr_after_first, addr, access, false)
+    } else {
+        Ok(addr_after_first)
+    }
+}
@@ -0,0 +1,243 @@
+use std::sync::Mutex;
+use std::sync::atomic::{self, AtomicBool, AtomicU64, Ordering};
+use std::thread;
+
+use emu_cpu::HaltReason;
+
+use crate::bus::Bus;
+use crate::devices::Htif;
+use crate::instructions::AtomicOp;
+use crate::memory::{MemoryBus, SharedRam};
+use crate::registers::RegisterFile;
+use crate::{execute_instruction, is_waiting_for_interrupt, trap, MemoryModel, RiscvEmulator};
+
+
+/// State shared by the hart threads, which is only accessed while holding the lock
+struct Devices {
+    bus: Bus,
+    htif: Option<Htif>,
+    /// Interrupt lines of every hart raised using `set_interrupt`
+    interrupt_lines: Vec<u64>,
+    /// Interrupts enabled in `mie` of every hart that is waiting for an interrupt, as of the last time the hart took the lock
+    waiting: Vec<Option<u64>>,
+    /// Ticks executed and skipped by all harts
+    ticks: u64,
+    halt: Option<HaltReason>,
+}
+
+impl Devices {
+    /// Interrupt lines of a hart, combining the lines raised using `set_interrupt` and by the devices on the bus
+    fn lines(&self, hart: usize) -> u64 {
+        self.interrupt_lines[hart] | self.bus.interrupt_lines(hart)
+    }
+
+    /// Check if all harts are waiting for an interrupt and no interrupt line wakes them up
+    fn is_idle(&self) -> bool {
+        self.waiting.iter().enumerate().all(|(hart, mie)| mie.is_some_and(|mie| self.lines(hart) & mie == 0))
+    }
+}
+
+/// Execution state shared by the hart threads
+struct Control<'a> {
+    ram: &'a SharedRam,
+    devices: Mutex<Devices>,
+    /// Remaining number of ticks, if limited
+    budget: Option<AtomicU64>,
+    /// Set when the emulator halts, which stops all harts at the end of their current batch
+    stop: AtomicBool,
+    /// Number of ticks a hart executes between taking the lock
+    quantum: u64,
+    print_instructions: bool,
+}
+
+impl Control<'_> {
+    /// Take up to `max` ticks from the budget
+    fn take_budget(&self, max: u64) -> u64 {
+        match &self.budget {
+            Some(budget) => budget.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |remaining| Some(remaining - remaining.min(max))).unwrap().min(max),
+            None => max,
+        }
+    }
+
+    /// Give back ticks that were taken from the budget, but not used
+    fn return_budget(&self, ticks: u64) {
+        if let Some(budget) = &self.budget {
+            budget.fetch_add(ticks, Ordering::SeqCst);
+        }
+    }
+
+    /// Halt the emulator, only the first halt is returned
+    fn halt(&self, devices: &mut Devices, halt: HaltReason) {
+        devices.halt.get_or_insert(halt);
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+/// View of the bus used by a hart thread, accessing RAM directly using host atomics, and the devices while holding the lock
+struct SharedBus<'a> {
+    control: &'a Control<'a>,
+    /// Reservation of the last LR to RAM, as its address, size and the loaded value, which SC compares against memory
+    reservation: Option<(u64, usize, u64)>,
+}
+
+impl MemoryBus for SharedBus<'_> {
+    fn read(&mut self, addr: u64, buf: &mut [u8]) -> bool {
+        self.control.ram.read(addr, buf) || self.control.devices.lock().unwrap().bus.read(addr, buf)
+    }
+
+    fn write(&mut self, addr: u64, buf: &[u8]) -> bool {
+        self.control.ram.write(addr, buf) || self.control.devices.lock().unwrap().bus.write(addr, buf)
+    }
+
+    fn load_reserved(&mut self, hart: usize, addr: u64, size: usize) -> Option<u64> {
+        match self.control.ram.load(addr, size) {
+            Some(value) => {
+                self.reservation = Some((addr, size, value));
+                Some(value)
+            },
+            None => self.control.devices.lock().unwrap().bus.load_reserved(hart, addr, size),
+        }
+    }
+
+    fn store_conditional(&mut self, hart: usize, addr: u64, size: usize, value: u64) -> Option<bool> {
+        match self.reservation.take() {
+            // The reservation only holds as long as memory still contains the loaded value
+            Some((reserved_addr, reserved_size, loaded)) if reserved_addr == addr && reserved_size == size => self.control.ram.compare_exchange(addr, size, loaded, value),
+            _ => self.control.devices.lock().unwrap().bus.store_conditional(hart, addr, size, value),
+        }
+    }
+
+    fn amo(&mut self, addr: u64, size: usize, op: AtomicOp, src: u64, big_endian: bool) -> Option<u64> {
+        self.control.ram.amo(addr, size, op, src, big_endian).or_else(|| self.control.devices.lock().unwrap().bus.amo(addr, size, op, src, big_endian))
+    }
+
+    fn fence(&mut self, _pred: u8, _succ: u8) {
+        atomic::fence(Ordering::SeqCst);
+    }
+
+    fn acquire(&mut self) {
+        atomic::fence(Ordering::Acquire);
+    }
+
+    fn release(&mut self) {
+        atomic::fence(Ordering::Release);
+    }
+}
+
+/// Execute a hart in batches of `quantum` ticks, advancing the devices and updating the interrupt lines of the hart between batches, until the emulator halts
+fn run_hart(control: &Control, hart: usize, register_file: &mut RegisterFile) {
+    let mut memory = SharedBus { control, reservation: None };
+    while !control.stop.load(Ordering::SeqCst) {
+        let batch = control.take_budget(control.quantum);
+        if batch == 0 {
+            control.stop.store(true, Ordering::SeqCst);
+            break;
+        }
+
+        let mut executed = 0;
+        let mut retired = 0;
+        while executed < batch && !is_waiting_for_interrupt(register_file) {
+            match execute_instruction(register_file, &mut memory, control.print_instructions) {
+                Ok(()) => retired += 1,
+                Err(trap) => trap::take_trap(register_file, trap),
+            }
+            executed += 1;
+        }
+
+        let mut devices = control.devices.lock().unwrap();
+        // The devices advance by the ticks of all harts, like when the harts are executed round-robin
+        for tick in 0..executed {
+            devices.bus.tick(tick < retired);
+        }
+        devices.ticks += executed;
+
+        let waiting = is_waiting_for_interrupt(register_file);
+        devices.waiting[hart] = waiting.then_some(register_file.csr().mie);
+        let mut unused = batch - executed;
+        if waiting && devices.is_idle() {
+            // While all harts are idle, only the devices can wake them up, so the idle time up to the next device event can be skipped
+            match devices.bus.next_event() {
+                Some(event) => {
+                    let ticks = event.min(unused);
+                    devices.bus.skip(ticks);
+                    devices.ticks += ticks;
+                    unused -= ticks;
+                },
+                None => control.halt(&mut devices, HaltReason::Idle),
+            }
+        }
+        control.return_budget(unused);
+
+        register_file.csr_mut().interrupt_lines = devices.lines(hart);
+        register_file.csr_mut().mtime = devices.bus.time();
+        if let Some(halt) = devices.bus.take_halt_request() {
+            control.halt(&mut devices, halt);
+        }
+        let Devices { bus, htif, .. } = &mut *devices;
+        if let Some(halt) = htif.as_mut().and_then(|htif| htif.poll(bus)) {
+            control.halt(&mut devices, halt);
+        }
+        drop(devices);
+
+        if waiting {
+            thread::yield_now();
+        }
+    }
+}
+
+impl RiscvEmulator {
+    /// Execute the harts in parallel, each on its own host thread, until the emulator halts or `num_instructions` ticks have been executed by all harts together.
+    ///
+    /// RAM is accessed directly by all threads using host atomics, naturally aligned accesses of up to 8 bytes are single-copy atomic and AMOs map to the host atomic of the same size.
+    /// Each hart executes `quantum` ticks between taking the lock of the devices, which advance by the ticks executed by all harts.
+    ///
+    /// Compared to the round-robin execution, the following determinism is lost:
+    /// - the interleaving of the harts depends on the host scheduler, so results of racing accesses can differ between runs
+    /// - devices, and with it time and interrupts, only advance at the end of a batch, so interrupts are taken up to `quantum` ticks later than when executing round-robin
+    /// - SC succeeds as long as memory still holds the value loaded by LR, even when other harts wrote the same value in between (ABA)
+    /// - the instruction limit is shared by all harts, so the number of instructions executed by each hart varies
+    /// - RAM is single-copy atomic for naturally aligned accesses only, misaligned and mixed-size racing accesses can tear
+    /// - the built-in SBI, which needs access to all harts, and memory models aren't supported, `execute` executes the harts round-robin when either is used
+    pub(crate) fn execute_parallel(&mut self, num_instructions: Option<u32>) -> HaltReason {
+        debug_assert!(self.sbi.is_none() && self.memory_model == MemoryModel::Sequential);
+        if let Some(halt) = self.halt.take() {
+            return halt;
+        }
+
+        let ram = self.memory.share_ram();
+        let control = Control {
+            ram: &ram,
+            devices: Mutex::new(Devices {
+                bus: std::mem::take(&mut self.memory),
+                htif: self.htif.take(),
+                interrupt_lines: self.interrupt_lines.clone(),
+                waiting: vec![None; self.harts.len()],
+                ticks: 0,
+                halt: None,
+            }),
+            budget: num_instructions.map(|count| AtomicU64::new(count as u64)),
+            stop: AtomicBool::new(false),
+            quantum: self.quantum.max(1),
+            print_instructions: self.settings.print_instructions,
+        };
+
+        thread::scope(|scope| {
+            for (hart, register_file) in self.harts.iter_mut().enumerate() {
+                let control = &control;
+                scope.spawn(move || run_hart(control, hart, register_file));
+            }
+        });
+
+        let devices = control.devices.into_inner().unwrap();
+        drop(ram);
+        self.memory = devices.bus;
+        self.memory.unshare_ram();
+        self.htif = devices.htif;
+        self.cycles += devices.ticks;
+        self.update_interrupt_lines();
+        devices.halt.unwrap_or(HaltReason::InstructionLimit)
+    }
+}
+
+#[cfg(test)]
+mod tests;
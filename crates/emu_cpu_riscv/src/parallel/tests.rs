@@ -0,0 +1,140 @@
+use emu_cpu::{CpuEmulator, EmulationSettings, MisalignedPolicy};
+
+use crate::devices::BufferBackend;
+use crate::{ExtensionIsa, VirtConfig};
+
+use super::*;
+
+// 4 harts each increment the counter at 0x100 using AMOs and the counter at 0x108 using LR/SC 1000 times
+//
+//         addi    a0, x0, 0x100
+//         addi    a1, x0, 0x108
+//         addi    a4, x0, 1000
+// loop:   addi    t2, x0, 1
+//         amoadd.w x0, t2, (a0)
+// retry:  lr.w    t0, (a1)
+//         addi    t0, t0, 1
+//         sc.w    t1, t0, (a1)
+//         bne     t1, x0, retry
+//         addi    a3, a3, 1
+//         bne     a3, a4, loop
+//         wfi
+const PARALLEL_COUNTERS: [u32; 12] = [
+    0x10000513, 0x10800593, 0x3E800713, 0x00100393, 0x0075202F, 0x1005A2AF, 0x00128293, 0x1855A32F, 0xFE031AE3, 0x00168693, 0xFEE692E3, 0x10500073,
+];
+
+fn parallel_emulator() -> RiscvEmulator {
+    let mut emulator = RiscvEmulator::new_smp(EmulationSettings { print_instructions: false, misaligned: MisalignedPolicy::Trap }, 4);
+    emulator.set_base_isa("RV64I");
+    emulator.set_extensions(ExtensionIsa::A);
+    emulator.parallel = true;
+    emulator.quantum = 64;
+    emulator.memory.ram = vec![0; 0x1000];
+    emulator.set_code(PARALLEL_COUNTERS.iter().flat_map(|instr| instr.to_le_bytes()).collect());
+    emulator.set_instruction_pointer(0);
+    emulator
+}
+
+fn read_counter(emulator: &mut RiscvEmulator, addr: u64) -> u32 {
+    let mut counter = [0; 4];
+    assert!(emulator.memory.read(addr, &mut counter));
+    u32::from_le_bytes(counter)
+}
+
+#[test]
+fn test_parallel_atomics() {
+    // No devices can wake the harts once they all wait for an interrupt
+    let mut emulator = parallel_emulator();
+    assert_eq!(emulator.execute(None), HaltReason::Idle);
+    assert_eq!(read_counter(&mut emulator, 0x100), 4000);
+    assert_eq!(read_counter(&mut emulator, 0x108), 4000);
+    for register_file in &emulator.harts {
+        assert_eq!(register_file.read_x_register(13), 1000);
+        assert_eq!(register_file.read_pc(), 0x30);
+    }
+}
+
+#[test]
+fn test_parallel_ram_restored() {
+    // RAM is moved back into the bus once the harts halt
+    let mut emulator = parallel_emulator();
+    assert_eq!(emulator.execute(None), HaltReason::Idle);
+    assert_eq!(emulator.memory.ram.len(), 0x1000);
+    assert_eq!(&emulator.memory.ram[..4], &PARALLEL_COUNTERS[0].to_le_bytes());
+}
+
+#[test]
+fn test_parallel_instruction_limit() {
+    // The instruction limit is shared by the harts
+    let mut emulator = parallel_emulator();
+    assert_eq!(emulator.execute(Some(1000)), HaltReason::InstructionLimit);
+    assert_eq!(emulator.cycles, 1000);
+    let executed = emulator.harts.iter().map(|register_file| register_file.read_x_register(13)).sum::<u64>();
+    assert!(executed > 0 && executed < 4000);
+}
+
+#[test]
+fn test_parallel_memory_model() {
+    // Memory models fall back to executing the harts round-robin
+    let run = |mut emulator: RiscvEmulator| {
+        assert_eq!(emulator.execute(None), HaltReason::Idle);
+        assert_eq!(read_counter(&mut emulator, 0x100), 4000);
+        assert_eq!(read_counter(&mut emulator, 0x108), 4000);
+    };
+
+    let mut emulator = parallel_emulator();
+    emulator.set_base_isa("RVWMO");
+    assert_eq!(emulator.memory_model, MemoryModel::Rvwmo);
+    run(emulator);
+
+    let mut emulator = parallel_emulator();
+    emulator.set_isa("rv64ia_ztso").unwrap();
+    assert_eq!(emulator.memory_model, MemoryModel::Tso);
+    run(emulator);
+}
+
+#[test]
+fn test_parallel_sbi() {
+    // The built-in SBI needs access to all harts, so the harts are executed round-robin
+    //
+    // lui   a7, 0x53525
+    // addi  a7, a7, 0x354
+    // addi  a6, x0, 0
+    // addi  a0, x0, 0
+    // addi  a1, x0, 0
+    // ecall                 ; srst: system_reset(shutdown, no reason)
+    let code = [0x535258B7u32, 0x35488893, 0x00000813, 0x00000513, 0x00000593, 0x00000073];
+    let mut config = VirtConfig::new(0x100_0000, Box::new(BufferBackend::new()));
+    config.num_harts = 2;
+    config.kernel = Some(code.iter().flat_map(|instr| instr.to_le_bytes()).collect());
+    config.builtin_sbi = true;
+    let mut emulator = RiscvEmulator::new_virt(EmulationSettings { print_instructions: false, misaligned: MisalignedPolicy::Trap }, config).unwrap();
+    emulator.parallel = true;
+    assert_eq!(emulator.execute(None), HaltReason::Exit(0));
+}
+
+#[test]
+fn test_shared_ram_unaligned_base() {
+    // Naturally aligned accesses stay aligned on the host when RAM isn't mapped at an 8-byte aligned address
+    let ram = SharedRam::new((0..0x20).collect(), 0x1004);
+    let mut buf = [0; 8];
+    assert!(ram.read(0x1008, &mut buf));
+    assert_eq!(buf, [4, 5, 6, 7, 8, 9, 10, 11]);
+    assert_eq!(ram.load(0x1008, 8), Some(u64::from_le_bytes(buf)));
+    assert_eq!(ram.amo(0x1004, 4, AtomicOp::Add, 1, false), Some(0x0302_0100));
+    assert_eq!(ram.load(0x1004, 4), Some(0x0302_0101));
+    assert_eq!(ram.compare_exchange(0x1010, 8, u64::from_le_bytes([12, 13, 14, 15, 16, 17, 18, 19]), 0), Some(true));
+
+    // Atomics that aren't naturally aligned aren't performed on RAM
+    assert_eq!(ram.load(0x1006, 4), None);
+    assert_eq!(ram.compare_exchange(0x100C, 8, 0, 0), None);
+    assert_eq!(ram.amo(0x1006, 4, AtomicOp::Swap, 0, false), None);
+
+    // Other accesses are split into bytes
+    assert!(ram.write(0x1005, &[0xAA; 4]));
+    assert!(!ram.write(0x1022, &[0; 4]));
+    let ram = ram.into_vec();
+    assert_eq!(ram.len(), 0x20);
+    assert_eq!(&ram[..6], &[1, 0xAA, 0xAA, 0xAA, 0xAA, 5]);
+    assert_eq!(&ram[0xC..0x14], &[0; 8]);
+}
@@ -1,13 +1,67 @@
 use std::fmt;
 
+use emu_cpu::MisalignedPolicy;
 use emu_utils::sign_extend_64;
 
+use crate::csr::CsrFile;
+use crate::isa::ExtensionIsa;
+use crate::trap::Trap;
 
 
+/// Privilege level a hart is running at
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum PrivilegeMode {
+    User       = 0,
+    Supervisor = 1,
+    Machine    = 3,
+}
+
+impl PrivilegeMode {
+    /// Get the privilege mode from its 2-bit encoding, the reserved encoding maps to `None`
+    pub fn from_bits(bits: u64) -> Option<Self> {
+        match bits & 0x3 {
+            0 => Some(PrivilegeMode::User),
+            1 => Some(PrivilegeMode::Supervisor),
+            3 => Some(PrivilegeMode::Machine),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for PrivilegeMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PrivilegeMode::User       => f.pad("U"),
+            PrivilegeMode::Supervisor => f.pad("S"),
+            PrivilegeMode::Machine    => f.pad("M"),
+        }
+    }
+}
+
+
+
+#[derive(Clone)]
 pub struct RegisterFile {
     is_32_bit: bool,
     x: [u64; 32],
     pc: u64,
+
+    privilege: PrivilegeMode,
+    /// Virtualization mode (V), set when running in VS- or VU-mode
+    virtualized: bool,
+    csr: CsrFile,
+    /// Enabled extensions, single-letter extensions are checked in `misa` instead
+    extensions: ExtensionIsa,
+
+    /// Trap raised by the last executed instruction, but not yet taken
+    trap: Option<Trap>,
+    /// Set by `WFI`, the hart is stalled until an interrupt becomes pending
+    waiting: bool,
+
+    /// Handling of misaligned accesses
+    misaligned_policy: MisalignedPolicy,
+    /// Number of misaligned accesses emulated while counting them
+    misaligned_accesses: u64,
 }
 
 impl RegisterFile {
@@ -16,11 +70,20 @@ impl RegisterFile {
             is_32_bit,
             x: [0;32],
             pc: 0,
+            privilege: PrivilegeMode::Machine,
+            virtualized: false,
+            csr: CsrFile::new(is_32_bit),
+            extensions: ExtensionIsa::Zicsr | ExtensionIsa::Zifencei,
+            trap: None,
+            waiting: false,
+            misaligned_policy: MisalignedPolicy::Emulate { count: false },
+            misaligned_accesses: 0,
         }
     }
 
     pub fn set_32_bit(&mut self, b: bool) {
         self.is_32_bit = b;
+        self.csr.set_32_bit(b);
         
         if b {
             for val in &mut self.x {
@@ -33,6 +96,14 @@ impl RegisterFile {
         self.is_32_bit
     }
 
+    pub fn extensions(&self) -> ExtensionIsa {
+        self.extensions
+    }
+
+    pub fn set_extensions(&mut self, extensions: ExtensionIsa) {
+        self.extensions = extensions;
+    }
+
     pub fn read_x_register(&self, index: u8) -> u64 {
         debug_assert!(index < 32);
         self.x[index as usize]
@@ -67,9 +138,74 @@ impl RegisterFile {
         assert!(size & 1 == 0, "Can only increment pc with a multiple of 2");
         self.pc += size;
     }
+
+    pub fn privilege(&self) -> PrivilegeMode {
+        self.privilege
+    }
+
+    pub fn set_privilege(&mut self, privilege: PrivilegeMode) {
+        self.privilege = privilege;
+    }
+
+    pub fn is_virtualized(&self) -> bool {
+        self.virtualized
+    }
+
+    pub fn set_virtualized(&mut self, virtualized: bool) {
+        self.virtualized = virtualized;
+    }
+
+    pub fn csr(&self) -> &CsrFile {
+        &self.csr
+    }
+
+    pub fn csr_mut(&mut self) -> &mut CsrFile {
+        &mut self.csr
+    }
+
+    /// Raise a trap, it will be taken once the current instruction has finished executing
+    pub fn raise_trap(&mut self, trap: Trap) {
+        self.trap = Some(trap);
+    }
+
+    /// Get the currently raised trap, if any
+    pub fn pending_trap(&self) -> Option<Trap> {
+        self.trap
+    }
+
+    /// Take the currently raised trap, if any
+    pub fn take_trap(&mut self) -> Option<Trap> {
+        self.trap.take()
+    }
+
+    pub fn is_waiting(&self) -> bool {
+        self.waiting
+    }
+
+    pub fn set_waiting(&mut self, waiting: bool) {
+        self.waiting = waiting;
+    }
+
+    pub fn misaligned_policy(&self) -> MisalignedPolicy {
+        self.misaligned_policy
+    }
+
+    pub fn set_misaligned_policy(&mut self, policy: MisalignedPolicy) {
+        self.misaligned_policy = policy;
+    }
+
+    /// Number of misaligned accesses emulated while counting them
+    pub fn misaligned_accesses(&self) -> u64 {
+        self.misaligned_accesses
+    }
+
+    pub fn count_misaligned_access(&mut self) {
+        self.misaligned_accesses += 1;
+    }
 }
 
 impl fmt::Display for RegisterFile {
+    #[allow(clippy::identity_op)]
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "RISC-V Register File:")?;
 
@@ -84,6 +220,7 @@ impl fmt::Display for RegisterFile {
             )?;
         }
 
-        write!(f, "    pc : {:0width$X}", self.pc)
+        writeln!(f, "    pc : {:0width$X}", self.pc)?;
+        write!(f, "    mode: {}{}", if self.virtualized { "V" } else { "" }, self.privilege)
     }
 }
\ No newline at end of file
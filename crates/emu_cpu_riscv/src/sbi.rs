@@ -0,0 +1,260 @@
+use emu_cpu::HaltReason;
+
+use crate::csr::{set_bits, S_INTERRUPTS};
+use crate::memory::MemoryBus;
+use crate::registers::PrivilegeMode;
+use crate::trap::Interrupt;
+use crate::RiscvEmulator;
+
+
+// Extension ids, passed in `a7`, with the function id in `a6`
+const EXT_LEGACY_CONSOLE_PUTCHAR: u64 = 0x01;
+const EXT_LEGACY_CONSOLE_GETCHAR: u64 = 0x02;
+const EXT_BASE: u64 = 0x10;
+const EXT_TIME: u64 = 0x5449_4D45;
+const EXT_IPI: u64 = 0x0073_5049;
+const EXT_RFENCE: u64 = 0x5246_4E43;
+const EXT_HSM: u64 = 0x0048_534D;
+const EXT_SRST: u64 = 0x5352_5354;
+
+const SBI_SUCCESS: i64 = 0;
+const SBI_ERR_NOT_SUPPORTED: i64 = -2;
+const SBI_ERR_INVALID_PARAM: i64 = -3;
+const SBI_ERR_ALREADY_AVAILABLE: i64 = -6;
+
+/// SBI v2.0
+const SBI_SPEC_VERSION: u64 = 2 << 24;
+/// Implementation id of the emulator, which is not a registered id
+const SBI_IMPL_ID: u64 = 0x0045_4D55;
+const SBI_IMPL_VERSION: u64 = 1;
+
+const HSM_STATE_STARTED: u64 = 0;
+const HSM_STATE_STOPPED: u64 = 1;
+const HSM_SUSPEND_RETENTIVE: u64 = 0x0000_0000;
+const HSM_SUSPEND_NON_RETENTIVE: u64 = 0x8000_0000;
+
+const SRST_TYPE_SHUTDOWN: u64 = 0;
+const SRST_TYPE_COLD_REBOOT: u64 = 1;
+const SRST_TYPE_WARM_REBOOT: u64 = 2;
+const SRST_REASON_NONE: u64 = 0;
+
+// Registers of the devices used by the SBI implementation
+const CLINT_MSIP: u64 = 0x0000;
+const CLINT_MTIMECMP: u64 = 0x4000;
+const UART_RBR_THR: u64 = 0;
+const UART_LSR: u64 = 5;
+const UART_LSR_DR: u8 = 1 << 0;
+
+/// Exceptions delegated to S-mode when using the built-in SBI, which are all delegable exceptions except `ecall`s from HS-mode
+const SBI_MEDELEG: u64 = 0xB1FF;
+/// Exceptions delegated to S-mode when using the built-in SBI with the hypervisor extension, including `ecall`s from VS-mode and guest-page faults
+const SBI_MEDELEG_H: u64 = 0xF0B5FF;
+
+/// Built-in implementation of the supervisor binary interface, replacing the M-mode firmware.
+///
+/// Implements the base, TIME, IPI, RFENCE, HSM and SRST extensions, and the legacy console extensions.
+/// Timers and IPIs use the CLINT, with its machine interrupts being forwarded as supervisor interrupts, and the console uses a NS16550A UART
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Sbi {
+    pub clint_base: u64,
+    pub uart_base: Option<u64>,
+}
+
+impl Sbi {
+    pub fn new(clint_base: u64, uart_base: Option<u64>) -> Self {
+        Self { clint_base, uart_base }
+    }
+}
+
+impl RiscvEmulator {
+    /// Handle the SBI calls of S-mode in the emulator, and start running in S-mode at `entry` on the current hart, with the hart id in `a0` and `opaque` (usually the devicetree) in `a1`.
+    ///
+    /// The other harts are stopped, until they are started using the HSM extension
+    pub fn start_with_sbi(&mut self, sbi: Sbi, entry: u64, opaque: u64) {
+        self.sbi = Some(sbi);
+
+        let boot_hart = self.current_hart;
+        for hart in 0..self.harts.len() {
+            self.stopped[hart] = hart != boot_hart;
+        }
+        self.start_sbi_hart(boot_hart, entry, opaque);
+    }
+
+    /// Start a hart in S-mode at `entry`, with the hart id in `a0` and `opaque` in `a1`
+    fn start_sbi_hart(&mut self, hart: usize, entry: u64, opaque: u64) {
+        let register_file = &mut self.harts[hart];
+        let csr = register_file.csr_mut();
+        csr.medeleg = if csr.has_extension('H') { SBI_MEDELEG_H } else { SBI_MEDELEG };
+        csr.mideleg = S_INTERRUPTS;
+        csr.mcounteren = u32::MAX as u64;
+        register_file.set_privilege(PrivilegeMode::Supervisor);
+        register_file.set_waiting(false);
+        register_file.write_x_register(10, hart as u64);
+        register_file.write_x_register(11, opaque);
+        register_file.write_pc(entry);
+        self.stopped[hart] = false;
+    }
+
+    /// Forward the machine timer and software interrupts of the CLINT on a hart as supervisor interrupts, as the M-mode firmware would.
+    ///
+    /// Returns the remaining interrupt lines
+    pub(crate) fn forward_sbi_interrupts(&mut self, hart: usize, lines: u64) -> u64 {
+        let Some(sbi) = self.sbi else { return lines; };
+
+        if lines & Interrupt::MachineTimer.mask() != 0 {
+            // Disable the timer until S-mode sets a new one
+            self.memory.write(sbi.clint_base + CLINT_MTIMECMP + 8 * hart as u64, &u64::MAX.to_le_bytes());
+            self.set_supervisor_pending(hart, Interrupt::SupervisorTimer, true);
+        }
+        if lines & Interrupt::MachineSoftware.mask() != 0 {
+            self.memory.write(sbi.clint_base + CLINT_MSIP + 4 * hart as u64, &0u32.to_le_bytes());
+            self.set_supervisor_pending(hart, Interrupt::SupervisorSoftware, true);
+        }
+        lines & !(Interrupt::MachineTimer.mask() | Interrupt::MachineSoftware.mask())
+    }
+
+    fn set_supervisor_pending(&mut self, hart: usize, interrupt: Interrupt, pending: bool) {
+        let csr = self.harts[hart].csr_mut();
+        csr.mip = set_bits(csr.mip, interrupt.mask(), pending);
+    }
+
+    /// Handle an `ecall` from S-mode on the current hart, returns `false` if the built-in SBI is not used
+    pub(crate) fn handle_sbi_call(&mut self) -> bool {
+        let Some(sbi) = self.sbi else { return false; };
+
+        let hart = self.current_hart;
+        let register_file = &mut self.harts[hart];
+        let args: [u64; 6] = std::array::from_fn(|idx| register_file.read_x_register(10 + idx as u8));
+        let ext = register_file.read_x_register(17);
+        let func = register_file.read_x_register(16);
+        let pc = register_file.read_pc();
+        register_file.write_pc(pc + 4);
+
+        // Legacy extensions only return a value in `a0`
+        match ext {
+            EXT_LEGACY_CONSOLE_PUTCHAR => {
+                if let Some(uart) = sbi.uart_base {
+                    self.memory.write(uart + UART_RBR_THR, &[args[0] as u8]);
+                }
+                self.harts[hart].write_x_register(10, SBI_SUCCESS as u64);
+                return true;
+            },
+            EXT_LEGACY_CONSOLE_GETCHAR => {
+                let byte = sbi.uart_base.and_then(|uart| {
+                    let mut lsr = [0];
+                    self.memory.read(uart + UART_LSR, &mut lsr);
+                    let mut byte = [0];
+                    (lsr[0] & UART_LSR_DR != 0 && self.memory.read(uart + UART_RBR_THR, &mut byte)).then_some(byte[0])
+                });
+                self.harts[hart].write_x_register(10, byte.map_or(-1i64 as u64, |byte| byte as u64));
+                return true;
+            },
+            _ => {},
+        }
+
+        let (error, value) = match (ext, func) {
+            (EXT_BASE, 0) => (SBI_SUCCESS, SBI_SPEC_VERSION),
+            (EXT_BASE, 1) => (SBI_SUCCESS, SBI_IMPL_ID),
+            (EXT_BASE, 2) => (SBI_SUCCESS, SBI_IMPL_VERSION),
+            (EXT_BASE, 3) => (SBI_SUCCESS, Self::sbi_has_extension(args[0]) as u64),
+            // mvendorid, marchid and mimpid
+            (EXT_BASE, 4..=6) => (SBI_SUCCESS, 0),
+
+            (EXT_TIME, 0) => {
+                self.set_supervisor_pending(hart, Interrupt::SupervisorTimer, false);
+                self.memory.write(sbi.clint_base + CLINT_MTIMECMP + 8 * hart as u64, &args[0].to_le_bytes());
+                (SBI_SUCCESS, 0)
+            },
+
+            (EXT_IPI, 0) => match self.sbi_harts(args[0], args[1]) {
+                Some(harts) => {
+                    for hart in harts {
+                        self.memory.write(sbi.clint_base + CLINT_MSIP + 4 * hart, &1u32.to_le_bytes());
+                    }
+                    (SBI_SUCCESS, 0)
+                },
+                None => (SBI_ERR_INVALID_PARAM, 0),
+            },
+
+            // Address translations are not cached, so fences only need to check the harts
+            (EXT_RFENCE, 0..=2) => match self.sbi_harts(args[0], args[1]) {
+                Some(_) => (SBI_SUCCESS, 0),
+                None => (SBI_ERR_INVALID_PARAM, 0),
+            },
+            (EXT_RFENCE, 3..=6) if self.harts[hart].csr().has_extension('H') => match self.sbi_harts(args[0], args[1]) {
+                Some(_) => (SBI_SUCCESS, 0),
+                None => (SBI_ERR_INVALID_PARAM, 0),
+            },
+
+            (EXT_HSM, 0) => match self.stopped.get(args[0] as usize) {
+                Some(true) => {
+                    self.start_sbi_hart(args[0] as usize, args[1], args[2]);
+                    (SBI_SUCCESS, 0)
+                },
+                Some(false) => (SBI_ERR_ALREADY_AVAILABLE, 0),
+                None => (SBI_ERR_INVALID_PARAM, 0),
+            },
+            (EXT_HSM, 1) => {
+                // A stopped hart doesn't return from the call
+                self.stopped[hart] = true;
+                return true;
+            },
+            (EXT_HSM, 2) => match self.stopped.get(args[0] as usize) {
+                Some(&stopped) => (SBI_SUCCESS, if stopped { HSM_STATE_STOPPED } else { HSM_STATE_STARTED }),
+                None => (SBI_ERR_INVALID_PARAM, 0),
+            },
+            (EXT_HSM, 3) => match args[0] {
+                HSM_SUSPEND_RETENTIVE => {
+                    self.harts[hart].set_waiting(true);
+                    (SBI_SUCCESS, 0)
+                },
+                HSM_SUSPEND_NON_RETENTIVE => {
+                    // The hart resumes at `resume_addr` with the hart id in `a0` and `opaque` in `a1`, instead of returning from the call
+                    let register_file = &mut self.harts[hart];
+                    register_file.write_pc(args[1]);
+                    register_file.write_x_register(10, hart as u64);
+                    register_file.write_x_register(11, args[2]);
+                    register_file.set_waiting(true);
+                    return true;
+                },
+                _ => (SBI_ERR_INVALID_PARAM, 0),
+            },
+
+            (EXT_SRST, 0) => match args[0] {
+                SRST_TYPE_SHUTDOWN => {
+                    self.halt = Some(HaltReason::Exit(if args[1] == SRST_REASON_NONE { 0 } else { 1 }));
+                    (SBI_SUCCESS, 0)
+                },
+                SRST_TYPE_COLD_REBOOT | SRST_TYPE_WARM_REBOOT => {
+                    self.halt = Some(HaltReason::Reset);
+                    (SBI_SUCCESS, 0)
+                },
+                _ => (SBI_ERR_INVALID_PARAM, 0),
+            },
+
+            _ => (SBI_ERR_NOT_SUPPORTED, 0),
+        };
+        let register_file = &mut self.harts[hart];
+        register_file.write_x_register(10, error as u64);
+        register_file.write_x_register(11, value);
+        true
+    }
+
+    fn sbi_has_extension(ext: u64) -> bool {
+        matches!(ext, EXT_LEGACY_CONSOLE_PUTCHAR | EXT_LEGACY_CONSOLE_GETCHAR | EXT_BASE | EXT_TIME | EXT_IPI | EXT_RFENCE | EXT_HSM | EXT_SRST)
+    }
+
+    /// Decode a hart mask, `None` if it contains harts that don't exist
+    fn sbi_harts(&self, mask: u64, mask_base: u64) -> Option<Vec<u64>> {
+        let num_harts = self.harts.len() as u64;
+        // A base of -1 selects all harts
+        if mask_base == u64::MAX {
+            return Some((0..num_harts).collect());
+        }
+        let harts = (0..64).filter(|bit| mask & (1 << bit) != 0).map(|bit| mask_base.saturating_add(bit)).collect::<Vec<_>>();
+        harts.iter().all(|&hart| hart < num_harts).then_some(harts)
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,114 @@
+use emu_cpu::{CpuEmulator, EmulationSettings, MisalignedPolicy};
+
+use crate::devices::BufferBackend;
+use crate::{VirtConfig, VIRT_RAM_BASE, VIRT_CLINT_BASE};
+
+use super::*;
+
+const KERNEL: u64 = VIRT_RAM_BASE + 0x20_0000;
+
+// lui   a7, 0x53525
+// addi  a7, a7, 0x354
+// addi  a6, x0, 0
+// addi  a0, x0, 0
+// addi  a1, x0, 0
+// ecall                 ; srst: system_reset(shutdown, no reason)
+const SHUTDOWN: [u32; 6] = [0x535258B7, 0x35488893, 0x00000813, 0x00000513, 0x00000593, 0x00000073];
+
+/// Boot `code` as the kernel of a virt machine using the built-in SBI
+fn boot(code: &[u32], console: BufferBackend) -> RiscvEmulator {
+    let mut config = VirtConfig::new(0x100_0000, Box::new(console));
+    config.kernel = Some(code.iter().flat_map(|instr| instr.to_le_bytes()).collect());
+    config.builtin_sbi = true;
+    RiscvEmulator::new_virt(EmulationSettings { print_instructions: false, misaligned: MisalignedPolicy::Trap }, config).unwrap()
+}
+
+#[test]
+fn test_sbi_boot() {
+    let emulator = boot(&SHUTDOWN, BufferBackend::new());
+    assert_eq!(emulator.harts[0].read_pc(), KERNEL);
+    assert_eq!(emulator.harts[0].privilege(), PrivilegeMode::Supervisor);
+    assert_eq!(emulator.harts[0].read_x_register(10), 0);
+    assert!(emulator.harts[0].read_x_register(11) > KERNEL);
+}
+
+#[test]
+fn test_sbi_base() {
+    // addi  a7, x0, 0x10
+    // addi  a6, x0, 0
+    // ecall                 ; base: get_spec_version
+    let mut emulator = boot(&[0x01000893, 0x00000813, 0x00000073], BufferBackend::new());
+    assert_eq!(emulator.execute(Some(3)), HaltReason::InstructionLimit);
+    assert_eq!(emulator.harts[0].read_pc(), KERNEL + 12);
+    assert_eq!(emulator.harts[0].read_x_register(10), SBI_SUCCESS as u64);
+    assert_eq!(emulator.harts[0].read_x_register(11), SBI_SPEC_VERSION);
+}
+
+#[test]
+fn test_sbi_legacy_console() {
+    // addi  a7, x0, 1
+    // addi  a0, x0, 0x68
+    // ecall                 ; legacy: console_putchar('h')
+    // addi  a7, x0, 2
+    // ecall                 ; legacy: console_getchar
+    let console = BufferBackend::new();
+    console.push_input(b"x");
+    let mut emulator = boot(&[0x00100893, 0x06800513, 0x00000073, 0x00200893, 0x00000073], console.clone());
+    assert_eq!(emulator.execute(Some(5)), HaltReason::InstructionLimit);
+    assert_eq!(console.take_output(), b"h");
+    assert_eq!(emulator.harts[0].read_x_register(10), b'x' as u64);
+}
+
+#[test]
+fn test_sbi_set_timer() {
+    // lui   a7, 0x54494
+    // addi  a7, a7, 0x7FF
+    // addi  a7, a7, 0x546
+    // addi  a6, x0, 0
+    // addi  a0, x0, 100
+    // ecall                 ; time: set_timer(100)
+    // addi  t0, x0, 0x20
+    // csrrs x0, sie, t0
+    // wfi
+    // csrrs t1, sip, x0
+    let code = [0x544948B7, 0x7FF88893, 0x54688893, 0x00000813, 0x06400513, 0x00000073, 0x02000293, 0x1042A073, 0x10500073, 0x14402373];
+    let mut emulator = boot(&[&code[..], &SHUTDOWN].concat(), BufferBackend::new());
+    assert_eq!(emulator.execute(Some(6)), HaltReason::InstructionLimit);
+    assert_eq!(emulator.harts[0].read_x_register(10), SBI_SUCCESS as u64);
+    assert_eq!(emulator.harts[0].csr().mip() & Interrupt::SupervisorTimer.mask(), 0);
+
+    // The timer interrupt wakes up the hart as a supervisor interrupt
+    assert_eq!(emulator.execute(None), HaltReason::Exit(0));
+    assert_eq!(emulator.harts[0].read_x_register(6), Interrupt::SupervisorTimer.mask());
+    let mut mtime = [0; 8];
+    assert!(emulator.memory.read(VIRT_CLINT_BASE + 0xBFF8, &mut mtime));
+    assert!(u64::from_le_bytes(mtime) >= 100);
+}
+
+#[test]
+fn test_sbi_counters() {
+    // rdtime    a0
+    // rdinstret a1
+    // rdtime    a2
+    // rdcycle   a3
+    let mut emulator = boot(&[0xC0102573, 0xC02025F3, 0xC0102673, 0xC00026F3], BufferBackend::new());
+    assert_eq!(emulator.execute(Some(4)), HaltReason::InstructionLimit);
+    assert_eq!(emulator.harts[0].privilege(), PrivilegeMode::Supervisor);
+    assert_eq!(emulator.harts[0].read_pc(), KERNEL + 16);
+
+    // `time` is `mtime` of the CLINT, which advances with every retired instruction
+    let mut mtime = [0; 8];
+    assert!(emulator.memory.read(VIRT_CLINT_BASE + 0xBFF8, &mut mtime));
+    let time = emulator.harts[0].read_x_register(10);
+    assert_eq!(emulator.harts[0].read_x_register(12), time + 2);
+    assert_eq!(u64::from_le_bytes(mtime), time + 4);
+
+    assert_eq!(emulator.harts[0].read_x_register(11), 1);
+    assert_eq!(emulator.harts[0].read_x_register(13), 4);
+}
+
+#[test]
+fn test_sbi_system_reset() {
+    let mut emulator = boot(&SHUTDOWN, BufferBackend::new());
+    assert_eq!(emulator.execute(None), HaltReason::Exit(0));
+}
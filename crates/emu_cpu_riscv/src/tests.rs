@@ -0,0 +1,85 @@
+use emu_cpu::MisalignedPolicy;
+
+use crate::devices::{Clint, Timebase, CLINT_SIZE};
+use crate::trap::Interrupt;
+
+use super::*;
+
+// Both harts increment the counter at 0x100 3 times, counting the failed SCs in a2, after which hart 0 sends an IPI to hart 1
+//
+//         addi  a0, x0, 0x100
+//         addi  a4, x0, 3
+// loop:   lr.w  t0, (a0)
+//         addi  t0, t0, 1
+//         sc.w  t1, t0, (a0)
+//         beq   t1, x0, done
+//         addi  a2, a2, 1
+//         beq   x0, x0, loop
+// done:   addi  a3, a3, 1
+//         bne   a3, a4, loop
+//         lui   t2, 0x2000
+//         addi  t2, t2, 4         ; msip of hart 1
+//         csrrs t4, mhartid, x0
+//         bne   t4, x0, hart1
+//         addi  t3, x0, 1
+//         sw    t3, 0(t2)
+//         wfi
+// hart1:  addi  t5, x0, 8
+//         csrrs x0, mie, t5
+//         wfi
+//         csrrs a5, mip, x0
+//         sw    x0, 0(t2)
+//         wfi
+const SMP_COUNTER: [u32; 23] = [
+    0x10000513, 0x00300713, 0x100522AF, 0x00128293, 0x1855232F, 0x00030663, 0x00160613, 0xFE0006E3, 0x00168693, 0xFEE692E3, 0x020003B7, 0x00438393,
+    0xF1402EF3, 0x000E9863, 0x00100E13, 0x01C3A023, 0x10500073, 0x00800F13, 0x304F2073, 0x10500073, 0x344027F3, 0x0003A023, 0x10500073,
+];
+
+/// Execute `SMP_COUNTER` on 2 harts scheduled for `quantum` ticks at a time
+fn run_smp_counter(quantum: u64) -> RiscvEmulator {
+    let mut emulator = RiscvEmulator::new_smp(EmulationSettings { print_instructions: false, misaligned: MisalignedPolicy::Trap }, 2);
+    emulator.set_base_isa("RV64I");
+    emulator.set_extensions(ExtensionIsa::A | ExtensionIsa::Zicsr);
+    emulator.quantum = quantum;
+    emulator.memory.ram = vec![0; 0x1000];
+    emulator.memory.add_device(0x200_0000, CLINT_SIZE, Box::new(Clint::new(2, Timebase::InstructionsRetired)));
+    emulator.set_code(SMP_COUNTER.iter().flat_map(|instr| instr.to_le_bytes()).collect());
+    emulator.set_instruction_pointer(0);
+
+    assert_eq!(emulator.execute(Some(1000)), HaltReason::InstructionLimit);
+    let mut counter = [0; 4];
+    assert!(emulator.memory.read(0x100, &mut counter));
+    assert_eq!(u32::from_le_bytes(counter), 6);
+    assert_eq!(emulator.harts[0].read_x_register(13), 3);
+    assert_eq!(emulator.harts[1].read_x_register(13), 3);
+    emulator
+}
+
+#[test]
+fn test_smp_mhartid() {
+    let emulator = run_smp_counter(1);
+    assert_eq!(emulator.harts[0].csr().mhartid, 0);
+    assert_eq!(emulator.harts[1].csr().mhartid, 1);
+}
+
+#[test]
+fn test_smp_interleaved() {
+    // With a quantum of 1, the harts interleave, so SCs fail because the other hart wrote the counter
+    let emulator = run_smp_counter(1);
+    assert!(emulator.harts[0].read_x_register(12) + emulator.harts[1].read_x_register(12) > 0);
+}
+
+#[test]
+fn test_smp_quantum() {
+    // With a quantum larger than the loop, a hart finishes before the next hart runs and no SC fails
+    let emulator = run_smp_counter(100);
+    assert_eq!(emulator.harts[0].read_x_register(12), 0);
+    assert_eq!(emulator.harts[1].read_x_register(12), 0);
+}
+
+#[test]
+fn test_smp_ipi() {
+    let emulator = run_smp_counter(1);
+    assert_eq!(emulator.harts[1].read_x_register(15) & Interrupt::MachineSoftware.mask(), Interrupt::MachineSoftware.mask());
+    assert_eq!(emulator.harts[1].read_pc(), 0x5C);
+}
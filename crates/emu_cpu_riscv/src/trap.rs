@@ -0,0 +1,325 @@
+use std::fmt;
+
+use crate::csr::*;
+use crate::registers::{RegisterFile, PrivilegeMode};
+
+
+/// Synchronous exception causes, as written to the `xcause` registers
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Exception {
+    InstructionAddressMisaligned = 0,
+    InstructionAccessFault       = 1,
+    IllegalInstruction           = 2,
+    Breakpoint                   = 3,
+    LoadAddressMisaligned        = 4,
+    LoadAccessFault              = 5,
+    StoreAddressMisaligned       = 6,
+    StoreAccessFault             = 7,
+    /// Environment call from U-mode or VU-mode
+    UserEnvironmentCall          = 8,
+    /// Environment call from HS-mode
+    SupervisorEnvironmentCall    = 9,
+    /// Environment call from VS-mode
+    VirtualSupervisorEnvironmentCall = 10,
+    MachineEnvironmentCall       = 11,
+    InstructionPageFault         = 12,
+    LoadPageFault                = 13,
+    StorePageFault               = 15,
+    InstructionGuestPageFault    = 20,
+    LoadGuestPageFault           = 21,
+    VirtualInstruction           = 22,
+    StoreGuestPageFault          = 23,
+}
+
+impl Exception {
+    pub fn code(self) -> u64 {
+        self as u64
+    }
+}
+
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Exception::InstructionAddressMisaligned     => f.pad("instruction address misaligned"),
+            Exception::InstructionAccessFault           => f.pad("instruction access fault"),
+            Exception::IllegalInstruction               => f.pad("illegal instruction"),
+            Exception::Breakpoint                       => f.pad("breakpoint"),
+            Exception::LoadAddressMisaligned            => f.pad("load address misaligned"),
+            Exception::LoadAccessFault                  => f.pad("load access fault"),
+            Exception::StoreAddressMisaligned           => f.pad("store/AMO address misaligned"),
+            Exception::StoreAccessFault                 => f.pad("store/AMO access fault"),
+            Exception::UserEnvironmentCall              => f.pad("environment call from U-mode"),
+            Exception::SupervisorEnvironmentCall        => f.pad("environment call from HS-mode"),
+            Exception::VirtualSupervisorEnvironmentCall => f.pad("environment call from VS-mode"),
+            Exception::MachineEnvironmentCall           => f.pad("environment call from M-mode"),
+            Exception::InstructionPageFault             => f.pad("instruction page fault"),
+            Exception::LoadPageFault                    => f.pad("load page fault"),
+            Exception::StorePageFault                   => f.pad("store/AMO page fault"),
+            Exception::InstructionGuestPageFault        => f.pad("instruction guest-page fault"),
+            Exception::LoadGuestPageFault               => f.pad("load guest-page fault"),
+            Exception::VirtualInstruction               => f.pad("virtual instruction"),
+            Exception::StoreGuestPageFault              => f.pad("store/AMO guest-page fault"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Trap {
+    pub cause: Exception,
+    /// Value written to `xtval`
+    pub tval: u64,
+    /// Guest physical address shifted right by 2, written to `mtval2`/`htval` for guest-page faults
+    pub tval2: u64,
+    /// Transformed instruction, written to `mtinst`/`htinst`
+    pub tinst: u64,
+    /// Whether `tval` holds a guest virtual address
+    pub gva: bool,
+}
+
+impl Trap {
+    pub fn new(cause: Exception, tval: u64) -> Self {
+        Self { cause, tval, tval2: 0, tinst: 0, gva: false }
+    }
+
+    pub fn illegal_instruction() -> Self {
+        Self::new(Exception::IllegalInstruction, 0)
+    }
+
+    pub fn virtual_instruction() -> Self {
+        Self::new(Exception::VirtualInstruction, 0)
+    }
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (tval: {:X})", self.cause, self.tval)
+    }
+}
+
+/// Interrupt causes, as written to the `xcause` registers
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Interrupt {
+    SupervisorSoftware        = 1,
+    VirtualSupervisorSoftware = 2,
+    MachineSoftware           = 3,
+    SupervisorTimer           = 5,
+    VirtualSupervisorTimer    = 6,
+    MachineTimer              = 7,
+    SupervisorExternal        = 9,
+    VirtualSupervisorExternal = 10,
+    MachineExternal           = 11,
+    SupervisorGuestExternal   = 12,
+}
+
+impl Interrupt {
+    pub fn code(self) -> u64 {
+        self as u64
+    }
+
+    /// Get the bit of the interrupt in `mip` and `mie`
+    pub fn mask(self) -> u64 {
+        1 << self.code()
+    }
+}
+
+impl fmt::Display for Interrupt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Interrupt::SupervisorSoftware        => f.pad("supervisor software interrupt"),
+            Interrupt::VirtualSupervisorSoftware => f.pad("virtual supervisor software interrupt"),
+            Interrupt::MachineSoftware           => f.pad("machine software interrupt"),
+            Interrupt::SupervisorTimer           => f.pad("supervisor timer interrupt"),
+            Interrupt::VirtualSupervisorTimer    => f.pad("virtual supervisor timer interrupt"),
+            Interrupt::MachineTimer              => f.pad("machine timer interrupt"),
+            Interrupt::SupervisorExternal        => f.pad("supervisor external interrupt"),
+            Interrupt::VirtualSupervisorExternal => f.pad("virtual supervisor external interrupt"),
+            Interrupt::MachineExternal           => f.pad("machine external interrupt"),
+            Interrupt::SupervisorGuestExternal   => f.pad("supervisor guest external interrupt"),
+        }
+    }
+}
+
+/// Privilege mode a trap is taken into
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum TrapTarget {
+    Machine,
+    Supervisor,
+    VirtualSupervisor,
+}
+
+/// Take a trap: select the privilege mode handling it (following `medeleg` and `hedeleg`), update the trap CSRs and jump to the trap vector
+pub fn take_trap(register_file: &mut RegisterFile, trap: Trap) {
+    let code = trap.cause.code();
+
+    let csr = register_file.csr();
+    let delegated_to_s = register_file.privilege() != PrivilegeMode::Machine && (csr.medeleg >> code) & 1 == 1;
+    let delegated_to_vs = delegated_to_s && register_file.is_virtualized() && (csr.hedeleg >> code) & 1 == 1;
+
+    let target = match (delegated_to_s, delegated_to_vs) {
+        (_    , true ) => TrapTarget::VirtualSupervisor,
+        (true , false) => TrapTarget::Supervisor,
+        (false, false) => TrapTarget::Machine,
+    };
+    enter_trap(register_file, target, code, Some(&trap));
+}
+
+/// Take an interrupt, `interrupt` should be the result of `pending_interrupt`
+pub fn take_interrupt(register_file: &mut RegisterFile, interrupt: Interrupt) {
+    let code = interrupt.code();
+
+    let csr = register_file.csr();
+    let target = if (csr.mideleg() >> code) & 1 == 0 {
+        TrapTarget::Machine
+    } else if (csr.hideleg >> code) & 1 == 0 {
+        TrapTarget::Supervisor
+    } else {
+        TrapTarget::VirtualSupervisor
+    };
+
+    // VS-level interrupts are seen by the guest as the corresponding S-level interrupts
+    let code = if target == TrapTarget::VirtualSupervisor { code - 1 } else { code };
+    enter_trap(register_file, target, code, None);
+}
+
+/// Update the trap CSRs of the target privilege mode and jump to its trap vector, `trap` is `None` for interrupts
+fn enter_trap(register_file: &mut RegisterFile, target: TrapTarget, code: u64, trap: Option<&Trap>) {
+    let pc = register_file.read_pc();
+    let privilege = register_file.privilege();
+    let virtualized = register_file.is_virtualized();
+
+    let interrupt = trap.is_none();
+    let interrupt_bit = if register_file.is_32_bit() { 1 << 31 } else { 1 << 63 };
+    let cause = if interrupt { interrupt_bit | code } else { code };
+    let (tval, tval2, tinst, gva) = trap.map_or((0, 0, 0, false), |trap| (trap.tval, trap.tval2, trap.tinst, trap.gva));
+
+    let csr = register_file.csr_mut();
+    let tvec = match target {
+        TrapTarget::VirtualSupervisor => {
+            csr.vsepc = pc;
+            csr.vscause = cause;
+            csr.vstval = tval;
+
+            let sie = csr.vsstatus & MSTATUS_SIE != 0;
+            csr.vsstatus = set_bits(csr.vsstatus, MSTATUS_SPIE, sie);
+            csr.vsstatus &= !MSTATUS_SIE;
+            csr.vsstatus = set_bits(csr.vsstatus, MSTATUS_SPP, privilege == PrivilegeMode::Supervisor);
+
+            csr.vstvec
+        },
+        TrapTarget::Supervisor => {
+            csr.sepc = pc;
+            csr.scause = cause;
+            csr.stval = tval;
+            csr.htval = tval2;
+            csr.htinst = tinst;
+
+            csr.hstatus = set_bits(csr.hstatus, HSTATUS_SPV, virtualized);
+            csr.hstatus = set_bits(csr.hstatus, HSTATUS_GVA, gva);
+            if virtualized {
+                csr.hstatus = set_bits(csr.hstatus, HSTATUS_SPVP, privilege == PrivilegeMode::Supervisor);
+            }
+
+            let sie = csr.mstatus & MSTATUS_SIE != 0;
+            csr.mstatus = set_bits(csr.mstatus, MSTATUS_SPIE, sie);
+            csr.mstatus &= !MSTATUS_SIE;
+            csr.mstatus = set_bits(csr.mstatus, MSTATUS_SPP, privilege == PrivilegeMode::Supervisor);
+
+            csr.stvec
+        },
+        TrapTarget::Machine => {
+            csr.mepc = pc;
+            csr.mcause = cause;
+            csr.mtval = tval;
+            csr.mtval2 = tval2;
+            csr.mtinst = tinst;
+
+            csr.mstatus = set_bits(csr.mstatus, MSTATUS_MPV, virtualized);
+            csr.mstatus = set_bits(csr.mstatus, MSTATUS_GVA, gva);
+
+            let mie = csr.mstatus & MSTATUS_MIE != 0;
+            csr.mstatus = set_bits(csr.mstatus, MSTATUS_MPIE, mie);
+            csr.mstatus &= !MSTATUS_MIE;
+            csr.mstatus = (csr.mstatus & !MSTATUS_MPP) | ((privilege as u64) << MSTATUS_MPP_SHIFT);
+
+            csr.mtvec
+        },
+    };
+
+    match target {
+        TrapTarget::VirtualSupervisor => register_file.set_privilege(PrivilegeMode::Supervisor),
+        TrapTarget::Supervisor => {
+            register_file.set_privilege(PrivilegeMode::Supervisor);
+            register_file.set_virtualized(false);
+        },
+        TrapTarget::Machine => {
+            register_file.set_privilege(PrivilegeMode::Machine);
+            register_file.set_virtualized(false);
+        },
+    }
+
+    // In vectored mode, interrupts jump to `BASE + 4 * cause`
+    let base = tvec & !0x3;
+    if interrupt && tvec & 0x3 == 1 {
+        register_file.write_pc(base.wrapping_add(4 * code));
+    } else {
+        register_file.write_pc(base);
+    }
+}
+
+/// Get the highest priority interrupt that is pending, enabled and not masked by the current privilege mode
+pub fn pending_interrupt(register_file: &RegisterFile) -> Option<Interrupt> {
+    let privilege = register_file.privilege();
+    let virtualized = register_file.is_virtualized();
+    let csr = register_file.csr();
+
+    let pending = csr.mip() & csr.mie;
+    if pending == 0 {
+        return None;
+    }
+
+    let mideleg = csr.mideleg();
+    let hideleg = if csr.has_extension('H') { csr.hideleg } else { 0 };
+
+    // Interrupts for a higher privilege mode are always enabled, interrupts for the current privilege mode depend on `xstatus.xIE`
+    let m_enabled = privilege != PrivilegeMode::Machine || csr.mstatus & MSTATUS_MIE != 0;
+    let s_enabled = match (privilege, virtualized) {
+        (PrivilegeMode::Machine, _) => false,
+        (PrivilegeMode::Supervisor, false) => csr.mstatus & MSTATUS_SIE != 0,
+        _ => true,
+    };
+    let vs_enabled = match (privilege, virtualized) {
+        (PrivilegeMode::Supervisor, true) => csr.vsstatus & MSTATUS_SIE != 0,
+        (PrivilegeMode::User, true) => true,
+        _ => false,
+    };
+
+    let mut enabled = 0;
+    if m_enabled {
+        enabled |= pending & !mideleg;
+    }
+    if s_enabled {
+        enabled |= pending & mideleg & !hideleg;
+    }
+    if vs_enabled {
+        enabled |= pending & mideleg & hideleg;
+    }
+
+    // Interrupts taken into a higher privilege mode have priority, followed by the fixed priority order of the interrupts
+    const PRIORITY: [Interrupt; 10] = [
+        Interrupt::MachineExternal,
+        Interrupt::MachineSoftware,
+        Interrupt::MachineTimer,
+        Interrupt::SupervisorExternal,
+        Interrupt::SupervisorSoftware,
+        Interrupt::SupervisorTimer,
+        Interrupt::SupervisorGuestExternal,
+        Interrupt::VirtualSupervisorExternal,
+        Interrupt::VirtualSupervisorSoftware,
+        Interrupt::VirtualSupervisorTimer,
+    ];
+
+    let find = |pending: u64| PRIORITY.iter().copied().find(|interrupt| (pending >> interrupt.code()) & 1 == 1);
+    find(enabled & !mideleg)
+        .or_else(|| find(enabled & mideleg & !hideleg))
+        .or_else(|| find(enabled & mideleg & hideleg))
+}
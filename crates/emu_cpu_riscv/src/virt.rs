@@ -0,0 +1,250 @@
+use std::fmt;
+
+use emu_cpu::{CpuEmulator, EmulationSettings};
+
+use crate::devices::{Device, SerialBackend, Clint, Timebase, CLINT_SIZE, Plic, PLIC_SIZE, Uart, UART_SIZE, TestFinisher, FINISHER_SIZE, Rtc, RtcClock, RTC_SIZE, VIRTIO_MMIO_SIZE};
+use crate::elf::{Elf, ElfError};
+use crate::fdt::FdtNode;
+use crate::isa::{BaseIsa, ExtensionIsa, BASE_ISA_INFO};
+use crate::sbi::Sbi;
+use crate::RiscvEmulator;
+
+
+// Memory map (qemu `virt` machine)
+//
+// | address     | device                        | irq   |
+// |-------------|-------------------------------|-------|
+// | 0x0010_0000 | test finisher                 |       |
+// | 0x0010_1000 | Goldfish RTC                  | 11    |
+// | 0x0200_0000 | CLINT                         |       |
+// | 0x0C00_0000 | PLIC                          |       |
+// | 0x1000_0000 | NS16550A UART                 | 10    |
+// | 0x1000_1000 | 8 virtio-mmio slots of 0x1000 | 1 - 8 |
+// | 0x8000_0000 | RAM                           |       |
+
+pub const VIRT_TEST_BASE: u64 = 0x0010_0000;
+pub const VIRT_RTC_BASE: u64 = 0x0010_1000;
+pub const VIRT_CLINT_BASE: u64 = 0x0200_0000;
+pub const VIRT_PLIC_BASE: u64 = 0x0C00_0000;
+pub const VIRT_UART_BASE: u64 = 0x1000_0000;
+pub const VIRT_VIRTIO_BASE: u64 = 0x1000_1000;
+pub const VIRT_RAM_BASE: u64 = 0x8000_0000;
+
+/// Max number of virtio devices on the `virt` machine
+pub const VIRT_MAX_VIRTIO: usize = 8;
+
+const VIRT_RTC_IRQ: u32 = 11;
+const VIRT_UART_IRQ: u32 = 10;
+const VIRT_VIRTIO_IRQ: u32 = 1;
+const VIRT_PLIC_NUM_SOURCES: usize = 95;
+
+/// Size at the end of RAM reserved for the devicetree and the `fw_dynamic` info
+const VIRT_FDT_RESERVED: u64 = 0x20_0000;
+
+const FW_DYNAMIC_INFO_MAGIC: u64 = 0x4942_534F;
+const FW_DYNAMIC_INFO_VERSION: u64 = 2;
+const FW_DYNAMIC_INFO_NEXT_MODE_S: u64 = 1;
+
+/// Error while setting up a machine
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BootError {
+    Elf(ElfError),
+    /// An image doesn't fit in RAM
+    DoesNotFit(&'static str),
+    TooManyVirtioDevices,
+}
+
+impl fmt::Display for BootError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BootError::Elf(err)             => write!(f, "{err}"),
+            BootError::DoesNotFit(image)    => write!(f, "{image} does not fit in RAM"),
+            BootError::TooManyVirtioDevices => write!(f, "at most {VIRT_MAX_VIRTIO} virtio devices are supported"),
+        }
+    }
+}
+
+impl From<ElfError> for BootError {
+    fn from(err: ElfError) -> Self {
+        BootError::Elf(err)
+    }
+}
+
+/// Configuration of the `virt` machine
+pub struct VirtConfig {
+    pub num_harts: usize,
+    pub base_isa: BaseIsa,
+    /// Extensions of the harts, which are also advertised in the devicetree
+    pub extensions: ExtensionIsa,
+    pub ram_size: u64,
+    pub timebase: Timebase,
+    /// Frequency of `mtime` reported to the guest
+    pub timebase_frequency: u32,
+    pub rtc_clock: RtcClock,
+    /// Backend of the UART
+    pub console: Box<dyn SerialBackend>,
+    /// Virtio-mmio devices, mapped to consecutive slots
+    pub virtio: Vec<Box<dyn Device>>,
+    /// M-mode firmware following the `fw_jump` or `fw_dynamic` conventions, as an ELF file or a raw binary loaded at the start of RAM
+    pub firmware: Option<Vec<u8>>,
+    /// Kernel, as an ELF file or a raw image loaded 2 MiB (4 MiB on RV32) after the start of RAM, where `fw_jump` expects it
+    pub kernel: Option<Vec<u8>>,
+    /// Initial ramdisk, loaded below the devicetree
+    pub initrd: Option<Vec<u8>>,
+    pub bootargs: Option<String>,
+    /// Handle the SBI calls of the kernel in the emulator and start the kernel in S-mode, only used when there is no firmware
+    pub builtin_sbi: bool,
+}
+
+impl VirtConfig {
+    pub fn new(ram_size: u64, console: Box<dyn SerialBackend>) -> Self {
+        Self {
+            num_harts: 1,
+            base_isa: BaseIsa::RV64I,
+            // Only the extensions that are implemented, so software doesn't try to use the others
+            extensions: ExtensionIsa::A | ExtensionIsa::Zicsr | ExtensionIsa::Zifencei | ExtensionIsa::S,
+            ram_size,
+            timebase: Timebase::InstructionsRetired,
+            timebase_frequency: 10_000_000,
+            rtc_clock: RtcClock::Host,
+            console,
+            virtio: Vec::new(),
+            firmware: None,
+            kernel: None,
+            initrd: None,
+            bootargs: None,
+            builtin_sbi: false,
+        }
+    }
+}
+
+impl RiscvEmulator {
+    /// Create a machine modelled on the qemu `virt` machine.
+    ///
+    /// All harts start in M-mode at the entry of the firmware, or the kernel when there is no firmware, with `a0` containing the hart id,
+    /// `a1` the address of the devicetree and `a2` the address of the `fw_dynamic` info, which points the firmware to the kernel.
+    /// When using the built-in SBI, the kernel is started in S-mode on hart 0 instead, with the other harts stopped
+    pub fn new_virt(settings: EmulationSettings, config: VirtConfig) -> Result<Self, BootError> {
+        if config.virtio.len() > VIRT_MAX_VIRTIO {
+            return Err(BootError::TooManyVirtioDevices);
+        }
+        if config.ram_size < VIRT_FDT_RESERVED {
+            return Err(BootError::DoesNotFit("devicetree"));
+        }
+
+        let mut emulator = RiscvEmulator::new_smp(settings, config.num_harts);
+        emulator.set_base_isa(BASE_ISA_INFO[config.base_isa as usize].name);
+        emulator.set_extensions(config.extensions);
+
+        let bus = &mut emulator.memory;
+        bus.ram = vec![0; config.ram_size as usize];
+        bus.ram_base = VIRT_RAM_BASE;
+        bus.add_device(VIRT_TEST_BASE, FINISHER_SIZE, Box::new(TestFinisher::new()));
+        bus.add_device_with_irq(VIRT_RTC_BASE, RTC_SIZE, VIRT_RTC_IRQ, Box::new(Rtc::new(config.rtc_clock)));
+        bus.add_device(VIRT_CLINT_BASE, CLINT_SIZE, Box::new(Clint::new(config.num_harts, config.timebase)));
+        bus.add_device(VIRT_PLIC_BASE, PLIC_SIZE, Box::new(Plic::new(VIRT_PLIC_NUM_SOURCES, config.num_harts)));
+        bus.add_device_with_irq(VIRT_UART_BASE, UART_SIZE, VIRT_UART_IRQ, Box::new(Uart::new(config.console)));
+        for (idx, device) in config.virtio.into_iter().enumerate() {
+            bus.add_device_with_irq(VIRT_VIRTIO_BASE + idx as u64 * VIRTIO_MMIO_SIZE, VIRTIO_MMIO_SIZE, VIRT_VIRTIO_IRQ + idx as u32, device);
+        }
+
+        let ram_end = VIRT_RAM_BASE + config.ram_size;
+        let firmware_entry = match &config.firmware {
+            Some(firmware) => Some(emulator.load_image(firmware, VIRT_RAM_BASE, "firmware")?),
+            None => None,
+        };
+        let is_32_bit = emulator.harts[0].is_32_bit();
+        let kernel_addr = VIRT_RAM_BASE + if is_32_bit { 0x40_0000 } else { 0x20_0000 };
+        let (kernel_entry, kernel_end) = match &config.kernel {
+            Some(kernel) => (emulator.load_image(kernel, kernel_addr, "kernel")?, kernel_addr + kernel.len() as u64),
+            None => (kernel_addr, kernel_addr),
+        };
+        if kernel_end > ram_end - VIRT_FDT_RESERVED {
+            return Err(BootError::DoesNotFit("kernel"));
+        }
+
+        let mut devicetree = emulator.devicetree(config.timebase_frequency, config.bootargs.as_deref());
+        if let Some(initrd) = &config.initrd {
+            let end = ram_end - VIRT_FDT_RESERVED;
+            let start = end.checked_sub(initrd.len() as u64).ok_or(BootError::DoesNotFit("initrd"))? & !0xFFF;
+            if start < kernel_end {
+                return Err(BootError::DoesNotFit("initrd"));
+            }
+            let offset = (start - VIRT_RAM_BASE) as usize;
+            emulator.memory.ram[offset..offset + initrd.len()].copy_from_slice(initrd);
+
+            let chosen = devicetree.child_mut("chosen").unwrap();
+            chosen.property_u64s("linux,initrd-start", &[start])
+                .property_u64s("linux,initrd-end", &[start + initrd.len() as u64]);
+        }
+        Self::add_virt_syscon_nodes(&mut devicetree);
+
+        let devicetree_addr = emulator.load_devicetree(&devicetree);
+
+        // The info is only read by the firmware before it jumps to the kernel, so it can be placed in memory the kernel reuses
+        let info_addr = ram_end - VIRT_FDT_RESERVED;
+        // The boot hart performs the cold boot of the firmware, while the other harts wait for it
+        let boot_hart = emulator.current_hart() as u64;
+        let info = [FW_DYNAMIC_INFO_MAGIC, FW_DYNAMIC_INFO_VERSION, kernel_entry, FW_DYNAMIC_INFO_NEXT_MODE_S, 0, boot_hart];
+        let info = if is_32_bit {
+            info.iter().flat_map(|&field| (field as u32).to_le_bytes()).collect::<Vec<_>>()
+        } else {
+            info.iter().flat_map(|&field| field.to_le_bytes()).collect::<Vec<_>>()
+        };
+        let offset = (info_addr - VIRT_RAM_BASE) as usize;
+        emulator.memory.ram[offset..offset + info.len()].copy_from_slice(&info);
+        for register_file in &mut emulator.harts {
+            register_file.write_x_register(12, info_addr);
+        }
+
+        match firmware_entry {
+            Some(entry) => emulator.set_instruction_pointer(entry as usize),
+            None if config.builtin_sbi => emulator.start_with_sbi(Sbi::new(VIRT_CLINT_BASE, Some(VIRT_UART_BASE)), kernel_entry, devicetree_addr),
+            None => emulator.set_instruction_pointer(kernel_entry as usize),
+        }
+        Ok(emulator)
+    }
+
+    /// Load an ELF file, or a raw binary at `addr`, returning the entry point
+    fn load_image(&mut self, image: &[u8], addr: u64, name: &'static str) -> Result<u64, BootError> {
+        match Elf::parse(image) {
+            Ok(_) => {
+                let elf = self.load_elf(image)?;
+                Ok(elf.entry)
+            },
+            Err(ElfError::InvalidMagic) => {
+                let offset = (addr - self.memory.ram_base) as usize;
+                let dst = self.memory.ram.get_mut(offset..offset + image.len()).ok_or(BootError::DoesNotFit(name))?;
+                dst.copy_from_slice(image);
+                Ok(addr)
+            },
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Add the `syscon-poweroff` and `syscon-reboot` nodes, which use the test finisher
+    fn add_virt_syscon_nodes(devicetree: &mut FdtNode) {
+        let soc = devicetree.child_mut("soc").unwrap();
+        let phandle = 1 + soc.children.iter()
+            .filter_map(|node| node.get_property("phandle"))
+            .map(|value| u32::from_be_bytes(value.try_into().unwrap()))
+            .max()
+            .unwrap_or(0);
+        soc.child_mut(&format!("test@{VIRT_TEST_BASE:x}")).unwrap().property_u32("phandle", phandle);
+
+        let mut poweroff = FdtNode::new("poweroff");
+        poweroff.property_string("compatible", "syscon-poweroff")
+            .property_u32("regmap", phandle)
+            .property_u32("offset", 0)
+            .property_u32("value", 0x5555);
+        let mut reboot = FdtNode::new("reboot");
+        reboot.property_string("compatible", "syscon-reboot")
+            .property_u32("regmap", phandle)
+            .property_u32("offset", 0)
+            .property_u32("value", 0x7777);
+        devicetree.child(poweroff).child(reboot);
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,96 @@
+use emu_cpu::{CpuEmulator, HaltReason, MisalignedPolicy};
+
+use crate::devices::{BufferBackend, VirtioMmio, VirtioRng};
+use crate::fdt::tests::{read_dtb, find_node, cells};
+
+use super::*;
+
+const RAM_SIZE: u64 = 0x100_0000;
+
+fn settings() -> EmulationSettings {
+    EmulationSettings { print_instructions: false, misaligned: MisalignedPolicy::Trap }
+}
+
+/// Boot a machine with firmware powering off the machine using the test finisher, a kernel, an initrd and a virtio device
+fn boot() -> RiscvEmulator {
+    let mut config = VirtConfig::new(RAM_SIZE, Box::new(BufferBackend::new()));
+    // lui   ra, 0x100
+    // lui   sp, 0x5
+    // addi  sp, sp, 0x555
+    // sw    sp, 0(ra)
+    // jal   x0, 0
+    let firmware = [0x001000B7u32, 0x00005137, 0x55510113, 0x0020A023, 0x0000006F];
+    config.firmware = Some(firmware.iter().flat_map(|instr| instr.to_le_bytes()).collect());
+    config.kernel = Some(vec![0x13, 0, 0, 0]);
+    config.initrd = Some(vec![0xAA; 0x1800]);
+    config.bootargs = Some("console=ttyS0".to_string());
+    config.virtio.push(Box::new(VirtioMmio::new(VirtioRng::new(0))));
+    RiscvEmulator::new_virt(settings(), config).unwrap()
+}
+
+#[test]
+fn test_virt_boot() {
+    let emulator = boot();
+    assert_eq!(emulator.harts[0].read_pc(), VIRT_RAM_BASE);
+    assert_eq!(emulator.harts[0].read_x_register(10), 0);
+    assert_eq!(&emulator.memory.ram[0x20_0000..0x20_0004], &[0x13, 0, 0, 0]);
+    assert!(emulator.harts[0].read_x_register(11) > emulator.harts[0].read_x_register(12));
+}
+
+#[test]
+fn test_virt_fw_dynamic_info() {
+    // The info points to the kernel in S-mode
+    let emulator = boot();
+    let info_addr = emulator.harts[0].read_x_register(12);
+    assert_eq!(info_addr, VIRT_RAM_BASE + RAM_SIZE - VIRT_FDT_RESERVED);
+    let offset = (info_addr - VIRT_RAM_BASE) as usize;
+    let info = emulator.memory.ram[offset..offset + 48].chunks(8).map(|field| u64::from_le_bytes(field.try_into().unwrap())).collect::<Vec<_>>();
+    assert_eq!(info, [FW_DYNAMIC_INFO_MAGIC, FW_DYNAMIC_INFO_VERSION, VIRT_RAM_BASE + 0x20_0000, FW_DYNAMIC_INFO_NEXT_MODE_S, 0, 0]);
+}
+
+#[test]
+fn test_virt_initrd() {
+    let emulator = boot();
+    let tree = read_dtb(&emulator, emulator.harts[0].read_x_register(11));
+    let chosen = find_node(&tree, "/chosen");
+    let initrd_start = u64::from_be_bytes(chosen.get_property("linux,initrd-start").unwrap().try_into().unwrap());
+    let initrd_end = u64::from_be_bytes(chosen.get_property("linux,initrd-end").unwrap().try_into().unwrap());
+    assert_eq!(initrd_start & 0xFFF, 0);
+    assert_eq!(initrd_end - initrd_start, 0x1800);
+    assert!(initrd_end <= emulator.harts[0].read_x_register(12));
+    let offset = (initrd_start - VIRT_RAM_BASE) as usize;
+    assert!(emulator.memory.ram[offset..offset + 0x1800].iter().all(|&byte| byte == 0xAA));
+    assert_eq!(chosen.get_property("stdout-path"), Some(&b"/soc/serial@10000000\0"[..]));
+}
+
+#[test]
+fn test_virt_devicetree() {
+    let emulator = boot();
+    let tree = read_dtb(&emulator, emulator.harts[0].read_x_register(11));
+    assert_eq!(find_node(&tree, "/cpus/cpu@0").get_property("riscv,isa"), Some(&b"rv64ia_zicsr_zifencei\0"[..]));
+    let soc = find_node(&tree, "/soc");
+    assert_eq!(soc.children.len(), 6);
+    assert_eq!(cells(find_node(soc, "rtc@101000").get_property("interrupts").unwrap()), [VIRT_RTC_IRQ]);
+    assert_eq!(cells(find_node(soc, "serial@10000000").get_property("interrupts").unwrap()), [VIRT_UART_IRQ]);
+    assert_eq!(cells(find_node(soc, "virtio_mmio@10001000").get_property("interrupts").unwrap()), [VIRT_VIRTIO_IRQ]);
+    let test_phandle = cells(find_node(soc, "test@100000").get_property("phandle").unwrap())[0];
+    assert_eq!(test_phandle, 3);
+    assert_eq!(cells(find_node(&tree, "/poweroff").get_property("regmap").unwrap()), [test_phandle]);
+    assert_eq!(cells(find_node(&tree, "/reboot").get_property("value").unwrap()), [0x7777]);
+}
+
+#[test]
+fn test_virt_poweroff() {
+    let mut emulator = boot();
+    assert_eq!(emulator.execute(Some(100)), HaltReason::Exit(0));
+}
+
+#[test]
+fn test_virt_does_not_fit() {
+    let mut config = VirtConfig::new(RAM_SIZE, Box::new(BufferBackend::new()));
+    config.initrd = Some(vec![0; RAM_SIZE as usize]);
+    assert_eq!(RiscvEmulator::new_virt(settings(), config).err(), Some(BootError::DoesNotFit("initrd")));
+
+    let config = VirtConfig::new(0x1000, Box::new(BufferBackend::new()));
+    assert_eq!(RiscvEmulator::new_virt(settings(), config).err(), Some(BootError::DoesNotFit("devicetree")));
+}